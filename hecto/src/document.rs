@@ -1,10 +1,12 @@
 use std::fs;
 
+use crate::Position;
 use crate::Row;
 
 #[derive(Default)]
 pub struct Document {
     rows: Vec<Row>,
+    file_name: Option<String>,
 }
 
 impl Document {
@@ -14,7 +16,10 @@ impl Document {
         for line in content.lines() {
             rows.push(Row::from(line));
         }
-        Ok(Self { rows })
+        Ok(Self {
+            rows,
+            file_name: Some(filename.to_string()),
+        })
     }
     pub fn row(&self, index: usize) -> Option<&Row> {
         self.rows.get(index)
@@ -25,4 +30,50 @@ impl Document {
     pub fn len(&self) -> usize {
         self.rows.len()
     }
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+    // Returns the whole document as it would be written to disk
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for row in &self.rows {
+            text.push_str(row.as_str());
+            text.push('\n');
+        }
+        text
+    }
+    pub fn insert(&mut self, at: &Position, c: char) {
+        if at.y > self.len() {
+            return;
+        }
+        if c == '\n' {
+            self.insert_newline(at);
+        } else if at.y == self.len() {
+            let mut row = Row::from("");
+            row.insert(0, c);
+            self.rows.push(row);
+        } else {
+            self.rows[at.y].insert(at.x, c);
+        }
+    }
+    fn insert_newline(&mut self, at: &Position) {
+        if at.y == self.len() {
+            self.rows.push(Row::from(""));
+            return;
+        }
+        let new_row = self.rows[at.y].split(at.x);
+        self.rows.insert(at.y + 1, new_row);
+    }
+    // Deletes the character at `at`, joining the next row when `at` is past the end of its row
+    pub fn delete(&mut self, at: &Position) {
+        if at.y >= self.len() {
+            return;
+        }
+        if at.x >= self.rows[at.y].len() && at.y + 1 < self.len() {
+            let next_row = self.rows.remove(at.y + 1);
+            self.rows[at.y].append(&next_row);
+        } else {
+            self.rows[at.y].delete(at.x);
+        }
+    }
 }
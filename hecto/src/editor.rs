@@ -1,18 +1,22 @@
 use crate::document::Document;
+use crate::lsp::{self, Severity};
 use crate::Row;
 use crate::Terminal;
 use std::env;
-use std::io::{self, Write};
+use termion::color;
 use termion::event::Key;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+// room for a diagnostic marker and a space in front of every row, reserved while a language
+// server is running
+const GUTTER_WIDTH: usize = 2;
 
 fn die(e: &std::io::Error) {
     Terminal::clear_screen();
     panic!("{e}");
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -24,11 +28,14 @@ pub struct Editor {
     current_position: Position,
     offset: Position,
     document: Document,
+    lsp: Option<lsp::Client>,
+    message: String,
 }
 
 impl Editor {
     pub fn run(&mut self) {
         loop {
+            self.poll_lsp();
             if let Err(err) = self.refresh_screen() {
                 die(&err);
             }
@@ -40,6 +47,7 @@ impl Editor {
             }
         }
     }
+    #[allow(clippy::should_implement_trait, clippy::missing_panics_doc)]
     pub fn default() -> Self {
         let terminal = Terminal::default().expect("Failed to initialize terminal");
         let args: Vec<String> = env::args().collect();
@@ -48,18 +56,65 @@ impl Editor {
         } else {
             Document::default()
         };
-        Self {
+        let mut message = String::new();
+        let lsp = document
+            .file_name()
+            .map(lsp::Client::for_file)
+            .transpose()
+            .unwrap_or_else(|err| {
+                message = format!("LSP: failed to start language server: {err}");
+                None
+            })
+            .flatten();
+        let mut editor = Self {
             should_quit: false,
             terminal,
             current_position: Position::default(),
             offset: Position::default(),
             document,
-        }
+            lsp,
+            message,
+        };
+        let text = editor.document.text();
+        editor.with_lsp(|lsp| lsp.did_open(&text));
+        editor
     }
     fn process_key_press(&mut self) -> Result<(), std::io::Error> {
         let pressed_key = Terminal::read_key()?;
+        self.message.clear();
         match pressed_key {
             Key::Ctrl('q') => self.should_quit = true,
+            Key::Ctrl('g') => self.go_to_definition(),
+            Key::Ctrl('k') => self.hover(),
+            Key::Char(c) => {
+                self.document.insert(&self.current_position, c);
+                if c == '\n' {
+                    self.current_position = Position {
+                        x: 0,
+                        y: self.current_position.y.saturating_add(1),
+                    };
+                } else {
+                    self.move_cursor(Key::Right);
+                }
+                self.document_changed();
+            }
+            Key::Delete => {
+                self.document.delete(&self.current_position);
+                self.document_changed();
+            }
+            Key::Backspace => {
+                let Position { x, y } = self.current_position;
+                if x > 0 || y > 0 {
+                    self.current_position = if x > 0 {
+                        Position { x: x - 1, y }
+                    } else {
+                        let x = self.document.row(y - 1).map_or(0, Row::len);
+                        Position { x, y: y - 1 }
+                    };
+                    self.document.delete(&self.current_position);
+                    self.document_changed();
+                }
+            }
             Key::Up | Key::Down | Key::Right | Key::Left => self.move_cursor(pressed_key),
             _ => (),
         }
@@ -87,6 +142,89 @@ impl Editor {
         }
         self.current_position = Position { x, y }
     }
+    // Runs `f` against the language server, if there is one. Errors end up in the message bar
+    // instead of killing the editor, since the server is not essential for editing.
+    fn with_lsp<T>(
+        &mut self,
+        f: impl FnOnce(&mut lsp::Client) -> Result<T, std::io::Error>,
+    ) -> Option<T> {
+        let lsp = self.lsp.as_mut()?;
+        match f(lsp) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.message = format!("LSP: {err}");
+                None
+            }
+        }
+    }
+    fn poll_lsp(&mut self) {
+        self.with_lsp(lsp::Client::poll);
+    }
+    fn document_changed(&mut self) {
+        let text = self.document.text();
+        self.with_lsp(|lsp| lsp.did_change(&text));
+    }
+    fn go_to_definition(&mut self) {
+        let position = self.to_lsp_position(self.current_position);
+        match self.with_lsp(|lsp| lsp.definition(&position)) {
+            Some(Some(location)) => {
+                let is_current_document = self.document.file_name().is_some_and(|file_name| {
+                    std::fs::canonicalize(file_name).is_ok_and(|path| path == location.path())
+                });
+                if is_current_document {
+                    self.current_position = self.to_editor_position(location.position);
+                } else {
+                    self.message = format!(
+                        "Definition: {}:{}:{}",
+                        location.path().display(),
+                        location.position.y.saturating_add(1),
+                        location.position.x.saturating_add(1)
+                    );
+                }
+            }
+            Some(None) => self.message = "No definition found".to_string(),
+            None => (),
+        }
+    }
+    fn hover(&mut self) {
+        let position = self.to_lsp_position(self.current_position);
+        match self.with_lsp(|lsp| lsp.hover(&position)) {
+            Some(Some(text)) => {
+                // the message bar is a single line, so we show the first line that is not a
+                // markdown code fence
+                self.message = text
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty() && !line.starts_with("```"))
+                    .unwrap_or_default()
+                    .to_string();
+            }
+            Some(None) => self.message = "No hover information".to_string(),
+            None => (),
+        }
+    }
+    // The language server counts columns in UTF-16 code units, the editor in characters
+    fn to_lsp_position(&self, position: Position) -> Position {
+        let x = self
+            .document
+            .row(position.y)
+            .map_or(position.x, |row| row.utf16_column(position.x));
+        Position { x, y: position.y }
+    }
+    fn to_editor_position(&self, position: Position) -> Position {
+        let x = self
+            .document
+            .row(position.y)
+            .map_or(position.x, |row| row.column_from_utf16(position.x));
+        Position { x, y: position.y }
+    }
+    fn gutter_width(&self) -> usize {
+        if self.lsp.is_some() {
+            GUTTER_WIDTH
+        } else {
+            0
+        }
+    }
     fn refresh_screen(&self) -> Result<(), std::io::Error> {
         Terminal::hide_cursor();
         Terminal::position_cursor(&Position::default());
@@ -95,7 +233,11 @@ impl Editor {
             println!("Goodbye.\r");
         } else {
             self.draw_rows();
-            Terminal::position_cursor(&self.current_position);
+            self.draw_message_bar();
+            Terminal::position_cursor(&Position {
+                x: self.current_position.x.saturating_add(self.gutter_width()),
+                y: self.current_position.y,
+            });
         }
         Terminal::show_cursor();
         Terminal::flush()
@@ -110,11 +252,50 @@ impl Editor {
         welcome_message.truncate(width);
         println!("{}\r", welcome_message);
     }
-    fn draw_row(&self, row: &Row) {
+    fn draw_gutter(&self, y: usize) {
+        let Some(lsp) = &self.lsp else {
+            return;
+        };
+        let severity = lsp
+            .diagnostics()
+            .iter()
+            .filter(|diagnostic| diagnostic.covers_line(y))
+            .map(|diagnostic| diagnostic.severity)
+            .min();
+        match severity {
+            Some(severity @ Severity::Error) => print!(
+                "{}{} {}",
+                color::Fg(color::Red),
+                severity.marker(),
+                color::Fg(color::Reset)
+            ),
+            Some(severity @ Severity::Warning) => print!(
+                "{}{} {}",
+                color::Fg(color::Yellow),
+                severity.marker(),
+                color::Fg(color::Reset)
+            ),
+            Some(severity) => print!("{} ", severity.marker()),
+            None => print!("{}", " ".repeat(GUTTER_WIDTH)),
+        }
+    }
+    fn draw_row(&self, row: &Row, y: usize) {
+        self.draw_gutter(y);
         let start = self.offset.x;
-        let width = self.terminal.size().width as usize;
+        let width = (self.terminal.size().width as usize).saturating_sub(self.gutter_width());
         let end = width + self.offset.x;
-        let row = row.render(start, end);
+        let spans: Vec<(usize, usize)> = self.lsp.as_ref().map_or_else(Vec::new, |lsp| {
+            lsp.diagnostics()
+                .iter()
+                .filter(|diagnostic| diagnostic.covers_line(y))
+                .map(|diagnostic| {
+                    let (from, to) = diagnostic.span_on_line(y);
+                    let from = row.column_from_utf16(from);
+                    (from, row.column_from_utf16(to).max(from.saturating_add(1)))
+                })
+                .collect()
+        });
+        let row = row.render_underlined(start, end, &spans);
         print!("{}\r", row)
     }
     fn scroll(&mut self) {
@@ -137,8 +318,9 @@ impl Editor {
         let height = self.terminal.size().height;
         for terminal_row in 0..height - 1 {
             Terminal::clear_current_line();
-            if let Some(row) = self.document.row(terminal_row as usize + self.offset.y) {
-                self.draw_row(row);
+            let y = terminal_row as usize + self.offset.y;
+            if let Some(row) = self.document.row(y) {
+                self.draw_row(row, y);
             } else if self.document.is_empty() && terminal_row == height / 3 {
                 self.draw_welcome_message();
             } else {
@@ -147,4 +329,27 @@ impl Editor {
             println!("\r");
         }
     }
+    // The last line of the screen shows the latest message, or else the diagnostic under the
+    // cursor
+    fn draw_message_bar(&self) {
+        Terminal::clear_current_line();
+        let y = self.current_position.y;
+        let diagnostic = self.lsp.as_ref().and_then(|lsp| {
+            lsp.diagnostics()
+                .iter()
+                .filter(|diagnostic| diagnostic.covers_line(y))
+                .min_by_key(|diagnostic| diagnostic.severity)
+        });
+        let mut message = match diagnostic {
+            Some(diagnostic) if self.message.is_empty() => diagnostic
+                .message
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            _ => self.message.clone(),
+        };
+        message.truncate(self.terminal.size().width as usize);
+        print!("{message}");
+    }
 }
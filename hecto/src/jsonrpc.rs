@@ -0,0 +1,53 @@
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+/// Writes a single JSON-RPC message using the base protocol framing of LSP: a `Content-Length`
+/// header, an empty line, and then the JSON body.
+///
+/// # Errors
+///
+/// Fails if writing to `writer` fails.
+pub fn write_message(writer: &mut impl Write, message: &Value) -> Result<(), io::Error> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    writer.flush()
+}
+
+/// Reads a single JSON-RPC message. Returns `Ok(None)` once the other side closes the stream.
+///
+/// # Errors
+///
+/// Fails if reading fails or the message is not framed or encoded correctly.
+pub fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>, io::Error> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        // other headers (such as `Content-Type`) are allowed, but we only care about the length
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let content_length = content_length.ok_or_else(|| invalid_data("missing Content-Length"))?;
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(invalid_data)
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
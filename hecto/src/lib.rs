@@ -0,0 +1,11 @@
+#![warn(clippy::all, clippy::pedantic)]
+mod document;
+mod editor;
+pub mod jsonrpc;
+pub mod lsp;
+mod row;
+mod terminal;
+pub use editor::Editor;
+pub use editor::Position;
+pub use row::Row;
+pub use terminal::Terminal;
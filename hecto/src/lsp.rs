@@ -0,0 +1,493 @@
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::jsonrpc;
+use crate::Position;
+
+// how long we wait for the language server to answer a request before giving up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// the server gets less time to answer `shutdown`, so that quitting the editor stays snappy
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Language servers known to the editor, as (file extension, language id, command) triples.
+// The command can be overridden with the `HECTO_LSP` environment variable.
+const SERVERS: &[(&str, &str, &str)] = &[
+    ("rs", "rust", "rust-analyzer"),
+    ("c", "c", "clangd"),
+    ("h", "c", "clangd"),
+    ("cpp", "cpp", "clangd"),
+    ("go", "go", "gopls"),
+    ("py", "python", "pylsp"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error = 1,
+    Warning,
+    Information,
+    Hint,
+}
+
+impl Severity {
+    fn from_lsp(value: Option<u64>) -> Self {
+        match value {
+            Some(2) => Severity::Warning,
+            Some(3) => Severity::Information,
+            Some(4) => Severity::Hint,
+            // the spec lets the client decide when no severity is given, so we assume the worst
+            _ => Severity::Error,
+        }
+    }
+    #[must_use]
+    pub fn marker(self) -> char {
+        match self {
+            Severity::Error => 'E',
+            Severity::Warning => 'W',
+            Severity::Information => 'I',
+            Severity::Hint => 'H',
+        }
+    }
+}
+
+// Positions coming from and going to the server count columns in UTF-16 code units, as the spec
+// requires. `Row` converts them from and to character columns.
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub start: Position,
+    pub end: Position,
+    pub message: String,
+}
+
+impl Diagnostic {
+    #[must_use]
+    pub fn covers_line(&self, y: usize) -> bool {
+        self.start.y <= y && y <= self.end.y
+    }
+    // Returns the UTF-16 columns of row `y` that this diagnostic should underline
+    #[must_use]
+    pub fn span_on_line(&self, y: usize) -> (usize, usize) {
+        let start = if self.start.y == y { self.start.x } else { 0 };
+        let end = if self.end.y == y {
+            self.end.x
+        } else {
+            usize::MAX
+        };
+        // empty ranges still get a single underlined character, otherwise they would be invisible
+        (start, end.max(start.saturating_add(1)))
+    }
+}
+
+pub struct Location {
+    pub uri: String,
+    pub position: Position,
+}
+
+impl Location {
+    /// The path of a `file://` URI, with its percent-encoded bytes decoded
+    #[must_use]
+    pub fn path(&self) -> PathBuf {
+        let path = self.uri.strip_prefix("file://").unwrap_or(&self.uri);
+        PathBuf::from(OsString::from_vec(percent_decode(path)))
+    }
+}
+
+// A client for a single document served by a language server.
+//
+// Messages from the server are read on a background thread and handed over through a channel,
+// so the editor only has to `poll` between key presses to pick up new diagnostics.
+pub struct Client {
+    child: Option<Child>,
+    writer: Box<dyn Write + Send>,
+    incoming: Receiver<Value>,
+    next_id: u64,
+    uri: String,
+    language_id: String,
+    version: i64,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Client {
+    /// Starts the language server for `file_name`, if there is one for its file type.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the server can't be started or doesn't answer `initialize`.
+    pub fn for_file(file_name: &str) -> Result<Option<Self>, io::Error> {
+        let extension = Path::new(file_name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+        let server = SERVERS.iter().find(|(ext, _, _)| *ext == extension);
+        let language_id = server.map_or("plaintext", |(_, language_id, _)| language_id);
+        let command = match (env::var("HECTO_LSP"), server) {
+            (Ok(command), _) => command,
+            (Err(_), Some((_, _, command))) => (*command).to_string(),
+            (Err(_), None) => return Ok(None),
+        };
+        Self::spawn(&command, &path_to_uri(Path::new(file_name)), language_id).map(Some)
+    }
+    /// Spawns `command` and talks to it over its stdin and stdout. The process is killed when
+    /// the client is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `command` is empty or can't be started, or the server doesn't answer
+    /// `initialize`.
+    pub fn spawn(command: &str, uri: &str, language_id: &str) -> Result<Self, io::Error> {
+        let mut args = command.split_whitespace();
+        let program = args
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty LSP command"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // anything the server logs would otherwise end up on top of the editor
+            .stderr(Stdio::null())
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            unreachable!("stdin and stdout are piped");
+        };
+        let mut client = Self::new(stdout, stdin, uri, language_id)?;
+        client.child = Some(child);
+        Ok(client)
+    }
+    /// Creates a client on top of an already connected transport and performs the `initialize`
+    /// handshake.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the server doesn't answer `initialize` in time or answers with an error.
+    pub fn new(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+        uri: &str,
+        language_id: &str,
+    ) -> Result<Self, io::Error> {
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            while let Ok(Some(message)) = jsonrpc::read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut client = Self {
+            child: None,
+            writer: Box::new(writer),
+            incoming,
+            next_id: 0,
+            uri: uri.to_string(),
+            language_id: language_id.to_string(),
+            version: 0,
+            diagnostics: Vec::new(),
+        };
+        let root_uri = env::current_dir().ok().map(|dir| path_to_uri(&dir));
+        client.request(
+            "initialize",
+            &json!({
+                "processId": std::process::id(),
+                "rootUri": root_uri,
+                "capabilities": {
+                    "textDocument": {
+                        "publishDiagnostics": {},
+                        "definition": {},
+                        "hover": { "contentFormat": ["plaintext", "markdown"] },
+                    },
+                },
+            }),
+        )?;
+        client.notify("initialized", &json!({}))?;
+        Ok(client)
+    }
+    /// # Errors
+    ///
+    /// Fails if the notification can't be written to the server.
+    pub fn did_open(&mut self, text: &str) -> Result<(), io::Error> {
+        self.notify(
+            "textDocument/didOpen",
+            &json!({
+                "textDocument": {
+                    "uri": self.uri,
+                    "languageId": self.language_id,
+                    "version": self.version,
+                    "text": text,
+                },
+            }),
+        )
+    }
+    /// We always send the whole document, which every server has to support.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the notification can't be written to the server.
+    pub fn did_change(&mut self, text: &str) -> Result<(), io::Error> {
+        self.version += 1;
+        self.notify(
+            "textDocument/didChange",
+            &json!({
+                "textDocument": { "uri": self.uri, "version": self.version },
+                "contentChanges": [{ "text": text }],
+            }),
+        )
+    }
+    /// Handles everything the server has sent since the last call without blocking. Returns
+    /// whether the diagnostics have changed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a request from the server can't be answered.
+    pub fn poll(&mut self) -> Result<bool, io::Error> {
+        let mut changed = false;
+        while let Ok(message) = self.incoming.try_recv() {
+            changed |= self.handle(&message)?;
+        }
+        Ok(changed)
+    }
+    #[must_use]
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+    /// # Errors
+    ///
+    /// Fails if the server doesn't answer in time or answers with an error.
+    pub fn definition(&mut self, position: &Position) -> Result<Option<Location>, io::Error> {
+        let params = self.position_params(position);
+        let result = self.request("textDocument/definition", &params)?;
+        Ok(parse_location(&result))
+    }
+    /// # Errors
+    ///
+    /// Fails if the server doesn't answer in time or answers with an error.
+    pub fn hover(&mut self, position: &Position) -> Result<Option<String>, io::Error> {
+        let params = self.position_params(position);
+        let result = self.request("textDocument/hover", &params)?;
+        let text = result.get("contents").map(hover_text).unwrap_or_default();
+        Ok(if text.trim().is_empty() {
+            None
+        } else {
+            Some(text)
+        })
+    }
+    fn position_params(&self, position: &Position) -> Value {
+        json!({
+            "textDocument": { "uri": self.uri },
+            "position": { "line": position.y, "character": position.x },
+        })
+    }
+    fn notify(&mut self, method: &str, params: &Value) -> Result<(), io::Error> {
+        let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        jsonrpc::write_message(&mut self.writer, &message)
+    }
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, io::Error> {
+        let id = self.send_request(method, params)?;
+        self.wait_for_response(id, REQUEST_TIMEOUT)
+    }
+    fn send_request(&mut self, method: &str, params: &Value) -> Result<u64, io::Error> {
+        self.next_id += 1;
+        let message = json!({
+            "jsonrpc": "2.0",
+            "id": self.next_id,
+            "method": method,
+            "params": params,
+        });
+        jsonrpc::write_message(&mut self.writer, &message)?;
+        Ok(self.next_id)
+    }
+    // Blocks until the response to request `id` arrives, handling any notifications that the
+    // server sends in the meantime.
+    fn wait_for_response(&mut self, id: u64, timeout: Duration) -> Result<Value, io::Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let message = match self.incoming.recv_timeout(remaining) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "language server did not respond",
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "language server exited",
+                    ))
+                }
+            };
+            if message.get("method").is_none() && message.get("id") == Some(&json!(id)) {
+                if let Some(error) = message.get("error") {
+                    let text = error["message"].as_str().unwrap_or("request failed");
+                    return Err(io::Error::other(text.to_string()));
+                }
+                return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+            }
+            self.handle(&message)?;
+        }
+    }
+    fn handle(&mut self, message: &Value) -> Result<bool, io::Error> {
+        let Some(method) = message.get("method").and_then(Value::as_str) else {
+            // a response to a request we have already given up on
+            return Ok(false);
+        };
+        if let Some(id) = message.get("id") {
+            // The server is asking us something (e.g. `window/workDoneProgress/create`). We do
+            // not support any of these, but an answer keeps the server from waiting forever.
+            let response = json!({ "jsonrpc": "2.0", "id": id, "result": null });
+            jsonrpc::write_message(&mut self.writer, &response)?;
+            return Ok(false);
+        }
+        let params = &message["params"];
+        if method != "textDocument/publishDiagnostics" || params["uri"] != json!(self.uri) {
+            return Ok(false);
+        }
+        self.diagnostics = params["diagnostics"]
+            .as_array()
+            .map(|diagnostics| diagnostics.iter().filter_map(parse_diagnostic).collect())
+            .unwrap_or_default();
+        Ok(true)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Ok(id) = self.send_request("shutdown", &Value::Null) {
+            let _ = self.wait_for_response(id, SHUTDOWN_TIMEOUT);
+        }
+        let _ = self.notify("exit", &Value::Null);
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// Paths can contain any byte but `/` and 0, a URI only the unreserved characters, the rest is
+// percent-encoded
+fn path_to_uri(path: &Path) -> String {
+    let path = path
+        .canonicalize()
+        .or_else(|_| env::current_dir().map(|dir| dir.join(path)))
+        .unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = String::from("file://");
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(char::from(byte));
+        } else {
+            let _ = write!(uri, "%{byte:02X}");
+        }
+    }
+    uri
+}
+
+// Invalid escapes are kept as they are
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) if bytes[i] == b'%' => {
+                decoded.push(byte);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+fn parse_position(value: &Value) -> Option<Position> {
+    let y = usize::try_from(value.get("line")?.as_u64()?).ok()?;
+    let x = usize::try_from(value.get("character")?.as_u64()?).ok()?;
+    Some(Position { x, y })
+}
+
+fn parse_diagnostic(value: &Value) -> Option<Diagnostic> {
+    let range = value.get("range")?;
+    Some(Diagnostic {
+        severity: Severity::from_lsp(value.get("severity").and_then(Value::as_u64)),
+        start: parse_position(range.get("start")?)?,
+        end: parse_position(range.get("end")?)?,
+        message: value.get("message")?.as_str()?.to_string(),
+    })
+}
+
+// The result of `textDocument/definition` can be a single `Location`, a list of them, or a list
+// of `LocationLink`s. We only ever jump to the first one.
+fn parse_location(value: &Value) -> Option<Location> {
+    let value = match value {
+        Value::Array(locations) => locations.first()?,
+        value => value,
+    };
+    let uri = value.get("uri").or_else(|| value.get("targetUri"))?;
+    let range = value
+        .get("range")
+        .or_else(|| value.get("targetSelectionRange"))?;
+    Some(Location {
+        uri: uri.as_str()?.to_string(),
+        position: parse_position(range.get("start")?)?,
+    })
+}
+
+// Hover contents can be a plain string, a `{ language, value }` pair, `MarkupContent`, or a list
+// of the first two.
+fn hover_text(contents: &Value) -> String {
+    match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(hover_text)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Value::Object(object) => object
+            .get("value")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{path_to_uri, Location};
+    use crate::Position;
+    use std::path::Path;
+
+    #[test]
+    fn paths_are_percent_encoded() {
+        let path = Path::new("/tmp/hecto test/ä%.rs");
+        let uri = path_to_uri(path);
+        assert_eq!(uri, "file:///tmp/hecto%20test/%C3%A4%25.rs");
+        let location = Location {
+            uri,
+            position: Position::default(),
+        };
+        assert_eq!(location.path(), path);
+        let location = Location {
+            uri: "file:///tmp/100%.rs".to_string(),
+            position: Position::default(),
+        };
+        assert_eq!(location.path(), Path::new("/tmp/100%.rs"));
+    }
+}
@@ -1,12 +1,5 @@
 #![warn(clippy::all, clippy::pedantic)]
-mod document;
-mod editor;
-mod row;
-mod terminal;
-use editor::Editor;
-pub use editor::Position;
-pub use row::Row;
-pub use terminal::Terminal;
+use hecto::Editor;
 
 fn main() {
     Editor::default().run();
@@ -1,5 +1,7 @@
 use std::cmp;
 
+use termion::style;
+
 pub struct Row {
     string: String,
 }
@@ -13,9 +15,121 @@ impl From<&str> for Row {
 }
 
 impl Row {
+    #[must_use]
     pub fn render(&self, start: usize, end: usize) -> String {
-        let end = cmp::min(end, self.string.len());
+        let end = cmp::min(end, self.len());
         let start = cmp::min(start, end);
-        self.string.get(start..end).unwrap_or_default().to_string()
+        self.string.chars().skip(start).take(end - start).collect()
+    }
+    // Renders the row like `render`, but underlines every column that falls into one of `spans`
+    #[must_use]
+    pub fn render_underlined(&self, start: usize, end: usize, spans: &[(usize, usize)]) -> String {
+        let mut result = String::new();
+        let mut underlined = false;
+        for (column, c) in (start..).zip(self.render(start, end).chars()) {
+            let in_span = spans
+                .iter()
+                .any(|&(from, to)| from <= column && column < to);
+            if in_span != underlined {
+                underlined = in_span;
+                if underlined {
+                    result.push_str(style::Underline.as_ref());
+                } else {
+                    result.push_str(style::NoUnderline.as_ref());
+                }
+            }
+            result.push(c);
+        }
+        if underlined {
+            result.push_str(style::NoUnderline.as_ref());
+        }
+        result
+    }
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
+    // The length in characters, which is what the cursor moves over
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.string.chars().count()
+    }
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.string.is_empty()
+    }
+    pub fn insert(&mut self, at: usize, c: char) {
+        let at = self.byte_index(at);
+        self.string.insert(at, c);
+    }
+    pub fn delete(&mut self, at: usize) {
+        if at < self.len() {
+            let at = self.byte_index(at);
+            self.string.remove(at);
+        }
+    }
+    pub fn append(&mut self, other: &Row) {
+        self.string.push_str(&other.string);
+    }
+    // Cuts the row at `at` and returns everything after it as a new row
+    #[must_use]
+    pub fn split(&mut self, at: usize) -> Row {
+        let at = self.byte_index(at);
+        Row {
+            string: self.string.split_off(at),
+        }
+    }
+    // LSP counts columns in UTF-16 code units, these convert between them and character columns
+    #[must_use]
+    pub fn utf16_column(&self, at: usize) -> usize {
+        self.string.chars().take(at).map(char::len_utf16).sum()
+    }
+    #[must_use]
+    pub fn column_from_utf16(&self, units: usize) -> usize {
+        let mut counted = 0;
+        self.string
+            .chars()
+            .take_while(|c| {
+                counted += c.len_utf16();
+                counted <= units
+            })
+            .count()
+    }
+    // The byte offset of column `at`, or the end of the row if `at` is past it
+    fn byte_index(&self, at: usize) -> usize {
+        self.string
+            .char_indices()
+            .nth(at)
+            .map_or(self.string.len(), |(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Row;
+
+    #[test]
+    fn edits_by_character() {
+        let mut row = Row::from("aé😀b");
+        assert_eq!(row.len(), 4);
+        row.insert(2, 'x');
+        assert_eq!(row.as_str(), "aéx😀b");
+        row.delete(3);
+        assert_eq!(row.as_str(), "aéxb");
+        row.insert(10, 'ü');
+        assert_eq!(row.as_str(), "aéxbü");
+        let rest = row.split(2);
+        assert_eq!((row.as_str(), rest.as_str()), ("aé", "xbü"));
+        assert_eq!(rest.render(1, 3), "bü");
+    }
+
+    #[test]
+    fn converts_utf16_columns() {
+        let row = Row::from("aé😀b");
+        assert_eq!(row.utf16_column(3), 4);
+        assert_eq!(row.utf16_column(10), 5);
+        assert_eq!(row.column_from_utf16(4), 3);
+        assert_eq!(row.column_from_utf16(3), 2);
+        assert_eq!(row.column_from_utf16(usize::MAX), 4);
     }
 }
@@ -0,0 +1,91 @@
+// The tests run without the libtest harness (see Cargo.toml), since this binary is also the mock
+// server the tests talk to: started with `--mock-server`, it serves stdin and stdout instead of
+// running the tests, which would print to stdout as well.
+mod mock_server;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use hecto::lsp::{Client, Severity};
+use hecto::Position;
+
+const URI: &str = "file:///tmp/main.rs";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    if args.next().as_deref() == Some("--mock-server") {
+        let log = args.next().expect("no log file given");
+        mock_server::run(Path::new(&log)).unwrap();
+        return;
+    }
+    let tests: [(&str, fn()); 3] = [
+        (
+            "publishes_diagnostics_for_every_change",
+            publishes_diagnostics_for_every_change,
+        ),
+        ("definition_and_hover", definition_and_hover),
+        (
+            "shuts_the_server_down_and_kills_it_on_drop",
+            shuts_the_server_down_and_kills_it_on_drop,
+        ),
+    ];
+    println!("\nrunning {} tests", tests.len());
+    for (name, test) in tests {
+        print!("test {name} ... ");
+        test();
+        println!("ok");
+    }
+    println!();
+}
+
+// Starts this binary as the mock server, which logs what it receives to the returned file
+fn connect(name: &str) -> (Client, PathBuf) {
+    let log = env::temp_dir().join(format!("hecto-mock-lsp-{}-{name}.log", process::id()));
+    let exe = env::current_exe().unwrap();
+    let command = format!("{} --mock-server {}", exe.display(), log.display());
+    let client = Client::spawn(&command, URI, "rust").unwrap();
+    (client, log)
+}
+
+fn publishes_diagnostics_for_every_change() {
+    let (mut client, _) = connect("diagnostics");
+    client.did_open("fn main() {\n    oops\n}\n").unwrap();
+    // the hover round trip makes sure the diagnostics notification has arrived
+    client.hover(&Position::default()).unwrap();
+    assert_eq!(client.diagnostics().len(), 1);
+    let diagnostic = &client.diagnostics()[0];
+    assert_eq!(diagnostic.severity, Severity::Warning);
+    assert_eq!(diagnostic.message, "version 0");
+    assert!(diagnostic.covers_line(1));
+    assert_eq!(diagnostic.span_on_line(1), (4, 9));
+
+    client.did_change("fn main() {\n    oops!\n}\n").unwrap();
+    client.hover(&Position::default()).unwrap();
+    assert_eq!(client.diagnostics()[0].message, "version 1");
+}
+
+fn definition_and_hover() {
+    let (mut client, _) = connect("definition");
+    let location = client
+        .definition(&Position { x: 5, y: 1 })
+        .unwrap()
+        .unwrap();
+    assert_eq!(location.path(), Path::new("/tmp/main.rs"));
+    assert_eq!((location.position.x, location.position.y), (3, 0));
+    let hover = client.hover(&Position { x: 5, y: 1 }).unwrap();
+    assert_eq!(hover.as_deref(), Some("fn main()"));
+}
+
+fn shuts_the_server_down_and_kills_it_on_drop() {
+    let (client, log) = connect("drop");
+    drop(client);
+    let log = fs::read_to_string(&log).unwrap();
+    let mut lines = log.lines();
+    let pid = lines.next().unwrap();
+    // the mock ignores `exit`, so it is only gone if the client killed it
+    assert!(!Path::new("/proc").join(pid).exists());
+    let methods: Vec<&str> = lines.take(3).collect();
+    assert_eq!(methods, ["initialize", "initialized", "shutdown"]);
+}
@@ -0,0 +1,62 @@
+// A tiny language server for the tests, speaking JSON-RPC over stdio just like a real one. It
+// publishes one diagnostic for every document version it sees, and writes its process id and
+// then every method it receives to the log file. It does not exit on `exit`, so the tests can
+// check that the client kills it.
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+use std::process;
+
+use hecto::jsonrpc;
+use serde_json::{json, Value};
+
+pub fn run(log_path: &Path) -> Result<(), io::Error> {
+    let mut log = File::create(log_path)?;
+    writeln!(log, "{}", process::id())?;
+    let mut reader = BufReader::new(io::stdin());
+    let mut writer = io::stdout();
+    while let Some(message) = jsonrpc::read_message(&mut reader)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        writeln!(log, "{method}")?;
+        let result = match method {
+            "initialize" => json!({ "capabilities": { "definitionProvider": true } }),
+            "textDocument/definition" => json!([{
+                "uri": message["params"]["textDocument"]["uri"],
+                "range": {
+                    "start": { "line": 0, "character": 3 },
+                    "end": { "line": 0, "character": 7 },
+                },
+            }]),
+            "textDocument/hover" => json!({
+                "contents": { "kind": "markdown", "value": "fn main()" },
+            }),
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let text_document = &message["params"]["textDocument"];
+                let version = &text_document["version"];
+                let notification = json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": {
+                        "uri": text_document["uri"],
+                        "diagnostics": [{
+                            "range": {
+                                "start": { "line": 1, "character": 4 },
+                                "end": { "line": 1, "character": 9 },
+                            },
+                            "severity": 2,
+                            "message": format!("version {version}"),
+                        }],
+                    },
+                });
+                jsonrpc::write_message(&mut writer, &notification)?;
+                Value::Null
+            }
+            _ => Value::Null,
+        };
+        if let Some(id) = message.get("id") {
+            let response = json!({ "jsonrpc": "2.0", "id": id, "result": result });
+            jsonrpc::write_message(&mut writer, &response)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,326 @@
+// Builds the FAT32 disk image that the tests attach as a second virtio disk (see the test-args in
+// Cargo.toml). It contains the files in `tests/disk`, so the tests can check what the kernel
+// reads against files that can be looked at on the host.
+//
+// The image is written without any host tools, so that `cargo test` works everywhere QEMU does.
+// It goes to `$OUT_DIR/test-disk.img`, where runner.sh picks it up, since the test-args can't
+// point into the `OUT_DIR` of the build.
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const SECTOR_SIZE: usize = 512;
+// FAT32 needs at least 65525 clusters, with one sector per cluster that's a bit more than 32 MiB.
+// The file is sparse, most of it is never written.
+const TOTAL_SECTORS: u32 = 40 * 1024 * 1024 / SECTOR_SIZE as u32;
+const SECTORS_PER_CLUSTER: u32 = 1;
+const CLUSTER_SIZE: usize = SECTOR_SIZE * SECTORS_PER_CLUSTER as usize;
+const RESERVED_SECTORS: u32 = 32;
+const FAT_COUNT: u32 = 2;
+const ROOT_CLUSTER: u32 = 2;
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+// in the reserved byte of an entry: the base name and the extension are shown in lower case
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+// 2024-01-01, the files get a fixed date so that the image doesn't change between builds
+const DATE: u16 = (2024 - 1980) << 9 | 1 << 5 | 1;
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let source = manifest_dir.join("tests/disk");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", source.display());
+
+    let mut image = Image::new();
+    image.add_directory(&source, ROOT_CLUSTER, 0).unwrap();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    image.write(&out_dir.join("test-disk.img")).unwrap();
+}
+
+fn fat_sectors() -> u32 {
+    // the formula from Microsoft's FAT specification, it can overestimate a little
+    let tmp1 = TOTAL_SECTORS - RESERVED_SECTORS;
+    let tmp2 = (256 * SECTORS_PER_CLUSTER + FAT_COUNT) / 2;
+    tmp1.div_ceil(tmp2)
+}
+
+fn data_start() -> u32 {
+    RESERVED_SECTORS + FAT_COUNT * fat_sectors()
+}
+
+struct Image {
+    fat: Vec<u32>,
+    clusters: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Image {
+    fn new() -> Self {
+        let mut image = Image {
+            // the first two entries hold the media type and the clean shutdown bits
+            fat: vec![0x0fff_fff8, 0xffff_ffff],
+            clusters: BTreeMap::new(),
+        };
+        let root = image.allocate_chain(CLUSTER_SIZE);
+        assert_eq!(root, ROOT_CLUSTER);
+        image
+    }
+
+    // Allocates enough clusters for `size` bytes (at least one) and returns the first one
+    fn allocate_chain(&mut self, size: usize) -> u32 {
+        let count = size.max(1).div_ceil(CLUSTER_SIZE);
+        let first = self.fat.len() as u32;
+        for i in 0..count as u32 {
+            let next = if i + 1 == count as u32 {
+                END_OF_CHAIN
+            } else {
+                first + i + 1
+            };
+            self.fat.push(next);
+        }
+        first
+    }
+
+    // Makes the chain that starts at `first` long enough for `size` bytes. The new clusters come
+    // after all the others, so the chain is not contiguous anymore.
+    fn extend_chain(&mut self, first: u32, size: usize) {
+        let mut last = first;
+        let mut count = 1;
+        while self.fat[last as usize] != END_OF_CHAIN {
+            last = self.fat[last as usize];
+            count += 1;
+        }
+        let missing = size.div_ceil(CLUSTER_SIZE).saturating_sub(count);
+        if missing > 0 {
+            self.fat[last as usize] = self.allocate_chain(missing * CLUSTER_SIZE);
+        }
+    }
+
+    // Writes `data` to the chain that starts at `first`, which must be long enough
+    fn write_chain(&mut self, first: u32, data: &[u8]) {
+        let mut cluster = first;
+        for chunk in data.chunks(CLUSTER_SIZE) {
+            let mut bytes = chunk.to_vec();
+            bytes.resize(CLUSTER_SIZE, 0);
+            self.clusters.insert(cluster, bytes);
+            cluster = self.fat[cluster as usize];
+        }
+    }
+
+    // Writes the entries of the directory at `path` into the chain at `cluster`, which is extended
+    // if they don't fit. Subdirectories get a chain of their own.
+    fn add_directory(&mut self, path: &Path, cluster: u32, parent: u32) -> io::Result<()> {
+        let mut children: Vec<_> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+        children.sort_by_key(|entry| entry.file_name());
+
+        let mut entries = Vec::new();
+        if cluster != ROOT_CLUSTER {
+            entries.push(short_entry(*b".          ", ATTR_DIRECTORY, 0, cluster, 0));
+            // a `..` that points to the root directory uses cluster 0
+            let parent = if parent == ROOT_CLUSTER { 0 } else { parent };
+            entries.push(short_entry(*b"..         ", ATTR_DIRECTORY, 0, parent, 0));
+        }
+        let mut short_names = Vec::new();
+        for child in children {
+            let name = child
+                .file_name()
+                .into_string()
+                .expect("file name is not UTF-8");
+            let metadata = child.metadata()?;
+            let (short_name, case, long_name) = short_name(&name, &short_names);
+            short_names.push(short_name);
+            let (attributes, first, size) = if metadata.is_dir() {
+                let first = self.allocate_chain(CLUSTER_SIZE);
+                self.add_directory(&child.path(), first, cluster)?;
+                (ATTR_DIRECTORY, first, 0)
+            } else {
+                let data = fs::read(child.path())?;
+                let first = self.allocate_chain(data.len());
+                self.write_chain(first, &data);
+                (ATTR_ARCHIVE, first, data.len() as u32)
+            };
+            if long_name {
+                entries.extend(long_name_entries(&name, &short_name));
+            }
+            entries.push(short_entry(short_name, attributes, case, first, size));
+        }
+        let data: Vec<u8> = entries.concat();
+        self.extend_chain(cluster, data.len());
+        self.write_chain(cluster, &data);
+        Ok(())
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        file.set_len(u64::from(TOTAL_SECTORS) * SECTOR_SIZE as u64)?;
+        let boot_sector = boot_sector();
+        let fs_info = fs_info(self.fat.len() as u32);
+        // sector 6 holds a copy of the boot sector and the FSInfo sector
+        for start in [0, 6] {
+            write_at(&mut file, start, &boot_sector)?;
+            write_at(&mut file, start + 1, &fs_info)?;
+        }
+        let fat: Vec<u8> = self
+            .fat
+            .iter()
+            .flat_map(|entry| entry.to_le_bytes())
+            .collect();
+        for i in 0..FAT_COUNT {
+            write_at(&mut file, RESERVED_SECTORS + i * fat_sectors(), &fat)?;
+        }
+        for (&cluster, data) in &self.clusters {
+            let sector = data_start() + (cluster - 2) * SECTORS_PER_CLUSTER;
+            write_at(&mut file, sector, data)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_at(file: &mut fs::File, sector: u32, data: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(u64::from(sector) * SECTOR_SIZE as u64))?;
+    file.write_all(data)
+}
+
+fn boot_sector() -> [u8; SECTOR_SIZE] {
+    let mut sector = [0; SECTOR_SIZE];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        sector[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    put(0, &[0xeb, 0x58, 0x90]);
+    put(3, b"RUST-OS ");
+    put(11, &(SECTOR_SIZE as u16).to_le_bytes());
+    put(13, &[SECTORS_PER_CLUSTER as u8]);
+    put(14, &(RESERVED_SECTORS as u16).to_le_bytes());
+    put(16, &[FAT_COUNT as u8]);
+    // media descriptor of a fixed disk
+    put(21, &[0xf8]);
+    put(24, &32u16.to_le_bytes());
+    put(26, &64u16.to_le_bytes());
+    put(32, &TOTAL_SECTORS.to_le_bytes());
+    put(36, &fat_sectors().to_le_bytes());
+    put(44, &ROOT_CLUSTER.to_le_bytes());
+    // the FSInfo sector and the backup of the boot sector
+    put(48, &1u16.to_le_bytes());
+    put(50, &6u16.to_le_bytes());
+    put(64, &[0x80]);
+    put(66, &[0x29]);
+    put(67, &0x2024_0101u32.to_le_bytes());
+    put(71, b"RUST-OS    ");
+    put(82, b"FAT32   ");
+    put(510, &[0x55, 0xaa]);
+    sector
+}
+
+fn fs_info(used_clusters: u32) -> [u8; SECTOR_SIZE] {
+    let clusters = (TOTAL_SECTORS - data_start()) / SECTORS_PER_CLUSTER;
+    let mut sector = [0; SECTOR_SIZE];
+    sector[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    sector[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    // the free clusters and where to look for the next one
+    sector[488..492].copy_from_slice(&(clusters + 2 - used_clusters).to_le_bytes());
+    sector[492..496].copy_from_slice(&used_clusters.to_le_bytes());
+    sector[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+    sector
+}
+
+fn short_entry(name: [u8; 11], attributes: u8, case: u8, cluster: u32, size: u32) -> Vec<u8> {
+    let mut entry = vec![0; 32];
+    entry[0..11].copy_from_slice(&name);
+    entry[11] = attributes;
+    entry[12] = case;
+    entry[16..18].copy_from_slice(&DATE.to_le_bytes());
+    entry[18..20].copy_from_slice(&DATE.to_le_bytes());
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[24..26].copy_from_slice(&DATE.to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+// Returns the 8.3 name of `name`, the case flags and whether it needs a long name as well
+fn short_name(name: &str, taken: &[[u8; 11]]) -> ([u8; 11], u8, bool) {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+    let fits = |part: &str, len: usize| {
+        part.len() <= len
+            && part
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"_-~!#$%&'()@^`{}".contains(&b))
+    };
+    let single_case =
+        |part: &str| part == part.to_ascii_lowercase() || part == part.to_ascii_uppercase();
+    let mut short = [b' '; 11];
+    if fits(base, 8) && fits(ext, 3) && single_case(base) && single_case(ext) {
+        short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+        let mut case = 0;
+        if base != base.to_ascii_uppercase() {
+            case |= CASE_LOWER_BASE;
+        }
+        if ext != ext.to_ascii_uppercase() {
+            case |= CASE_LOWER_EXT;
+        }
+        return (short, case, false);
+    }
+
+    // like Windows does it: the first valid characters of the base name and `~n`
+    let keep = |part: &str| -> Vec<u8> {
+        part.bytes()
+            .filter(|b| b.is_ascii_alphanumeric())
+            .map(|b| b.to_ascii_uppercase())
+            .collect()
+    };
+    let (base, ext) = (keep(base), keep(ext));
+    for n in 1.. {
+        let suffix = format!("~{}", n);
+        let base_len = base.len().min(8 - suffix.len());
+        short = [b' '; 11];
+        short[..base_len].copy_from_slice(&base[..base_len]);
+        short[base_len..base_len + suffix.len()].copy_from_slice(suffix.as_bytes());
+        let ext_len = ext.len().min(3);
+        short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+        if !taken.contains(&short) {
+            break;
+        }
+    }
+    (short, 0, true)
+}
+
+// The long name entries come before the 8.3 entry, in reverse order: the entry with the end of
+// the name first
+fn long_name_entries(name: &str, short_name: &[u8; 11]) -> Vec<Vec<u8>> {
+    let checksum = short_name
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b));
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    // the name ends with a 0, unless it fills the last entry, and is padded with 0xffff
+    if !chars.len().is_multiple_of(13) {
+        chars.push(0);
+    }
+    while !chars.len().is_multiple_of(13) {
+        chars.push(0xffff);
+    }
+    let count = chars.len() / 13;
+    let mut entries = Vec::new();
+    for (i, part) in chars.chunks(13).enumerate() {
+        let mut entry = vec![0; 32];
+        entry[0] = (i + 1) as u8 | if i + 1 == count { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+        for (&offset, c) in offsets.iter().zip(part) {
+            entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    entries.reverse();
+    entries
+}
@@ -0,0 +1,17 @@
+#!/bin/sh
+# The runner for `cargo run` and `cargo test` (see .cargo/config.toml). It starts QEMU through
+# `bootimage runner`, which adds the run-args or test-args from Cargo.toml, and attaches the FAT32
+# image that build.rs writes to its OUT_DIR to the tests as the second virtio disk (see
+# tests/fat32.rs). The image is the same for every build of the crate, so if there are several
+# build directories, e.g. one per feature set, the newest image is used.
+set -e
+kernel="$1"
+case "$kernel" in
+*/deps/*)
+    # the tests are in target/<target>/<profile>/deps, the build script output next to them
+    profile_dir=$(dirname "$(dirname "$kernel")")
+    image=$(ls -t "$profile_dir"/build/rust-os-*/out/test-disk.img | head -n 1)
+    exec bootimage runner "$@" -drive "format=raw,file=$image,if=virtio,readonly=on"
+    ;;
+esac
+exec bootimage runner "$@"
@@ -0,0 +1,239 @@
+/**
+ * A minimal parser for the ACPI tables we need: the RSDP to find the root table, the MADT
+ * (Multiple APIC Description Table) which lists the processors, the local APIC address, the
+ * IOAPICs and how the legacy ISA interrupts are wired to them, and the MCFG, which tells where the
+ * PCI configuration space is memory-mapped.
+ *
+ * The tables live in normal memory, so they are read through the physical memory mapping of the
+ * bootloader (see `memory::phys_to_virt`).
+ */
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::PhysAddr;
+
+use crate::memory::phys_to_virt;
+
+// every system description table starts with this header
+const SDT_HEADER_SIZE: u64 = 36;
+
+// Reads a value from physical memory. ACPI tables are packed, so the reads can be unaligned.
+//
+// This function is unsafe because the caller must guarantee that the address is backed by memory.
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr::<T>())
+}
+
+unsafe fn checksum_ok(addr: PhysAddr, len: u64) -> bool {
+    let mut sum: u8 = 0;
+    for i in 0..len {
+        sum = sum.wrapping_add(read::<u8>(addr + i));
+    }
+    sum == 0
+}
+
+// Searches the places where the BIOS can put the RSDP (Root System Description Pointer): the
+// first KiB of the EBDA (Extended BIOS Data Area) and the read-only BIOS area below 1 MiB.
+fn find_rsdp() -> Option<PhysAddr> {
+    // the real mode segment of the EBDA is stored at 0x40E
+    let ebda = u64::from(unsafe { read::<u16>(PhysAddr::new(0x40e)) }) << 4;
+    let ebda_range = (ebda..ebda + 1024).step_by(16);
+    let bios_range = (0xe0000..0x100000).step_by(16);
+    ebda_range
+        .chain(bios_range)
+        .map(PhysAddr::new)
+        .find(|&addr| unsafe { read::<[u8; 8]>(addr) == *b"RSD PTR " && checksum_ok(addr, 20) })
+}
+
+// Returns the physical address of the ACPI table with the given signature (e.g. `b"APIC"` for
+// the MADT), or `None` if the firmware did not provide one.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp = find_rsdp()?;
+    let revision: u8 = unsafe { read(rsdp + 15u64) };
+    // ACPI 2.0 added the XSDT with 64 bit pointers, older versions only have the RSDT
+    let (root, entry_size, root_signature) = if revision >= 2 {
+        (
+            PhysAddr::new(unsafe { read::<u64>(rsdp + 24u64) }),
+            8,
+            b"XSDT",
+        )
+    } else {
+        (
+            PhysAddr::new(u64::from(unsafe { read::<u32>(rsdp + 16u64) })),
+            4,
+            b"RSDT",
+        )
+    };
+    // like the RSDP, the root table is only used if its checksum is right
+    let root_len = u64::from(unsafe { read::<u32>(root + 4u64) });
+    let root_ok =
+        unsafe { read::<[u8; 4]>(root) == *root_signature && checksum_ok(root, root_len) };
+    if !root_ok || root_len < SDT_HEADER_SIZE {
+        return None;
+    }
+    let entries = (root_len - SDT_HEADER_SIZE) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + SDT_HEADER_SIZE + i * entry_size;
+            if entry_size == 8 {
+                PhysAddr::new(unsafe { read::<u64>(entry) })
+            } else {
+                PhysAddr::new(u64::from(unsafe { read::<u32>(entry) }))
+            }
+        })
+        .find(|&table| unsafe {
+            read::<[u8; 4]>(table) == *signature
+                && checksum_ok(table, u64::from(read::<u32>(table + 4u64)))
+        })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    // the first global system interrupt handled by this IOAPIC
+    pub gsi_base: u32,
+}
+
+// Describes an ISA interrupt that is not connected to the IOAPIC input with the same number, or
+// that does not use the ISA default of an active high, edge triggered signal.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    // whether the system also has the legacy 8259 PICs, which then have to be disabled
+    pub has_8259: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    pub fn parse() -> Option<Madt> {
+        let table = find_table(b"APIC")?;
+        let len = u64::from(unsafe { read::<u32>(table + 4u64) });
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(unsafe {
+                read::<u32>(table + SDT_HEADER_SIZE)
+            })),
+            has_8259: unsafe { read::<u32>(table + SDT_HEADER_SIZE + 4u64) } & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        // the fixed fields are followed by a list of variable sized entries
+        let mut offset = SDT_HEADER_SIZE + 8;
+        while offset + 2 <= len {
+            let entry = table + offset;
+            let (entry_type, entry_len) = unsafe { (read::<u8>(entry), read::<u8>(entry + 1u64)) };
+            if entry_len < 2 {
+                break;
+            }
+            unsafe { madt.parse_entry(entry_type, entry) };
+            offset += u64::from(entry_len);
+        }
+        Some(madt)
+    }
+
+    unsafe fn parse_entry(&mut self, entry_type: u8, entry: PhysAddr) {
+        match entry_type {
+            // processor local APIC
+            0 => {
+                let flags: u32 = read(entry + 4u64);
+                // bit 0: enabled, bit 1: can be enabled later
+                if flags & 0b11 != 0 {
+                    self.processors.push(Processor {
+                        acpi_id: read(entry + 2u64),
+                        apic_id: read(entry + 3u64),
+                    });
+                }
+            }
+            1 => self.io_apics.push(IoApic {
+                id: read(entry + 2u64),
+                address: PhysAddr::new(u64::from(read::<u32>(entry + 4u64))),
+                gsi_base: read(entry + 8u64),
+            }),
+            // interrupt source override
+            2 => {
+                let flags: u16 = read(entry + 8u64);
+                self.overrides.push(InterruptOverride {
+                    source: read(entry + 3u64),
+                    gsi: read(entry + 4u64),
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                });
+            }
+            // 64 bit local APIC address override
+            5 => self.local_apic_address = PhysAddr::new(read(entry + 4u64)),
+            _ => {}
+        }
+    }
+
+    // Returns how the given ISA IRQ is connected to the IOAPICs
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .find(|o| o.source == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                source: irq,
+                gsi: u32::from(irq),
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+}
+
+// A range of PCI buses whose configuration space is memory-mapped (ECAM, the "enhanced
+// configuration access mechanism"). Every function of the buses has 4 KiB of registers there.
+#[derive(Debug, Clone, Copy)]
+pub struct EcamRegion {
+    // the address of the registers of bus 0, even if the region starts at a later bus
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[derive(Debug)]
+pub struct Mcfg {
+    pub regions: Vec<EcamRegion>,
+}
+
+impl Mcfg {
+    // Returns `None` if there is no MCFG, e.g. on QEMU's default i440FX machine, which only
+    // supports the legacy configuration ports
+    pub fn parse() -> Option<Mcfg> {
+        let table = find_table(b"MCFG")?;
+        let len = u64::from(unsafe { read::<u32>(table + 4u64) });
+        // the header is followed by 8 reserved bytes and then one 16 byte entry per region
+        let mut regions = Vec::new();
+        let mut offset = SDT_HEADER_SIZE + 8;
+        while offset + 16 <= len {
+            let entry = table + offset;
+            regions.push(unsafe {
+                EcamRegion {
+                    base: PhysAddr::new(read(entry)),
+                    segment: read(entry + 8u64),
+                    start_bus: read(entry + 10u64),
+                    end_bus: read(entry + 11u64),
+                }
+            });
+            offset += 16;
+        }
+        Some(Mcfg { regions })
+    }
+}
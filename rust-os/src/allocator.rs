@@ -1,54 +1,397 @@
 pub mod bump;
 pub mod fixed_size_block;
+pub mod linked_list;
 
-use linked_list_allocator::LockedHeap;
-use spin::MutexGuard;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, OffsetPageTable, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
-use self::bump::BumpAllocator;
+use crate::{
+    memory::{self, BootInfoFrameAllocator},
+    sync::{IrqSafeMutex, IrqSafeMutexGuard},
+};
+use fixed_size_block::BLOCK_SIZES;
+
+// The allocator behind `#[global_allocator]` is picked with a cargo feature, see Cargo.toml.
+// Without any of the features we fall back to the BumpAllocator.
+#[cfg(all(
+    feature = "linked_list_allocator",
+    feature = "fixed_size_block_allocator"
+))]
+compile_error!("only one of the allocator features can be enabled at a time");
+
+#[cfg(not(any(
+    feature = "linked_list_allocator",
+    feature = "fixed_size_block_allocator"
+)))]
+pub type GlobalAllocator = bump::BumpAllocator;
+#[cfg(feature = "linked_list_allocator")]
+pub type GlobalAllocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "fixed_size_block_allocator")]
+pub type GlobalAllocator = fixed_size_block::FixedSizeBlockAllocator;
 
 // calling Box::new() will use this allocator to allocate and deallocate dynamic memory (from the Heap region)
 #[global_allocator]
-static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+static ALLOCATOR: Heap<GlobalAllocator> = Heap::new(GlobalAllocator::new());
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
+// the size of the heap that is mapped up front by `init_heap`
 pub const HEAP_SIZE: usize = 100 * 1024;
+// the heap is never grown beyond this size, unless the limit is changed with `set_heap_limit`
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+// the heap grows by at least this much at once, so that it doesn't have to grow for every
+// allocation
+const HEAP_GROW_STEP: usize = 64 * 1024;
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+// the sizes in `BLOCK_SIZES`, and one for the larger allocations, which the statistics count
+// separately for every allocator
+pub const SIZE_CLASSES: usize = BLOCK_SIZES.len() + 1;
+// In debug mode, freed memory is filled with this byte, so that a use after free reads something
+// that stands out instead of the old data. The allocator then writes its own data over the start.
+pub const POISON: u8 = 0x6b;
 
-// This function creates a virtual memory region for the Heap and maps it to physical memory
+// Whether freed memory is poisoned and checked for double frees. On by default in debug builds,
+// like the lock order checker.
+static DEBUG: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+
+// This function creates a virtual memory region for the Heap and maps it to physical memory.
+//
+// The page table and the frame allocator are handed over to the kernel memory (see
+// `memory::with_kernel_memory`), so that the heap can map more pages when it runs out.
 pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
 ) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let page_start: Page = Page::containing_address(heap_start);
-        let page_end = Page::containing_address(heap_end);
-        Page::range_inclusive(page_start, page_end)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+    memory::set_kernel_memory(mapper, frame_allocator);
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    memory::with_kernel_memory(|memory| unsafe {
+        memory::map_range(
+            heap_start,
+            HEAP_SIZE as u64,
+            HEAP_FLAGS,
+            &mut memory.mapper,
+            &mut memory.frame_allocator,
+        )
+    })?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.allocator.lock().init(HEAP_START, HEAP_SIZE);
     }
+    ALLOCATOR.size.store(HEAP_SIZE, Ordering::Relaxed);
     Ok(())
 }
 
-// a wrapper around spin::Mutex to permit trait implementations.
+// Returns the number of bytes that are currently allocated on the heap
+pub fn heap_used() -> usize {
+    ALLOCATOR.used.load(Ordering::Relaxed)
+}
+
+// Returns the number of bytes that are mapped for the heap
+pub fn heap_size() -> usize {
+    ALLOCATOR.size.load(Ordering::Relaxed)
+}
+
+pub fn heap_limit() -> usize {
+    ALLOCATOR.limit.load(Ordering::Relaxed)
+}
+
+// Changes the size up to which the heap can grow. A limit below the current size only prevents
+// further growth.
+pub fn set_heap_limit(limit: usize) {
+    ALLOCATOR.limit.store(limit, Ordering::Relaxed);
+}
+
+pub fn debug_mode() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+// Switches the poisoning of freed memory and the double free check on or off. Memory freed while
+// it was off isn't poisoned, but double frees of it are still detected.
+pub fn set_debug_mode(enabled: bool) {
+    DEBUG.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the freed memory at `ptr` is still filled with `POISON`, apart from the first 16 bytes,
+/// where the allocators may keep their lists. For the tests of the debug mode: the memory is read
+/// under the allocator's lock, so it can't be handed out again in the meantime.
+///
+/// # Safety
+///
+/// The caller must guarantee that the `size` bytes at `ptr` were allocated on the heap and are
+/// freed now.
+#[cfg(debug_assertions)]
+pub unsafe fn is_poisoned(ptr: *const u8, size: usize) -> bool {
+    let _allocator = ALLOCATOR.allocator.lock();
+    (16..size).all(|i| ptr::read_volatile(ptr.add(i)) == POISON)
+}
+
+// Returns the statistics of the heap. Doesn't wait for the allocator if another CPU or the
+// interrupted code holds its lock, e.g. in the panic handler, its part is missing then.
+pub fn stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+// The index into `HeapStats::classes` for an allocation
+fn size_class(layout: &Layout) -> usize {
+    fixed_size_block::list_index(layout).unwrap_or(BLOCK_SIZES.len())
+}
+
+// Implemented by the allocators, so that the heap can hand them more memory
+pub trait Growable {
+    /// Adds the memory region starting at `start`, which is right after the current end of the
+    /// heap, to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the region is mapped and unused.
+    unsafe fn extend(&mut self, start: usize, size: usize);
+}
+
+// Implemented by the allocators, so that the heap can free memory while it holds the allocator's
+// lock for the double free check
+pub trait Deallocate {
+    /// Frees the allocation at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `ptr` was allocated with the same `layout` and was not freed
+    /// yet.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+}
+
+// Implemented by the allocators, so that the heap can report on their free memory and check the
+// memory that is freed
+pub trait Instrumented {
+    fn stats(&self) -> AllocatorStats;
+
+    // Whether the memory of the allocation at `ptr` is free already, so that freeing it would be
+    // a double free
+    fn is_free(&self, ptr: *mut u8, layout: Layout) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    // the bytes requested by the allocations that are alive
+    pub used: usize,
+    // the most bytes that were in use at once
+    pub peak: usize,
+    // the bytes mapped for the heap
+    pub size: usize,
+    pub classes: [ClassStats; SIZE_CLASSES],
+    // `None` if the allocator was locked
+    pub allocator: Option<AllocatorStats>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    // the largest allocation of the class, `None` for the ones larger than every block size
+    pub block_size: Option<usize>,
+    // the allocations since boot, and how many of them are alive
+    pub allocations: usize,
+    pub live: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocatorStats {
+    // the bytes that can be allocated without growing the heap, including the free blocks of the
+    // fixed size block allocator
+    pub free: usize,
+    // the largest allocation that fits without growing the heap
+    pub largest_free: usize,
+    // the number of free blocks of every size in `BLOCK_SIZES`, only the fixed size block
+    // allocator has them
+    pub free_blocks: [usize; BLOCK_SIZES.len()],
+    // the allocations that the fixed size block allocator passed on to its fallback allocator,
+    // because they were too large or there was no free block of their size
+    pub fallback_allocations: usize,
+}
+
+impl AllocatorStats {
+    // The share of the free memory, in percent, that is not part of the largest free piece. A
+    // heap with a high fragmentation has to grow for a large allocation although enough is free.
+    pub fn fragmentation(&self) -> usize {
+        match self.free {
+            0 => 0,
+            free => 100 - self.largest_free * 100 / free,
+        }
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "heap: {} of {} bytes used, at most {}",
+            self.used, self.size, self.peak
+        )?;
+        writeln!(f, "  size   allocations   alive")?;
+        for class in self.classes.iter().filter(|class| class.allocations > 0) {
+            match class.block_size {
+                Some(size) => write!(f, "  {:>5}", size)?,
+                None => write!(f, "  {:>5}", "more")?,
+            }
+            writeln!(f, " {:>13} {:>7}", class.allocations, class.live)?;
+        }
+        let allocator = match self.allocator {
+            Some(allocator) => allocator,
+            None => return write!(f, "the allocator is locked"),
+        };
+        write!(
+            f,
+            "{} bytes free, the largest piece {} ({}% fragmentation)",
+            allocator.free,
+            allocator.largest_free,
+            allocator.fragmentation()
+        )?;
+        if allocator.fallback_allocations > 0 || allocator.free_blocks.iter().any(|&n| n > 0) {
+            write!(
+                f,
+                "\n{} fallback allocations, free blocks:",
+                allocator.fallback_allocations
+            )?;
+            for (size, count) in BLOCK_SIZES.iter().zip(allocator.free_blocks) {
+                if count > 0 {
+                    write!(f, " {}x{}", count, size)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Wraps the selected allocator to keep track of how much memory is in use, and to map more memory
+// after the end of the heap when the allocator runs out. Only the requested sizes are counted,
+// not the padding an allocator might add.
+//
+// The counters are updated outside of the allocator's lock, so the statistics are only a snapshot
+// while other CPUs allocate.
+pub struct Heap<A> {
+    allocator: Locked<A>,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    size: AtomicUsize,
+    limit: AtomicUsize,
+    // by size class
+    allocations: [AtomicUsize; SIZE_CLASSES],
+    live: [AtomicUsize; SIZE_CLASSES],
+}
+
+impl<A> Heap<A> {
+    pub const fn new(allocator: A) -> Self {
+        Heap {
+            allocator: Locked::new(allocator),
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            size: AtomicUsize::new(0),
+            limit: AtomicUsize::new(HEAP_MAX_SIZE),
+            allocations: [const { AtomicUsize::new(0) }; SIZE_CLASSES],
+            live: [const { AtomicUsize::new(0) }; SIZE_CLASSES],
+        }
+    }
+}
+
+impl<A: Instrumented> Heap<A> {
+    fn stats(&self) -> HeapStats {
+        let mut classes = [ClassStats::default(); SIZE_CLASSES];
+        for (i, class) in classes.iter_mut().enumerate() {
+            class.block_size = BLOCK_SIZES.get(i).copied();
+            class.allocations = self.allocations[i].load(Ordering::Relaxed);
+            class.live = self.live[i].load(Ordering::Relaxed);
+        }
+        HeapStats {
+            used: self.used.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            size: self.size.load(Ordering::Relaxed),
+            classes,
+            allocator: self.allocator.try_lock().map(|allocator| allocator.stats()),
+        }
+    }
+}
+
+impl<A: Growable> Heap<A> {
+    // Maps enough memory for the given allocation after the end of the heap. Returns whether the
+    // heap has grown.
+    fn grow(&self, layout: Layout) -> bool {
+        // the allocation might need padding for its alignment
+        let needed = layout.size() + layout.align();
+        let grow_by = align_up(needed.max(HEAP_GROW_STEP), 4096);
+        // the kernel memory lock also makes sure that the heap is only grown once at a time
+        let grown = memory::try_with_kernel_memory(|memory| {
+            let size = self.size.load(Ordering::Relaxed);
+            if size == 0 || size + grow_by > self.limit.load(Ordering::Relaxed) {
+                return false;
+            }
+            let start = HEAP_START + size;
+            let mapped = unsafe {
+                memory::map_range(
+                    VirtAddr::new(start as u64),
+                    grow_by as u64,
+                    HEAP_FLAGS,
+                    &mut memory.mapper,
+                    &mut memory.frame_allocator,
+                )
+            };
+            // `map_range` unmaps the part it mapped before failing, so the heap stays as it was
+            if mapped.is_err() {
+                return false;
+            }
+            unsafe { self.allocator.lock().extend(start, grow_by) };
+            self.size.store(size + grow_by, Ordering::Relaxed);
+            true
+        });
+        grown == Some(true)
+    }
+}
+
+unsafe impl<A: Growable + Instrumented + Deallocate> GlobalAlloc for Heap<A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = self.allocator.alloc(layout);
+        if ptr.is_null() && self.grow(layout) {
+            ptr = self.allocator.alloc(layout);
+        }
+        if !ptr.is_null() {
+            let used = self.used.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            self.peak.fetch_max(used, Ordering::Relaxed);
+            let class = size_class(&layout);
+            self.allocations[class].fetch_add(1, Ordering::Relaxed);
+            self.live[class].fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // the check, the poisoning and the freeing happen under one lock, so that another CPU
+        // can't free or reuse the memory in between
+        let mut allocator = self.allocator.lock();
+        if debug_mode() {
+            if allocator.is_free(ptr, layout) {
+                // the lock is released before panicking, so the panic handler can still allocate
+                drop(allocator);
+                panic!("double free of {} bytes at {:p}", layout.size(), ptr);
+            }
+            ptr::write_bytes(ptr, POISON, layout.size());
+        }
+        allocator.deallocate(ptr, layout);
+        drop(allocator);
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.live[size_class(&layout)].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// a wrapper around IrqSafeMutex to permit trait implementations. An interrupt handler may
+// allocate, so the interrupted code must not hold the lock.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqSafeMutex<A>,
 }
 
 impl<A> Locked<A> {
@@ -56,12 +399,16 @@ impl<A> Locked<A> {
     // ALLOCATOR
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqSafeMutex::new(inner),
         }
     }
-    pub fn lock(&self) -> MutexGuard<A> {
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, A> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
 }
 
 // Align the given address `addr` upwards to alignment `align`.
@@ -1,6 +1,6 @@
 use core::{alloc::GlobalAlloc, ptr};
 
-use super::{align_up, Locked};
+use super::{align_up, AllocatorStats, Deallocate, Growable, Instrumented, Locked};
 
 /// A Bump Allocator is a very simple allocator that only allows the heap to grow linearly.
 /// `next` will always point to the boundary between used and unused memory.
@@ -47,6 +47,41 @@ impl BumpAllocator {
     }
 }
 
+impl Growable for BumpAllocator {
+    unsafe fn extend(&mut self, start: usize, size: usize) {
+        // the heap only grows at its end, so `next` can simply continue into the new memory
+        assert_eq!(start, self.heap_end);
+        self.heap_end += size;
+    }
+}
+
+impl Instrumented for BumpAllocator {
+    fn stats(&self) -> AllocatorStats {
+        // the freed memory only comes back once everything is freed
+        let free = self.heap_end - self.next;
+        AllocatorStats {
+            free,
+            largest_free: free,
+            ..AllocatorStats::default()
+        }
+    }
+
+    // Only catches the double frees after everything was freed, or of memory that was never
+    // allocated, since the allocator doesn't know which of the allocations below `next` are alive.
+    fn is_free(&self, ptr: *mut u8, _layout: core::alloc::Layout) -> bool {
+        self.allocations == 0 || ptr as usize >= self.next
+    }
+}
+
+impl Deallocate for BumpAllocator {
+    unsafe fn deallocate(&mut self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     // we can only get immutable reference to self in this trait function because we are defining
     // the allocator as a static variable and static variables are immutable.
@@ -69,11 +104,6 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         }
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let mut bump = self.lock(); // get a mutable reference
-
-        bump.allocations -= 1;
-        if bump.allocations == 0 {
-            bump.next = bump.heap_start;
-        }
+        self.lock().deallocate(ptr, layout)
     }
 }
@@ -1,3 +1,5 @@
+use super::linked_list::LinkedListAllocator;
+
 pub struct ListNode {
     next: Option<&'static mut ListNode>,
 }
@@ -6,11 +8,13 @@ pub struct ListNode {
 ///
 /// The sizes must each be power of 2 because they are also used as
 /// the block alignment (alignments must be always powers of 2).
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap,
+    fallback_allocator: LinkedListAllocator,
+    // how often `fallback_alloc` was called, for the statistics
+    fallback_allocations: usize,
 }
 
 impl FixedSizeBlockAllocator {
@@ -19,7 +23,8 @@ impl FixedSizeBlockAllocator {
         const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty(),
+            fallback_allocator: LinkedListAllocator::new(),
+            fallback_allocations: 0,
         }
     }
 
@@ -34,29 +39,66 @@ impl FixedSizeBlockAllocator {
 }
 
 use alloc::alloc::Layout;
-use core::ptr;
 
 impl FixedSizeBlockAllocator {
     /// Allocates using the fallback allocator.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
-        }
+        self.fallback_allocations += 1;
+        self.fallback_allocator.allocate(layout)
     }
 }
 
 /// Choose an appropriate block size for the given layout.
 ///
 /// Returns an index into the `BLOCK_SIZES` array.
-fn list_index(layout: &Layout) -> Option<usize> {
+pub(super) fn list_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
-use super::Locked;
+use super::{AllocatorStats, Deallocate, Growable, Instrumented, Locked};
 use alloc::alloc::GlobalAlloc;
-use core::{mem, ptr::NonNull};
+use core::mem;
+
+impl Growable for FixedSizeBlockAllocator {
+    unsafe fn extend(&mut self, start: usize, size: usize) {
+        // new blocks are taken from the fallback allocator once the lists are empty
+        self.fallback_allocator.extend(start, size);
+    }
+}
+
+impl FixedSizeBlockAllocator {
+    fn blocks(&self, index: usize) -> impl Iterator<Item = &ListNode> {
+        core::iter::successors(self.list_heads[index].as_deref(), |node| {
+            node.next.as_deref()
+        })
+    }
+}
+
+impl Instrumented for FixedSizeBlockAllocator {
+    fn stats(&self) -> AllocatorStats {
+        let mut stats = self.fallback_allocator.stats();
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            let count = self.blocks(index).count();
+            stats.free_blocks[index] = count;
+            stats.free += count * block_size;
+            if count > 0 {
+                stats.largest_free = stats.largest_free.max(block_size);
+            }
+        }
+        stats.fallback_allocations = self.fallback_allocations;
+        stats
+    }
+
+    fn is_free(&self, ptr: *mut u8, layout: Layout) -> bool {
+        match list_index(&layout) {
+            Some(index) => self
+                .blocks(index)
+                .any(|node| node as *const ListNode as *mut u8 == ptr),
+            None => self.fallback_allocator.is_free(ptr, layout),
+        }
+    }
+}
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
@@ -83,23 +125,25 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
+        self.lock().deallocate(ptr, layout)
+    }
+}
+
+impl Deallocate for FixedSizeBlockAllocator {
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
         match list_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
+                    next: self.list_heads[index].take(),
                 };
                 // verify that block has size and alignment required for storing node
                 assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
                 assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
                 let new_node_ptr = ptr as *mut ListNode;
                 new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
-            None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
             }
+            None => self.fallback_allocator.deallocate(ptr, layout),
         }
     }
 }
@@ -0,0 +1,234 @@
+use core::{alloc::GlobalAlloc, mem, ptr};
+
+use super::{align_up, AllocatorStats, Deallocate, Growable, Instrumented, Locked};
+
+/// A Linked List Allocator keeps track of the free memory regions by storing a `ListNode` at the
+/// start of every one of them. The list is kept sorted by address, so that a freed region can be
+/// merged with its neighbours and the heap does not fall apart into small pieces over time.
+///
+///  head
+///  |
+///  v      ------------          ----------------
+/// |  | -> |size|next| ------> |size|next| ...  |
+///         ------------          ----------------
+///         ^free region          ^free region
+pub struct LinkedListAllocator {
+    // a dummy node with size 0 that points to the first free region
+    head: ListNode,
+}
+
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkedListAllocator {
+    // This function is defined as a const fn so that it can be used for initializing the static
+    // ALLOCATOR
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ListNode::new(0),
+        }
+    }
+
+    /// Adds the heap to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The caller needs to guarantee that the memory region given by the heap_start and heap_size
+    /// bound is a valid one and is unused. It must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    // Adds the given memory region to the list, merging it with the free regions right before and
+    // after it.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // the freed region must be capable of holding a ListNode
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // find the last region that starts before `addr`, so that the list stays sorted
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        let mut size = size;
+        let mut next = current.next.take();
+        // merge with the following region
+        if next
+            .as_ref()
+            .is_some_and(|next| addr + size == next.start_addr())
+        {
+            let following = next.unwrap();
+            size += following.size;
+            next = following.next.take();
+        }
+        // merge with the preceding region (the dummy head has size 0 and is never merged)
+        if current.size > 0 && current.end_addr() == addr {
+            current.size += size;
+            current.next = next;
+        } else {
+            let mut node = ListNode::new(size);
+            node.next = next;
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+        }
+    }
+
+    // Looks for a free region with the given size and alignment and removes it from the list.
+    //
+    // Returns the region and the start address of the allocation inside of it.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                // the region is suitable -> remove it from the list
+                let next = region.next.take();
+                let region = current.next.take().unwrap();
+                current.next = next;
+                return Some((region, alloc_start));
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+        None
+    }
+
+    // Tries to use the given region for an allocation with the given size and alignment.
+    //
+    // Returns the allocation start address on success.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let front_padding = alloc_start - region.start_addr();
+        if front_padding > 0 && front_padding < mem::size_of::<ListNode>() {
+            // the padding in front of the allocation would be too small to be put back into the
+            // list, so we move the allocation further up
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            // region too small
+            return Err(());
+        }
+
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            // the rest of the region is too small to hold a ListNode, which is required because
+            // the allocation splits the region in a used and a free part
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    // Adjusts the given layout so that the resulting allocated memory region is also capable of
+    // storing a `ListNode` once it is freed.
+    fn size_align(layout: core::alloc::Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+
+    pub fn allocate(&mut self, layout: core::alloc::Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+
+        match self.find_region(size, align) {
+            Some((region, alloc_start)) => {
+                let region_start = region.start_addr();
+                let region_end = region.end_addr();
+                let alloc_end = alloc_start + size;
+                // give the unused parts before and after the allocation back to the list
+                unsafe {
+                    if alloc_start > region_start {
+                        self.add_free_region(region_start, alloc_start - region_start);
+                    }
+                    if region_end > alloc_end {
+                        self.add_free_region(alloc_end, region_end - alloc_end);
+                    }
+                }
+                alloc_start as *mut u8
+            }
+            None => ptr::null_mut(),
+        }
+    }
+}
+
+impl Deallocate for LinkedListAllocator {
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: core::alloc::Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+}
+
+impl Growable for LinkedListAllocator {
+    unsafe fn extend(&mut self, start: usize, size: usize) {
+        // merged with the last free region if that one reaches up to the old end of the heap
+        self.add_free_region(start, size);
+    }
+}
+
+impl LinkedListAllocator {
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        core::iter::successors(self.head.next.as_deref(), |region| region.next.as_deref())
+    }
+}
+
+impl Instrumented for LinkedListAllocator {
+    fn stats(&self) -> AllocatorStats {
+        let mut stats = AllocatorStats::default();
+        for region in self.regions() {
+            stats.free += region.size;
+            stats.largest_free = stats.largest_free.max(region.size);
+        }
+        stats
+    }
+
+    fn is_free(&self, ptr: *mut u8, layout: core::alloc::Layout) -> bool {
+        // after a merge, the allocation is somewhere inside of a free region
+        let (size, _) = Self::size_align(layout);
+        let (start, end) = (ptr as usize, ptr as usize + size);
+        self.regions()
+            .any(|region| region.start_addr() < end && start < region.end_addr())
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+}
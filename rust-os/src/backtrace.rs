@@ -0,0 +1,110 @@
+/**
+ * Stack walking through the frame pointer chain.
+ *
+ * The kernel is compiled with `-C force-frame-pointers=yes` (see .cargo/config.toml), so every
+ * function starts with `push rbp; mov rbp, rsp`. This turns the stack into a linked list: `rbp`
+ * points to the saved `rbp` of the caller, and the return address sits right above it.
+ *
+ *           |  ...           |
+ *           | return address |  <- rbp + 8
+ *   rbp ->  | caller's rbp   |  -> next frame
+ *           | locals         |
+ *
+ * The return addresses are looked up in the kernel's symbol table, which `symbols::load` reads
+ * from the kernel file that the bootloader left in memory, and printed as demangled function names
+ * with offsets.
+ */
+use core::{arch::asm, fmt};
+
+pub mod symbols;
+
+// we stop after this many frames, in case the chain is corrupted and loops
+const MAX_FRAMES: usize = 32;
+// a frame bigger than this is more likely a corrupted `rbp` than a real frame
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+// Returns the frame pointer of the calling function
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// Walks the frame pointer chain starting at `rbp` and returns an iterator over the return
+/// addresses.
+///
+/// # Safety
+///
+/// The caller must guarantee that `rbp` is a frame pointer of the current stack (e.g. from
+/// `frame_pointer`). The chain itself is checked for plausibility before every read, but a
+/// corrupted stack can still make us read garbage.
+pub unsafe fn walk(rbp: u64) -> Frames {
+    Frames { rbp, depth: 0 }
+}
+
+pub struct Frames {
+    rbp: u64,
+    depth: usize,
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.rbp == 0 || !self.rbp.is_multiple_of(8) || self.depth >= MAX_FRAMES {
+            return None;
+        }
+        let frame = self.rbp as *const u64;
+        let (next_rbp, return_address) = unsafe { (*frame, *frame.add(1)) };
+        // the stack grows downwards, so the caller's frame must be above ours
+        if next_rbp <= self.rbp || next_rbp - self.rbp > MAX_FRAME_SIZE {
+            self.rbp = 0;
+        } else {
+            self.rbp = next_rbp;
+        }
+        if return_address == 0 {
+            return None;
+        }
+        self.depth += 1;
+        Some(return_address)
+    }
+}
+
+// Prints a backtrace, one frame per line. `first` is the address the backtrace starts at (e.g.
+// the faulting instruction) and `rbp` the frame pointer of the function it belongs to.
+pub struct Backtrace {
+    pub first: Option<u64>,
+    pub rbp: u64,
+}
+
+impl Backtrace {
+    // The backtrace of the calling function, starting at its caller
+    #[inline(always)]
+    pub fn here() -> Self {
+        Backtrace {
+            first: None,
+            rbp: frame_pointer(),
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        let first = self.first.map(|address| (address, address));
+        // a return address points after the call, which can be the start of the next function
+        let returns = unsafe { walk(self.rbp) }.map(|address| (address, address - 1));
+        for (i, (address, inside)) in first.into_iter().chain(returns).enumerate() {
+            write!(f, "  #{:<2} {:#018x}", i, address)?;
+            if let Some(symbol) = symbols::lookup(inside) {
+                let offset = symbol.offset + (address - inside);
+                // the alternate form leaves out the hashes
+                let name = rustc_demangle::demangle(symbol.name);
+                write!(f, " {:#}+{:#x}", name, offset)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
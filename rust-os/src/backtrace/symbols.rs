@@ -0,0 +1,146 @@
+// The kernel's ELF symbol table, which gives the addresses in a backtrace their function names.
+//
+// The bootloader loads the whole kernel file into memory and maps the segments from there, and the
+// memory map marks the file as `Kernel`. Only the debug information is stripped from the file when
+// the boot image is built, so it still has its `.symtab` and `.strtab` sections, which we find
+// through the section headers. The frame allocator only hands out `Usable` frames, so the file is
+// never overwritten.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use core::{slice, str};
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+// the section type of `.symtab`
+const SHT_SYMTAB: u32 = 2;
+// the symbol type of functions, in the low four bits of `st_info`
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24;
+
+static SYMBOLS: OnceCell<SymbolTable> = OnceCell::uninit();
+
+#[derive(Debug)]
+pub enum LoadError {
+    // no `Kernel` region of the memory map starts with an ELF header
+    NoKernelFile,
+    // the file has no symbol table, or its section headers point outside of it
+    NoSymbolTable,
+}
+
+struct SymbolTable {
+    // the `Elf64_Sym` entries
+    symbols: &'static [u8],
+    // the names that `st_name` points into
+    names: &'static [u8],
+}
+
+// The function an address belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    // the mangled name, `rustc_demangle::demangle` turns it into a path
+    pub name: &'static str,
+    // the distance of the address from the start of the function
+    pub offset: u64,
+}
+
+/// Finds the kernel file in the memory map and loads its symbol table. Returns the number of
+/// functions in it. Backtraces only show addresses until this was called.
+///
+/// # Safety
+///
+/// `memory::init` must have been called before, since the file is read through the physical
+/// memory mapping, and the memory map has to be the one that the bootloader passed to the kernel.
+pub unsafe fn load(memory_map: &MemoryMap) -> Result<usize, LoadError> {
+    let file = memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Kernel)
+        .map(|region| {
+            let start = region.range.start_frame_number * 4096;
+            let len = (region.range.end_frame_number * 4096 - start) as usize;
+            let start = memory::phys_to_virt(PhysAddr::new(start));
+            slice::from_raw_parts(start.as_ptr::<u8>(), len)
+        })
+        .find(|region| region.starts_with(ELF_MAGIC))
+        .ok_or(LoadError::NoKernelFile)?;
+    let table = symbol_table(file).ok_or(LoadError::NoSymbolTable)?;
+    let table = SYMBOLS.get_or_init(|| table);
+    Ok(table.functions().count())
+}
+
+// Finds `.symtab` and the string table it links to in the ELF file
+fn symbol_table(file: &'static [u8]) -> Option<SymbolTable> {
+    // only 64 bit little endian files
+    if file.get(4..6) != Some(&[2, 1]) {
+        return None;
+    }
+    let section_headers = read_u64(file, 0x28)? as usize;
+    let header_size = read_u16(file, 0x3a)? as usize;
+    let header_count = read_u16(file, 0x3c)? as usize;
+    let section = |index: usize| -> Option<(u32, &'static [u8], usize)> {
+        let header = section_headers.checked_add(index.checked_mul(header_size)?)?;
+        let kind = read_u32(file, header + 4)?;
+        let offset = read_u64(file, header + 0x18)? as usize;
+        let size = read_u64(file, header + 0x20)? as usize;
+        let link = read_u32(file, header + 0x28)? as usize;
+        Some((kind, file.get(offset..offset.checked_add(size)?)?, link))
+    };
+    let (symbols, link) = (0..header_count).find_map(|index| match section(index)? {
+        (SHT_SYMTAB, data, link) => Some((data, link)),
+        _ => None,
+    })?;
+    let (_, names, _) = section(link)?;
+    Some(SymbolTable { symbols, names })
+}
+
+// Returns the function that contains `address`, if the symbol table was loaded
+pub fn lookup(address: u64) -> Option<Symbol> {
+    let table = SYMBOLS.try_get().ok()?;
+    table
+        .functions()
+        .find(|&(start, size, _)| start <= address && address - start < size)
+        .and_then(|(start, _, name)| {
+            Some(Symbol {
+                name: table.name(name)?,
+                offset: address - start,
+            })
+        })
+}
+
+impl SymbolTable {
+    // The start, the size and the name offset of every function
+    fn functions(&self) -> impl Iterator<Item = (u64, u64, usize)> + '_ {
+        self.symbols
+            .chunks_exact(SYMBOL_SIZE)
+            .filter(|symbol| symbol[4] & 0xf == STT_FUNC)
+            .filter_map(|symbol| {
+                let name = read_u32(symbol, 0)? as usize;
+                Some((read_u64(symbol, 8)?, read_u64(symbol, 16)?, name))
+            })
+    }
+
+    // The names are null-terminated
+    fn name(&self, offset: usize) -> Option<&'static str> {
+        let names: &'static [u8] = self.names;
+        let name = names.get(offset..)?;
+        let len = name.iter().position(|&byte| byte == 0)?;
+        str::from_utf8(&name[..len]).ok()
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
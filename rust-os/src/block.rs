@@ -0,0 +1,130 @@
+/**
+ * Block devices: disks that are read and written in fixed size blocks.
+ *
+ * The drivers implement `BlockDevice` and register their devices here, so that e.g. a file system
+ * can be mounted without knowing which driver is behind a disk. Reads and writes are futures,
+ * which the drivers complete from their device interrupt, so a task waiting for the disk doesn't
+ * keep the executor from running the other tasks.
+ */
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use core::{future::Future, pin::Pin};
+use spin::Mutex;
+
+// every block device uses blocks of this size, like the sectors of most disks
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    // the blocks are not all on the device
+    OutOfRange,
+    // the length of the buffer is not a multiple of the block size
+    UnalignedBuffer,
+    ReadOnly,
+    // the memory for the request could not be allocated
+    OutOfMemory,
+    // the device reported an error
+    Device,
+}
+
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BlockError>> + Send + 'a>>;
+
+pub trait BlockDevice: Send + Sync {
+    // the name it is registered under, like `vda`
+    fn name(&self) -> &str;
+
+    fn block_count(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    // Reads `buf.len() / BLOCK_SIZE` blocks starting at block `start`
+    fn read_blocks<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a>;
+
+    // Writes `buf.len() / BLOCK_SIZE` blocks starting at block `start`
+    fn write_blocks<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a>;
+}
+
+// Checks that a buffer of `len` bytes covers whole blocks that are all on the device, for the
+// implementations of `BlockDevice`
+pub fn check_request(device: &dyn BlockDevice, start: u64, len: usize) -> Result<(), BlockError> {
+    if !len.is_multiple_of(BLOCK_SIZE) {
+        return Err(BlockError::UnalignedBuffer);
+    }
+    let end = start.checked_add((len / BLOCK_SIZE) as u64);
+    match end {
+        Some(end) if end <= device.block_count() => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+pub fn register(device: Arc<dyn BlockDevice>) {
+    log::info!(
+        "block device {}: {} KiB{}",
+        device.name(),
+        device.block_count() * BLOCK_SIZE as u64 / 1024,
+        if device.read_only() {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    DEVICES.lock().push(device);
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+// A block device in memory, for tests and for scratch space
+pub struct RamDisk {
+    name: String,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    pub fn new(name: &str, block_count: usize) -> Self {
+        RamDisk {
+            name: String::from(name),
+            data: Mutex::new(vec![0; block_count * BLOCK_SIZE]),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            check_request(self, start, buf.len())?;
+            let offset = start as usize * BLOCK_SIZE;
+            buf.copy_from_slice(&self.data.lock()[offset..offset + buf.len()]);
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            check_request(self, start, buf.len())?;
+            let offset = start as usize * BLOCK_SIZE;
+            self.data.lock()[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(())
+        })
+    }
+}
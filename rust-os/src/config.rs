@@ -0,0 +1,176 @@
+/**
+ * The boot configuration, read from `/rust-os.cfg` once the root file system is mounted.
+ *
+ * The file has one `key = value` setting per line, `#` starts a comment. Settings that are not
+ * in the file keep the defaults that are compiled in. The known keys are:
+ * - `layout`: the keyboard layout, one of the names the `layout` shell command lists
+ * - `ip`: the address of the network interface with the length of the network prefix, like
+ *   `10.0.2.15/24`
+ * - `gateway`: the address of the router
+ * - `console`: `framebuffer` to draw the console on a framebuffer instead of the VGA text mode,
+ *   or `text`
+ */
+use crate::{
+    framebuffer, fs,
+    keyboard::{self, Layout},
+    net::{self, ipv4::Ipv4Address},
+    vga_buffer,
+};
+
+pub const PATH: &str = "/rust-os.cfg";
+
+// the resolution of the framebuffer console, 128 columns and 59 rows of text
+const FRAMEBUFFER_WIDTH: usize = 1024;
+const FRAMEBUFFER_HEIGHT: usize = 768;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub layout: Option<Layout>,
+    // the address and the prefix length
+    pub ip: Option<(Ipv4Address, u8)>,
+    pub gateway: Option<Ipv4Address>,
+    pub console: Option<Console>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Text,
+    Framebuffer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    // the line is not a comment, but has no `=`
+    MissingEquals,
+    UnknownKey,
+    InvalidValue,
+}
+
+// Parses the settings. A line that can't be parsed is skipped and passed to `error` with its
+// line number, starting at 1.
+pub fn parse(text: &str, mut error: impl FnMut(usize, ParseError)) -> Config {
+    let mut config = Config::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = match line.split_once('#') {
+            Some((setting, _comment)) => setting,
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                error(i + 1, ParseError::MissingEquals);
+                continue;
+            }
+        };
+        match key {
+            "layout" => match Layout::from_name(value) {
+                Some(layout) => config.layout = Some(layout),
+                None => error(i + 1, ParseError::InvalidValue),
+            },
+            "ip" => match parse_ip(value) {
+                Some(ip) => config.ip = Some(ip),
+                None => error(i + 1, ParseError::InvalidValue),
+            },
+            "gateway" => match Ipv4Address::parse(value) {
+                Some(gateway) => config.gateway = Some(gateway),
+                None => error(i + 1, ParseError::InvalidValue),
+            },
+            "console" => match value {
+                "text" => config.console = Some(Console::Text),
+                "framebuffer" => config.console = Some(Console::Framebuffer),
+                _ => error(i + 1, ParseError::InvalidValue),
+            },
+            _ => error(i + 1, ParseError::UnknownKey),
+        }
+    }
+    config
+}
+
+// An address with the prefix length, like `10.0.2.15/24`
+fn parse_ip(value: &str) -> Option<(Ipv4Address, u8)> {
+    let (address, prefix_len) = value.split_once('/')?;
+    let prefix_len = prefix_len.parse().ok().filter(|&len| len <= 32)?;
+    Some((Ipv4Address::parse(address)?, prefix_len))
+}
+
+// Reads the configuration from the root file system and applies it. Without a file, nothing
+// changes.
+pub async fn load() {
+    let text = match fs::read_file(PATH).await {
+        Ok(text) => text,
+        Err(fs::FsError::NotFound) | Err(fs::FsError::NotMounted) => return,
+        Err(error) => {
+            log::warn!("{}: {}", PATH, error.as_str());
+            return;
+        }
+    };
+    let text = match core::str::from_utf8(&text) {
+        Ok(text) => text,
+        Err(_) => {
+            log::warn!("{}: not UTF-8", PATH);
+            return;
+        }
+    };
+    let config = parse(text, |line, error| {
+        log::warn!("{}:{}: {:?}, line ignored", PATH, line, error)
+    });
+    log::info!("loaded the configuration from {}", PATH);
+    if let Some(layout) = config.layout {
+        keyboard::set_layout(layout);
+    }
+    if config.ip.is_some() || config.gateway.is_some() {
+        let mut ip_config = net::config();
+        if let Some((address, prefix_len)) = config.ip {
+            ip_config.address = address;
+            ip_config.prefix_len = prefix_len;
+        }
+        ip_config.gateway = config.gateway.or(ip_config.gateway);
+        net::set_config(ip_config);
+    }
+    // the console starts in text mode
+    if config.console == Some(Console::Framebuffer) {
+        match framebuffer::init(FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT) {
+            Some(framebuffer) => vga_buffer::use_framebuffer(framebuffer),
+            None => log::warn!("no framebuffer, the console stays in text mode"),
+        }
+    }
+}
+
+#[test_case]
+fn test_parse() {
+    let text = "# a comment\n\n  layout = dvorak  # the comment is ignored\nip = 192.168.1.2/16\n";
+    let config = parse(text, |_, _| panic!("unexpected error"));
+    assert_eq!(config.layout, Some(Layout::Dvorak));
+    assert_eq!(config.ip, Some((Ipv4Address([192, 168, 1, 2]), 16)));
+    assert_eq!(config.gateway, None);
+    assert_eq!(config.console, None);
+    let config = parse("console = framebuffer", |_, _| panic!("unexpected error"));
+    assert_eq!(config.console, Some(Console::Framebuffer));
+}
+
+#[test_case]
+fn test_parse_errors() {
+    let text = "layout = qwertz\nlayout\nspeed = 3\nlayout = uk\nip = 10.0.2.15/33\nip = 10.0.2.15";
+    let mut errors = [None; 5];
+    let mut count = 0;
+    let config = parse(text, |line, error| {
+        errors[count] = Some((line, error));
+        count += 1;
+    });
+    // the lines with errors are skipped, the others still count
+    assert_eq!(config.layout, Some(Layout::Uk));
+    assert_eq!(
+        errors,
+        [
+            Some((1, ParseError::InvalidValue)),
+            Some((2, ParseError::MissingEquals)),
+            Some((3, ParseError::UnknownKey)),
+            Some((5, ParseError::InvalidValue)),
+            Some((6, ParseError::InvalidValue)),
+        ]
+    );
+}
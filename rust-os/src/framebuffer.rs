@@ -0,0 +1,341 @@
+/**
+ * Graphics on a linear framebuffer: a block of memory with a few bytes for every pixel on the
+ * screen, row after row.
+ *
+ * With UEFI there is no VGA text mode, the firmware (GOP) sets up such a framebuffer and the
+ * bootloader passes it on. Our bootloader only starts kernels from the BIOS, so for now `init`
+ * gets a framebuffer from the display adapter of QEMU and Bochs instead (see `bochs.rs`). A
+ * framebuffer from anywhere else only needs its `FrameBufferInfo` and its memory.
+ *
+ * `vga_buffer::use_framebuffer` moves the console, and with it `println!`, onto a framebuffer.
+ */
+use core::{fmt, slice};
+use x86_64::VirtAddr;
+
+use crate::vga_buffer::cp437;
+
+mod bochs;
+pub mod font;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // red in the lowest byte, like the GOP's `PixelRedGreenBlueReserved8BitPerColor`
+    Rgb,
+    // blue in the lowest byte, which is what most adapters use
+    Bgr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBufferInfo {
+    pub width: usize,
+    pub height: usize,
+    // the number of pixels from the start of one row to the start of the next, which can be more
+    // than the width
+    pub stride: usize,
+    // 3 or 4, the fourth byte is unused
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+impl FrameBufferInfo {
+    pub fn size(&self) -> usize {
+        self.stride * self.height * self.bytes_per_pixel
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+
+    pub const fn new(red: u8, green: u8, blue: u8) -> Rgb {
+        Rgb { red, green, blue }
+    }
+}
+
+// Tries to get a framebuffer, in the given resolution if the display can choose it. Needs the
+// PCI devices and the kernel memory.
+pub fn init(width: usize, height: usize) -> Option<FrameBuffer> {
+    bochs::init(width, height)
+}
+
+pub struct FrameBuffer {
+    info: FrameBufferInfo,
+    buffer: &'static mut [u8],
+}
+
+impl FrameBuffer {
+    /// Takes over the framebuffer at `start`, e.g. the one the bootloader set up.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `start` is the mapped framebuffer described by `info`, and
+    /// that nothing else accesses it.
+    pub unsafe fn new(info: FrameBufferInfo, start: VirtAddr) -> FrameBuffer {
+        FrameBuffer {
+            info,
+            buffer: slice::from_raw_parts_mut(start.as_mut_ptr(), info.size()),
+        }
+    }
+
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    // the bytes of a pixel, in the order of the framebuffer
+    fn encode(&self, color: Rgb) -> [u8; 4] {
+        match self.info.format {
+            PixelFormat::Rgb => [color.red, color.green, color.blue, 0],
+            PixelFormat::Bgr => [color.blue, color.green, color.red, 0],
+        }
+    }
+
+    fn offset(&self, x: usize, y: usize) -> usize {
+        (y * self.info.stride + x) * self.info.bytes_per_pixel
+    }
+
+    // Sets a pixel, pixels outside of the screen are ignored
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x < self.info.width && y < self.info.height {
+            let bytes_per_pixel = self.info.bytes_per_pixel;
+            let offset = self.offset(x, y);
+            let pixel = self.encode(color);
+            self.buffer[offset..offset + bytes_per_pixel]
+                .copy_from_slice(&pixel[..bytes_per_pixel]);
+        }
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        if x >= self.info.width || y >= self.info.height {
+            return None;
+        }
+        let offset = self.offset(x, y);
+        let pixel = &self.buffer[offset..offset + 3];
+        Some(match self.info.format {
+            PixelFormat::Rgb => Rgb::new(pixel[0], pixel[1], pixel[2]),
+            PixelFormat::Bgr => Rgb::new(pixel[2], pixel[1], pixel[0]),
+        })
+    }
+
+    // Fills a rectangle, the part outside of the screen is cut off
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let pixel = self.encode(color);
+        // saturating, so that a rectangle that reaches past `usize::MAX` is cut off as well
+        let right = x.saturating_add(width).min(self.info.width);
+        let bottom = y.saturating_add(height).min(self.info.height);
+        for row in y..bottom {
+            for column in x..right {
+                let offset = self.offset(column, row);
+                self.buffer[offset..offset + bytes_per_pixel]
+                    .copy_from_slice(&pixel[..bytes_per_pixel]);
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: Rgb) {
+        self.fill_rect(0, 0, self.info.width, self.info.height, color);
+    }
+
+    // Copies an image with rows of `width` pixels to (x, y), the part outside of the screen is
+    // cut off
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, pixels: &[Rgb]) {
+        if width == 0 {
+            return;
+        }
+        for (row, line) in pixels.chunks(width).enumerate() {
+            for (column, &color) in line.iter().enumerate() {
+                self.set_pixel(x.saturating_add(column), y.saturating_add(row), color);
+            }
+        }
+    }
+
+    // Copies `height` rows of pixels starting at row `from` to row `to`, e.g. to scroll. The
+    // ranges can overlap.
+    pub fn copy_rows(&mut self, from: usize, to: usize, height: usize) {
+        let height = height
+            .min(self.info.height.saturating_sub(from))
+            .min(self.info.height.saturating_sub(to));
+        let row_size = self.info.stride * self.info.bytes_per_pixel;
+        self.buffer
+            .copy_within(from * row_size..(from + height) * row_size, to * row_size);
+    }
+}
+
+impl fmt::Debug for FrameBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FrameBuffer")
+            .field("info", &self.info)
+            .finish()
+    }
+}
+
+// Draws the code page 437 character `byte` with its top left corner at (x, y)
+pub fn draw_char(framebuffer: &mut FrameBuffer, x: usize, y: usize, byte: u8, colors: (Rgb, Rgb)) {
+    let (foreground, background) = colors;
+    for (row, bits) in font::glyph(byte).iter().enumerate() {
+        for column in 0..font::WIDTH {
+            let lit = bits & (0x80 >> column) != 0;
+            let color = if lit { foreground } else { background };
+            framebuffer.set_pixel(x + column, y + row, color);
+        }
+    }
+}
+
+// Writes text onto a framebuffer, in lines from the top left corner of a rectangle. The text wraps
+// at the right edge of the rectangle and is cut off at the bottom. Doesn't scroll, see
+// `vga_buffer::use_framebuffer` for a console.
+pub struct TextWriter<'a> {
+    framebuffer: &'a mut FrameBuffer,
+    left: usize,
+    right: usize,
+    bottom: usize,
+    x: usize,
+    y: usize,
+    pub foreground: Rgb,
+    pub background: Rgb,
+}
+
+impl<'a> TextWriter<'a> {
+    // Writes into the rectangle at (x, y) with the given size
+    pub fn new(
+        framebuffer: &'a mut FrameBuffer,
+        (x, y): (usize, usize),
+        (width, height): (usize, usize),
+    ) -> TextWriter<'a> {
+        let right = (x + width).min(framebuffer.width());
+        let bottom = (y + height).min(framebuffer.height());
+        TextWriter {
+            framebuffer,
+            left: x,
+            right,
+            bottom,
+            x,
+            y,
+            foreground: Rgb::WHITE,
+            background: Rgb::BLACK,
+        }
+    }
+
+    // the position where the next character goes
+    pub fn position(&self) -> (usize, usize) {
+        (self.x, self.y)
+    }
+
+    fn new_line(&mut self) {
+        self.x = self.left;
+        self.y += font::HEIGHT;
+    }
+}
+
+impl fmt::Write for TextWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\n' => self.new_line(),
+                '\r' => self.x = self.left,
+                c => {
+                    if self.x + font::WIDTH > self.right {
+                        self.new_line();
+                    }
+                    if self.y + font::HEIGHT > self.bottom {
+                        break;
+                    }
+                    let colors = (self.foreground, self.background);
+                    let byte = cp437::encode(c);
+                    draw_char(self.framebuffer, self.x, self.y, byte, colors);
+                    self.x += font::WIDTH;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// a framebuffer for two characters side by side, 24 bits per pixel with a padded stride
+#[cfg(test)]
+const TEST_INFO: FrameBufferInfo = FrameBufferInfo {
+    width: 2 * font::WIDTH,
+    height: font::HEIGHT,
+    stride: 2 * font::WIDTH + 2,
+    bytes_per_pixel: 3,
+    format: PixelFormat::Bgr,
+};
+
+#[cfg(test)]
+fn test_framebuffer(memory: &mut [u8]) -> FrameBuffer {
+    assert_eq!(memory.len(), TEST_INFO.size());
+    unsafe { FrameBuffer::new(TEST_INFO, VirtAddr::from_ptr(memory.as_mut_ptr())) }
+}
+
+#[test_case]
+fn test_pixels() {
+    let mut memory = [0; 18 * 13 * 3];
+    let red = Rgb::new(0xff, 0, 0);
+    // the framebuffer is done with the memory at the end of the block
+    {
+        let mut framebuffer = test_framebuffer(&mut memory);
+        framebuffer.set_pixel(1, 2, red);
+        assert_eq!(framebuffer.get_pixel(1, 2), Some(red));
+        assert_eq!(framebuffer.get_pixel(16, 0), None);
+        // the rectangle is cut off at the right edge, the padding stays untouched
+        framebuffer.fill_rect(14, 0, 10, 1, Rgb::WHITE);
+        assert_eq!(framebuffer.get_pixel(15, 0), Some(Rgb::WHITE));
+        framebuffer.copy_rows(0, 3, 1);
+        assert_eq!(framebuffer.get_pixel(15, 3), Some(Rgb::WHITE));
+        assert_eq!(framebuffer.get_pixel(0, 3), Some(Rgb::BLACK));
+        framebuffer.blit(0, 5, 2, &[red, Rgb::WHITE, Rgb::WHITE, red]);
+        assert_eq!(framebuffer.get_pixel(1, 5), Some(Rgb::WHITE));
+        assert_eq!(framebuffer.get_pixel(1, 6), Some(red));
+        // nothing to draw, but it must not overflow
+        framebuffer.fill_rect(usize::MAX, 0, 2, usize::MAX, red);
+        framebuffer.blit(usize::MAX, 0, 2, &[red, red]);
+    }
+    // blue comes first
+    assert_eq!(memory[(2 * 18 + 1) * 3..(2 * 18 + 2) * 3], [0, 0, 0xff]);
+    assert_eq!(memory[(18 + 16) * 3..(18 + 16) * 3 + 6], [0; 6]);
+}
+
+#[test_case]
+fn test_text_writer() {
+    use core::fmt::Write;
+
+    let mut memory = [0; 18 * 13 * 3];
+    let mut framebuffer = test_framebuffer(&mut memory);
+    let mut writer = TextWriter::new(&mut framebuffer, (0, 0), (16, 13));
+    // the third character doesn't fit anymore
+    write!(writer, "|é|").unwrap();
+    assert_eq!(writer.position(), (0, 13));
+    // the vertical bar is a line in the middle of the glyph
+    let lit = |framebuffer: &FrameBuffer, x| framebuffer.get_pixel(x, 6) == Some(Rgb::WHITE);
+    assert_eq!(
+        (0..8).filter(|&x| lit(&framebuffer, x)).count(),
+        1,
+        "one pixel wide"
+    );
+    // the é is code page 437 0x82
+    for (row, bits) in font::glyph(0x82).iter().enumerate() {
+        for column in 0..font::WIDTH {
+            let expected = if bits & (0x80 >> column) != 0 {
+                Rgb::WHITE
+            } else {
+                Rgb::BLACK
+            };
+            assert_eq!(framebuffer.get_pixel(8 + column, row), Some(expected));
+        }
+    }
+}
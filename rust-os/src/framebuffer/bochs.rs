@@ -0,0 +1,108 @@
+// The display adapter of Bochs, which QEMU's standard VGA card (`-vga std`, the default) and
+// VirtualBox implement as well. Next to the VGA modes it has a linear framebuffer in any
+// resolution, set through two I/O ports (the "VBE DISPI" interface). The framebuffer is in the
+// first BAR of the PCI device.
+
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+use super::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use crate::{
+    memory,
+    pci::{self, Bar},
+};
+
+const INDEX_PORT: u16 = 0x1ce;
+const DATA_PORT: u16 = 0x1cf;
+
+const REGISTER_ID: u16 = 0;
+const REGISTER_X_RESOLUTION: u16 = 1;
+const REGISTER_Y_RESOLUTION: u16 = 2;
+const REGISTER_BITS_PER_PIXEL: u16 = 3;
+const REGISTER_ENABLE: u16 = 4;
+// the width of the framebuffer, which can be larger than the screen
+const REGISTER_VIRTUAL_WIDTH: u16 = 6;
+
+// the versions count up from 0xb0c0, 32 bits per pixel and the linear framebuffer need 0xb0c2
+const MIN_VERSION: u16 = 0xb0c2;
+const ENABLED: u16 = 0x01;
+const LINEAR_FRAMEBUFFER: u16 = 0x40;
+const BITS_PER_PIXEL: u16 = 32;
+
+fn read(register: u16) -> u16 {
+    let mut index: Port<u16> = Port::new(INDEX_PORT);
+    let mut data: Port<u16> = Port::new(DATA_PORT);
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+fn write(register: u16, value: u16) {
+    let mut index: Port<u16> = Port::new(INDEX_PORT);
+    let mut data: Port<u16> = Port::new(DATA_PORT);
+    unsafe {
+        index.write(register);
+        data.write(value);
+    }
+}
+
+// Switches the adapter to the given resolution with 32 bits per pixel. This ends the VGA text
+// mode, the text buffer isn't shown anymore.
+pub fn init(width: usize, height: usize) -> Option<FrameBuffer> {
+    let is_bochs = |d: &pci::PciDevice| d.is(0x1234, 0x1111) || d.is(0x80ee, 0xbeef);
+    let device = pci::claim("bochs-display", is_bochs)?;
+    let (address, bar_size) = match device.bars[0] {
+        Some(Bar::Memory { address, size, .. }) => (address, size),
+        _ => return None,
+    };
+    let version = read(REGISTER_ID);
+    if version < MIN_VERSION {
+        log::warn!("bochs-display: version {:#x} is too old", version);
+        return None;
+    }
+
+    // the mode can only be changed while the adapter is disabled
+    write(REGISTER_ENABLE, 0);
+    write(REGISTER_X_RESOLUTION, width as u16);
+    write(REGISTER_Y_RESOLUTION, height as u16);
+    write(REGISTER_BITS_PER_PIXEL, BITS_PER_PIXEL);
+    write(REGISTER_ENABLE, ENABLED | LINEAR_FRAMEBUFFER);
+    // the adapter picks another resolution if it doesn't support this one
+    let info = FrameBufferInfo {
+        width: usize::from(read(REGISTER_X_RESOLUTION)),
+        height: usize::from(read(REGISTER_Y_RESOLUTION)),
+        stride: usize::from(read(REGISTER_VIRTUAL_WIDTH)),
+        bytes_per_pixel: 4,
+        format: PixelFormat::Bgr,
+    };
+    if info.size() as u64 > bar_size {
+        log::warn!("bochs-display: framebuffer larger than its BAR");
+        return None;
+    }
+    let start = map(address, info.size())?;
+    log::info!(
+        "bochs-display: {}x{} framebuffer at {:#x}",
+        info.width,
+        info.height,
+        address.as_u64()
+    );
+    Some(unsafe { FrameBuffer::new(info, start) })
+}
+
+fn map(address: PhysAddr, size: usize) -> Option<VirtAddr> {
+    let mapped = memory::with_kernel_memory(|memory| unsafe {
+        memory::map_mmio(
+            address,
+            size as u64,
+            &mut memory.mapper,
+            &mut memory.frame_allocator,
+        )
+    });
+    match mapped {
+        Ok(start) => Some(start),
+        Err(error) => {
+            log::warn!("bochs-display: mapping the framebuffer failed: {:?}", error);
+            None
+        }
+    }
+}
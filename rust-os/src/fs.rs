@@ -0,0 +1,233 @@
+/**
+ * The virtual file system: one tree of paths over the file systems that are mounted into it.
+ *
+ * A file system driver implements `FileSystem` and `Inode`, the rest of the kernel only goes
+ * through the functions here, which find the mount a path belongs to and walk the remaining
+ * components with `Inode::lookup`. Like the block devices, everything that can touch the disk is
+ * a future.
+ *
+ * The file systems are read-only for now, so there is nothing to write or create.
+ */
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use core::{future::Future, pin::Pin};
+use spin::Mutex;
+
+use crate::block::{self, BlockError};
+
+pub mod fat32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    // in bytes, 0 for directories
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    // e.g. an empty path component after a `..` that leaves the root
+    InvalidPath,
+    // there is no file system mounted that the path could be on
+    NotMounted,
+    AlreadyMounted,
+    // the disk doesn't contain a file system the driver can read
+    Unsupported,
+    // the structures on the disk contradict each other
+    Corrupt,
+    Io(BlockError),
+}
+
+impl FsError {
+    // for the shell, which prints the errors
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::InvalidPath => "invalid path",
+            FsError::NotMounted => "no file system mounted",
+            FsError::AlreadyMounted => "a file system is already mounted there",
+            FsError::Unsupported => "unsupported file system",
+            FsError::Corrupt => "file system is corrupt",
+            FsError::Io(_) => "I/O error",
+        }
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        FsError::Io(error)
+    }
+}
+
+pub type FsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FsError>> + Send + 'a>>;
+
+// A file or a directory of a mounted file system
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    // Reads from the file at `offset` and returns the number of bytes read, which is only less
+    // than `buf.len()` at the end of the file
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize>;
+
+    // The entries of the directory, without `.` and `..`
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>>;
+
+    // The entry of the directory called `name`
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>>;
+}
+
+pub trait FileSystem: Send + Sync {
+    // the type of the file system, like `fat32`
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::AlreadyMounted);
+    }
+    log::info!("mounted {} file system at {}", fs.name(), path);
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(FsError::NotMounted)?;
+    mounts.remove(index);
+    Ok(())
+}
+
+// The mount points and the types of the file systems mounted there
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (mount.path.clone(), mount.fs.name()))
+        .collect()
+}
+
+// Mounts the first disk with a file system we can read at `/`, if nothing is mounted there yet
+pub async fn mount_root() -> Result<(), FsError> {
+    if MOUNTS.lock().iter().any(|mount| mount.path == "/") {
+        return Err(FsError::AlreadyMounted);
+    }
+    for device in block::devices() {
+        let name = String::from(device.name());
+        match fat32::Fat32::mount(device).await {
+            Ok(fs) => return mount("/", fs),
+            Err(FsError::Unsupported) => {}
+            Err(error) => log::warn!("{}: {}", name, error.as_str()),
+        }
+    }
+    Err(FsError::NotMounted)
+}
+
+// Turns `path` into an absolute path without `.`, `..` and empty components. There is no working
+// directory, so a relative path starts at the root as well.
+pub fn normalize(path: &str) -> Result<String, FsError> {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop().ok_or(FsError::InvalidPath)?;
+            }
+            component => components.push(component),
+        }
+    }
+    let mut normalized = String::with_capacity(path.len() + 1);
+    for component in &components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+// Finds the mount with the longest path that `path` is in and returns its file system and the
+// rest of the path. `path` has to be normalized.
+fn find_mount(path: &str) -> Option<(Arc<dyn FileSystem>, &str)> {
+    let mounts = MOUNTS.lock();
+    mounts
+        .iter()
+        .filter_map(|mount| {
+            let rest = path.strip_prefix(mount.path.as_str())?;
+            // `/mnt` is not in `/mn`
+            let on_boundary = mount.path == "/" || rest.is_empty() || rest.starts_with('/');
+            on_boundary.then_some((mount, rest))
+        })
+        .max_by_key(|(mount, _)| mount.path.len())
+        .map(|(mount, rest)| (mount.fs.clone(), rest))
+}
+
+pub async fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    let path = normalize(path)?;
+    let (fs, rest) = find_mount(&path).ok_or(FsError::NotMounted)?;
+    let mut inode = fs.root();
+    for name in rest.split('/').filter(|name| !name.is_empty()) {
+        if inode.metadata().file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        inode = inode.lookup(name).await?;
+    }
+    Ok(inode)
+}
+
+pub async fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let inode = lookup(path).await?;
+    if inode.metadata().file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    inode.read_dir().await
+}
+
+// Reads the whole file
+pub async fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let inode = lookup(path).await?;
+    let metadata = inode.metadata();
+    if metadata.file_type != FileType::File {
+        return Err(FsError::IsADirectory);
+    }
+    let mut data = vec![0; metadata.size as usize];
+    let mut read = 0;
+    while read < data.len() {
+        match inode.read_at(read as u64, &mut data[read..]).await? {
+            0 => return Err(FsError::Corrupt),
+            n => read += n,
+        }
+    }
+    Ok(data)
+}
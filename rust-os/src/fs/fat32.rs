@@ -0,0 +1,433 @@
+/**
+ * A read-only driver for FAT32, the file system of most USB sticks and EFI system partitions.
+ *
+ * The disk starts with the boot sector, which describes the layout: a few reserved sectors, the
+ * file allocation tables (FATs) and the data area, which is divided into clusters. A file or a
+ * directory is stored in a chain of clusters, the FAT entry of a cluster holds the number of the
+ * next one. A directory is a file of 32 byte entries, each with an 8.3 name, the first cluster
+ * and the size. Longer names are stored in extra entries in front of the 8.3 entry.
+ *
+ * The file system either starts at the first block of the disk, or in a partition of an MBR
+ * partition table.
+ */
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsFuture, Inode, Metadata};
+use crate::block::{BlockDevice, BLOCK_SIZE};
+
+// the FAT entries at and above this end the chain
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+const BAD_CLUSTER: u32 = 0x0fff_fff7;
+// only the lower 28 bits of a FAT entry are used
+const ENTRY_MASK: u32 = 0x0fff_ffff;
+
+const ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0f;
+// the first byte of the name of an entry that has been deleted
+const DELETED: u8 = 0xe5;
+// in the reserved byte of an entry: the base name and the extension are shown in lower case
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+// the sequence number of the last long name entry, which comes first on the disk
+const LAST_LONG_ENTRY: u8 = 0x40;
+
+// the partition types of FAT32, with CHS and with LBA addressing
+const PARTITION_TYPES: [u8; 2] = [0x0b, 0x0c];
+
+pub struct Fat32 {
+    volume: Arc<Volume>,
+}
+
+impl Fat32 {
+    // Returns `FsError::Unsupported` if the device doesn't contain a FAT32 file system
+    pub async fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<Fat32>, FsError> {
+        let mut sector = vec![0; BLOCK_SIZE];
+        device.read_blocks(0, &mut sector).await?;
+        let start = if is_boot_sector(&sector) {
+            0
+        } else {
+            // the first partition of the MBR that could be FAT32
+            let start = (0..4)
+                .map(|i| &sector[446 + i * 16..][..16])
+                .find(|partition| PARTITION_TYPES.contains(&partition[4]))
+                .map(|partition| u64::from(read_u32(partition, 8)))
+                .ok_or(FsError::Unsupported)?;
+            device.read_blocks(start, &mut sector).await?;
+            if !is_boot_sector(&sector) {
+                return Err(FsError::Unsupported);
+            }
+            start
+        };
+        let volume = Volume::new(device, start, &sector)?;
+        Ok(Arc::new(Fat32 {
+            volume: Arc::new(volume),
+        }))
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatInode {
+            volume: self.volume.clone(),
+            first_cluster: self.volume.root_cluster,
+            metadata: Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            },
+        })
+    }
+}
+
+// Whether the sector looks like the boot sector of a FAT32 file system
+fn is_boot_sector(sector: &[u8]) -> bool {
+    let jump = sector[0] == 0xeb || sector[0] == 0xe9;
+    // FAT12 and FAT16 have a fixed root directory and a FAT size that fits into 16 bits
+    let fat32 = read_u16(sector, 17) == 0 && read_u16(sector, 22) == 0 && read_u32(sector, 36) != 0;
+    jump && fat32 && sector[510..512] == [0x55, 0xaa]
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+// The layout of the file system, from the boot sector
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    blocks_per_cluster: u64,
+    // the first block of the first FAT and of the data area, from the start of the device
+    fat_start: u64,
+    data_start: u64,
+    // the clusters are numbered from 2 to `cluster_count + 1`
+    cluster_count: u32,
+    root_cluster: u32,
+}
+
+impl Volume {
+    fn new(
+        device: Arc<dyn BlockDevice>,
+        start: u64,
+        boot_sector: &[u8],
+    ) -> Result<Volume, FsError> {
+        let bytes_per_sector = usize::from(read_u16(boot_sector, 11));
+        let sectors_per_cluster = boot_sector[13];
+        let reserved_sectors = u64::from(read_u16(boot_sector, 14));
+        let fat_count = u64::from(boot_sector[16]);
+        let total_sectors = match read_u16(boot_sector, 19) {
+            0 => u64::from(read_u32(boot_sector, 32)),
+            sectors => u64::from(sectors),
+        };
+        let fat_size = u64::from(read_u32(boot_sector, 36));
+        let root_cluster = read_u32(boot_sector, 44);
+
+        // larger sectors would be fine as well, but every device we have uses 512 bytes
+        if bytes_per_sector != BLOCK_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        if !sectors_per_cluster.is_power_of_two() || reserved_sectors == 0 || fat_count == 0 {
+            return Err(FsError::Corrupt);
+        }
+        let data_start = reserved_sectors + fat_count * fat_size;
+        if total_sectors <= data_start || start + total_sectors > device.block_count() {
+            return Err(FsError::Corrupt);
+        }
+        let blocks_per_cluster = u64::from(sectors_per_cluster);
+        // the FAT may have more entries than there are clusters, but not fewer
+        let cluster_count = ((total_sectors - data_start) / blocks_per_cluster)
+            .min(fat_size * BLOCK_SIZE as u64 / 4 - 2) as u32;
+        let volume = Volume {
+            device,
+            blocks_per_cluster,
+            fat_start: start + reserved_sectors,
+            data_start: start + data_start,
+            cluster_count,
+            root_cluster,
+        };
+        if !volume.is_valid_cluster(root_cluster) {
+            return Err(FsError::Corrupt);
+        }
+        Ok(volume)
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.blocks_per_cluster as usize * BLOCK_SIZE
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    async fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        let block = self.data_start + u64::from(cluster - 2) * self.blocks_per_cluster;
+        self.device.read_blocks(block, buf).await?;
+        Ok(())
+    }
+
+    // The clusters of the chain that starts at `first`
+    async fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        let mut sector = vec![0; BLOCK_SIZE];
+        // the number of the FAT sector in `sector`
+        let mut loaded = None;
+        loop {
+            if !self.is_valid_cluster(cluster) {
+                return Err(FsError::Corrupt);
+            }
+            // a chain that is longer than the disk has to have a loop
+            if chain.len() == self.cluster_count as usize {
+                return Err(FsError::Corrupt);
+            }
+            chain.push(cluster);
+
+            let offset = cluster as usize * 4;
+            let fat_sector = (offset / BLOCK_SIZE) as u64;
+            if loaded != Some(fat_sector) {
+                self.device
+                    .read_blocks(self.fat_start + fat_sector, &mut sector)
+                    .await?;
+                loaded = Some(fat_sector);
+            }
+            cluster = read_u32(&sector, offset % BLOCK_SIZE) & ENTRY_MASK;
+            match cluster {
+                END_OF_CHAIN.. => return Ok(chain),
+                BAD_CLUSTER => return Err(FsError::Corrupt),
+                _ => {}
+            }
+        }
+    }
+
+    // Reads the entries of the directory that starts at `first`
+    async fn read_dir(&self, first: u32) -> Result<Vec<RawEntry>, FsError> {
+        let mut entries = Vec::new();
+        let mut long_name = LongName::default();
+        let mut data = vec![0; self.cluster_size()];
+        for cluster in self.chain(first).await? {
+            self.read_cluster(cluster, &mut data).await?;
+            for entry in data.chunks(ENTRY_SIZE) {
+                match entry[0] {
+                    // this entry and all the following ones are unused
+                    0 => return Ok(entries),
+                    DELETED => {
+                        long_name = LongName::default();
+                        continue;
+                    }
+                    _ => {}
+                }
+                let attributes = entry[11];
+                if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                    long_name.add(entry);
+                    continue;
+                }
+                let name = core::mem::take(&mut long_name);
+                if attributes & ATTR_VOLUME_ID != 0 || entry[0] == b'.' {
+                    continue;
+                }
+                let short_name = short_name(entry);
+                let cluster = u32::from(read_u16(entry, 20)) << 16 | u32::from(read_u16(entry, 26));
+                let file_type = if attributes & ATTR_DIRECTORY != 0 {
+                    FileType::Directory
+                } else {
+                    FileType::File
+                };
+                entries.push(RawEntry {
+                    name: name.finish(entry).unwrap_or_else(|| short_name.clone()),
+                    short_name,
+                    first_cluster: cluster,
+                    metadata: Metadata {
+                        file_type,
+                        size: match file_type {
+                            FileType::File => u64::from(read_u32(entry, 28)),
+                            FileType::Directory => 0,
+                        },
+                    },
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+struct RawEntry {
+    name: String,
+    short_name: String,
+    // 0 for an empty file
+    first_cluster: u32,
+    metadata: Metadata,
+}
+
+// The 8.3 name of the entry as it is shown, e.g. `hello.txt`
+fn short_name(entry: &[u8]) -> String {
+    let case = entry[12];
+    let mut name = String::new();
+    push_name_part(&mut name, &entry[..8], case & CASE_LOWER_BASE != 0);
+    // 0x05 stands for a name that starts with 0xe5, which marks deleted entries
+    if entry[0] == 0x05 {
+        name.replace_range(..1, "\u{e5}");
+    }
+    if entry[8] != b' ' {
+        name.push('.');
+        push_name_part(&mut name, &entry[8..11], case & CASE_LOWER_EXT != 0);
+    }
+    name
+}
+
+// The base name and the extension are padded with spaces
+fn push_name_part(name: &mut String, part: &[u8], lower: bool) {
+    for &b in part.iter().take_while(|&&b| b != b' ') {
+        let c = char::from(b);
+        name.push(if lower { c.to_ascii_lowercase() } else { c });
+    }
+}
+
+// Collects the long name entries in front of an 8.3 entry. They come in reverse order, each with
+// 13 UTF-16 characters of the name and the checksum of the 8.3 name it belongs to.
+#[derive(Default)]
+struct LongName {
+    chars: Vec<u16>,
+    checksum: u8,
+    // the sequence number of the entry that comes next, 0 when complete
+    next: u8,
+    valid: bool,
+}
+
+impl LongName {
+    fn add(&mut self, entry: &[u8]) {
+        let sequence = entry[0] & !LAST_LONG_ENTRY;
+        if entry[0] & LAST_LONG_ENTRY != 0 {
+            *self = LongName {
+                chars: Vec::new(),
+                checksum: entry[13],
+                next: sequence,
+                valid: sequence != 0,
+            };
+        } else if self.next == 0 || sequence != self.next || entry[13] != self.checksum {
+            // an orphaned entry, e.g. left over when the 8.3 entry was written by an old system
+            self.valid = false;
+        }
+        if !self.valid {
+            return;
+        }
+        // the entries come from the end of the name, so the characters are collected reversed
+        let offsets = [30, 28, 24, 22, 20, 18, 16, 14, 9, 7, 5, 3, 1];
+        self.chars
+            .extend(offsets.iter().map(|&offset| read_u16(entry, offset)));
+        self.next -= 1;
+    }
+
+    // The name, if the long name entries were complete and belong to the 8.3 entry
+    fn finish(self, entry: &[u8]) -> Option<String> {
+        if !self.valid || self.next != 0 {
+            return None;
+        }
+        let checksum = entry[..11]
+            .iter()
+            .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b));
+        if checksum != self.checksum {
+            return None;
+        }
+        // the name ends with a 0 and is padded with 0xffff, unless it fills the last entry
+        let chars = self.chars.into_iter().rev().take_while(|&c| c != 0);
+        Some(
+            char::decode_utf16(chars)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+struct FatInode {
+    volume: Arc<Volume>,
+    first_cluster: u32,
+    metadata: Metadata,
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        self.metadata
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if self.metadata.file_type != FileType::File {
+                return Err(FsError::IsADirectory);
+            }
+            let end = self.metadata.size.min(offset + buf.len() as u64);
+            if offset >= end {
+                return Ok(0);
+            }
+            let cluster_size = self.volume.cluster_size() as u64;
+            let chain = self.volume.chain(self.first_cluster).await?;
+            let mut data = vec![0; cluster_size as usize];
+            let mut position = offset;
+            while position < end {
+                let cluster = *chain
+                    .get((position / cluster_size) as usize)
+                    .ok_or(FsError::Corrupt)?;
+                self.volume.read_cluster(cluster, &mut data).await?;
+                let start = (position % cluster_size) as usize;
+                let len = (cluster_size - start as u64).min(end - position) as usize;
+                let target = (position - offset) as usize;
+                buf[target..target + len].copy_from_slice(&data[start..start + len]);
+                position += len as u64;
+            }
+            Ok((end - offset) as usize)
+        })
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            if self.metadata.file_type != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+            let entries = self.volume.read_dir(self.first_cluster).await?;
+            Ok(entries
+                .into_iter()
+                .map(|entry| DirEntry {
+                    name: entry.name,
+                    metadata: entry.metadata,
+                })
+                .collect())
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            if self.metadata.file_type != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+            // FAT ignores the case of names, and a file can also be found by its 8.3 name
+            let entry = self
+                .volume
+                .read_dir(self.first_cluster)
+                .await?
+                .into_iter()
+                .find(|entry| {
+                    entry.name.eq_ignore_ascii_case(name)
+                        || entry.short_name.eq_ignore_ascii_case(name)
+                })
+                .ok_or(FsError::NotFound)?;
+            // an empty file doesn't have any clusters, a directory always has one
+            if entry.first_cluster == 0 && entry.metadata.file_type == FileType::Directory {
+                return Err(FsError::Corrupt);
+            }
+            let inode: Arc<dyn Inode> = Arc::new(FatInode {
+                volume: self.volume.clone(),
+                first_cluster: entry.first_cluster,
+                metadata: entry.metadata,
+            });
+            Ok(inode)
+        })
+    }
+}
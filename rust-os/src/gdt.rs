@@ -3,63 +3,143 @@
  * purposes and intializes a new TaskStateSegment (TSS) which has a new entry in the
  * InterruptStackTable to be used for preventing triple faults when stack overflow occurs by
  * switching to this newly initialized stack
+ *
+ * The GDT also holds the segments for user mode. Their order is fixed by the `syscall`
+ * instruction, which derives the kernel and user selectors from the STAR register (see
+ * `process::syscall`): kernel code, kernel data, user data, user code.
+ *
+ * Every CPU has its own GDT and TSS, since the TSS holds the stacks that the CPU switches to. The
+ * ones of the boot CPU are statics, because they are needed before there is a heap. The
+ * application processors allocate theirs. The selectors are the same in every GDT.
  */
+use alloc::{boxed::Box, vec};
+use core::{ops::Range, ptr};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::percpu;
+
 // newly created stack table index
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// 4096 bytes * 5 = 20 kilobytes - size of the stack
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+// the stack that the CPU switches to when an interrupt or exception arrives in user mode
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 5;
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
-    GDT.0.load();
+    let cpu = percpu::current();
+    let (tss, gdt, selectors) = if cpu.is_boot_cpu() {
+        (&*TSS, &GDT.0, &GDT.1)
+    } else {
+        // the stacks, tables and selectors are used for as long as the CPU runs
+        let stack_end =
+            |size| VirtAddr::from_ptr(Box::leak(vec![0u8; size].into_boxed_slice())) + size;
+        let tss: &'static TaskStateSegment = Box::leak(Box::new(new_tss(
+            stack_end(DOUBLE_FAULT_STACK_SIZE),
+            stack_end(PRIVILEGE_STACK_SIZE),
+        )));
+        let (gdt, selectors) = Box::leak(Box::new(new_gdt(tss)));
+        (tss, &*gdt, &*selectors)
+    };
+    gdt.load();
     unsafe {
         // setting the new code_segment
-        CS::set_reg(GDT.1.code_selector);
+        CS::set_reg(selectors.code_selector);
+        // the selectors of the bootloader's GDT are not valid in ours, and `iretq` reloads SS
+        SS::set_reg(selectors.data_selector);
+        DS::set_reg(selectors.data_selector);
+        ES::set_reg(selectors.data_selector);
         // loading the new tss table
-        load_tss(GDT.1.tss_selector);
+        load_tss(selectors.tss_selector);
     }
+    cpu.set_tss(tss);
+}
+
+// Returns the address range of the stack that the running CPU switches to on a double fault
+pub fn double_fault_stack() -> Range<VirtAddr> {
+    let tss = percpu::current().tss().expect("gdt::init was not called");
+    let stack_end = tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize];
+    (stack_end - DOUBLE_FAULT_STACK_SIZE)..stack_end
+}
+
+// The selectors of the kernel code and data segments, in this order
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+// The selectors of the user code and data segments, in this order. They request privilege
+// level 3.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+// Creates a TSS with the stacks that end at the given addresses
+fn new_tss(double_fault_stack_end: VirtAddr, privilege_stack_end: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    // `interrupt_stack_table`, which is a part of the TSS (Task State Segment), is a table of 7 pointers to known-good stacks
+    // we are assigning the stack for DOUBLE FAULT to the 0th index of the Interrupt Stack Table.
+    // the stack on x86 grows downwards (high address to low address) and hence we are using the
+    // top address
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+    // Interrupts from user mode must not run on the user stack, so the CPU loads the stack
+    // pointer for privilege level 0 from here. A CPU runs at most one user process at a time, and
+    // it is interrupted at most once at a time, so a single stack per CPU is enough.
+    tss.privilege_stack_table[0] = privilege_stack_end;
+    tss
+}
+
+// GlobalDescriptorTable (GDT) is the legacy standard for memory segmentation between
+// processes.
+// Nowadays Paging is used. But this is still kept in x86 architectures for backward
+// compatibility and for user-space to kernel stapce switching and some other needs.
+// We are creating a GDT and adding out TSS entry into it.
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_data_selector,
+            user_code_selector,
+            tss_selector,
+        },
+    )
 }
 
 lazy_static! {
+    // the TSS of the boot CPU
     static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        // `interrupt_stack_table`, which is a part of the TSS (Task State Segment), is a table of 7 pointers to known-good stacks
-        // we are creating a new Stack for DOUBLE FAULT in the memory and assigning it to the 0th index of the
-        // Interrupt Stack Table.
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // 4096 bytes * 5 = 20 kilobytes - size of the stack
-            const STACK_SIZE: usize = 4096 * 5;
-            // 8 bits is 1 one byte, so
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+        static mut PRIVILEGE_STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            // the stack on x86 grows downwards (high address to low address) and hence we are
-            // returning the top address
-            stack_end
-        };
-        tss
+        let double_fault_stack = VirtAddr::from_ptr(ptr::addr_of!(DOUBLE_FAULT_STACK));
+        let privilege_stack = VirtAddr::from_ptr(ptr::addr_of!(PRIVILEGE_STACK));
+        new_tss(
+            double_fault_stack + DOUBLE_FAULT_STACK_SIZE,
+            privilege_stack + PRIVILEGE_STACK_SIZE,
+        )
     };
 }
 
 lazy_static! {
-    // GlobalDescriptorTable (GDT) is the legacy standard for memory segmentation between
-    // processes.
-    // Nowadays Paging is used. But this is still kept in x86 architectures for backward
-    // compatibility and for user-space to kernel stapce switching and some other needs.
-    // We are creating a GDT and adding out TSS entry into it.
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors{ code_selector, tss_selector})
-    };
+    // the GDT of the boot CPU
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
 }
+
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
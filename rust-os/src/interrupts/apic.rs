@@ -0,0 +1,298 @@
+/**
+ * The APIC (Advanced Programmable Interrupt Controller) replaces the two 8259 PICs on every
+ * modern x86 system. It consists of two parts:
+ * - a local APIC in every CPU core, which delivers the interrupts to its core and receives the
+ *   `end of interrupt` signals
+ * - one or more IOAPICs, which receive the interrupts of the devices and route them to the local
+ *   APICs. Every input pin of an IOAPIC is identified by its `global system interrupt` (GSI).
+ *
+ * Both are programmed through memory-mapped registers, whose addresses we find in the ACPI MADT.
+ * The registers of every local APIC are at the same address, each CPU sees its own there.
+ *
+ * The local APICs can also send interrupts to each other (inter-processor interrupts, IPIs),
+ * which is how the application processors are started (see `smp`) and woken up.
+ */
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB},
+    VirtAddr,
+};
+
+use super::{InterruptsIndex, PICS};
+use crate::acpi::{self, Madt};
+use crate::memory;
+
+// the model specific register that holds the physical address and the enable bit of the local APIC
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC registers (offsets from its base address)
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TASK_PRIORITY: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SPURIOUS_VECTOR: u64 = 0xf0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+// the interrupt command register, the destination goes into the high half and writing the low
+// half sends the IPI
+const LAPIC_ICR_LOW: u64 = 0x300;
+const LAPIC_ICR_HIGH: u64 = 0x310;
+// delivery modes
+const ICR_FIXED: u32 = 0b000 << 8;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+// IOAPIC registers are accessed indirectly: the index of the register is written to IOREGSEL and
+// then its value can be accessed through IOWIN
+const IOAPIC_IOREGSEL: u64 = 0x00;
+const IOAPIC_IOWIN: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+// every redirection entry is 64 bit wide and made of two registers
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+// the ISA IRQs of the devices that we handle
+const TIMER_IRQ: u8 = 0;
+const KEYBOARD_IRQ: u8 = 1;
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+// the IOAPICs and how the ISA IRQs are wired to them, for routing device interrupts after `init`
+static ROUTING: OnceCell<Routing> = OnceCell::uninit();
+// set once the interrupts are routed through the APIC instead of the PICs
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum InitError {
+    // the firmware does not describe an APIC, so the PICs have to be used
+    MadtNotFound,
+    NoIoApicFor(u32),
+    Mapping(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for InitError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        InitError::Mapping(error)
+    }
+}
+
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, register: u64) -> u32 {
+        ptr::read_volatile((self.base + register).as_ptr())
+    }
+
+    unsafe fn write(&self, register: u64, value: u32) {
+        ptr::write_volatile((self.base + register).as_mut_ptr(), value);
+    }
+
+    pub fn id(&self) -> u8 {
+        (unsafe { self.read(LAPIC_ID) } >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(LAPIC_EOI, 0) };
+    }
+
+    // Sends an IPI and waits until the local APIC has delivered it
+    fn send(&self, apic_id: u8, command: u32) {
+        // an interrupt handler that sends an IPI in between would overwrite the destination
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            self.write(LAPIC_ICR_HIGH, u32::from(apic_id) << 24);
+            self.write(LAPIC_ICR_LOW, command);
+            while self.read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        });
+    }
+
+    // Raises the interrupt `vector` on the CPU with the given APIC id
+    pub fn send_ipi(&self, apic_id: u8, vector: u8) {
+        self.send(apic_id, ICR_FIXED | ICR_LEVEL_ASSERT | u32::from(vector));
+    }
+
+    // Resets the CPU with the given APIC id, which then waits for a startup IPI
+    pub fn send_init(&self, apic_id: u8) {
+        self.send(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    // Starts the CPU with the given APIC id in real mode at the address `page * 4096`, after it
+    // received an INIT
+    pub fn send_startup(&self, apic_id: u8, page: u8) {
+        self.send(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+    }
+
+    unsafe fn enable(&self) {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        apic_base.write(apic_base.read() | APIC_BASE_ENABLE);
+        // accept interrupts of every priority
+        self.write(LAPIC_TASK_PRIORITY, 0);
+        self.write(
+            LAPIC_SPURIOUS_VECTOR,
+            LAPIC_SOFTWARE_ENABLE | u32::from(InterruptsIndex::Spurious.as_u8()),
+        );
+    }
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn new(base: VirtAddr, gsi_base: u32) -> IoApic {
+        let mut io_apic = IoApic {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        // bits 16..24 of the version register hold the index of the last redirection entry
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile((self.base + IOAPIC_IOREGSEL).as_mut_ptr(), register);
+        ptr::read_volatile((self.base + IOAPIC_IOWIN).as_ptr())
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile((self.base + IOAPIC_IOREGSEL).as_mut_ptr(), register);
+        ptr::write_volatile((self.base + IOAPIC_IOWIN).as_mut_ptr(), value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    // Routes the ISA IRQ to the given vector of the local APIC with the given id
+    unsafe fn route(&self, irq: acpi::InterruptOverride, vector: u8, apic_id: u8) {
+        let entry = IOAPIC_REDIRECTION_TABLE + (irq.gsi - self.gsi_base) * 2;
+        let mut low = u32::from(vector);
+        if irq.active_low {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+        if irq.level_triggered {
+            low |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        self.write(entry + 1, u32::from(apic_id) << 24);
+        // the mask bit (16) is left cleared, so this enables the interrupt
+        self.write(entry, low);
+    }
+}
+
+// Switches from the 8259 PICs to the APIC. If this fails, the PICs stay in use.
+//
+// Needs the heap (for parsing the MADT) and a mapper to map the APIC registers.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), InitError> {
+    let madt = Madt::parse().ok_or(InitError::MadtNotFound)?;
+
+    let lapic_base =
+        unsafe { memory::map_mmio(madt.local_apic_address, 4096, mapper, frame_allocator)? };
+    LOCAL_APIC
+        .try_init_once(|| LocalApic { base: lapic_base })
+        .expect("apic::init should only be called once");
+    let local_apic = LOCAL_APIC.try_get().expect("local APIC not initialized");
+
+    let mut io_apics = Vec::new();
+    for info in &madt.io_apics {
+        let base = unsafe { memory::map_mmio(info.address, 4096, mapper, frame_allocator)? };
+        io_apics.push(unsafe { IoApic::new(base, info.gsi_base) });
+    }
+    let io_apic_for = |gsi| {
+        io_apics
+            .iter()
+            .find(|io_apic| io_apic.handles(gsi))
+            .ok_or(InitError::NoIoApicFor(gsi))
+    };
+    let timer = madt.isa_irq(TIMER_IRQ);
+    let keyboard = madt.isa_irq(KEYBOARD_IRQ);
+    let timer_io_apic = io_apic_for(timer.gsi)?;
+    let keyboard_io_apic = io_apic_for(keyboard.gsi)?;
+
+    // no interrupt may arrive while only half of the controllers are configured
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        if madt.has_8259 {
+            // the PICs are still remapped to vector 32, so a spurious interrupt they raise while
+            // they are masked does not look like an exception
+            PICS.lock().disable();
+        }
+        local_apic.enable();
+        timer_io_apic.route(timer, InterruptsIndex::Timer.as_u8(), local_apic.id());
+        keyboard_io_apic.route(keyboard, InterruptsIndex::Keyboard.as_u8(), local_apic.id());
+        APIC_ENABLED.store(true, Ordering::SeqCst);
+    });
+    log::info!(
+        "APIC enabled ({} processors, {} IOAPICs)",
+        madt.processors.len(),
+        madt.io_apics.len()
+    );
+    ROUTING
+        .try_init_once(|| Routing { io_apics, madt })
+        .expect("apic::init should only be called once");
+    Ok(())
+}
+
+struct Routing {
+    io_apics: Vec<IoApic>,
+    madt: Madt,
+}
+
+// Routes the legacy IRQ of a PCI device (its interrupt line) to the given vector. Returns `false`
+// if no IOAPIC handles the IRQ.
+pub(crate) fn route_pci_irq(irq: u8, vector: u8) -> bool {
+    let (routing, local_apic) = match (ROUTING.try_get(), LOCAL_APIC.try_get()) {
+        (Ok(routing), Ok(local_apic)) => (routing, local_apic),
+        _ => return false,
+    };
+    // PCI interrupts are level triggered, since several devices can share a line. The firmware
+    // describes them as active high, an override in the MADT takes precedence.
+    let wiring = acpi::InterruptOverride {
+        level_triggered: true,
+        ..routing.madt.isa_irq(irq)
+    };
+    match routing
+        .io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(wiring.gsi))
+    {
+        Some(io_apic) => {
+            unsafe { io_apic.route(wiring, vector, local_apic.id()) };
+            true
+        }
+        None => false,
+    }
+}
+
+// Enables the local APIC of an application processor, so it can receive IPIs. Device interrupts
+// are all routed to the boot CPU.
+pub(crate) fn enable_local_apic() {
+    if let Some(local_apic) = local_apic() {
+        unsafe { local_apic.enable() };
+    }
+}
+
+// the MADT that `init` found, with the processors of the system
+pub fn madt() -> Option<&'static Madt> {
+    ROUTING.try_get().ok().map(|routing| &routing.madt)
+}
+
+pub fn is_enabled() -> bool {
+    APIC_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.try_get().ok()
+}
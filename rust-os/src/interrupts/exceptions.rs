@@ -0,0 +1,408 @@
+/**
+ * Handlers for the CPU exceptions (vectors 0-31).
+ *
+ * Every exception gets its own handler, so that a fault is reported with its real cause instead
+ * of escalating into a double fault. The handlers print a `FaultReport` with the decoded error
+ * code, the interrupt stack frame, the control registers where they are relevant, and a
+ * backtrace of the interrupted code.
+ *
+ * Exceptions caused by a user process only kill that process, the kernel keeps running.
+ */
+use core::fmt;
+
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+use crate::{backtrace, gdt, percpu::KernelGs, println, process};
+
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt
+        .set_handler_fn(non_maskable_interrupt_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            // Currently, we only have a single Stack. Whevener a page fault occurs due to stack overflow,
+            // the exception handler will try to push a `InterruptStackFrame` onto the stack and it will
+            // trigger a double fault (since the stack has already overflowed). Now, the double fault will try
+            // to push the `InterruptStackFrame` onto the stack and it will trigger the `triple fault` for the
+            // same reason. We need to prevent this. So, we will be switching to a different stack whenever a
+            // double fault occurs.
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception
+        .set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception
+        .set_handler_fn(hv_injection_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception
+        .set_handler_fn(security_exception_handler);
+}
+
+// The error codes pushed by the CPU, decoded according to the exception that pushed them
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorCode {
+    // #TS, #NP, #SS and #GP report the segment selector that caused the fault (or 0)
+    Selector(u64),
+    PageFault(PageFaultErrorCode),
+    ControlProtection(u64),
+    // error codes without further structure, like the always-zero code of a double fault
+    Raw(u64),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorCode::Selector(0) => write!(f, "0x0 (not related to a segment)"),
+            ErrorCode::Selector(code) => {
+                let table = match (code >> 1) & 0b11 {
+                    0b00 => "GDT",
+                    0b10 => "LDT",
+                    _ => "IDT",
+                };
+                write!(
+                    f,
+                    "{:#x} ({} index {}{})",
+                    code,
+                    table,
+                    (code >> 3) & 0x1fff,
+                    if code & 1 != 0 { ", external event" } else { "" }
+                )
+            }
+            ErrorCode::PageFault(code) => write!(f, "{:#x} ({:?})", code.bits(), code),
+            ErrorCode::ControlProtection(code) => {
+                let cause = match code & 0x7fff {
+                    1 => "near return",
+                    2 => "far return or iret",
+                    3 => "missing endbranch",
+                    4 => "rstorssp",
+                    5 => "setssbsy",
+                    _ => "unknown",
+                };
+                write!(f, "{:#x} ({})", code, cause)
+            }
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+pub struct FaultReport<'a> {
+    pub name: &'static str,
+    pub mnemonic: &'static str,
+    pub vector: u8,
+    pub stack_frame: &'a InterruptStackFrame,
+    pub error_code: Option<ErrorCode>,
+    // whether CR2 (the faulting address) and CR3 (the active page table) should be shown
+    pub control_registers: bool,
+    // the frame pointer of the interrupted code
+    pub rbp: u64,
+}
+
+impl fmt::Display for FaultReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = self.stack_frame;
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {})",
+            self.name, self.mnemonic, self.vector
+        )?;
+        if let Some(error_code) = self.error_code {
+            writeln!(f, "Error code: {}", error_code)?;
+        }
+        writeln!(
+            f,
+            "RIP: {:#018x}  CS: {:#06x}  RFLAGS: {:#010x}",
+            frame.instruction_pointer.as_u64(),
+            frame.code_segment,
+            frame.cpu_flags
+        )?;
+        writeln!(
+            f,
+            "RSP: {:#018x}  SS: {:#06x}  RBP: {:#018x}",
+            frame.stack_pointer.as_u64(),
+            frame.stack_segment,
+            self.rbp
+        )?;
+        if self.control_registers {
+            // CR2 is set by the CPU on a page fault and contains the virtual address that caused it
+            let (l4_frame, flags) = Cr3::read();
+            writeln!(
+                f,
+                "CR2: {:#018x}  CR3: {:#018x} {:?}",
+                Cr2::read().as_u64(),
+                l4_frame.start_address().as_u64(),
+                flags
+            )?;
+        }
+        write!(
+            f,
+            "{}",
+            backtrace::Backtrace {
+                first: Some(frame.instruction_pointer.as_u64()),
+                rbp: self.rbp,
+            }
+        )
+    }
+}
+
+// Returns the frame pointer of the code that was interrupted.
+//
+// With frame pointers, the prologue of the handler pushes the interrupted code's `rbp` and points
+// `rbp` at it, so it is the first thing in the handler's frame. Must be used directly in the
+// handler.
+macro_rules! interrupted_rbp {
+    () => {
+        unsafe { *(backtrace::frame_pointer() as *const u64) }
+    };
+}
+
+// Swaps the kernel's GS base in for a handler that never returns, if it interrupted user mode
+fn enter_kernel_gs(stack_frame: &InterruptStackFrame) {
+    core::mem::forget(KernelGs::enter(stack_frame));
+}
+
+// Kills the running user process if the exception interrupted user mode (requested privilege
+// level 3 in the code segment). The report is not printed then, its backtrace would walk the
+// stack of the process.
+fn kill_if_user_mode(
+    stack_frame: &InterruptStackFrame,
+    vector: u8,
+    name: &'static str,
+    address: Option<VirtAddr>,
+) {
+    // the handler either kills the process or panics, so it doesn't go back to user mode
+    enter_kernel_gs(stack_frame);
+    // an NMI is not caused by the interrupted code
+    if stack_frame.code_segment & 3 == 3 && vector != 2 {
+        let fault = process::Fault {
+            name,
+            rip: stack_frame.instruction_pointer,
+            address,
+        };
+        unsafe { process::kill_current(fault) };
+    }
+}
+
+// Defines a handler that reports the exception and panics. None of these exceptions can be
+// recovered from in the kernel, so the panic handler takes it from here.
+macro_rules! fatal_handler {
+    ($handler:ident, $name:expr, $mnemonic:expr, $vector:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            kill_if_user_mode(&stack_frame, $vector, $name, None);
+            let report = FaultReport {
+                name: $name,
+                mnemonic: $mnemonic,
+                vector: $vector,
+                stack_frame: &stack_frame,
+                error_code: None,
+                control_registers: false,
+                rbp: interrupted_rbp!(),
+            };
+            panic!("{}", report);
+        }
+    };
+    ($handler:ident, $name:expr, $mnemonic:expr, $vector:expr, $decode:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            kill_if_user_mode(&stack_frame, $vector, $name, None);
+            let report = FaultReport {
+                name: $name,
+                mnemonic: $mnemonic,
+                vector: $vector,
+                stack_frame: &stack_frame,
+                error_code: Some($decode(error_code)),
+                control_registers: false,
+                rbp: interrupted_rbp!(),
+            };
+            panic!("{}", report);
+        }
+    };
+}
+
+fatal_handler!(divide_error_handler, "DIVIDE ERROR", "#DE", 0);
+fatal_handler!(non_maskable_interrupt_handler, "NON-MASKABLE INTERRUPT", "NMI", 2);
+fatal_handler!(overflow_handler, "OVERFLOW", "#OF", 4);
+fatal_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED", "#BR", 5);
+fatal_handler!(invalid_opcode_handler, "INVALID OPCODE", "#UD", 6);
+fatal_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE", "#NM", 7);
+fatal_handler!(invalid_tss_handler, "INVALID TSS", "#TS", 10, ErrorCode::Selector);
+fatal_handler!(
+    segment_not_present_handler,
+    "SEGMENT NOT PRESENT",
+    "#NP",
+    11,
+    ErrorCode::Selector
+);
+fatal_handler!(
+    stack_segment_fault_handler,
+    "STACK-SEGMENT FAULT",
+    "#SS",
+    12,
+    ErrorCode::Selector
+);
+fatal_handler!(
+    general_protection_fault_handler,
+    "GENERAL PROTECTION FAULT",
+    "#GP",
+    13,
+    ErrorCode::Selector
+);
+fatal_handler!(x87_floating_point_handler, "x87 FLOATING-POINT EXCEPTION", "#MF", 16);
+fatal_handler!(alignment_check_handler, "ALIGNMENT CHECK", "#AC", 17, ErrorCode::Raw);
+fatal_handler!(simd_floating_point_handler, "SIMD FLOATING-POINT EXCEPTION", "#XM", 19);
+fatal_handler!(virtualization_handler, "VIRTUALIZATION EXCEPTION", "#VE", 20);
+fatal_handler!(
+    control_protection_handler,
+    "CONTROL PROTECTION EXCEPTION",
+    "#CP",
+    21,
+    ErrorCode::ControlProtection
+);
+fatal_handler!(hv_injection_handler, "HYPERVISOR INJECTION EXCEPTION", "#HV", 28);
+fatal_handler!(
+    vmm_communication_handler,
+    "VMM COMMUNICATION EXCEPTION",
+    "#VC",
+    29,
+    ErrorCode::Raw
+);
+fatal_handler!(security_exception_handler, "SECURITY EXCEPTION", "#SX", 30, ErrorCode::Raw);
+
+// the x86-interrupt calling convention makes sure that all the
+// registers are preserved. A calling convention divides the existing registers into
+// two categories - 'preserved regsiters' and 'scratch registers'
+// The calling convention ensures that the `preserved registers` are not modified when the
+// function returns, whereas the `scratch registers` can be modified.
+// This is fine for normal function calls which happen only with the `call` instruction
+// but in case of exceptions, it can happen at any instruction and so there is a need to make sure
+// that all the registers are preserved - which is done by `x86-interrupt` calling convention
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+// debug exceptions are triggered by the debug registers (e.g. hardware breakpoints) and are
+// reported without stopping the kernel, just like breakpoints
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    kill_if_user_mode(&stack_frame, 14, "PAGE FAULT", Some(Cr2::read()));
+    let report = FaultReport {
+        name: "PAGE FAULT",
+        mnemonic: "#PF",
+        vector: 14,
+        stack_frame: &stack_frame,
+        error_code: Some(ErrorCode::PageFault(error_code)),
+        control_registers: true,
+        rbp: interrupted_rbp!(),
+    };
+    panic!("{}", report);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    enter_kernel_gs(&stack_frame);
+    // a double fault is often caused by a page fault that could not be handled, so the control
+    // registers are interesting here as well
+    let report = FaultReport {
+        name: "DOUBLE FAULT",
+        mnemonic: "#DF",
+        vector: 8,
+        stack_frame: &stack_frame,
+        error_code: Some(ErrorCode::Raw(error_code)),
+        control_registers: true,
+        rbp: interrupted_rbp!(),
+    };
+    panic!("{}", report);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    enter_kernel_gs(&stack_frame);
+    let report = FaultReport {
+        name: "MACHINE CHECK",
+        mnemonic: "#MC",
+        vector: 18,
+        stack_frame: &stack_frame,
+        error_code: None,
+        control_registers: false,
+        rbp: interrupted_rbp!(),
+    };
+    panic!("{}", report);
+}
+
+// Formats into a fixed buffer, since the lib tests run without a heap
+#[cfg(test)]
+fn format_error_code(error_code: ErrorCode, buffer: &mut [u8]) -> &str {
+    struct Buffer<'a> {
+        bytes: &'a mut [u8],
+        len: usize,
+    }
+    impl fmt::Write for Buffer<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.bytes
+                .get_mut(self.len..end)
+                .ok_or(fmt::Error)?
+                .copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    let mut writer = Buffer {
+        bytes: buffer,
+        len: 0,
+    };
+    fmt::write(&mut writer, format_args!("{}", error_code)).expect("buffer too small");
+    let len = writer.len;
+    core::str::from_utf8(&buffer[..len]).unwrap()
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    let mut buffer = [0; 64];
+    assert_eq!(
+        format_error_code(ErrorCode::Selector(0x10), &mut buffer),
+        "0x10 (GDT index 2)"
+    );
+    assert_eq!(
+        format_error_code(ErrorCode::Selector(0x6b), &mut buffer),
+        "0x6b (IDT index 13, external event)"
+    );
+    assert_eq!(
+        format_error_code(ErrorCode::ControlProtection(3), &mut buffer),
+        "0x3 (missing endbranch)"
+    );
+}
@@ -0,0 +1,111 @@
+/**
+ * Interrupt handlers of devices that are found at runtime, like the PCI devices.
+ *
+ * The legacy IRQs 3 to 15 go to the vectors right after the timer and the keyboard, under the
+ * PICs as well as under the APIC. A driver registers a handler for the interrupt line of its
+ * device with `register`, which also unmasks the line. PCI devices can share a line, so every
+ * handler of the line runs and has to check whether its device raised the interrupt.
+ */
+use alloc::{boxed::Box, vec::Vec};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use super::{apic, PICS, PIC_1_OFFSET};
+use crate::{percpu::KernelGs, sync::IrqSafeMutex};
+
+type Handler = Box<dyn Fn() + Send + Sync>;
+
+const IRQ_COUNT: usize = 16;
+
+// The handlers run in the interrupt, so they must not block or allocate
+static HANDLERS: [IrqSafeMutex<Vec<Handler>>; IRQ_COUNT] =
+    [const { IrqSafeMutex::new(Vec::new()) }; IRQ_COUNT];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    // the timer, the keyboard and the cascade of the PICs can't be shared
+    Reserved,
+    OutOfRange,
+    // the APIC is in use, but no IOAPIC handles the line
+    NotRoutable,
+}
+
+pub fn vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+// Runs `handler` on every interrupt of the given line and unmasks the line
+pub fn register(irq: u8, handler: Handler) -> Result<(), RegisterError> {
+    match irq {
+        0..=2 => return Err(RegisterError::Reserved),
+        3..=15 => {}
+        _ => return Err(RegisterError::OutOfRange),
+    }
+    let mut handlers = HANDLERS[usize::from(irq)].lock();
+    let first = handlers.is_empty();
+    handlers.push(handler);
+    if first {
+        if apic::is_enabled() {
+            if !apic::route_pci_irq(irq, vector(irq)) {
+                handlers.pop();
+                return Err(RegisterError::NotRoutable);
+            }
+        } else {
+            unmask_pic(irq);
+        }
+    }
+    Ok(())
+}
+
+fn unmask_pic(irq: u8) {
+    let mut pics = PICS.lock();
+    unsafe {
+        let [mut primary, mut secondary] = pics.read_masks();
+        if irq < 8 {
+            primary &= !(1 << irq);
+        } else {
+            secondary &= !(1 << (irq - 8));
+            // the secondary PIC is connected to line 2 of the primary one
+            primary &= !(1 << 2);
+        }
+        pics.write_masks(primary, secondary);
+    }
+}
+
+fn handle(irq: u8) {
+    for handler in HANDLERS[usize::from(irq)].lock().iter() {
+        handler();
+    }
+    super::end_of_interrupt(vector(irq));
+}
+
+// one entry point per line, since the handler doesn't learn the vector it was called for
+macro_rules! device_irqs {
+    ($($irq:literal => $handler:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+                let _gs = KernelGs::enter(&stack_frame);
+                handle($irq);
+            }
+        )*
+
+        pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+            $(idt[usize::from(vector($irq))].set_handler_fn($handler);)*
+        }
+    };
+}
+
+device_irqs!(
+    3 => irq3,
+    4 => irq4,
+    5 => irq5,
+    6 => irq6,
+    7 => irq7,
+    8 => irq8,
+    9 => irq9,
+    10 => irq10,
+    11 => irq11,
+    12 => irq12,
+    13 => irq13,
+    14 => irq14,
+    15 => irq15,
+);
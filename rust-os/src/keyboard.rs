@@ -0,0 +1,344 @@
+/**
+ * The keyboard subsystem.
+ *
+ * The keyboard interrupt handler passes every scancode to `handle_scancode`, which decodes it with
+ * the selected layout into a `KeyEvent`. Every event goes to all subscribers: `subscribe` returns
+ * a stream of the key presses and releases, with the state of the modifiers at that time, so a
+ * consumer like the shell doesn't have to deal with scancodes or track the modifiers itself.
+ *
+ * The layout can be switched at runtime with `set_layout`.
+ */
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+
+pub use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+
+use crate::sync::IrqSafeMutex;
+
+// the number of events a subscriber can fall behind before events are dropped
+const QUEUE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us = 0,
+    Uk,
+    Dvorak,
+    Azerty,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [Layout::Us, Layout::Uk, Layout::Dvorak, Layout::Azerty];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::Dvorak => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL
+            .iter()
+            .copied()
+            .find(|layout| layout.name() == name)
+    }
+
+    fn from_u8(value: u8) -> Layout {
+        Layout::ALL[usize::from(value)]
+    }
+}
+
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+pub fn layout() -> Layout {
+    Layout::from_u8(LAYOUT.load(Ordering::Relaxed))
+}
+
+// Selects the layout for the following key presses
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+    DECODER.lock().set_layout(layout);
+}
+
+// The modifier keys that are held down, and the lock keys that are on
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    // Updates the modifiers for a key event. Returns whether the key is a modifier.
+    fn update(&mut self, code: KeyCode, state: KeyState) -> bool {
+        let down = state == KeyState::Down;
+        match code {
+            KeyCode::ShiftLeft => self.left_shift = down,
+            KeyCode::ShiftRight => self.right_shift = down,
+            KeyCode::ControlLeft => self.left_ctrl = down,
+            KeyCode::ControlRight => self.right_ctrl = down,
+            KeyCode::AltLeft => self.alt = down,
+            KeyCode::AltRight => self.alt_gr = down,
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if down => self.num_lock = !self.num_lock,
+            KeyCode::CapsLock | KeyCode::NumpadLock => {}
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    // the modifiers after this event was applied
+    pub modifiers: Modifiers,
+    // What the key stands for in the selected layout. Only set when a key other than a modifier
+    // is pressed. Ctrl does not change the key, see `ctrl_combination`.
+    pub key: Option<DecodedKey>,
+}
+
+impl KeyEvent {
+    // Returns the letter if this is a Ctrl-<letter> press, e.g. 'c' for Ctrl-C
+    pub fn ctrl_combination(&self) -> Option<char> {
+        match self.key {
+            Some(DecodedKey::Unicode(c)) if self.modifiers.ctrl() && c.is_ascii_alphabetic() => {
+                Some(c.to_ascii_lowercase())
+            }
+            _ => None,
+        }
+    }
+}
+
+// `pc_keyboard` picks the layout at compile time, so there is a keyboard for every layout
+enum LayoutKeyboard {
+    Us(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Uk(Keyboard<layouts::Uk105Key, ScancodeSet1>),
+    Dvorak(Keyboard<layouts::Dvorak104Key, ScancodeSet1>),
+    Azerty(Keyboard<layouts::Azerty, ScancodeSet1>),
+}
+
+// calls the same method on the keyboard of whichever layout is selected
+macro_rules! with_keyboard {
+    ($keyboard:expr, $k:ident => $body:expr) => {
+        match $keyboard {
+            LayoutKeyboard::Us($k) => $body,
+            LayoutKeyboard::Uk($k) => $body,
+            LayoutKeyboard::Dvorak($k) => $body,
+            LayoutKeyboard::Azerty($k) => $body,
+        }
+    };
+}
+
+impl LayoutKeyboard {
+    fn new(layout: Layout) -> Self {
+        // Ctrl is reported in the modifiers, the keys are not turned into control characters
+        let ctrl = HandleControl::Ignore;
+        match layout {
+            Layout::Us => LayoutKeyboard::Us(Keyboard::new(layouts::Us104Key, ScancodeSet1, ctrl)),
+            Layout::Uk => LayoutKeyboard::Uk(Keyboard::new(layouts::Uk105Key, ScancodeSet1, ctrl)),
+            Layout::Dvorak => {
+                LayoutKeyboard::Dvorak(Keyboard::new(layouts::Dvorak104Key, ScancodeSet1, ctrl))
+            }
+            Layout::Azerty => {
+                LayoutKeyboard::Azerty(Keyboard::new(layouts::Azerty, ScancodeSet1, ctrl))
+            }
+        }
+    }
+}
+
+// Turns scancodes into key events
+struct Decoder {
+    keyboard: LayoutKeyboard,
+    modifiers: Modifiers,
+}
+
+impl Decoder {
+    fn new(layout: Layout) -> Self {
+        Decoder {
+            keyboard: LayoutKeyboard::new(layout),
+            modifiers: Modifiers::default(),
+        }
+    }
+
+    fn set_layout(&mut self, layout: Layout) {
+        self.keyboard = LayoutKeyboard::new(layout);
+    }
+
+    // Returns the event once the scancode completed one, some keys send several bytes
+    fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let event = with_keyboard!(&mut self.keyboard, k => k.add_byte(scancode)).ok()??;
+        let (code, state) = (event.code, event.state);
+        let is_modifier = self.modifiers.update(code, state);
+        // the keyboard of `pc_keyboard` tracks the modifiers on its own, so it has to see every
+        // event
+        let decoded = with_keyboard!(&mut self.keyboard, k => k.process_keyevent(event));
+        let key = if is_modifier || state != KeyState::Down {
+            None
+        } else {
+            decoded
+        };
+        Some(KeyEvent {
+            code,
+            state,
+            modifiers: self.modifiers,
+            key,
+        })
+    }
+}
+
+struct Subscriber {
+    queue: ArrayQueue<KeyEvent>,
+    waker: AtomicWaker,
+}
+
+lazy_static! {
+    static ref DECODER: IrqSafeMutex<Decoder> = IrqSafeMutex::new(Decoder::new(Layout::Us));
+}
+
+// The interrupt handler goes through this list, so it must not allocate while it holds the lock.
+// The subscribers are only added and removed by tasks.
+static SUBSCRIBERS: IrqSafeMutex<Vec<Arc<Subscriber>>> = IrqSafeMutex::new(Vec::new());
+
+// Called by the keyboard interrupt handler with every byte read from the keyboard controller.
+//
+// Must not block or allocate
+pub(crate) fn handle_scancode(scancode: u8) {
+    let event = match DECODER.lock().add_byte(scancode) {
+        Some(event) => event,
+        None => return,
+    };
+    for subscriber in SUBSCRIBERS.lock().iter() {
+        if subscriber.queue.push(event.clone()).is_err() {
+            log::warn!("key event queue full; dropping keyboard input");
+        } else {
+            subscriber.waker.wake();
+        }
+    }
+}
+
+// Returns a stream of all key events from now on
+pub fn subscribe() -> KeyEvents {
+    let subscriber = Arc::new(Subscriber {
+        queue: ArrayQueue::new(QUEUE_SIZE),
+        waker: AtomicWaker::new(),
+    });
+    SUBSCRIBERS.lock().push(subscriber.clone());
+    KeyEvents { subscriber }
+}
+
+pub struct KeyEvents {
+    subscriber: Arc<Subscriber>,
+}
+
+impl Stream for KeyEvents {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+        let subscriber = &self.subscriber;
+        // fast path
+        if let Ok(event) = subscriber.queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        // an event could arrive between the check above and the registration, so we check again
+        subscriber.waker.register(cx.waker());
+        match subscriber.queue.pop() {
+            Ok(event) => {
+                subscriber.waker.take();
+                Poll::Ready(Some(event))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for KeyEvents {
+    fn drop(&mut self) {
+        SUBSCRIBERS
+            .lock()
+            .retain(|subscriber| !Arc::ptr_eq(subscriber, &self.subscriber));
+    }
+}
+
+#[test_case]
+fn test_modifiers() {
+    let mut modifiers = Modifiers::default();
+    assert!(modifiers.update(KeyCode::ShiftLeft, KeyState::Down));
+    assert!(modifiers.update(KeyCode::ShiftRight, KeyState::Down));
+    assert!(modifiers.update(KeyCode::ShiftLeft, KeyState::Up));
+    assert!(modifiers.shift());
+    // the lock keys toggle on every press
+    modifiers.update(KeyCode::CapsLock, KeyState::Down);
+    modifiers.update(KeyCode::CapsLock, KeyState::Up);
+    assert!(modifiers.caps_lock);
+    modifiers.update(KeyCode::CapsLock, KeyState::Down);
+    assert!(!modifiers.caps_lock);
+    assert!(!modifiers.update(KeyCode::A, KeyState::Down));
+}
+
+#[test_case]
+fn test_ctrl_combination() {
+    let mut event = KeyEvent {
+        code: KeyCode::C,
+        state: KeyState::Down,
+        modifiers: Modifiers::default(),
+        key: Some(DecodedKey::Unicode('c')),
+    };
+    assert_eq!(event.ctrl_combination(), None);
+    event.modifiers.right_ctrl = true;
+    assert_eq!(event.ctrl_combination(), Some('c'));
+}
+
+#[test_case]
+fn test_layout_names() {
+    for layout in Layout::ALL {
+        assert_eq!(Layout::from_name(layout.name()), Some(layout));
+        assert_eq!(Layout::from_u8(layout as u8), layout);
+    }
+    assert_eq!(Layout::from_name("qwertz"), None);
+}
+
+#[test_case]
+fn test_decoder() {
+    let mut decoder = Decoder::new(Layout::Us);
+    // left shift, then A
+    assert_eq!(decoder.add_byte(0x2a).unwrap().key, None);
+    let event = decoder.add_byte(0x1e).unwrap();
+    assert_eq!(event.key, Some(DecodedKey::Unicode('A')));
+    assert!(event.modifiers.shift());
+    let event = decoder.add_byte(0x9e).unwrap();
+    assert_eq!((event.state, event.key), (KeyState::Up, None));
+    decoder.add_byte(0xaa);
+
+    // the key labeled S on a US keyboard is the O of the Dvorak layout
+    decoder.set_layout(Layout::Dvorak);
+    let event = decoder.add_byte(0x1f).unwrap();
+    assert_eq!(event.key, Some(DecodedKey::Unicode('o')));
+    assert!(!event.modifiers.shift());
+}
@@ -3,6 +3,11 @@
 #![no_main]
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
+// the default test framework depends on the standard library, so we use our own test runner
+// (see `test_runner` below)
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
@@ -11,12 +16,36 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 extern crate alloc;
 
+use core::panic::PanicInfo;
+
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
+
+pub mod acpi;
 pub mod allocator;
+pub mod backtrace;
+pub mod block;
+pub mod config;
+pub mod framebuffer;
+pub mod fs;
 pub mod gdt;
 pub mod interrupts;
+pub mod keyboard;
+pub mod logger;
 pub mod memory;
+pub mod net;
+pub mod pci;
+pub mod percpu;
+pub mod process;
+pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod sync;
 pub mod task;
+pub mod thread;
+pub mod timer;
 pub mod vga_buffer;
+pub mod virtio;
 
 pub fn hlt_loop() -> ! {
     loop {
@@ -24,10 +53,111 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+// Resets the machine
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+
+    // the keyboard controller can pulse the reset line of the CPU
+    unsafe { Port::<u8>::new(0x64).write(0xfe) };
+    // if that didn't work, we cause a triple fault: without an IDT, the breakpoint exception
+    // can't be handled and neither can the double fault that follows
+    unsafe {
+        x86_64::instructions::tables::lidt(&x86_64::structures::DescriptorTablePointer {
+            limit: 0,
+            base: x86_64::VirtAddr::new(0),
+        });
+    }
+    x86_64::instructions::interrupts::int3();
+    hlt_loop();
+}
+
 pub fn init() {
+    // debug builds also log the debug messages
+    let log_level = if cfg!(debug_assertions) {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+    logger::init(log_level);
     interrupts::init_idt();
+    percpu::init();
     gdt::init();
+    process::init();
     unsafe { interrupts::PICS.lock().initialize() };
+    timer::init();
     // the CPU by default does not listen to external hardware interrupts, we enable it to do so here
     x86_64::instructions::interrupts::enable();
 }
+
+// Every test function is wrapped in this trait so that the runner can print its name before
+// running it and `[ok]` after it returned.
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+// The compiler collects all the functions annotated with `#[test_case]` and passes them to this
+// function. Since there is nothing to return to, we exit QEMU once all tests have passed.
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+// Called by the panic handlers of the test binaries. A panicking test fails the whole test run.
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    serial_print!("{}", backtrace::Backtrace::here());
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+// The exit codes written to QEMU's `isa-debug-exit` device. QEMU exits with `(value << 1) | 1`,
+// so the codes are chosen to not clash with QEMU's own exit codes. `Success` becomes 33, which is
+// configured as `test-success-exit-code` for bootimage in Cargo.toml.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        // 0xf4 is the `iobase` of the `isa-debug-exit` device given in the test-args
+        let mut port = Port::new(0xf4);
+        port.write(exit_code as u32);
+    }
+}
+
+// Entry point for `cargo test --lib`
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
+#[cfg(test)]
+fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+    init();
+    test_main();
+    hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
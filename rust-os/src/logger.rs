@@ -0,0 +1,59 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::timer;
+
+// A backend for the `log` crate, so that the kernel can use `log::info!` and friends. Every
+// record goes to the VGA buffer and to the serial port, which means that the messages also show
+// up on the host when QEMU runs with `-serial stdio`.
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+// Installs the kernel logger. Must only be called once.
+pub fn init(level: LevelFilter) {
+    log::set_logger(&LOGGER).expect("logger already initialized");
+    log::set_max_level(level);
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // the uptime is derived from the timer ticks, which is the only clock we have
+        let uptime = timer::uptime();
+        let (secs, millis) = (uptime.as_secs(), uptime.subsec_millis());
+        let level = level_name(record.level());
+        crate::vga_buffer::_print(format_args!(
+            "[{:>5}.{:03}] {} {}\n",
+            secs,
+            millis,
+            level,
+            record.args()
+        ));
+        crate::serial::_print(format_args!(
+            "[{:>5}.{:03}] {} {}: {}\n",
+            secs,
+            millis,
+            level,
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {}
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN ",
+        Level::Info => "INFO ",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
@@ -10,20 +10,22 @@
 // but for a free-standing binray, we don't have acces to `crt0`
 // we are using this attribute to tell the rust compiler that we don't want the normal entry point chain
 #![no_main]
+// the test framework needs to be set up for every binary, see lib.rs for the details
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
-use core::panic::PanicInfo;
+use core::{panic::PanicInfo, time::Duration};
 
 use bootloader::{entry_point, BootInfo};
 use rust_os::{
-    allocator, hlt_loop,
+    allocator, backtrace, config, fs, interrupts,
     memory::{self, BootInfoFrameAllocator},
-    println,
-    task::{
-        keyboard,
-        simple_executor::{self, SimpleExecutor},
-        Task,
-    },
+    net::{self, udp::UdpSocket},
+    pci, shell, smp,
+    task::{executor::Executor, Task},
+    thread, timer, virtio,
 };
 use x86_64::VirtAddr;
 
@@ -43,21 +45,111 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // Initialize Heap
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    log::info!("heap initialized");
+    // from now on, backtraces name the functions
+    match unsafe { backtrace::symbols::load(&boot_info.memory_map) } {
+        Ok(count) => log::info!("backtrace: {} functions in the symbol table", count),
+        Err(error) => log::warn!("backtrace: no symbols: {:?}", error),
+    }
 
-    let mut executor = SimpleExecutor::new();
-    executor.spawn(Task::new(keyboard::print_keypresses())); // new
+    // the PICs keep delivering the interrupts if the APIC can't be used
+    let apic = memory::with_kernel_memory(|memory| {
+        interrupts::apic::init(&mut memory.mapper, &mut memory.frame_allocator)
+    });
+    if let Err(error) = apic {
+        log::warn!("APIC unavailable, using the 8259 PICs: {:?}", error);
+    }
+    pci::init();
+    virtio::init();
+
+    // the boot code becomes the thread `main`, which runs the executor below
+    thread::init();
+    // the other CPUs run the executor as well, the tasks spawned below spread over them
+    if let Err(error) = smp::init() {
+        log::warn!("only using the boot CPU: {:?}", error);
+    }
+
+    #[cfg(test)]
+    test_main();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(shell::run()));
+    executor.spawn(Task::new(status_line()));
+    executor.spawn(Task::new(load_config()));
+    executor.spawn(Task::new(net::run()));
+    executor.spawn(Task::new(udp_echo()));
     executor.run();
+}
 
-    hlt_loop();
+// Keeps the uptime in the status line up to date, waking up once a second
+async fn status_line() {
+    loop {
+        let uptime = timer::uptime();
+        rust_os::print_status!(
+            " rust-os | uptime {:>5}.{:01}s",
+            uptime.as_secs(),
+            uptime.subsec_millis() / 100
+        );
+        timer::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// Sends every UDP datagram to port 7 back to where it came from, like the echo service of old
+async fn udp_echo() {
+    let socket = match UdpSocket::bind(7) {
+        Ok(socket) => socket,
+        Err(error) => {
+            log::warn!("udp echo: {}", error.as_str());
+            return;
+        }
+    };
+    loop {
+        let datagram = socket.recv_from().await;
+        let reply = socket.send_to(&datagram.data, datagram.source, datagram.source_port);
+        if let Err(error) = reply.await {
+            log::warn!("udp echo to {}: {}", datagram.source, error.as_str());
+        }
+    }
+}
+
+// Mounts the root file system and applies the boot configuration on it
+async fn load_config() {
+    if let Err(error) = fs::mount_root().await {
+        log::warn!("no root file system: {}", error.as_str());
+        return;
+    }
+    config::load().await;
 }
 
 // panic_handler, as the name suggests, is what knows how to handle a `panic`
 // this is needed as we have disabled the standard library
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use rust_os::{serial, vga_buffer};
+
+    // We might have panicked while printing, in which case the locks would never be released.
+    // Nothing else is going to run after this, so we can safely take them over.
+    unsafe {
+        serial::SERIAL1.force_unlock();
+        vga_buffer::WRITER.force_unlock();
+    }
+    // serial goes first, so that the message reaches the host even if the VGA buffer is unusable
+    rust_os::serial_println!("{}", info);
+    rust_os::println!("{}", info);
+    // the backtrace and the statistics don't fit on the screen next to the message, and they
+    // don't allocate
+    rust_os::serial_print!("{}", backtrace::Backtrace::here());
+    rust_os::serial_println!("{}", allocator::stats());
+    rust_os::hlt_loop();
+}
+
+// in test mode, the panic is reported over serial and QEMU exits with a failure
+#[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    hlt_loop();
+    rust_os::test_panic_handler(info)
 }
@@ -1,38 +1,391 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use x86_64::{
     registers::control::Cr3,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+use crate::sync::IrqSafeMutex;
+
+// the virtual address at which the bootloader mapped the complete physical memory, set by `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Returns the virtual address through which the given physical address can be accessed.
+//
+// Only valid after `init` was called, and only for normal memory (device registers should be
+// mapped with `map_mmio` instead, so that they are not cached).
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+// Returns the virtual address at which the complete physical memory is mapped
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+// Device registers are mapped into this region, right after each other
+const MMIO_START: u64 = 0x_5555_0000_0000;
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Maps the memory-mapped device registers at `phys_addr` (e.g. of the local APIC) into the MMIO
+/// region and returns their virtual address.
+///
+/// # Safety
+///
+/// The caller must guarantee that the given physical range belongs to a device, since it is
+/// mapped without caching and can alias other mappings.
+pub unsafe fn map_mmio(
+    phys_addr: PhysAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+    let last_frame = PhysFrame::containing_address(phys_addr + (size.max(1) - 1));
+    let frame_count = last_frame - first_frame + 1;
+    let virt_start = NEXT_MMIO.fetch_add(frame_count * 4096, Ordering::Relaxed);
+
+    // device registers must not be cached, otherwise writes might never reach the device
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+        let page = Page::containing_address(VirtAddr::new(virt_start + i as u64 * 4096));
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    }
+    Ok(VirtAddr::new(virt_start) + (phys_addr - first_frame.start_address()))
+}
+
+/// Maps the `size` bytes starting at `start` to newly allocated frames, e.g. for the heap.
+///
+/// Every part of the range that is 2 MiB aligned and at least 2 MiB large is mapped with a huge
+/// page if the frame allocator still has a free 2 MiB frame, the rest is mapped with 4 KiB pages.
+///
+/// If a page can't be mapped, the pages that were mapped before it are unmapped again and their
+/// frames freed, so that a failed mapping leaves nothing behind.
+///
+/// # Safety
+///
+/// The caller must guarantee that the range is not used yet.
+pub unsafe fn map_range<A>(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    map_range_inner(start, None, size, flags, mapper, frame_allocator).map_err(|(failed, error)| {
+        unmap_allocated(start, failed, mapper, frame_allocator);
+        error
+    })
+}
+
+// Unmaps the pages from `start` up to `end`, which `map_range_inner` mapped to frames it
+// allocated, and frees the frames. The page tables stay, the next mapping in this part of the
+// address space uses them again.
+unsafe fn unmap_allocated<A>(
+    start: VirtAddr,
+    end: VirtAddr,
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
+    frame_allocator: &mut A,
+) where
+    A: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB>,
+{
+    let mut addr = start.align_down(Size4KiB::SIZE);
+    while addr < end {
+        // `map_range_inner` used a huge page here if unmapping one works
+        if addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE {
+            let page = Page::<Size2MiB>::containing_address(addr);
+            if let Ok((frame, flush)) = Mapper::<Size2MiB>::unmap(mapper, page) {
+                flush.flush();
+                FrameDeallocator::<Size2MiB>::deallocate_frame(frame_allocator, frame);
+                addr += Size2MiB::SIZE;
+                continue;
+            }
+        }
+        let page = Page::<Size4KiB>::containing_address(addr);
+        if let Ok((frame, flush)) = Mapper::<Size4KiB>::unmap(mapper, page) {
+            flush.flush();
+            FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame);
+        }
+        addr += Size4KiB::SIZE;
+    }
+}
+
+/// Maps the `size` bytes starting at `start` to the physical memory at `phys_start`, e.g. for a
+/// framebuffer. Huge pages are used where both addresses are 2 MiB aligned. Both addresses must
+/// have the same offset into their page.
+///
+/// # Safety
+///
+/// The caller must guarantee that mapping the physical memory does not alias memory that is used
+/// otherwise.
+pub unsafe fn map_physical_range(
+    start: VirtAddr,
+    phys_start: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    // the physical frames are given, so the allocator is only used for the page tables and never
+    // asked for a 2 MiB frame
+    struct TablesOnly<'a, A>(&'a mut A);
+    unsafe impl<A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB> for TablesOnly<'_, A> {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+            self.0.allocate_frame()
+        }
+    }
+    unsafe impl<A> FrameAllocator<Size2MiB> for TablesOnly<'_, A> {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+            None
+        }
+    }
+    // `map_range_inner` only frees the frames it allocated itself, and it allocates none here
+    impl<A, S: PageSize> FrameDeallocator<S> for TablesOnly<'_, A> {
+        unsafe fn deallocate_frame(&mut self, _frame: PhysFrame<S>) {}
+    }
+
+    let mut frame_allocator = TablesOnly(frame_allocator);
+    map_range_inner(
+        start,
+        Some(phys_start),
+        size,
+        flags,
+        mapper,
+        &mut frame_allocator,
+    )
+    .map_err(|(_, error)| error)
+}
+
+// Maps the range like `map_range`, or to the physical memory at `phys_start`. On an error, it
+// returns the address of the page that couldn't be mapped along with the error. The pages before
+// it stay mapped, the frame allocated for the failed page is freed again.
+unsafe fn map_range_inner<A>(
+    start: VirtAddr,
+    phys_start: Option<PhysAddr>,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
+    frame_allocator: &mut A,
+) -> Result<(), (VirtAddr, MapToError<Size4KiB>)>
+where
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    let end = start + size;
+    let first_page = start.align_down(Size4KiB::SIZE);
+    let mut addr = first_page;
+    while addr < end {
+        let phys = phys_start.map(|phys| phys.align_down(Size4KiB::SIZE) + (addr - first_page));
+        let huge_fits = addr.is_aligned(Size2MiB::SIZE)
+            && end - addr >= Size2MiB::SIZE
+            && phys.is_none_or(|phys| phys.is_aligned(Size2MiB::SIZE));
+        if huge_fits {
+            let frame = match phys {
+                Some(phys) => Some(PhysFrame::containing_address(phys)),
+                None => FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator),
+            };
+            // without a free 2 MiB frame we fall back to 4 KiB pages below
+            if let Some(frame) = frame {
+                let page = Page::<Size2MiB>::containing_address(addr);
+                match Mapper::<Size2MiB>::map_to(mapper, page, frame, flags, frame_allocator) {
+                    Ok(flush) => flush.flush(),
+                    Err(error) => {
+                        if phys.is_none() {
+                            FrameDeallocator::<Size2MiB>::deallocate_frame(frame_allocator, frame);
+                        }
+                        return Err((addr, huge_map_error(error)));
+                    }
+                }
+                addr += Size2MiB::SIZE;
+                continue;
+            }
+        }
+
+        let frame = match phys {
+            Some(phys) => PhysFrame::containing_address(phys),
+            None => FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+                .ok_or((addr, MapToError::FrameAllocationFailed))?,
+        };
+        let page = Page::<Size4KiB>::containing_address(addr);
+        match Mapper::<Size4KiB>::map_to(mapper, page, frame, flags, frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(error) => {
+                if phys.is_none() {
+                    FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame);
+                }
+                return Err((addr, error));
+            }
+        }
+        addr += Size4KiB::SIZE;
+    }
+    Ok(())
+}
+
+// the callers only deal with one error type, so the frame of a 2 MiB mapping is reported as the
+// 4 KiB frame it starts with
+fn huge_map_error(error: MapToError<Size2MiB>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
+
+// the number of usable and used physical frames, for reporting the memory usage
+static USABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static USED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub used: usize,
+    pub free: usize,
+}
+
+impl FrameStats {
+    pub fn total(&self) -> usize {
+        self.used + self.free
+    }
+}
+
+// Returns how many of the usable physical frames are in use. The frames holding the bitmap of
+// the frame allocator count as used.
+pub fn frame_stats() -> FrameStats {
+    let used = USED_FRAMES.load(Ordering::Relaxed);
+    FrameStats {
+        used,
+        free: USABLE_FRAMES.load(Ordering::Relaxed) - used,
+    }
+}
+
+// The frame below 1 MiB that `BootInfoFrameAllocator::init` reserved for starting the application
+// processors (see `smp`), 0 if there was none
+static STARTUP_FRAME: AtomicU64 = AtomicU64::new(0);
+const STARTUP_FRAME_LIMIT: usize = 256;
+
+pub fn startup_frame() -> Option<PhysFrame> {
+    match STARTUP_FRAME.load(Ordering::Relaxed) {
+        0 => None,
+        frame => Some(PhysFrame::containing_address(PhysAddr::new(frame * 4096))),
+    }
+}
+
+// the number of 4 KiB frames in a 2 MiB frame, and the number of bitmap words covering them
+const FRAMES_PER_HUGE_FRAME: usize = 512;
+const WORDS_PER_HUGE_FRAME: usize = FRAMES_PER_HUGE_FRAME / 64;
+
 // Frame allocator created from the memory map provided by the BootInfo struct from the
 // bootloader.
+//
+// Every frame up to the end of the last usable region is represented by a bit in a bitmap, which
+// is set if the frame is not free (either because it is allocated or because it is not usable at
+// all). The bitmap is stored in the first usable region that is large enough for it.
+//
+// The search for a free frame starts at the word where the last frame was found, so allocating
+// usually only looks at a single word instead of walking the memory map from the start.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+    bitmap: &'static mut [u64],
+    next_word: usize,
 }
 
 impl BootInfoFrameAllocator {
-    // Creates a frame allocator from the given memory map
-    //
-    // This function is unsafe because the caller has to guarantee that the `USABLE` memory regions
-    // given by the memory map are in fact usable.
+    /// Creates a frame allocator from the given memory map
+    ///
+    /// # Safety
+    ///
+    /// The caller has to guarantee that the `USABLE` memory regions given by the memory map are in
+    /// fact usable. `memory::init` must have been called before, since the bitmap is accessed
+    /// through the physical memory mapping.
     pub unsafe fn init(mmap: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map: mmap,
-            next: 0,
+        let usable_regions = || {
+            mmap.iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| r.range.start_frame_number as usize..r.range.end_frame_number as usize)
+        };
+        let frame_count = usable_regions().map(|r| r.end).max().unwrap_or(0);
+        let words = frame_count.div_ceil(64);
+        let bitmap_frames = (words * 8).div_ceil(4096);
+        let bitmap_start = usable_regions()
+            .find(|r| r.len() >= bitmap_frames)
+            .expect("no usable memory region is large enough for the frame bitmap")
+            .start;
+
+        let bitmap_addr = phys_to_virt(PhysAddr::new(bitmap_start as u64 * 4096));
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_addr.as_mut_ptr::<u64>(), words);
+        bitmap.fill(u64::MAX);
+        let mut allocator = BootInfoFrameAllocator {
+            bitmap,
+            next_word: 0,
+        };
+        for region in usable_regions() {
+            region.for_each(|frame| allocator.set_used(frame, false));
+        }
+        for frame in bitmap_start..bitmap_start + bitmap_frames {
+            allocator.set_used(frame, true);
+        }
+        // the application processors start in real mode, so their first code has to be in the
+        // first MiB, which only the frame allocator knows to be free (frame 0 holds the real mode
+        // interrupt table)
+        let startup_frame =
+            (1..STARTUP_FRAME_LIMIT.min(frame_count)).find(|&frame| !allocator.is_used(frame));
+        if let Some(frame) = startup_frame {
+            allocator.set_used(frame, true);
+            STARTUP_FRAME.store(frame as u64, Ordering::Relaxed);
+        }
+
+        USABLE_FRAMES.store(usable_regions().map(|r| r.len()).sum(), Ordering::Relaxed);
+        let reserved = bitmap_frames + usize::from(startup_frame.is_some());
+        USED_FRAMES.store(reserved, Ordering::Relaxed);
+        allocator
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        let (word, bit) = (frame / 64, frame % 64);
+        if used {
+            self.bitmap[word] |= 1 << bit;
+        } else {
+            self.bitmap[word] &= !(1 << bit);
         }
     }
-    // Returns an iterator over the usable frames specified in the memory map.
-    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let memory_regions = self.memory_map.iter();
-        // filter only the regions marked `USABLE`
-        let usable_regions = memory_regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_range = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let phy_frame_addresses = addr_range.flat_map(|a| a.step_by(4096));
-        phy_frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    // Allocates `count` physically contiguous frames and returns the first one, e.g. for a device
+    // that accesses memory on its own. Unlike `allocate_frame`, this searches from the start.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame<Size4KiB>> {
+        let frames = self.bitmap.len() * 64;
+        let mut start = 0;
+        while start + count <= frames {
+            // a used frame in the range means that no range before it fits either
+            match (start..start + count)
+                .rev()
+                .find(|&frame| self.is_used(frame))
+            {
+                Some(used) => start = used + 1,
+                None => {
+                    (start..start + count).for_each(|frame| self.set_used(frame, true));
+                    USED_FRAMES.fetch_add(count, Ordering::Relaxed);
+                    let addr = PhysAddr::new(start as u64 * 4096);
+                    return Some(PhysFrame::containing_address(addr));
+                }
+            }
+        }
+        None
     }
 }
 
@@ -43,19 +396,147 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     // there is a need to create a new PageTable (because the pagetable does not exist). This
     // function provides a usable frame that can be used for the pagetable to be created.
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let words = self.bitmap.len();
+        let word = (0..words)
+            .map(|i| (self.next_word + i) % words)
+            .find(|&word| self.bitmap[word] != u64::MAX)?;
+        self.next_word = word;
+        let frame = word * 64 + self.bitmap[word].trailing_ones() as usize;
+        self.set_used(frame, true);
+        USED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        let addr = PhysAddr::new(frame as u64 * 4096);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+// A 2 MiB frame is made of 512 free 4 KiB frames, starting at a 2 MiB boundary. Since the bitmap
+// starts at frame 0, those are 8 aligned words of the bitmap that are all zero.
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let chunk = self
+            .bitmap
+            .chunks_exact_mut(WORDS_PER_HUGE_FRAME)
+            .enumerate()
+            .find(|(_, words)| words.iter().all(|&word| word == 0));
+        let (index, words) = chunk?;
+        words.fill(u64::MAX);
+        USED_FRAMES.fetch_add(FRAMES_PER_HUGE_FRAME, Ordering::Relaxed);
+        let addr = (index * FRAMES_PER_HUGE_FRAME * 4096) as u64;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let frame = (frame.start_address().as_u64() / 4096) as usize;
+        assert!(self.is_used(frame), "frame {:#x} freed twice", frame * 4096);
+        self.set_used(frame, false);
+        USED_FRAMES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let first = (frame.start_address().as_u64() / 4096) as usize;
+        let words = &mut self.bitmap[first / 64..][..WORDS_PER_HUGE_FRAME];
+        assert!(
+            words.iter().all(|&word| word == u64::MAX),
+            "2 MiB frame {:#x} is not completely allocated",
+            frame.start_address().as_u64()
+        );
+        words.fill(0);
+        USED_FRAMES.fetch_sub(FRAMES_PER_HUGE_FRAME, Ordering::Relaxed);
     }
 }
 
-// Initialize a new OffsetPageTable
+// The page table and frame allocator of the kernel, after they have been handed over with
+// `set_kernel_memory`. This lets code that can't get them passed in map memory too, like the heap
+// when it has to grow.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+static KERNEL_MEMORY: IrqSafeMutex<Option<KernelMemory>> = IrqSafeMutex::new(None);
+
+pub fn set_kernel_memory(
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+) {
+    *KERNEL_MEMORY.lock() = Some(KernelMemory {
+        mapper,
+        frame_allocator,
+    });
+}
+
+// Runs `f` with the kernel page table and frame allocator.
 //
-// This function is unsafe because the caller must guarantee that the
-// complete physical memory is mapped to virtual memory at the passed
-// `physical_memory_offset`. Also, this function must be only called once
-// to avoid aliasing `&mut` references (which is undefined behavior).
+// The heap can't grow while `f` runs, since growing needs the kernel memory itself, so `f`
+// should not allocate much.
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> R {
+    // an interrupt handler that uses the kernel memory must not find it locked by the code it
+    // interrupted, which the `IrqSafeMutex` makes sure of
+    let mut memory = KERNEL_MEMORY.lock();
+    f(memory.as_mut().expect("kernel memory not set"))
+}
+
+// Like `with_kernel_memory`, but returns `None` instead of waiting if the kernel memory is in use
+// (or not set yet). Used by the heap, which might be asked for memory while it's locked.
+pub(crate) fn try_with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    let mut memory = KERNEL_MEMORY.try_lock()?;
+    memory.as_mut().map(f)
+}
+
+// Physically contiguous, zeroed memory for a device that accesses memory on its own (DMA). The
+// device gets the physical address, the kernel accesses the memory through the physical memory
+// mapping. The frames are freed when the buffer is dropped, so the device must be done with it.
+pub struct DmaBuffer {
+    start: PhysFrame<Size4KiB>,
+    frames: usize,
+}
+
+impl DmaBuffer {
+    pub fn allocate(size: usize) -> Option<DmaBuffer> {
+        let frames = size.max(1).div_ceil(4096);
+        let start =
+            with_kernel_memory(|memory| memory.frame_allocator.allocate_contiguous(frames))?;
+        let buffer = DmaBuffer { start, frames };
+        unsafe { core::ptr::write_bytes(buffer.as_mut_ptr::<u8>(), 0, buffer.size()) };
+        Some(buffer)
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        phys_to_virt(self.phys_addr()).as_mut_ptr()
+    }
+
+    pub fn size(&self) -> usize {
+        self.frames * 4096
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        with_kernel_memory(|memory| {
+            for frame in PhysFrame::range(self.start, self.start + self.frames as u64) {
+                unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            }
+        });
+    }
+}
+
+/// Initialize a new OffsetPageTable
+///
+/// # Safety
+///
+/// The caller must guarantee that the complete physical memory is mapped to virtual memory at the
+/// passed `physical_memory_offset`. Also, this function must be only called once to avoid
+/// aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(phy_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(phy_mem_offset.as_u64(), Ordering::Relaxed);
     let l4_pt = active_level4_page_table(phy_mem_offset);
     OffsetPageTable::new(l4_pt, phy_mem_offset)
 }
@@ -75,10 +556,12 @@ unsafe fn active_level4_page_table(physical_memory_offset: VirtAddr) -> &'static
     &mut *p4_pointer
 }
 
-// Translates the given virtual address to the physical address
-//
-// This function is unsafe because the caller must guarantee that the virtual memory region is
-// mapped with the physical memory at a the given offset (phy_mem_offset)
+/// Translates the given virtual address to the physical address
+///
+/// # Safety
+///
+/// The caller must guarantee that the virtual memory region is mapped with the physical memory at
+/// the given offset (phy_mem_offset)
 pub unsafe fn translate_addr(addr: VirtAddr, phy_mem_offset: VirtAddr) -> Option<PhysAddr> {
     translate_addr_inner(addr, phy_mem_offset)
 }
@@ -100,7 +583,7 @@ fn translate_addr_inner(addr: VirtAddr, phy_mem_offset: VirtAddr) -> Option<Phys
     let mut frame = level_4_table_frame;
 
     // traverse the multi-level page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference
         let virt = phy_mem_offset + frame.start_address().as_u64();
         // this gives us an immutable raw pointer (note that *const is just the syntax for immutabe
@@ -113,7 +596,17 @@ fn translate_addr_inner(addr: VirtAddr, phy_mem_offset: VirtAddr) -> Option<Phys
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            // A huge page skips the remaining levels, the rest of the address is the offset into
+            // the page. Entries in the P3 table map 1 GiB pages, entries in the P2 table 2 MiB
+            // pages, the flag is not valid in the other tables.
+            Err(FrameError::HugeFrame) => {
+                let page_size = match level {
+                    1 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    _ => return None,
+                };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
@@ -0,0 +1,242 @@
+/**
+ * A small IPv4 network stack: ARP, ICMP echo and UDP sockets over one Ethernet interface.
+ *
+ * A network driver implements `NetDevice` and attaches its device with `attach`. The received
+ * frames are handled by the `run` task, which answers ARP requests and pings right away and puts
+ * the UDP datagrams into the queues of the sockets. There is no DHCP, the address is configured
+ * statically, by default to the one QEMU's user networking hands out (see `config.rs` for
+ * changing it).
+ */
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU16, Ordering},
+};
+use spin::Mutex;
+
+use ethernet::{Frame, MacAddress};
+use ipv4::Ipv4Address;
+
+pub mod arp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod udp;
+
+// the largest IPv4 packet that fits into an Ethernet frame
+pub const MTU: usize = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    NoInterface,
+    // nobody answered the ARP requests for the address
+    Unreachable,
+    // the data doesn't fit into a single packet
+    TooLarge,
+    AddressInUse,
+    // the device can't take any more frames right now
+    Busy,
+}
+
+impl NetError {
+    // for the shell, which prints the errors
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NetError::NoInterface => "no network interface",
+            NetError::Unreachable => "host unreachable",
+            NetError::TooLarge => "message too long",
+            NetError::AddressInUse => "address in use",
+            NetError::Busy => "device busy",
+        }
+    }
+}
+
+pub type ReceiveFuture<'a> = Pin<Box<dyn Future<Output = Vec<u8>> + Send + 'a>>;
+
+pub trait NetDevice: Send + Sync {
+    // the name of the interface, like `eth0`
+    fn name(&self) -> &str;
+
+    fn mac_address(&self) -> MacAddress;
+
+    // Sends an Ethernet frame, the device adds the checksum
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    // Waits for the next frame
+    fn receive(&self) -> ReceiveFuture<'_>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpConfig {
+    pub address: Ipv4Address,
+    // the number of bits of the address that make up the network, 24 for a netmask of
+    // 255.255.255.0
+    pub prefix_len: u8,
+    // the router for the addresses outside of our network
+    pub gateway: Option<Ipv4Address>,
+}
+
+impl IpConfig {
+    // the addresses of QEMU's user networking
+    pub const QEMU_USER: IpConfig = IpConfig {
+        address: Ipv4Address([10, 0, 2, 15]),
+        prefix_len: 24,
+        gateway: Some(Ipv4Address([10, 0, 2, 2])),
+    };
+}
+
+static CONFIG: Mutex<IpConfig> = Mutex::new(IpConfig::QEMU_USER);
+static INTERFACE: Mutex<Option<Arc<Interface>>> = Mutex::new(None);
+
+pub fn config() -> IpConfig {
+    *CONFIG.lock()
+}
+
+pub fn set_config(config: IpConfig) {
+    *CONFIG.lock() = config;
+    // the hosts in the cache may not be in the new network
+    arp::clear();
+}
+
+pub fn interface() -> Option<Arc<Interface>> {
+    INTERFACE.lock().clone()
+}
+
+// Makes `device` the network interface, instead of the one that was attached before
+pub fn attach(device: Arc<dyn NetDevice>) -> Arc<Interface> {
+    log::info!(
+        "network interface {}: {}",
+        device.name(),
+        device.mac_address()
+    );
+    let interface = Arc::new(Interface {
+        device,
+        next_id: AtomicU16::new(0),
+    });
+    *INTERFACE.lock() = Some(interface.clone());
+    arp::clear();
+    interface
+}
+
+// Handles the frames received by the interface, forever. Does nothing without an interface.
+pub async fn run() {
+    if let Some(interface) = interface() {
+        interface.run().await;
+    }
+}
+
+pub struct Interface {
+    device: Arc<dyn NetDevice>,
+    // identifies the packets we send
+    next_id: AtomicU16,
+}
+
+impl Interface {
+    pub fn device(&self) -> &Arc<dyn NetDevice> {
+        &self.device
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.device.mac_address()
+    }
+
+    pub async fn run(&self) {
+        loop {
+            let frame = self.device.receive().await;
+            self.handle_frame(&frame);
+        }
+    }
+
+    // Handles a received frame. Answers go out right away, so this doesn't have to wait.
+    pub fn handle_frame(&self, bytes: &[u8]) {
+        let frame = match Frame::parse(bytes) {
+            Some(frame) => frame,
+            None => return,
+        };
+        if frame.destination != self.mac_address() && frame.destination != MacAddress::BROADCAST {
+            return;
+        }
+        match frame.ethertype {
+            ethernet::TYPE_ARP => arp::handle(self, frame.payload),
+            ethernet::TYPE_IPV4 => self.handle_ipv4(&frame),
+            _ => {}
+        }
+    }
+
+    fn handle_ipv4(&self, frame: &Frame) {
+        let packet = match ipv4::Packet::parse(frame.payload) {
+            Some(packet) => packet,
+            None => return,
+        };
+        let config = config();
+        let broadcast = packet.destination == Ipv4Address::BROADCAST;
+        if packet.destination != config.address && !broadcast {
+            return;
+        }
+        match packet.protocol {
+            // we don't answer pings to the broadcast address, like most hosts today
+            ipv4::PROTOCOL_ICMP if !broadcast => icmp::handle(self, frame.source, &packet),
+            ipv4::PROTOCOL_UDP => udp::deliver(&packet),
+            _ => {}
+        }
+    }
+
+    pub fn send_frame(
+        &self,
+        destination: MacAddress,
+        ethertype: u16,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        let frame = Frame {
+            destination,
+            source: self.mac_address(),
+            ethertype,
+            payload,
+        };
+        self.device.send(&frame.build())
+    }
+
+    // Sends an IPv4 packet to the host with the Ethernet address `mac`, which is either the
+    // destination or the router on the way there
+    pub fn send_ipv4_to(
+        &self,
+        mac: MacAddress,
+        destination: Ipv4Address,
+        protocol: u8,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        if ipv4::HEADER_SIZE + payload.len() > MTU {
+            return Err(NetError::TooLarge);
+        }
+        let packet = ipv4::Packet {
+            source: config().address,
+            destination,
+            protocol,
+            payload,
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.send_frame(mac, ethernet::TYPE_IPV4, &packet.build(id))
+    }
+
+    // Sends an IPv4 packet, through the gateway if the destination is outside of our network
+    pub async fn send_ipv4(
+        &self,
+        destination: Ipv4Address,
+        protocol: u8,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        let config = config();
+        let mac = if destination == Ipv4Address::BROADCAST {
+            MacAddress::BROADCAST
+        } else {
+            let next_hop = if destination.same_network(config.address, config.prefix_len) {
+                destination
+            } else {
+                config.gateway.ok_or(NetError::Unreachable)?
+            };
+            arp::resolve(self, next_hop).await?
+        };
+        self.send_ipv4_to(mac, destination, protocol, payload)
+    }
+}
@@ -0,0 +1,177 @@
+/**
+ * ARP, which finds the Ethernet address that belongs to an IPv4 address in the local network.
+ *
+ * A request is broadcast to the whole network, the host with the address answers. The answers
+ * are kept in a cache, which also learns the sender of every request for our address, since that
+ * host is usually about to talk to us. Entries don't expire, the networks of a VM don't change.
+ */
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{
+    future::{self, Future},
+    task::{Poll, Waker},
+    time::Duration,
+};
+use futures_util::future::{select, Either};
+use spin::Mutex;
+
+use super::{
+    ethernet::{self, MacAddress},
+    ipv4::Ipv4Address,
+    Interface, NetError,
+};
+use crate::timer;
+
+const PACKET_SIZE: usize = 28;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+// the hardware type of Ethernet
+const HARDWARE_ETHERNET: u16 = 1;
+const REQUEST_ATTEMPTS: usize = 3;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+static CACHE: Mutex<BTreeMap<Ipv4Address, MacAddress>> = Mutex::new(BTreeMap::new());
+// the tasks waiting in `resolve` for a new entry
+static WAITERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl Packet {
+    // Only takes the packets for Ethernet and IPv4, the only combination there is for us
+    pub fn parse(bytes: &[u8]) -> Option<Packet> {
+        if bytes.len() < PACKET_SIZE {
+            return None;
+        }
+        let read_u16 = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let header_ok = read_u16(0) == HARDWARE_ETHERNET
+            && read_u16(2) == ethernet::TYPE_IPV4
+            && bytes[4] == 6
+            && bytes[5] == 4;
+        if !header_ok {
+            return None;
+        }
+        let mac = |offset: usize| {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&bytes[offset..offset + 6]);
+            MacAddress(mac)
+        };
+        let ip = |offset: usize| {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&bytes[offset..offset + 4]);
+            Ipv4Address(ip)
+        };
+        Some(Packet {
+            operation: read_u16(6),
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PACKET_SIZE);
+        bytes.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        bytes.extend_from_slice(&ethernet::TYPE_IPV4.to_be_bytes());
+        bytes.extend_from_slice(&[6, 4]);
+        bytes.extend_from_slice(&self.operation.to_be_bytes());
+        bytes.extend_from_slice(&self.sender_mac.0);
+        bytes.extend_from_slice(&self.sender_ip.0);
+        bytes.extend_from_slice(&self.target_mac.0);
+        bytes.extend_from_slice(&self.target_ip.0);
+        bytes
+    }
+}
+
+pub fn lookup(ip: Ipv4Address) -> Option<MacAddress> {
+    CACHE.lock().get(&ip).copied()
+}
+
+// The entries of the cache, for the shell
+pub fn entries() -> Vec<(Ipv4Address, MacAddress)> {
+    CACHE.lock().iter().map(|(&ip, &mac)| (ip, mac)).collect()
+}
+
+pub(super) fn clear() {
+    CACHE.lock().clear();
+}
+
+fn insert(ip: Ipv4Address, mac: MacAddress) {
+    CACHE.lock().insert(ip, mac);
+    for waker in WAITERS.lock().drain(..) {
+        waker.wake();
+    }
+}
+
+// Handles a received ARP packet
+pub(super) fn handle(interface: &Interface, payload: &[u8]) {
+    let packet = match Packet::parse(payload) {
+        Some(packet) => packet,
+        None => return,
+    };
+    let our_ip = super::config().address;
+    let for_us = packet.target_ip == our_ip && our_ip != Ipv4Address::UNSPECIFIED;
+    // the replies to our requests and the requests for us, not what others ask each other
+    if for_us || packet.operation == OPERATION_REPLY {
+        insert(packet.sender_ip, packet.sender_mac);
+    }
+    if for_us && packet.operation == OPERATION_REQUEST {
+        let reply = Packet {
+            operation: OPERATION_REPLY,
+            sender_mac: interface.mac_address(),
+            sender_ip: our_ip,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        // the sender will ask again if this gets lost
+        let _ = interface.send_frame(packet.sender_mac, ethernet::TYPE_ARP, &reply.build());
+    }
+}
+
+// Finds the Ethernet address of `ip`, asking the network if it's not in the cache yet
+pub async fn resolve(interface: &Interface, ip: Ipv4Address) -> Result<MacAddress, NetError> {
+    for _ in 0..REQUEST_ATTEMPTS {
+        if let Some(mac) = lookup(ip) {
+            return Ok(mac);
+        }
+        let request = Packet {
+            operation: OPERATION_REQUEST,
+            sender_mac: interface.mac_address(),
+            sender_ip: super::config().address,
+            target_mac: MacAddress([0; 6]),
+            target_ip: ip,
+        };
+        interface.send_frame(MacAddress::BROADCAST, ethernet::TYPE_ARP, &request.build())?;
+        let reply = Box::pin(wait_for(ip));
+        match select(reply, Box::pin(timer::sleep(REQUEST_TIMEOUT))).await {
+            Either::Left((mac, _)) => return Ok(mac),
+            Either::Right(((), _)) => {}
+        }
+    }
+    Err(NetError::Unreachable)
+}
+
+// Waits until `ip` is in the cache
+fn wait_for(ip: Ipv4Address) -> impl Future<Output = MacAddress> {
+    future::poll_fn(move |cx| {
+        if let Some(mac) = lookup(ip) {
+            return Poll::Ready(mac);
+        }
+        let mut waiters = WAITERS.lock();
+        if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+            waiters.push(cx.waker().clone());
+        }
+        drop(waiters);
+        // the entry could have been added before the waker
+        match lookup(ip) {
+            Some(mac) => Poll::Ready(mac),
+            None => Poll::Pending,
+        }
+    })
+}
@@ -0,0 +1,63 @@
+/**
+ * Ethernet frames: the destination and the source address and the type of the payload. The
+ * device adds the preamble and the checksum.
+ */
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const HEADER_SIZE: usize = 14;
+pub const TYPE_IPV4: u16 = 0x0800;
+pub const TYPE_ARP: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+pub struct Frame<'a> {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Option<Frame<'a>> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+        let mut destination = [0; 6];
+        let mut source = [0; 6];
+        destination.copy_from_slice(&bytes[0..6]);
+        source.copy_from_slice(&bytes[6..12]);
+        Some(Frame {
+            destination: MacAddress(destination),
+            source: MacAddress(source),
+            ethertype: u16::from_be_bytes([bytes[12], bytes[13]]),
+            // short frames are padded to 60 bytes, the protocols above know their own length
+            payload: &bytes[HEADER_SIZE..],
+        })
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.extend_from_slice(&self.destination.0);
+        bytes.extend_from_slice(&self.source.0);
+        bytes.extend_from_slice(&self.ethertype.to_be_bytes());
+        bytes.extend_from_slice(self.payload);
+        bytes
+    }
+}
@@ -0,0 +1,29 @@
+/**
+ * ICMP, of which we only answer the echo requests, so that the kernel can be pinged.
+ */
+use alloc::vec::Vec;
+
+use super::{ethernet::MacAddress, ipv4, Interface};
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const HEADER_SIZE: usize = 8;
+
+// Handles the ICMP message in a packet for us, from the host with the Ethernet address `source`
+pub(super) fn handle(interface: &Interface, source: MacAddress, packet: &ipv4::Packet) {
+    let message = packet.payload;
+    if message.len() < HEADER_SIZE || ipv4::checksum(0, message) != 0 {
+        return;
+    }
+    if message[0] != TYPE_ECHO_REQUEST || message[1] != 0 {
+        return;
+    }
+    // the reply carries the identifier, the sequence number and the data of the request
+    let mut reply = Vec::from(message);
+    reply[0] = TYPE_ECHO_REPLY;
+    reply[2..4].copy_from_slice(&[0, 0]);
+    let checksum = ipv4::checksum(0, &reply);
+    reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+    // it goes back the way the request came, so there is no need to ask ARP
+    let _ = interface.send_ipv4_to(source, packet.source, ipv4::PROTOCOL_ICMP, &reply);
+}
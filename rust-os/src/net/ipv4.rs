@@ -0,0 +1,171 @@
+/**
+ * IPv4 packets and addresses.
+ *
+ * We don't send options and don't reassemble fragments, the fragments of a packet are dropped.
+ * Nothing we send is larger than the MTU, so we never fragment either.
+ */
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const HEADER_SIZE: usize = 20;
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_UDP: u8 = 17;
+// the time to live of the packets we send, the number of routers they can pass
+const TTL: u8 = 64;
+// the "more fragments" flag and the fragment offset
+const FRAGMENT_MASK: u16 = 0x3fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([0xff; 4]);
+
+    // Parses the dotted decimal form, like `10.0.2.15`
+    pub fn parse(s: &str) -> Option<Ipv4Address> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+        for octet in &mut octets {
+            let part = parts.next()?;
+            // `parse` would also take a leading `+`
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            *octet = part.parse().ok()?;
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Ipv4Address(octets)),
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    // Whether both addresses are in the same network, whose addresses share the first
+    // `prefix_len` bits
+    pub fn same_network(self, other: Ipv4Address, prefix_len: u8) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0);
+        self.to_u32() & mask == other.to_u32() & mask
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+// The internet checksum of the IPv4 header, ICMP and UDP: the one's complement of the one's
+// complement sum of the 16 bit words. `sum` carries the sum of previous parts, like the pseudo
+// header of UDP, and an odd length is padded with a zero.
+pub fn checksum(sum: u32, data: &[u8]) -> u16 {
+    let mut sum = sum;
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub struct Packet<'a> {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    // Returns `None` for packets that are malformed, have a wrong checksum or are fragments
+    pub fn parse(bytes: &'a [u8]) -> Option<Packet<'a>> {
+        if bytes.len() < HEADER_SIZE || bytes[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = usize::from(bytes[0] & 0xf) * 4;
+        let total_len = usize::from(u16::from_be_bytes([bytes[2], bytes[3]]));
+        if header_len < HEADER_SIZE || total_len < header_len || total_len > bytes.len() {
+            return None;
+        }
+        if checksum(0, &bytes[..header_len]) != 0 {
+            return None;
+        }
+        if u16::from_be_bytes([bytes[6], bytes[7]]) & FRAGMENT_MASK != 0 {
+            return None;
+        }
+        let mut source = [0; 4];
+        let mut destination = [0; 4];
+        source.copy_from_slice(&bytes[12..16]);
+        destination.copy_from_slice(&bytes[16..20]);
+        Some(Packet {
+            source: Ipv4Address(source),
+            destination: Ipv4Address(destination),
+            protocol: bytes[9],
+            payload: &bytes[header_len..total_len],
+        })
+    }
+
+    // `id` identifies the fragments of a packet, it only has to differ between the packets of a
+    // short time
+    pub fn build(&self, id: u16) -> Vec<u8> {
+        let total_len = (HEADER_SIZE + self.payload.len()) as u16;
+        let mut bytes = Vec::with_capacity(usize::from(total_len));
+        bytes.extend_from_slice(&[0x45, 0]);
+        bytes.extend_from_slice(&total_len.to_be_bytes());
+        bytes.extend_from_slice(&id.to_be_bytes());
+        // don't fragment
+        bytes.extend_from_slice(&0x4000u16.to_be_bytes());
+        bytes.extend_from_slice(&[TTL, self.protocol, 0, 0]);
+        bytes.extend_from_slice(&self.source.0);
+        bytes.extend_from_slice(&self.destination.0);
+        let checksum = checksum(0, &bytes);
+        bytes[10..12].copy_from_slice(&checksum.to_be_bytes());
+        bytes.extend_from_slice(self.payload);
+        bytes
+    }
+}
+
+#[test_case]
+fn test_parse_address() {
+    assert_eq!(
+        Ipv4Address::parse("10.0.2.15"),
+        Some(Ipv4Address([10, 0, 2, 15]))
+    );
+    assert_eq!(Ipv4Address::parse("10.0.2"), None);
+    assert_eq!(Ipv4Address::parse("10.0.2.15.1"), None);
+    assert_eq!(Ipv4Address::parse("10.0.2.256"), None);
+    assert_eq!(Ipv4Address::parse("10.0.+2.1"), None);
+}
+
+#[test_case]
+fn test_same_network() {
+    let address = Ipv4Address([10, 0, 2, 15]);
+    assert!(address.same_network(Ipv4Address([10, 0, 2, 2]), 24));
+    assert!(!address.same_network(Ipv4Address([10, 0, 3, 2]), 24));
+    assert!(address.same_network(Ipv4Address([192, 168, 0, 1]), 0));
+    assert!(!address.same_network(Ipv4Address([10, 0, 2, 16]), 32));
+}
+
+#[test_case]
+fn test_checksum() {
+    // the header from the example on Wikipedia, with the checksum 0xb861 cleared
+    let mut header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    assert_eq!(checksum(0, &header), 0xb861);
+    header[10..12].copy_from_slice(&[0xb8, 0x61]);
+    assert_eq!(checksum(0, &header), 0);
+    // an odd length is padded
+    assert_eq!(checksum(0, &[0x12]), !0x1200);
+}
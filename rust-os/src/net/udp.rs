@@ -0,0 +1,177 @@
+/**
+ * UDP sockets for the async tasks.
+ *
+ * A socket is bound to a local port, the stack puts the datagrams for that port into the queue
+ * of the socket and wakes the task waiting in `recv_from`. When the queue is full, new datagrams
+ * are dropped, like a real network would drop them.
+ */
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{future, task::Poll};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::{ipv4, ipv4::Ipv4Address, NetError};
+
+const HEADER_SIZE: usize = 8;
+// the datagrams a socket can hold before new ones are dropped
+const QUEUE_SIZE: usize = 32;
+// `bind(0)` picks a port from here up, the dynamic range of the IANA
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+pub struct Datagram {
+    pub source: Ipv4Address,
+    pub source_port: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Queue {
+    datagrams: Mutex<VecDeque<Datagram>>,
+    waker: AtomicWaker,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Arc<Queue>>> = Mutex::new(BTreeMap::new());
+
+pub struct UdpSocket {
+    port: u16,
+    queue: Arc<Queue>,
+}
+
+impl UdpSocket {
+    // Binds a socket to `port`, or to a free ephemeral port if it's 0
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => (FIRST_EPHEMERAL_PORT..=u16::MAX)
+                .find(|port| !sockets.contains_key(port))
+                .ok_or(NetError::AddressInUse)?,
+            port if sockets.contains_key(&port) => return Err(NetError::AddressInUse),
+            port => port,
+        };
+        let queue = Arc::new(Queue::default());
+        sockets.insert(port, queue.clone());
+        Ok(UdpSocket { port, queue })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn send_to(
+        &self,
+        data: &[u8],
+        destination: Ipv4Address,
+        port: u16,
+    ) -> Result<(), NetError> {
+        let source = super::config().address;
+        let datagram = build(self.port, source, destination, port, data)?;
+        // a datagram to ourselves doesn't go through the device
+        if destination == source {
+            deliver(&ipv4::Packet {
+                source,
+                destination,
+                protocol: ipv4::PROTOCOL_UDP,
+                payload: &datagram,
+            });
+            return Ok(());
+        }
+        let interface = super::interface().ok_or(NetError::NoInterface)?;
+        interface
+            .send_ipv4(destination, ipv4::PROTOCOL_UDP, &datagram)
+            .await
+    }
+
+    // Waits for the next datagram
+    pub async fn recv_from(&self) -> Datagram {
+        future::poll_fn(|cx| {
+            if let Some(datagram) = self.queue.datagrams.lock().pop_front() {
+                return Poll::Ready(datagram);
+            }
+            self.queue.waker.register(cx.waker());
+            // a datagram could have arrived before the waker was registered
+            match self.queue.datagrams.lock().pop_front() {
+                Some(datagram) => Poll::Ready(datagram),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+// The sum of the pseudo header, which the checksum covers next to the datagram
+fn pseudo_header_sum(source: Ipv4Address, destination: Ipv4Address, len: usize) -> u32 {
+    let address_sum = |address: Ipv4Address| {
+        let [a, b, c, d] = address.0;
+        u32::from(u16::from_be_bytes([a, b])) + u32::from(u16::from_be_bytes([c, d]))
+    };
+    address_sum(source) + address_sum(destination) + u32::from(ipv4::PROTOCOL_UDP) + len as u32
+}
+
+fn build(
+    source_port: u16,
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    destination_port: u16,
+    data: &[u8],
+) -> Result<Vec<u8>, NetError> {
+    let len = HEADER_SIZE + data.len();
+    if ipv4::HEADER_SIZE + len > super::MTU {
+        return Err(NetError::TooLarge);
+    }
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&destination_port.to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+    let checksum = match ipv4::checksum(pseudo_header_sum(source, destination, len), &datagram) {
+        // 0 means that there is no checksum, the same value in one's complement is sent instead
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    Ok(datagram)
+}
+
+// Puts the datagram in the packet into the queue of the socket bound to its port
+pub(super) fn deliver(packet: &ipv4::Packet) {
+    let datagram = packet.payload;
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let read_u16 = |offset: usize| u16::from_be_bytes([datagram[offset], datagram[offset + 1]]);
+    let len = usize::from(read_u16(4));
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    // the checksum is optional, 0 means the sender didn't compute it
+    let sum = pseudo_header_sum(packet.source, packet.destination, len);
+    if read_u16(6) != 0 && ipv4::checksum(sum, datagram) != 0 {
+        return;
+    }
+    let queue = match SOCKETS.lock().get(&read_u16(2)) {
+        Some(queue) => queue.clone(),
+        None => return,
+    };
+    let mut datagrams = queue.datagrams.lock();
+    if datagrams.len() < QUEUE_SIZE {
+        datagrams.push_back(Datagram {
+            source: packet.source,
+            source_port: read_u16(0),
+            data: Vec::from(&datagram[HEADER_SIZE..]),
+        });
+        drop(datagrams);
+        queue.waker.wake();
+    }
+}
@@ -0,0 +1,480 @@
+/**
+ * PCI bus enumeration and the registry of the devices found.
+ *
+ * Every PCI function has a configuration space with its ids, class and resources. It is accessed
+ * either through the legacy ports 0xCF8/0xCFC (the address of a register is written to the first
+ * port, then the register can be accessed through the second), or through ECAM, where the
+ * configuration space is memory-mapped. ECAM is used if the ACPI MCFG table describes it (e.g. on
+ * QEMU's q35 machine), otherwise the ports.
+ *
+ * `init` scans the buses recursively, starting at bus 0 and following the PCI-to-PCI bridges, and
+ * records every function in the registry. A system with several host bridges has several root
+ * buses, only the first one is scanned. Drivers take their devices out of the registry with
+ * `claim`, which hands every device to one driver only.
+ */
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{fmt, ptr};
+use spin::Mutex;
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+use crate::{
+    acpi::{EcamRegion, Mcfg},
+    memory,
+    sync::IrqSafeMutex,
+};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// configuration space registers, every register is 32 bit wide
+const REG_ID: u16 = 0x00;
+const REG_COMMAND: u16 = 0x04;
+const REG_CLASS: u16 = 0x08;
+const REG_HEADER_TYPE: u16 = 0x0c;
+const REG_BAR0: u16 = 0x10;
+// only in the header of a bridge: the primary, secondary and subordinate bus numbers
+const REG_BUS_NUMBERS: u16 = 0x18;
+const REG_INTERRUPT: u16 = 0x3c;
+
+// bits of the command register
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+// every function has 4 KiB of configuration space in an ECAM region, so a bus has 1 MiB
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+// in the format that lspci uses, e.g. `00:1f.2`
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+enum ConfigAccess {
+    Ports,
+    Ecam {
+        region: EcamRegion,
+        // the configuration space of every bus is mapped on its first use
+        buses: BTreeMap<u8, VirtAddr>,
+    },
+}
+
+impl ConfigAccess {
+    fn read(&mut self, address: PciAddress, offset: u16) -> u32 {
+        match self {
+            ConfigAccess::Ports => unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(port_address(address, offset));
+                Port::<u32>::new(CONFIG_DATA).read()
+            },
+            ConfigAccess::Ecam { region, buses } => {
+                match ecam_register(region, buses, address, offset) {
+                    Some(register) => unsafe { ptr::read_volatile(register.as_ptr()) },
+                    // reads like a missing device
+                    None => 0xffff_ffff,
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, address: PciAddress, offset: u16, value: u32) {
+        match self {
+            ConfigAccess::Ports => unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(port_address(address, offset));
+                Port::<u32>::new(CONFIG_DATA).write(value);
+            },
+            ConfigAccess::Ecam { region, buses } => {
+                if let Some(register) = ecam_register(region, buses, address, offset) {
+                    unsafe { ptr::write_volatile(register.as_mut_ptr(), value) };
+                }
+            }
+        }
+    }
+}
+
+// The ports only reach the first 256 bytes of the configuration space
+fn port_address(address: PciAddress, offset: u16) -> u32 {
+    debug_assert!(
+        offset < 256,
+        "configuration register out of reach of the ports"
+    );
+    1 << 31
+        | u32::from(address.bus) << 16
+        | u32::from(address.device) << 11
+        | u32::from(address.function) << 8
+        | u32::from(offset & 0xfc)
+}
+
+// Returns the virtual address of a register in the ECAM region, or `None` if the bus is not part
+// of the region or can't be mapped
+fn ecam_register(
+    region: &EcamRegion,
+    buses: &mut BTreeMap<u8, VirtAddr>,
+    address: PciAddress,
+    offset: u16,
+) -> Option<VirtAddr> {
+    if address.bus < region.start_bus || address.bus > region.end_bus {
+        return None;
+    }
+    let bus_start = match buses.get(&address.bus) {
+        Some(&bus_start) => bus_start,
+        None => {
+            // the base address is where bus 0 would be, even if the region starts at a later bus
+            let phys = region.base + u64::from(address.bus) * ECAM_BUS_SIZE;
+            let mapped = memory::with_kernel_memory(|memory| unsafe {
+                memory::map_mmio(
+                    phys,
+                    ECAM_BUS_SIZE,
+                    &mut memory.mapper,
+                    &mut memory.frame_allocator,
+                )
+            });
+            match mapped {
+                Ok(bus_start) => *buses.entry(address.bus).or_insert(bus_start),
+                Err(error) => {
+                    log::warn!("failed to map PCI bus {:02x}: {:?}", address.bus, error);
+                    return None;
+                }
+            }
+        }
+    };
+    let function = u64::from(address.device) << 15 | u64::from(address.function) << 12;
+    Some(bus_start + function + u64::from(offset & 0xffc))
+}
+
+// The ports have to be written and read as a pair, so the access is locked. `init` switches to
+// ECAM if the firmware provides it.
+static CONFIG: IrqSafeMutex<ConfigAccess> = IrqSafeMutex::new(ConfigAccess::Ports);
+
+// Reads the 32 bit register at `offset` in the configuration space of a function. All bits are set
+// if there is no such function.
+pub fn read_config(address: PciAddress, offset: u16) -> u32 {
+    CONFIG.lock().read(address, offset)
+}
+
+/// Writes a 32 bit register in the configuration space of a function.
+///
+/// # Safety
+///
+/// The register can change how the device decodes addresses and whether it accesses memory on its
+/// own, so the caller must guarantee that the new value doesn't make the device access memory
+/// that is used otherwise.
+pub unsafe fn write_config(address: PciAddress, offset: u16, value: u32) {
+    CONFIG.lock().write(address, offset, value)
+}
+
+// A base address register: where the registers of the device are in memory or in the I/O ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+// Decodes a memory BAR from its value and what it read after writing all ones to it. The bits
+// above a 32 bit BAR must be set in `mask`.
+fn memory_bar(value: u64, mask: u64) -> Bar {
+    Bar::Memory {
+        address: PhysAddr::new(value & !0xf),
+        // the bits below the size of the BAR stay zero
+        size: (!(mask & !0xf)).wrapping_add(1),
+        prefetchable: value & 0b1000 != 0,
+    }
+}
+
+fn io_bar(value: u32, mask: u32) -> Bar {
+    Bar::Io {
+        port: (value & !0b11) as u16,
+        // some devices leave the upper 16 bits zero, so only the lower ones count
+        size: (!(mask & !0b11) as u16).wrapping_add(1),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    // A 64 bit BAR takes two slots, the second one is `None`. So is every BAR that isn't used.
+    pub bars: [Option<Bar>; 6],
+    // the IRQ the firmware routed the interrupt pin to, 0xff if none
+    pub interrupt_line: u8,
+    // 1 to 4 for INTA# to INTD#, 0 if the device doesn't use an interrupt pin
+    pub interrupt_pin: u8,
+}
+
+impl PciDevice {
+    pub fn is(&self, vendor_id: u16, device_id: u16) -> bool {
+        self.vendor_id == vendor_id && self.device_id == device_id
+    }
+
+    pub fn has_class(&self, class: u8, subclass: u8) -> bool {
+        self.class == class && self.subclass == subclass
+    }
+
+    pub fn read_config(&self, offset: u16) -> u32 {
+        read_config(self.address, offset)
+    }
+
+    /// Sets the given bits (e.g. `COMMAND_BUS_MASTER`) in the command register.
+    ///
+    /// # Safety
+    ///
+    /// The device starts to respond at its BARs and, with bus mastering, to access memory on its
+    /// own. The caller must guarantee that the BARs don't overlap memory that is used otherwise
+    /// and that the device is only given memory that belongs to it.
+    pub unsafe fn enable(&self, command: u16) {
+        let mut config = CONFIG.lock();
+        let current = config.read(self.address, REG_COMMAND) as u16;
+        // the upper half is the status register, whose bits are cleared by writing ones
+        config.write(self.address, REG_COMMAND, u32::from(current | command));
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "network controller",
+            (0x03, 0x00) => "VGA controller",
+            (0x03, _) => "display controller",
+            (0x04, _) => "multimedia controller",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "bridge",
+            (0x0c, 0x03) => "USB controller",
+            (0x0c, 0x05) => "SMBus controller",
+            (0x0c, _) => "serial bus controller",
+            _ => "unknown device",
+        }
+    }
+}
+
+// Reads the function at `address`, or returns `None` if there is none
+fn probe(config: &mut ConfigAccess, address: PciAddress) -> Option<PciDevice> {
+    let id = config.read(address, REG_ID);
+    if id & 0xffff == 0xffff {
+        return None;
+    }
+    let class = config.read(address, REG_CLASS);
+    // bit 7 of the header type only tells whether the device has several functions
+    let header_type = (config.read(address, REG_HEADER_TYPE) >> 16) as u8 & 0x7f;
+    let bar_count = match header_type {
+        0 => 6,
+        // bridges
+        1 => 2,
+        _ => 0,
+    };
+    let interrupt = config.read(address, REG_INTERRUPT);
+    Some(PciDevice {
+        address,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        bars: read_bars(config, address, bar_count),
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+    })
+}
+
+// The size of a BAR is found by writing all ones to it and reading back which address bits stuck.
+// The device stops decoding addresses meanwhile, so that it doesn't respond at the bogus address.
+fn read_bars(config: &mut ConfigAccess, address: PciAddress, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = config.read(address, REG_COMMAND) & 0xffff;
+    let decoding = u32::from(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE);
+    config.write(address, REG_COMMAND, command & !decoding);
+    let mut size_bar = |offset: u16| {
+        let value = config.read(address, offset);
+        config.write(address, offset, 0xffff_ffff);
+        let mask = config.read(address, offset);
+        config.write(address, offset, value);
+        (value, mask)
+    };
+    let mut i = 0;
+    while i < count {
+        let offset = REG_BAR0 + i as u16 * 4;
+        let (value, mask) = size_bar(offset);
+        if value & 1 == 1 {
+            if mask & !0b11 != 0 {
+                bars[i] = Some(io_bar(value, mask));
+            }
+        } else if (value >> 1) & 0b11 == 0b10 && i + 1 < count {
+            // a 64 bit BAR, the next one holds the upper half
+            let (high_value, high_mask) = size_bar(offset + 4);
+            let mask = u64::from(high_mask) << 32 | u64::from(mask);
+            if mask & !0xf != 0 {
+                bars[i] = Some(memory_bar(
+                    u64::from(high_value) << 32 | u64::from(value),
+                    mask,
+                ));
+            }
+            i += 1;
+        } else if mask & !0xf != 0 {
+            bars[i] = Some(memory_bar(
+                u64::from(value),
+                0xffff_ffff_0000_0000 | u64::from(mask),
+            ));
+        }
+        i += 1;
+    }
+    config.write(address, REG_COMMAND, command);
+    bars
+}
+
+// Records every function on `bus` and on the buses behind its bridges
+fn scan_bus(config: &mut ConfigAccess, bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let first = PciAddress::new(bus, device, 0);
+        if config.read(first, REG_ID) & 0xffff == 0xffff {
+            continue;
+        }
+        let multifunction = (config.read(first, REG_HEADER_TYPE) >> 16) & 0x80 != 0;
+        let functions = if multifunction { 8 } else { 1 };
+        for function in 0..functions {
+            let address = PciAddress::new(bus, device, function);
+            let found = match probe(config, address) {
+                Some(found) => found,
+                None => continue,
+            };
+            let is_bridge = found.has_class(0x06, 0x04);
+            devices.push(found);
+            if is_bridge {
+                let secondary = (config.read(address, REG_BUS_NUMBERS) >> 8) as u8;
+                // the firmware numbers the buses in the order of a depth-first scan, a smaller
+                // number would be a loop
+                if secondary > bus {
+                    scan_bus(config, secondary, devices);
+                }
+            }
+        }
+    }
+}
+
+struct Entry {
+    device: PciDevice,
+    // the driver that claimed the device
+    driver: Option<&'static str>,
+}
+
+static DEVICES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+// Scans the PCI buses and fills the registry. Needs the heap and the kernel memory, for ECAM.
+pub fn init() {
+    let mut config = CONFIG.lock();
+    let mut root_bus = 0;
+    if let Some(region) = Mcfg::parse().and_then(|mcfg| mcfg.regions.first().copied()) {
+        root_bus = region.start_bus;
+        *config = ConfigAccess::Ecam {
+            region,
+            buses: BTreeMap::new(),
+        };
+    }
+    let mut devices = Vec::new();
+    scan_bus(&mut config, root_bus, &mut devices);
+    let access = match *config {
+        ConfigAccess::Ports => "ports",
+        ConfigAccess::Ecam { .. } => "ECAM",
+    };
+    drop(config);
+
+    log::info!("PCI: {} functions (through {})", devices.len(), access);
+    for device in &devices {
+        log::info!(
+            "PCI: {} {:04x}:{:04x} {}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class_name()
+        );
+    }
+    *DEVICES.lock() = devices
+        .into_iter()
+        .map(|device| Entry {
+            device,
+            driver: None,
+        })
+        .collect();
+}
+
+// Returns every device found by `init`, with the name of the driver that claimed it
+pub fn devices() -> Vec<(PciDevice, Option<&'static str>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|entry| (entry.device.clone(), entry.driver))
+        .collect()
+}
+
+// Hands the first unclaimed device that `matches` to the driver `driver`. Every device is handed
+// out once only, so a driver can call this in a loop to get all of its devices.
+pub fn claim(driver: &'static str, matches: impl Fn(&PciDevice) -> bool) -> Option<PciDevice> {
+    let mut devices = DEVICES.lock();
+    let entry = devices
+        .iter_mut()
+        .find(|entry| entry.driver.is_none() && matches(&entry.device))?;
+    entry.driver = Some(driver);
+    Some(entry.device.clone())
+}
+
+#[test_case]
+fn test_decode_bars() {
+    // a 16 MiB prefetchable framebuffer, like the one of QEMU's VGA card
+    assert_eq!(
+        memory_bar(0xfd00_0008, 0xffff_ffff_ff00_0008),
+        Bar::Memory {
+            address: PhysAddr::new(0xfd00_0000),
+            size: 16 << 20,
+            prefetchable: true,
+        }
+    );
+    // a 64 bit BAR above 4 GiB
+    assert_eq!(
+        memory_bar(0x8_0000_000c, 0xffff_ffff_ffff_c00c),
+        Bar::Memory {
+            address: PhysAddr::new(0x8_0000_0000),
+            size: 16 << 10,
+            prefetchable: true,
+        }
+    );
+    assert_eq!(
+        io_bar(0xc041, 0x0000_ffc1),
+        Bar::Io {
+            port: 0xc040,
+            size: 64,
+        }
+    );
+}
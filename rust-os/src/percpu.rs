@@ -0,0 +1,173 @@
+/**
+ * Data that every CPU has a copy of, like its index and the state of the user process it runs.
+ *
+ * The GS base of every CPU points at its `PerCpu`, so `current` finds it with a single load
+ * through GS, instead of having to look up the APIC id first. The boot CPU uses a static
+ * `PerCpu` (index 0), the application processors get theirs from `smp`.
+ *
+ * While a user process runs, its own GS base is loaded and the kernel's waits in the
+ * `KernelGsBase` MSR, which user mode can't change (see `process::syscall`). Interrupt handlers
+ * that can interrupt user mode swap the kernel's back in first, with a `KernelGs`.
+ */
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering},
+};
+use x86_64::{
+    instructions::segmentation::GS,
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::{idt::InterruptStackFrame, tss::TaskStateSegment},
+    VirtAddr,
+};
+
+// the most CPUs that are used, the others are left halted
+pub const MAX_CPUS: usize = 16;
+
+// The assembly of `process::syscall` uses some of the fields, so don't change their order.
+#[repr(C)]
+pub struct PerCpu {
+    // the address of this struct, which `current` reads through GS
+    this: AtomicPtr<PerCpu>,
+    // the kernel stack pointer while a user process runs
+    pub(crate) kernel_rsp: UnsafeCell<u64>,
+    // the stack pointer of the user process while a system call saves its registers
+    pub(crate) user_rsp: UnsafeCell<u64>,
+    // the `UserContext` of the running user process
+    pub(crate) user_context: UnsafeCell<u64>,
+    index: usize,
+    apic_id: AtomicU8,
+    tss: OnceCell<&'static TaskStateSegment>,
+}
+
+// The fields that change are only used by their own CPU, or are atomic
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    pub(crate) const fn new(index: usize) -> PerCpu {
+        PerCpu {
+            this: AtomicPtr::new(ptr::null_mut()),
+            kernel_rsp: UnsafeCell::new(0),
+            user_rsp: UnsafeCell::new(0),
+            user_context: UnsafeCell::new(0),
+            index,
+            apic_id: AtomicU8::new(0),
+            tss: OnceCell::uninit(),
+        }
+    }
+
+    // 0 for the boot CPU, the application processors are counted from 1
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn is_boot_cpu(&self) -> bool {
+        self.index == 0
+    }
+
+    // the id of the CPU's local APIC, which interrupts are sent to
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    // the TSS of the CPU, set by `gdt::init`
+    pub fn tss(&self) -> Option<&'static TaskStateSegment> {
+        self.tss.try_get().ok().copied()
+    }
+
+    pub(crate) fn set_tss(&self, tss: &'static TaskStateSegment) {
+        self.tss
+            .try_init_once(|| tss)
+            .expect("the TSS of a CPU should only be set once");
+    }
+}
+
+static BOOT_CPU: PerCpu = PerCpu::new(0);
+// the `PerCpu` of every CPU that has called `load`, by index
+static CPUS: [OnceCell<&'static PerCpu>; MAX_CPUS] = [const { OnceCell::uninit() }; MAX_CPUS];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+// Until the boot CPU has loaded its GS base, `current` returns it without going through GS. The
+// application processors load theirs before anything else.
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+// Points the GS base of the boot CPU at its `PerCpu`
+pub fn init() {
+    unsafe { load(&BOOT_CPU) };
+}
+
+// Points the GS base of the running CPU at `cpu` and registers it
+//
+// This function is unsafe because `cpu` must not be used by any other CPU.
+pub(crate) unsafe fn load(cpu: &'static PerCpu) {
+    let address = cpu as *const PerCpu;
+    cpu.this.store(address as *mut PerCpu, Ordering::Relaxed);
+    // bits 24..32 of EBX are the id of the local APIC
+    let apic_id = (core::arch::x86_64::__cpuid(1).ebx >> 24) as u8;
+    cpu.apic_id.store(apic_id, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(address));
+    // the GS base of the user processes
+    KernelGsBase::write(VirtAddr::zero());
+    CPUS[cpu.index]
+        .try_init_once(|| cpu)
+        .expect("a CPU index should only be loaded once");
+    COUNT.fetch_add(1, Ordering::SeqCst);
+    LOADED.store(true, Ordering::SeqCst);
+}
+
+// Returns the `PerCpu` of the running CPU
+pub fn current() -> &'static PerCpu {
+    if !LOADED.load(Ordering::Relaxed) {
+        return &BOOT_CPU;
+    }
+    let this: *const PerCpu;
+    unsafe {
+        asm!(
+            "mov {}, qword ptr gs:[0]",
+            out(reg) this,
+            options(nostack, readonly, preserves_flags)
+        );
+        &*this
+    }
+}
+
+// Returns the index of the running CPU
+pub fn index() -> usize {
+    current().index
+}
+
+// Returns the number of CPUs that are running
+pub fn count() -> usize {
+    COUNT.load(Ordering::SeqCst).max(1)
+}
+
+// Returns the `PerCpu` of the CPU with the given index, if it is running
+pub fn get(index: usize) -> Option<&'static PerCpu> {
+    CPUS.get(index)?.try_get().ok().copied()
+}
+
+// Swaps the kernel's GS base in while an interrupt handler runs that interrupted user mode, and
+// the user's back when dropped. Must be created before the handler uses anything per CPU,
+// including the locks (see `sync::lockdep`).
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> KernelGs {
+        let swapped = stack_frame.code_segment & 3 == 3;
+        if swapped {
+            unsafe { GS::swap() };
+        }
+        KernelGs { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { GS::swap() };
+        }
+    }
+}
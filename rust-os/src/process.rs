@@ -0,0 +1,365 @@
+/**
+ * User mode processes.
+ *
+ * Every process has its own level 4 page table. The kernel is not linked into the higher half, so
+ * instead of sharing the upper 256 entries, a process gets a copy of all the kernel's entries
+ * (which are not accessible from user mode) and its own memory in the L4 entry `USER_L4_INDEX`,
+ * which the kernel doesn't use.
+ *
+ * A process is driven by an async task (see `spawn`): the task runs the process until it traps
+ * into the kernel, handles the system call and runs it again. Sleeping or yielding processes
+ * therefore don't block the executor. A process that causes an exception is killed, the kernel
+ * keeps running.
+ */
+use alloc::{string::String, vec, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, Translate, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PageTableIndex, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    memory::{self, phys_to_virt, KernelMemory},
+    percpu::{self, MAX_CPUS},
+    print,
+    sync::IrqSafeMutex,
+    task::{self, executor, Task},
+    timer,
+};
+
+pub mod programs;
+pub mod syscall;
+
+use syscall::{Trap, UserContext, SYSCALL_ERROR, SYS_EXIT, SYS_SLEEP, SYS_WRITE, SYS_YIELD};
+
+// The L4 entry that holds the memory of a process, the 512 GiB from `USER_START` to `USER_END`
+const USER_L4_INDEX: u16 = 1;
+pub const USER_START: u64 = 0x80_0000_0000;
+pub const USER_END: u64 = 0x100_0000_0000;
+// flat binaries are loaded at the start of user space, the stack is at its end
+pub const USER_CODE_START: u64 = USER_START;
+pub const USER_STACK_SIZE: u64 = 16 * 1024;
+// the most bytes a single `write` system call prints
+const MAX_WRITE_LEN: u64 = 4096;
+
+pub fn init() {
+    syscall::init();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+// The exception that killed a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub name: &'static str,
+    pub rip: VirtAddr,
+    // the accessed address for page faults
+    pub address: Option<VirtAddr>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:#x}", self.name, self.rip.as_u64())?;
+        if let Some(address) = self.address {
+            write!(f, " accessing {:#x}", address.as_u64())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    // the process called `exit` with this code
+    Exited(u64),
+    Killed(Fault),
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with {}", code),
+            ExitStatus::Killed(fault) => write!(f, "killed by {}", fault),
+        }
+    }
+}
+
+// Set by the exception handler before it leaves the process, taken by `Process::run`. One per
+// CPU, the task of the process doesn't move to another CPU in between.
+static LAST_FAULT: [IrqSafeMutex<Option<Fault>>; MAX_CPUS] =
+    [const { IrqSafeMutex::new(None) }; MAX_CPUS];
+
+// Kills the running user process because of the given exception. Called by the exception handlers
+// when they interrupted user mode, the kernel continues in the task of the process.
+//
+// This function is unsafe because it must only be called from an exception handler that
+// interrupted user mode.
+pub(crate) unsafe fn kill_current(fault: Fault) -> ! {
+    *LAST_FAULT[percpu::index()].lock() = Some(fault);
+    syscall::leave_faulted_user()
+}
+
+pub struct Process {
+    id: ProcessId,
+    name: &'static str,
+    context: UserContext,
+    address_space: AddressSpace,
+}
+
+impl Process {
+    // Creates a process that runs the given flat binary
+    pub fn new(name: &'static str, code: &[u8]) -> Result<Self, MapToError<Size4KiB>> {
+        let mut address_space = memory::with_kernel_memory(AddressSpace::new)?;
+        let code_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let stack_flags = code_flags | PageTableFlags::WRITABLE;
+        let stack_start = VirtAddr::new(USER_END - USER_STACK_SIZE);
+        memory::with_kernel_memory(|memory| {
+            let code_start = VirtAddr::new(USER_CODE_START);
+            address_space.map(memory, code_start, code.len() as u64, code, code_flags)?;
+            address_space.map(memory, stack_start, USER_STACK_SIZE, &[], stack_flags)
+        })?;
+        Ok(Process {
+            id: ProcessId::new(),
+            name,
+            // the stack pointer stays 16 byte aligned, like after the `call` of a function
+            context: UserContext::new(VirtAddr::new(USER_CODE_START), VirtAddr::new(USER_END - 8)),
+            address_space,
+        })
+    }
+
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Runs the process until it exits or is killed
+    pub async fn run(mut self) -> ExitStatus {
+        loop {
+            match self.enter() {
+                Trap::Fault => {
+                    let fault = LAST_FAULT[percpu::index()].lock().take();
+                    let fault = fault.expect("fault without a report");
+                    return ExitStatus::Killed(fault);
+                }
+                Trap::NonCanonicalRip => {
+                    // the `syscall` instruction that got the process there is two bytes long
+                    let rip = VirtAddr::new_truncate(self.context.rip.wrapping_sub(2));
+                    return ExitStatus::Killed(Fault {
+                        name: "GENERAL PROTECTION FAULT",
+                        rip,
+                        address: None,
+                    });
+                }
+                Trap::Syscall => {
+                    let context = &self.context;
+                    let (number, arg0, arg1) = (context.rax, context.rdi, context.rsi);
+                    let result = match number {
+                        SYS_WRITE => self.write(VirtAddr::try_new(arg0).ok(), arg1),
+                        SYS_EXIT => return ExitStatus::Exited(arg0),
+                        SYS_YIELD => {
+                            task::yield_now().await;
+                            0
+                        }
+                        SYS_SLEEP => {
+                            timer::sleep(Duration::from_millis(arg0)).await;
+                            0
+                        }
+                        _ => SYSCALL_ERROR,
+                    };
+                    self.context.rax = result;
+                }
+            }
+        }
+    }
+
+    // Switches to the address space of the process and runs it until the next trap
+    fn enter(&mut self) -> Trap {
+        let (kernel_l4_frame, flags) = Cr3::read();
+        unsafe {
+            Cr3::write(self.address_space.l4_frame, flags);
+            let trap = syscall::run_user(&mut self.context);
+            Cr3::write(kernel_l4_frame, flags);
+            trap
+        }
+    }
+
+    // the `write` system call, prints `len` bytes at `addr`
+    fn write(&mut self, addr: Option<VirtAddr>, len: u64) -> u64 {
+        let addr = match addr {
+            Some(addr) if len <= MAX_WRITE_LEN => addr,
+            _ => return SYSCALL_ERROR,
+        };
+        let mut buffer = vec![0; len as usize];
+        if self.address_space.read(addr, &mut buffer).is_err() {
+            return SYSCALL_ERROR;
+        }
+        print!("{}", String::from_utf8_lossy(&buffer));
+        len
+    }
+}
+
+// Runs the process in a new task of the executor and logs how it ended
+pub fn spawn(process: Process) -> ProcessId {
+    let id = process.id();
+    executor::spawn(Task::new(run_to_exit(process)));
+    id
+}
+
+async fn run_to_exit(process: Process) {
+    let (id, name) = (process.id(), process.name());
+    match process.run().await {
+        ExitStatus::Exited(0) => log::info!("process {} ({}) exited", id.as_u64(), name),
+        status => log::warn!("process {} ({}) {}", id.as_u64(), name, status),
+    }
+}
+
+// The page tables of a process. The frames of its memory and page tables are freed on drop.
+struct AddressSpace {
+    l4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    fn new(memory: &mut KernelMemory) -> Result<Self, MapToError<Size4KiB>> {
+        let l4_frame = FrameAllocator::<Size4KiB>::allocate_frame(&mut memory.frame_allocator)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let kernel_table = memory.mapper.level_4_table();
+        let user_index = PageTableIndex::new(USER_L4_INDEX);
+        assert!(
+            kernel_table[user_index].is_unused(),
+            "the kernel uses the L4 entry reserved for user space"
+        );
+        let table =
+            unsafe { &mut *phys_to_virt(l4_frame.start_address()).as_mut_ptr::<PageTable>() };
+        for (entry, kernel_entry) in table.iter_mut().zip(kernel_table.iter()) {
+            *entry = kernel_entry.clone();
+        }
+        Ok(AddressSpace { l4_frame })
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = phys_to_virt(self.l4_frame.start_address()).as_mut_ptr::<PageTable>();
+        unsafe { OffsetPageTable::new(&mut *table, memory::physical_memory_offset()) }
+    }
+
+    // Maps `size` bytes at `start` (page aligned) to new frames and copies `data` to the
+    // beginning, the rest is zeroed
+    fn map(
+        &mut self,
+        memory: &mut KernelMemory,
+        start: VirtAddr,
+        size: u64,
+        data: &[u8],
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let frame_allocator = &mut memory.frame_allocator;
+        let mut mapper = self.mapper();
+        for offset in (0..size).step_by(4096) {
+            let page = Page::<Size4KiB>::containing_address(start + offset);
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let bytes =
+                unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<[u8; 4096]>() };
+            bytes.fill(0);
+            let chunk = data.get(offset as usize..).unwrap_or(&[]);
+            let len = chunk.len().min(4096);
+            bytes[..len].copy_from_slice(&chunk[..len]);
+            // the address space is not active, so there is nothing to flush
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.ignore() };
+        }
+        Ok(())
+    }
+
+    // Copies the user memory at `addr` into `buffer`. Fails if any of it is not mapped as
+    // accessible from user mode.
+    fn read(&mut self, addr: VirtAddr, buffer: &mut [u8]) -> Result<(), ()> {
+        let end = addr.as_u64().checked_add(buffer.len() as u64).ok_or(())?;
+        if addr.as_u64() < USER_START || end > USER_END {
+            return Err(());
+        }
+        let mapper = self.mapper();
+        let mut done = 0;
+        while done < buffer.len() {
+            let current = addr + done as u64;
+            let (frame, offset) = match mapper.translate(current) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(frame),
+                    offset,
+                    flags,
+                } if flags.contains(PageTableFlags::USER_ACCESSIBLE) => (frame, offset),
+                _ => return Err(()),
+            };
+            let len = (4096 - offset as usize).min(buffer.len() - done);
+            let source = phys_to_virt(frame.start_address() + offset).as_ptr::<u8>();
+            unsafe {
+                core::ptr::copy_nonoverlapping(source, buffer[done..].as_mut_ptr(), len);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // the tables of the user memory belong to this address space, everything else to the
+        // kernel
+        let mut frames = Vec::new();
+        let l4_table =
+            unsafe { &*phys_to_virt(self.l4_frame.start_address()).as_ptr::<PageTable>() };
+        collect_frames(
+            &l4_table[PageTableIndex::new(USER_L4_INDEX)],
+            3,
+            &mut frames,
+        );
+        frames.push(self.l4_frame);
+        memory::with_kernel_memory(|memory| {
+            for frame in frames {
+                unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            }
+        });
+    }
+}
+
+// Collects the frames of the page table at `level` that `entry` points to, of all the tables
+// below it and of the mapped pages. User memory is only mapped with 4 KiB pages.
+fn collect_frames(
+    entry: &x86_64::structures::paging::page_table::PageTableEntry,
+    level: u8,
+    frames: &mut Vec<PhysFrame>,
+) {
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if level > 0 {
+        let table = unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<PageTable>() };
+        for entry in table.iter() {
+            collect_frames(entry, level - 1, frames);
+        }
+    }
+    frames.push(frame);
+}
@@ -0,0 +1,146 @@
+/**
+ * Flat binaries of user programs that are embedded into the kernel image.
+ *
+ * There is no file system yet, so the programs are written in assembly right here and copied into
+ * a process by `Process::new`. A flat binary is loaded at `USER_CODE_START` and starts executing
+ * at its first byte, so the code has to be position independent (addresses relative to `rip`).
+ * The bytes live in `.rodata`, the kernel never executes them itself.
+ */
+use core::arch::global_asm;
+
+use super::syscall::{SYS_EXIT, SYS_SLEEP, SYS_WRITE, SYS_YIELD};
+
+global_asm!(
+    ".pushsection .rodata.user_programs, \"a\"",
+    // the labels are read from Rust, which can be in another codegen unit
+    ".global USER_HELLO_START",
+    ".global USER_HELLO_END",
+    ".global USER_FAULT_START",
+    ".global USER_FAULT_END",
+    ".global USER_PRIVILEGED_START",
+    ".global USER_PRIVILEGED_END",
+    ".global USER_BAD_WRITE_START",
+    ".global USER_BAD_WRITE_END",
+    "",
+    // prints a greeting, sleeps, yields and exits with 0
+    "USER_HELLO_START:",
+    "lea rdi, [rip + user_hello_message]",
+    "lea rsi, [rip + USER_HELLO_END]",
+    "sub rsi, rdi",
+    "mov eax, {sys_write}",
+    "syscall",
+    "mov eax, {sys_sleep}",
+    "mov edi, 100",
+    "syscall",
+    "mov eax, {sys_yield}",
+    "syscall",
+    "mov eax, {sys_exit}",
+    "xor edi, edi",
+    "syscall",
+    "user_hello_message:",
+    ".ascii \"Hello from user mode!\\n\"",
+    "USER_HELLO_END:",
+    "",
+    // writes to the kernel heap, which is not accessible from user mode
+    "USER_FAULT_START:",
+    "mov rax, {kernel_address}",
+    "mov qword ptr [rax], 1",
+    "mov eax, {sys_exit}",
+    "xor edi, edi",
+    "syscall",
+    "USER_FAULT_END:",
+    "",
+    // executes a privileged instruction
+    "USER_PRIVILEGED_START:",
+    "hlt",
+    "mov eax, {sys_exit}",
+    "xor edi, edi",
+    "syscall",
+    "USER_PRIVILEGED_END:",
+    "",
+    // asks the kernel to print from the kernel heap and exits with the result
+    "USER_BAD_WRITE_START:",
+    "mov rdi, {kernel_address}",
+    "mov esi, 8",
+    "mov eax, {sys_write}",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {sys_exit}",
+    "syscall",
+    "USER_BAD_WRITE_END:",
+    "",
+    ".popsection",
+    sys_write = const SYS_WRITE,
+    sys_exit = const SYS_EXIT,
+    sys_yield = const SYS_YIELD,
+    sys_sleep = const SYS_SLEEP,
+    kernel_address = const crate::allocator::HEAP_START,
+);
+
+extern "C" {
+    static USER_HELLO_START: u8;
+    static USER_HELLO_END: u8;
+    static USER_FAULT_START: u8;
+    static USER_FAULT_END: u8;
+    static USER_PRIVILEGED_START: u8;
+    static USER_PRIVILEGED_END: u8;
+    static USER_BAD_WRITE_START: u8;
+    static USER_BAD_WRITE_END: u8;
+}
+
+pub struct Program {
+    pub name: &'static str,
+    pub help: &'static str,
+    code: fn() -> &'static [u8],
+}
+
+impl Program {
+    pub fn code(&self) -> &'static [u8] {
+        (self.code)()
+    }
+}
+
+// Every embedded program. To add a program, add its code to the assembly above and an entry here.
+pub const PROGRAMS: &[Program] = &[
+    Program {
+        name: "hello",
+        help: "print a greeting and exit",
+        code: || unsafe { between(&USER_HELLO_START, &USER_HELLO_END) },
+    },
+    Program {
+        name: "fault",
+        help: "write to kernel memory, which kills the process",
+        code: || unsafe { between(&USER_FAULT_START, &USER_FAULT_END) },
+    },
+    Program {
+        name: "privileged",
+        help: "execute `hlt`, which kills the process",
+        code: || unsafe { between(&USER_PRIVILEGED_START, &USER_PRIVILEGED_END) },
+    },
+    Program {
+        name: "bad-write",
+        help: "pass kernel memory to the write system call",
+        code: || unsafe { between(&USER_BAD_WRITE_START, &USER_BAD_WRITE_END) },
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Program> {
+    PROGRAMS.iter().find(|program| program.name == name)
+}
+
+// the bytes between two labels of the assembly
+unsafe fn between(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+#[test_case]
+fn test_programs_are_embedded() {
+    for (i, program) in PROGRAMS.iter().enumerate() {
+        assert!(PROGRAMS[..i].iter().all(|other| other.name != program.name));
+        assert!(!program.code().is_empty());
+    }
+    // the message is part of the binary
+    let hello = find("hello").unwrap().code();
+    assert!(hello.ends_with(b"Hello from user mode!\n"));
+}
@@ -0,0 +1,234 @@
+/**
+ * The switch between the kernel and a user process.
+ *
+ * A process runs until it traps back into the kernel, either with the `syscall` instruction or
+ * with an exception. `enter_user` saves the kernel's callee-saved registers and stack pointer,
+ * loads the registers of the process and `sysretq`s into it. On a trap, the registers of the
+ * process are saved into its `UserContext` and the kernel continues right after the call to
+ * `enter_user`, as if it had returned. The kernel then handles the system call and enters the
+ * process again.
+ *
+ * Every CPU can run a process, so the kernel stack pointer and the context of the process are
+ * kept in the `PerCpu` of the CPU, which the assembly reaches through GS. A process could change
+ * its GS base (by loading a selector into GS), so it runs with its own one and the kernel's is
+ * swapped in with `swapgs` on every way into the kernel (see `percpu::KernelGs`).
+ *
+ * System call ABI: the number is passed in `rax`, the arguments in `rdi`, `rsi` and `rdx`, and
+ * the result is returned in `rax`. Like on Linux, `rcx` and `r11` are clobbered by `syscall`.
+ * `sysretq` takes the `rip` and `rflags` of the process from them, which is why the process is
+ * entered with `rip` and `rflags` in `rcx` and `r11` as well, also the first time.
+ */
+use core::{
+    arch::{asm, global_asm},
+    mem::offset_of,
+};
+
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::{gdt, percpu::PerCpu};
+
+// the system call numbers
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+
+// returned in `rax` for an unknown system call or invalid arguments
+pub const SYSCALL_ERROR: u64 = u64::MAX;
+
+// why `enter_user` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Syscall,
+    Fault,
+    // the process can't be entered again, since it would continue at a non-canonical address
+    NonCanonicalRip,
+}
+
+// the values that `return_to_kernel` gets in `rax`
+const TRAP_SYSCALL: u64 = 0;
+const TRAP_FAULT: u64 = 1;
+
+// The registers of a user process while it is not running. The layout is used by the assembly
+// below, so don't change the order of the fields.
+#[repr(C)]
+#[derive(Debug, Default, Clone)]
+pub struct UserContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+}
+
+impl UserContext {
+    // A context that starts executing at `entry` with the stack pointer at `stack_top`
+    pub fn new(entry: VirtAddr, stack_top: VirtAddr) -> Self {
+        let (code_selector, data_selector) = gdt::user_selectors();
+        UserContext {
+            rip: entry.as_u64(),
+            rsp: stack_top.as_u64(),
+            // user processes can always be interrupted
+            rflags: RFlags::INTERRUPT_FLAG.bits() | 0x2,
+            cs: u64::from(code_selector.0),
+            ss: u64::from(data_selector.0),
+            ..UserContext::default()
+        }
+    }
+}
+
+global_asm!(
+    ".global enter_user",
+    "enter_user:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov gs:[{kernel_rsp}], rsp",
+    "mov gs:[{user_context}], rdi",
+    // `sysretq` continues at `rcx` with the flags in `r11`, and loads cs and ss from STAR
+    "mov rcx, [rdi + 120]",
+    "mov r11, [rdi + 136]",
+    "mov rax, [rdi + 0]",
+    "mov rbx, [rdi + 8]",
+    "mov rdx, [rdi + 24]",
+    "mov rsi, [rdi + 32]",
+    "mov rbp, [rdi + 48]",
+    "mov r8, [rdi + 56]",
+    "mov r9, [rdi + 64]",
+    "mov r10, [rdi + 72]",
+    "mov r12, [rdi + 88]",
+    "mov r13, [rdi + 96]",
+    "mov r14, [rdi + 104]",
+    "mov r15, [rdi + 112]",
+    // `sysretq` doesn't switch stacks, so we are on the stack of the process from here on. The
+    // interrupts are disabled, only an NMI or a machine check could arrive, which are fatal.
+    "mov rsp, [rdi + 128]",
+    "mov rdi, [rdi + 40]",
+    "swapgs",
+    "sysretq",
+    "",
+    // The CPU jumps here on `syscall`, with interrupts disabled by SFMASK. The user's `rip` is in
+    // `rcx` and its `rflags` in `r11`. The registers are pushed into the context, from its
+    // `rflags` field down to `rax`.
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_rsp}], rsp",
+    "mov rsp, gs:[{user_context}]",
+    "add rsp, 144",
+    "push r11",
+    "push qword ptr gs:[{user_rsp}]",
+    "push rcx",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rbp",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rbx",
+    "push rax",
+    "mov rax, {trap_syscall}",
+    "jmp return_to_kernel",
+    "",
+    // Continues the kernel after the call to `enter_user`, which returns the trap in `rax`. The
+    // kernel's GS base has been swapped in already.
+    ".global return_to_kernel",
+    "return_to_kernel:",
+    "mov rsp, gs:[{kernel_rsp}]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    kernel_rsp = const offset_of!(PerCpu, kernel_rsp),
+    user_context = const offset_of!(PerCpu, user_context),
+    user_rsp = const offset_of!(PerCpu, user_rsp),
+    trap_syscall = const TRAP_SYSCALL,
+);
+
+extern "C" {
+    fn enter_user(context: *mut UserContext) -> u64;
+    fn syscall_entry();
+    fn return_to_kernel();
+}
+
+// Enables the `syscall` instruction and sets the kernel entry point for it, on the running CPU
+pub fn init() {
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    Star::write(user_code, user_data, kernel_code, kernel_data)
+        .expect("GDT segments are not in the order required by syscall");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // the entry must not be interrupted before it switched to the kernel stack
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Runs the user process with the given registers until it makes a system call or causes an
+/// exception. The registers are updated with the ones of the process at that point.
+///
+/// # Safety
+///
+/// The caller must guarantee that the address space of the process is active, and that the
+/// registers are valid for it.
+pub unsafe fn run_user(context: &mut UserContext) -> Trap {
+    // `sysretq` raises a #GP for a non-canonical `rip` while still in kernel mode, but with the
+    // stack pointer of the process already loaded. A process gets there with a `syscall` as the
+    // last instruction below the non-canonical hole, so it must not be entered again.
+    if VirtAddr::try_new(context.rip).is_err() {
+        return Trap::NonCanonicalRip;
+    }
+    // a trap returns with interrupts disabled, and an interrupt arriving before we are in user
+    // mode must not find the half-saved kernel state
+    x86_64::instructions::interrupts::disable();
+    let trap = enter_user(context);
+    x86_64::instructions::interrupts::enable();
+    match trap {
+        TRAP_SYSCALL => Trap::Syscall,
+        _ => Trap::Fault,
+    }
+}
+
+// Leaves the user process that caused an exception and continues the kernel at `run_user`, which
+// returns `Trap::Fault`.
+//
+// This function is unsafe because it must only be called from an exception handler that
+// interrupted user mode, after it swapped the kernel's GS base in. The handler never returns, its
+// stack is simply abandoned.
+pub(crate) unsafe fn leave_faulted_user() -> ! {
+    asm!(
+        "jmp {}",
+        sym return_to_kernel,
+        in("rax") TRAP_FAULT,
+        options(noreturn)
+    );
+}
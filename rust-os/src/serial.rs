@@ -0,0 +1,42 @@
+use lazy_static::lazy_static;
+use uart_16550::SerialPort;
+
+use crate::sync::IrqSafeMutex;
+
+// The UART 16550 is the chip behind the serial port. QEMU can redirect the first serial port
+// (COM1, at I/O port 0x3F8) to the host's stdout, which is how the tests report their results
+// without a display. Interrupt handlers print to it too, so the interrupts are disabled while
+// it's locked.
+lazy_static! {
+    pub static ref SERIAL1: IrqSafeMutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        serial_port.init();
+        IrqSafeMutex::new(serial_port)
+    };
+}
+
+#[doc(hidden)]
+pub fn _print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+// Prints to the host through the serial interface.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*));
+    };
+}
+
+// Prints to the host through the serial interface, appending a newline.
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
@@ -0,0 +1,135 @@
+/**
+ * A small interactive shell for poking at the kernel while it runs. It subscribes to the key
+ * events of the `keyboard` subsystem, so it runs as a normal task on the executor.
+ *
+ * The commands are listed in `commands::COMMANDS`, adding a command only needs a new entry there.
+ */
+use alloc::{collections::VecDeque, string::String};
+use futures_util::StreamExt;
+
+use crate::keyboard::{self, DecodedKey, KeyCode};
+use crate::{print, println, vga_buffer};
+
+pub mod commands;
+
+const PROMPT: &str = "> ";
+// the line has to fit into a single row, since moving the cursor back does not wrap to the row
+// above
+const MAX_LINE_LEN: usize = 80 - PROMPT.len() - 1;
+// the number of lines kept in the history
+const HISTORY_SIZE: usize = 32;
+
+pub async fn run() {
+    let mut key_events = keyboard::subscribe();
+    let mut editor = LineEditor::new();
+
+    println!("rust-os shell, type `help` for a list of commands");
+    print!("{}", PROMPT);
+    while let Some(event) = key_events.next().await {
+        let shift = event.modifiers.shift();
+        match (event.ctrl_combination(), event.key) {
+            // Ctrl-C throws the current line away
+            (Some('c'), _) => {
+                editor.cancel();
+                print!("{}", PROMPT);
+            }
+            (Some(_), _) => {}
+            (None, Some(DecodedKey::RawKey(KeyCode::PageUp))) if shift => {
+                vga_buffer::scroll_page_up()
+            }
+            (None, Some(DecodedKey::RawKey(KeyCode::PageDown))) if shift => {
+                vga_buffer::scroll_page_down()
+            }
+            (None, Some(key)) => {
+                if let Some(line) = editor.handle_key(key) {
+                    commands::execute(&line).await;
+                    print!("{}", PROMPT);
+                }
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+// Edits the current line and keeps the history of the entered lines
+struct LineEditor {
+    line: String,
+    history: VecDeque<String>,
+    // the index of the history entry that is shown, `None` while editing a new line
+    history_index: Option<usize>,
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        LineEditor {
+            line: String::new(),
+            history: VecDeque::new(),
+            history_index: None,
+        }
+    }
+
+    // Returns the line once Enter was pressed
+    fn handle_key(&mut self, key: DecodedKey) -> Option<String> {
+        match key {
+            DecodedKey::Unicode('\n') => {
+                println!();
+                self.history_index = None;
+                let line = core::mem::take(&mut self.line);
+                if !line.trim().is_empty() && self.history.back() != Some(&line) {
+                    if self.history.len() == HISTORY_SIZE {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(line.clone());
+                }
+                return Some(line);
+            }
+            DecodedKey::Unicode('\x08') => {
+                if self.line.pop().is_some() {
+                    // overwrite the last character with a space
+                    print!("\x08 \x08");
+                }
+            }
+            DecodedKey::Unicode(c) if !c.is_control() && self.line.len() < MAX_LINE_LEN => {
+                self.line.push(c);
+                print!("{}", c);
+            }
+            DecodedKey::RawKey(KeyCode::ArrowUp) => {
+                let index = match self.history_index {
+                    Some(index) => index.checked_sub(1),
+                    None => self.history.len().checked_sub(1),
+                };
+                if let Some(index) = index {
+                    self.history_index = Some(index);
+                    self.replace_line(self.history[index].clone());
+                }
+            }
+            DecodedKey::RawKey(KeyCode::ArrowDown) => match self.history_index {
+                Some(index) if index + 1 < self.history.len() => {
+                    self.history_index = Some(index + 1);
+                    self.replace_line(self.history[index + 1].clone());
+                }
+                // going down from the newest entry brings back an empty line
+                Some(_) => {
+                    self.history_index = None;
+                    self.replace_line(String::new());
+                }
+                None => {}
+            },
+            _ => {}
+        }
+        None
+    }
+
+    // Abandons the current line
+    fn cancel(&mut self) {
+        println!("^C");
+        self.line.clear();
+        self.history_index = None;
+    }
+
+    fn replace_line(&mut self, line: String) {
+        self.line = line;
+        // go back to the start of the row and clear it, then print the new line
+        print!("\r\x1b[K{}{}", PROMPT, self.line);
+    }
+}
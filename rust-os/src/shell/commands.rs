@@ -0,0 +1,416 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{future::Future, pin::Pin};
+use x86_64::VirtAddr;
+
+use crate::{
+    allocator,
+    block::{self, BLOCK_SIZE},
+    fs::{self, FileType},
+    keyboard::{self, Layout},
+    memory,
+    net::{self, arp},
+    pci::{self, Bar},
+    print, println,
+    process::{self, programs, Process},
+    task, thread, timer,
+};
+
+pub struct Command {
+    pub name: &'static str,
+    // the arguments, shown by `help`
+    pub args: &'static str,
+    pub help: &'static str,
+    pub run: Run,
+}
+
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = Result<(), &'static str>> + Send + 'a>>;
+
+// The function of a command gets the arguments after the command name, the error is printed by
+// the shell
+pub enum Run {
+    Sync(fn(&[&str]) -> Result<(), &'static str>),
+    // for the commands that wait for something, e.g. the disk, without blocking the executor
+    Async(for<'a> fn(&'a [&'a str]) -> CommandFuture<'a>),
+}
+
+// Every command of the shell. To add a command, add an entry here.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        args: "",
+        help: "list the available commands",
+        run: Run::Sync(help),
+    },
+    Command {
+        name: "mem",
+        args: "",
+        help: "show the heap and physical frame usage",
+        run: Run::Sync(mem),
+    },
+    Command {
+        name: "heap",
+        args: "[on|off]",
+        help: "show the allocations by size, or switch the heap debug mode",
+        run: Run::Sync(heap),
+    },
+    Command {
+        name: "ticks",
+        args: "",
+        help: "show the timer ticks since boot",
+        run: Run::Sync(ticks),
+    },
+    Command {
+        name: "pt",
+        args: "<vaddr>",
+        help: "translate a virtual address (hex) through the page tables",
+        run: Run::Sync(pt),
+    },
+    Command {
+        name: "tasks",
+        args: "",
+        help: "list the tasks of the executor",
+        run: Run::Sync(tasks),
+    },
+    Command {
+        name: "threads",
+        args: "",
+        help: "list the kernel threads and their state",
+        run: Run::Sync(threads),
+    },
+    Command {
+        name: "lspci",
+        args: "[-v]",
+        help: "list the PCI devices, with -v also their BARs",
+        run: Run::Sync(lspci),
+    },
+    Command {
+        name: "disks",
+        args: "",
+        help: "list the block devices",
+        run: Run::Sync(disks),
+    },
+    Command {
+        name: "net",
+        args: "",
+        help: "show the network interface and the ARP cache",
+        run: Run::Sync(net),
+    },
+    Command {
+        name: "ls",
+        args: "[path]",
+        help: "list a directory, by default the root directory",
+        run: Run::Async(ls),
+    },
+    Command {
+        name: "cat",
+        args: "<path>",
+        help: "print a file",
+        run: Run::Async(cat),
+    },
+    Command {
+        name: "run",
+        args: "<prog>",
+        help: "start an embedded user program, without arguments list them",
+        run: Run::Sync(run),
+    },
+    Command {
+        name: "layout",
+        args: "[name]",
+        help: "show or select the keyboard layout",
+        run: Run::Sync(layout),
+    },
+    Command {
+        name: "reboot",
+        args: "",
+        help: "reset the machine",
+        run: Run::Sync(reboot),
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+// Runs the command line entered in the shell
+pub async fn execute(line: &str) {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return,
+    };
+    let args: Vec<&str> = words.collect();
+    match find(name) {
+        Some(command) => {
+            let result = match command.run {
+                Run::Sync(run) => run(&args),
+                Run::Async(run) => run(&args).await,
+            };
+            if let Err(error) = result {
+                println!("{}: {}", name, error);
+                println!("usage: {} {}", command.name, command.args);
+            }
+        }
+        None => println!("unknown command `{}`, try `help`", name),
+    }
+}
+
+fn help(_args: &[&str]) -> Result<(), &'static str> {
+    for command in COMMANDS {
+        println!("  {:<6} {:<8} {}", command.name, command.args, command.help);
+    }
+    Ok(())
+}
+
+fn mem(_args: &[&str]) -> Result<(), &'static str> {
+    let (heap_used, heap_size) = (allocator::heap_used(), allocator::heap_size());
+    println!(
+        "heap:   {:>6} of {:>6} bytes used ({}%), grows up to {} KiB",
+        heap_used,
+        heap_size,
+        heap_used * 100 / heap_size.max(1),
+        allocator::heap_limit() / 1024
+    );
+    let frames = memory::frame_stats();
+    println!(
+        "frames: {:>6} of {:>6} frames used, {} KiB free",
+        frames.used,
+        frames.total(),
+        frames.free * 4
+    );
+    Ok(())
+}
+
+fn heap(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            println!("{}", allocator::stats());
+            println!(
+                "debug mode {}",
+                if allocator::debug_mode() { "on" } else { "off" }
+            );
+        }
+        ["on"] => allocator::set_debug_mode(true),
+        ["off"] => allocator::set_debug_mode(false),
+        _ => return Err("expected on or off"),
+    }
+    Ok(())
+}
+
+fn ticks(_args: &[&str]) -> Result<(), &'static str> {
+    let uptime = timer::uptime();
+    println!(
+        "{} ticks at {} Hz ({}.{:03}s)",
+        timer::ticks(),
+        timer::TIMER_FREQUENCY,
+        uptime.as_secs(),
+        uptime.subsec_millis()
+    );
+    Ok(())
+}
+
+fn pt(args: &[&str]) -> Result<(), &'static str> {
+    let addr = match args {
+        [addr] => parse_address(addr).ok_or("not a canonical hexadecimal address")?,
+        _ => return Err("expected one address"),
+    };
+    println!(
+        "P4 {} P3 {} P2 {} P1 {} offset {:#x}",
+        u16::from(addr.p4_index()),
+        u16::from(addr.p3_index()),
+        u16::from(addr.p2_index()),
+        u16::from(addr.p1_index()),
+        u16::from(addr.page_offset())
+    );
+    // the complete physical memory is mapped at this offset by the bootloader
+    match unsafe { memory::translate_addr(addr, memory::physical_memory_offset()) } {
+        Some(phys) => println!("{:#x} -> {:#x}", addr.as_u64(), phys.as_u64()),
+        None => println!("{:#x} is not mapped", addr.as_u64()),
+    }
+    Ok(())
+}
+
+fn parse_address(s: &str) -> Option<VirtAddr> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    let addr = u64::from_str_radix(digits, 16).ok()?;
+    VirtAddr::try_new(addr).ok()
+}
+
+fn tasks(_args: &[&str]) -> Result<(), &'static str> {
+    for (id, name) in task::tasks() {
+        println!("  {:>3} {}", id.as_u64(), name);
+    }
+    Ok(())
+}
+
+fn threads(_args: &[&str]) -> Result<(), &'static str> {
+    for (id, name, state) in thread::threads() {
+        println!("  {:>3} {:<10} {:?}", id.as_u64(), name, state);
+    }
+    Ok(())
+}
+
+fn run(args: &[&str]) -> Result<(), &'static str> {
+    let program = match args {
+        [] => {
+            for program in programs::PROGRAMS {
+                println!("  {:<10} {}", program.name, program.help);
+            }
+            return Ok(());
+        }
+        [name] => programs::find(name).ok_or("no such program")?,
+        _ => return Err("expected one program"),
+    };
+    let process = Process::new(program.name, program.code()).map_err(|_| "not enough memory")?;
+    let id = process::spawn(process);
+    println!("started process {}", id.as_u64());
+    Ok(())
+}
+
+fn lspci(args: &[&str]) -> Result<(), &'static str> {
+    let verbose = match args {
+        [] => false,
+        ["-v"] => true,
+        _ => return Err("unknown option"),
+    };
+    for (device, driver) in pci::devices() {
+        println!(
+            "{} {:04x}:{:04x} {} [{}]",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class_name(),
+            driver.unwrap_or("no driver")
+        );
+        if !verbose {
+            continue;
+        }
+        for (i, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory { address, size, .. }) => println!(
+                    "    bar{} memory {:#x} ({} KiB)",
+                    i,
+                    address.as_u64(),
+                    size / 1024
+                ),
+                Some(Bar::Io { port, size }) => {
+                    println!("    bar{} io {:#x} ({} ports)", i, port, size)
+                }
+                None => {}
+            }
+        }
+        if device.interrupt_pin != 0 {
+            println!("    irq {}", device.interrupt_line);
+        }
+    }
+    Ok(())
+}
+
+fn disks(_args: &[&str]) -> Result<(), &'static str> {
+    for device in block::devices() {
+        println!(
+            "{:<6} {:>8} blocks {:>8} KiB{}",
+            device.name(),
+            device.block_count(),
+            device.block_count() * BLOCK_SIZE as u64 / 1024,
+            if device.read_only() { " read-only" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+fn net(_args: &[&str]) -> Result<(), &'static str> {
+    let interface = net::interface().ok_or(net::NetError::NoInterface.as_str())?;
+    let config = net::config();
+    println!(
+        "{} {} {}/{}",
+        interface.device().name(),
+        interface.mac_address(),
+        config.address,
+        config.prefix_len
+    );
+    if let Some(gateway) = config.gateway {
+        println!("gateway {}", gateway);
+    }
+    for (ip, mac) in arp::entries() {
+        println!("  {:<15} {}", ip, mac);
+    }
+    Ok(())
+}
+
+fn ls<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        let path = match args {
+            [] => "/",
+            [path] => *path,
+            _ => return Err("expected at most one path"),
+        };
+        let entries = fs::read_dir(path).await.map_err(|error| error.as_str())?;
+        for entry in entries {
+            match entry.metadata.file_type {
+                FileType::Directory => println!("  {:>8}  {}/", "", entry.name),
+                FileType::File => println!("  {:>8}  {}", entry.metadata.size, entry.name),
+            }
+        }
+        Ok(())
+    })
+}
+
+fn cat<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        let path = match args {
+            [path] => *path,
+            _ => return Err("expected one path"),
+        };
+        let data = fs::read_file(path).await.map_err(|error| error.as_str())?;
+        // the bytes that are not UTF-8 are shown as replacement characters
+        for chunk in data.utf8_chunks() {
+            print!("{}", chunk.valid());
+            if !chunk.invalid().is_empty() {
+                print!("{}", char::REPLACEMENT_CHARACTER);
+            }
+        }
+        if !data.is_empty() && !data.ends_with(b"\n") {
+            println!();
+        }
+        Ok(())
+    })
+}
+
+fn layout(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            let names: Vec<&str> = Layout::ALL.iter().map(|layout| layout.name()).collect();
+            println!(
+                "layout: {} (available: {})",
+                keyboard::layout().name(),
+                names.join(", ")
+            );
+        }
+        [name] => keyboard::set_layout(Layout::from_name(name).ok_or("unknown layout")?),
+        _ => return Err("expected at most one layout"),
+    }
+    Ok(())
+}
+
+fn reboot(_args: &[&str]) -> Result<(), &'static str> {
+    println!("rebooting...");
+    crate::reboot();
+}
+
+#[test_case]
+fn test_commands_are_unique() {
+    for (i, command) in COMMANDS.iter().enumerate() {
+        assert!(COMMANDS[..i].iter().all(|other| other.name != command.name));
+        assert!(find(command.name).is_some());
+    }
+    assert!(find("no-such-command").is_none());
+}
+
+#[test_case]
+fn test_parse_address() {
+    assert_eq!(parse_address("0xb8000"), Some(VirtAddr::new(0xb8000)));
+    assert_eq!(parse_address("deadbeef"), Some(VirtAddr::new(0xdead_beef)));
+    // not canonical, bits 48 to 63 have to be copies of bit 47
+    assert_eq!(parse_address("0x800000000000"), None);
+    assert_eq!(parse_address("xyz"), None);
+}
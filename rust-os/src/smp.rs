@@ -0,0 +1,201 @@
+/**
+ * Starts the application processors, the CPUs besides the boot CPU.
+ *
+ * After reset, only the boot CPU runs. The others wait until their local APIC receives an INIT
+ * IPI followed by a startup IPI (SIPI), which starts them in real mode at the start of a page
+ * below 1 MiB. `memory` reserves such a page for the trampoline (see `trampoline.rs`), which
+ * switches to long mode with the kernel's page table and calls `ap_main` on a stack of its own.
+ * The CPUs are started one after another, since they share the trampoline.
+ *
+ * An application processor then gets its own `PerCpu`, GDT and TSS, loads the IDT and enables its
+ * local APIC, so the others can wake it up with an IPI. After that it runs the executor forever,
+ * taking tasks from the other CPUs when it has none (see `task::executor`). The device interrupts
+ * and the timer stay with the boot CPU, and so do the kernel threads.
+ */
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    gdt,
+    interrupts::{self, apic},
+    memory,
+    percpu::{self, PerCpu, MAX_CPUS},
+    process,
+    task::executor::Executor,
+    thread::stack::Stack,
+    timer,
+};
+
+mod trampoline;
+
+use trampoline::Parameters;
+
+// how long an application processor has to start, in timer ticks
+const START_TIMEOUT: u64 = 100;
+
+// set by an application processor once it is running, the boot CPU waits for it
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum InitError {
+    // the processors are started through the local APIC, and only the MADT lists them
+    ApicDisabled,
+    // there was no free page below 1 MiB for the trampoline
+    NoStartupFrame,
+    // the trampoline loads the page table in 32 bit mode
+    PageTableAbove4GiB,
+    Mapping(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for InitError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        InitError::Mapping(error)
+    }
+}
+
+// Starts the application processors listed in the MADT and returns the number of CPUs running.
+// Needs the APIC, the timer interrupt and the kernel memory.
+pub fn init() -> Result<usize, InitError> {
+    let (madt, local_apic) = match (apic::madt(), apic::local_apic()) {
+        (Some(madt), Some(local_apic)) if apic::is_enabled() => (madt, local_apic),
+        _ => return Err(InitError::ApicDisabled),
+    };
+    let frame = memory::startup_frame().ok_or(InitError::NoStartupFrame)?;
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    if cr3 > u64::from(u32::MAX) {
+        return Err(InitError::PageTableAbove4GiB);
+    }
+    let mapped = map_identity(frame)?;
+
+    let boot_apic_id = local_apic.id();
+    let mut result = Ok(());
+    for processor in &madt.processors {
+        if processor.apic_id == boot_apic_id {
+            continue;
+        }
+        let index = percpu::count();
+        if index == MAX_CPUS {
+            log::warn!("smp: only {} CPUs are used", MAX_CPUS);
+            break;
+        }
+        match start(local_apic, frame, cr3, processor.apic_id, index) {
+            Ok(true) => {}
+            Ok(false) => {
+                // it could still start later, so the trampoline can't be used for the next one
+                log::warn!("smp: CPU with APIC id {} did not start", processor.apic_id);
+                break;
+            }
+            Err(error) => {
+                result = Err(error);
+                break;
+            }
+        }
+    }
+
+    if mapped {
+        unmap_identity(frame);
+    }
+    result?;
+    log::info!("smp: {} CPUs running", percpu::count());
+    Ok(percpu::count())
+}
+
+// Starts the CPU with the given APIC id and waits until it runs, returns `false` if it didn't
+fn start(
+    local_apic: &apic::LocalApic,
+    frame: PhysFrame,
+    cr3: u64,
+    apic_id: u8,
+    index: usize,
+) -> Result<bool, InitError> {
+    // the CPU uses both forever
+    let cpu: &'static PerCpu = Box::leak(Box::new(PerCpu::new(index)));
+    let stack = Stack::allocate()?;
+    let parameters = Parameters {
+        cr3,
+        stack: stack.top().as_u64(),
+        entry: ap_main as *const () as u64,
+        argument: cpu as *const PerCpu as u64,
+    };
+    core::mem::forget(stack);
+    unsafe { trampoline::install(frame, parameters) };
+    STARTED.store(false, Ordering::SeqCst);
+
+    // the INIT has to be followed by a wait of 10 milliseconds, a tick could be almost over
+    local_apic.send_init(apic_id);
+    wait_until(timer::ticks() + 2, || false);
+    // the page number is below 256, since the frame is below 1 MiB
+    let page = frame.start_address().as_u64() / 4096;
+    local_apic.send_startup(apic_id, page as u8);
+    // a second SIPI in case the first one was lost, like the MultiProcessor Specification says
+    if !wait_until(timer::ticks() + 2, || STARTED.load(Ordering::SeqCst)) {
+        local_apic.send_startup(apic_id, page as u8);
+    }
+    let deadline = timer::ticks() + START_TIMEOUT;
+    Ok(wait_until(deadline, || STARTED.load(Ordering::SeqCst)))
+}
+
+// Spins until `done` returns true or the tick `deadline` is reached, returns what `done` returned
+fn wait_until(deadline: u64, done: impl Fn() -> bool) -> bool {
+    while timer::ticks() < deadline {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    done()
+}
+
+// The trampoline enables paging while it runs in its page, so the page has to be mapped to itself.
+// Returns whether it had to be mapped.
+fn map_identity(frame: PhysFrame) -> Result<bool, InitError> {
+    let address = VirtAddr::new(frame.start_address().as_u64());
+    memory::with_kernel_memory(|memory| {
+        match memory.mapper.translate_addr(address) {
+            Some(physical) if physical == frame.start_address() => return Ok(false),
+            Some(_) => return Err(MapToError::PageAlreadyMapped(frame).into()),
+            None => {}
+        }
+        let page = Page::containing_address(address);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            memory
+                .mapper
+                .map_to(page, frame, flags, &mut memory.frame_allocator)?
+                .flush();
+        }
+        Ok(true)
+    })
+}
+
+fn unmap_identity(frame: PhysFrame) {
+    let page: Page<Size4KiB> =
+        Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    memory::with_kernel_memory(|memory| {
+        // the frame stays reserved for the trampoline
+        let (_, flush) = memory
+            .mapper
+            .unmap(page)
+            .expect("trampoline page not mapped");
+        flush.flush();
+    });
+}
+
+// The trampoline jumps here, on the stack from `start`
+extern "C" fn ap_main(cpu: &'static PerCpu) -> ! {
+    unsafe { percpu::load(cpu) };
+    gdt::init();
+    interrupts::init_idt();
+    process::init();
+    apic::enable_local_apic();
+    STARTED.store(true, Ordering::SeqCst);
+    x86_64::instructions::interrupts::enable();
+    Executor::new().run();
+}
@@ -0,0 +1,133 @@
+// The first code of an application processor. The startup IPI starts it in real mode at the start
+// of the page it names, so this code is copied into a page below 1 MiB and can't know its address
+// in advance: it takes it from CS, whose base is the page address.
+//
+// It loads a GDT of its own, switches to protected mode, enables PAE and loads the kernel's page
+// table, switches to long mode and jumps to the entry from the parameters, with the stack and
+// argument from there. The page has to be identity mapped, since paging is enabled while this
+// code runs. It uses the end of its page as stack until it jumps to the entry.
+
+use core::{arch::global_asm, ptr};
+use x86_64::structures::paging::PhysFrame;
+
+use crate::memory;
+
+// Read by the trampoline, at `ap_trampoline_parameters`. Don't change the order of the fields.
+#[repr(C)]
+pub struct Parameters {
+    // the physical address of the level 4 page table, which has to be below 4 GiB
+    pub cr3: u64,
+    pub stack: u64,
+    // an `extern "C" fn(argument) -> !`
+    pub entry: u64,
+    pub argument: u64,
+}
+
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_parameters",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    // the segments start at the page, `ebx` holds its address from now on
+    "mov ax, cs",
+    "mov ds, ax",
+    "mov ss, ax",
+    "mov sp, 4096",
+    "xor ebx, ebx",
+    "mov bx, ax",
+    "shl ebx, 4",
+    // the GDT pointer needs the linear address of the GDT
+    "lea eax, [ebx + .Lap_gdt_offset]",
+    "mov [.Lap_gdt_pointer_offset + 2], eax",
+    "lgdt [.Lap_gdt_pointer_offset]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    // a far return to the 32 bit code segment, with a 32 bit offset: `push dword 8`, `retfd`
+    "lea eax, [ebx + .Lap_protected_mode_offset]",
+    ".byte 0x66, 0x6a, 0x08",
+    "push eax",
+    ".byte 0x66, 0xcb",
+    ".code32",
+    ".Lap_protected_mode:",
+    "mov ax, 16",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    "lea esp, [ebx + 4096]",
+    // PAE
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [ebx + .Lap_parameters_offset]",
+    "mov cr3, eax",
+    // long mode and no-execute in the EFER
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    // paging and write protection, which enables long mode
+    "mov eax, cr0",
+    "or eax, (1 << 31) | (1 << 16)",
+    "mov cr0, eax",
+    "lea eax, [ebx + .Lap_long_mode_offset]",
+    "push 24",
+    "push eax",
+    "retf",
+    ".code64",
+    ".Lap_long_mode:",
+    "mov ebx, ebx",
+    "mov rsp, [rbx + .Lap_parameters_offset + 8]",
+    "mov rax, [rbx + .Lap_parameters_offset + 16]",
+    "mov rdi, [rbx + .Lap_parameters_offset + 24]",
+    // the end of the frame pointer chain, and the return address of a call
+    "xor ebp, ebp",
+    "push 0",
+    "jmp rax",
+    // null, 32 bit code, data, 64 bit code
+    ".balign 8",
+    ".Lap_gdt:",
+    ".quad 0",
+    ".quad 0x00cf9a000000ffff",
+    ".quad 0x00cf92000000ffff",
+    ".quad 0x00af9a000000ffff",
+    ".Lap_gdt_pointer:",
+    ".word 4 * 8 - 1",
+    ".long 0",
+    ".balign 8",
+    "ap_trampoline_parameters:",
+    ".fill 4, 8, 0",
+    "ap_trampoline_end:",
+    // the offsets of the labels from the start, since the code runs at another address
+    ".set .Lap_gdt_offset, .Lap_gdt - ap_trampoline_start",
+    ".set .Lap_gdt_pointer_offset, .Lap_gdt_pointer - ap_trampoline_start",
+    ".set .Lap_protected_mode_offset, .Lap_protected_mode - ap_trampoline_start",
+    ".set .Lap_long_mode_offset, .Lap_long_mode - ap_trampoline_start",
+    ".set .Lap_parameters_offset, ap_trampoline_parameters - ap_trampoline_start",
+    ".popsection",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_parameters: u8;
+    static ap_trampoline_end: u8;
+}
+
+// Copies the trampoline to the start of `frame`, with the given parameters
+//
+// This function is unsafe because nothing else may use the frame, and no CPU may run the
+// trampoline at the same time.
+pub unsafe fn install(frame: PhysFrame, parameters: Parameters) {
+    let start = ptr::addr_of!(ap_trampoline_start);
+    let len = ptr::addr_of!(ap_trampoline_end).offset_from(start) as usize;
+    let offset = ptr::addr_of!(ap_trampoline_parameters).offset_from(start) as usize;
+    let target = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    ptr::copy_nonoverlapping(start, target, len);
+    ptr::write_volatile(target.add(offset).cast::<Parameters>(), parameters);
+}
@@ -0,0 +1,171 @@
+/**
+ * The locks of the kernel.
+ *
+ * - `IrqSafeMutex` for data that interrupt handlers use too (or code that runs with the
+ *   interrupts disabled, like the scheduler), see below.
+ * - `spin::Mutex` for data that only tasks and threads use, and only briefly. A thread that is
+ *   preempted while holding one only makes the others spin until it runs again.
+ * - `AsyncMutex` for data that tasks keep locked across an `.await`. Waiting tasks sleep instead
+ *   of spinning, which would block the executor (and with it the task holding the lock).
+ *
+ * A plain `spin::Mutex` deadlocks when an interrupt handler tries to take a lock that the code it
+ * interrupted is holding: the handler spins forever and the holder never runs again. The same
+ * happens with the preemptive scheduler, which switches threads from the timer interrupt. An
+ * `IrqSafeMutex` disables the interrupts while it is held and restores the previous state (the
+ * interrupt flag in RFLAGS) when the guard is dropped, so the holder can't be interrupted.
+ *
+ * In debug builds, the `IrqSafeMutex`es check the order in which they are taken (see
+ * `lockdep.rs`).
+ */
+#[cfg(debug_assertions)]
+use core::panic::Location;
+use core::{
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+mod async_mutex;
+#[cfg(debug_assertions)]
+mod lockdep;
+
+pub use async_mutex::{AsyncMutex, AsyncMutexGuard};
+
+pub struct IrqSafeMutex<T: ?Sized> {
+    // where the lock was created, which the lock order checker uses to tell the locks apart
+    #[cfg(debug_assertions)]
+    class: &'static Location<'static>,
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            #[cfg(debug_assertions)]
+            class: Location::caller(),
+            inner: Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    // Disables the interrupts and takes the lock, spinning until it is free
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        #[cfg(debug_assertions)]
+        lockdep::acquire(self.address(), self.class);
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled,
+            #[cfg(debug_assertions)]
+            lock: self.address(),
+        }
+    }
+
+    // Like `lock`, but returns `None` instead of spinning if the lock is held
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(debug_assertions)]
+                lockdep::acquired(self.address(), self.class);
+                Some(IrqSafeMutexGuard {
+                    guard: ManuallyDrop::new(guard),
+                    interrupts_enabled,
+                    #[cfg(debug_assertions)]
+                    lock: self.address(),
+                })
+            }
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Releases the lock without a guard, e.g. in the panic handler, which might have interrupted
+    /// the holder.
+    ///
+    /// # Safety
+    ///
+    /// The holder must never use its guard again.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        lockdep::release(self.address());
+        self.inner.force_unlock();
+    }
+
+    // identifies the lock for the lock order checker
+    #[cfg(debug_assertions)]
+    fn address(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSafeMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "IrqSafeMutex {{ data: {:?} }}", &*guard),
+            None => write!(f, "IrqSafeMutex {{ <locked> }}"),
+        }
+    }
+}
+
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    // whether the interrupts were enabled before the lock was taken
+    interrupts_enabled: bool,
+    #[cfg(debug_assertions)]
+    lock: usize,
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // the lock has to be released before an interrupt can arrive
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(debug_assertions)]
+        lockdep::release(self.lock);
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_lock_disables_interrupts() {
+    let mutex = IrqSafeMutex::new(1);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = mutex.lock();
+        assert!(!interrupts::are_enabled());
+        *guard += 1;
+        // a nested lock keeps them disabled when it is released
+        let other = IrqSafeMutex::new(());
+        drop(other.lock());
+        assert!(!interrupts::are_enabled());
+        assert!(mutex.try_lock().is_none());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*mutex.lock(), 2);
+}
@@ -0,0 +1,130 @@
+/**
+ * A mutex for async tasks, whose guard can be held across an `.await`.
+ *
+ * A task that finds the lock held registers its waker and returns `Pending`, so the executor
+ * runs the other tasks, among them the one holding the lock. Unlocking wakes every waiting task
+ * and the first one to run gets the lock, the others wait again. Waking only one would lose the
+ * wakeup if that task dropped its `lock` future in the meantime.
+ */
+use alloc::vec::Vec;
+use core::{
+    cell::UnsafeCell,
+    fmt, future,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Poll, Waker},
+};
+use spin::Mutex;
+
+pub struct AsyncMutex<T: ?Sized> {
+    locked: AtomicBool,
+    // the tasks waiting for the lock
+    waiters: Mutex<Vec<Waker>>,
+    value: UnsafeCell<T>,
+}
+
+// the lock makes sure that only one task at a time accesses the value
+unsafe impl<T: ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(value: T) -> Self {
+        AsyncMutex {
+            locked: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> AsyncMutex<T> {
+    // Waits until the lock is free and takes it
+    pub async fn lock(&self) -> AsyncMutexGuard<'_, T> {
+        future::poll_fn(|cx| {
+            if let Some(guard) = self.try_lock() {
+                return Poll::Ready(guard);
+            }
+            let mut waiters = self.waiters.lock();
+            if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+            drop(waiters);
+            // the lock could have been released before we were in the list
+            match self.try_lock() {
+                Some(guard) => Poll::Ready(guard),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    // Takes the lock if it's free
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| AsyncMutexGuard {
+                mutex: self,
+                _value: PhantomData,
+            })
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AsyncMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "AsyncMutex {{ data: {:?} }}", &*guard),
+            None => write!(f, "AsyncMutex {{ <locked> }}"),
+        }
+    }
+}
+
+pub struct AsyncMutexGuard<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
+    // The guard hands out `&mut T`, so it must only be `Send` and `Sync` when that is. Without
+    // this, it would be `Sync` whenever the mutex is, which only asks for `T: Send`.
+    _value: PhantomData<&'a mut T>,
+}
+
+// a shared guard only gives out `&T`, like std's `MutexGuard`
+unsafe impl<T: ?Sized + Sync> Sync for AsyncMutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        let waiters = core::mem::take(&mut *self.mutex.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+#[test_case]
+fn test_try_lock() {
+    let mutex = AsyncMutex::new(1);
+    {
+        let mut guard = mutex.try_lock().expect("lock is free");
+        *guard += 1;
+        assert!(mutex.try_lock().is_none());
+    }
+    assert_eq!(*mutex.try_lock().unwrap(), 2);
+}
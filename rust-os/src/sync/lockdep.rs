@@ -0,0 +1,201 @@
+/**
+ * A lock order checker for the `IrqSafeMutex`es, in debug builds only.
+ *
+ * Two locks that are taken in different orders can deadlock: one CPU holds A and waits for B,
+ * while the other holds B and waits for A. This is rare enough to never show up in a test, so we
+ * record every order in which locks were taken and panic when a new order closes a cycle, e.g.
+ * when B is taken while holding A after A was taken while holding B. Taking a lock that is
+ * already held panics right away, since that deadlocks every time.
+ *
+ * The orders are recorded per lock class: the place where the lock was created. Locks created
+ * at the same place (e.g. one per device) share the rules, like the locks of the Linux lockdep.
+ * Nothing here may allocate, because the heap itself is behind an `IrqSafeMutex`.
+ */
+use core::panic::Location;
+use spin::Mutex;
+
+use crate::percpu::{self, MAX_CPUS};
+
+pub type Class = &'static Location<'static>;
+
+// how many locks can be held at once
+const MAX_HELD: usize = 16;
+// how many different orders are remembered, later ones aren't checked anymore
+const MAX_ORDERS: usize = 256;
+
+#[derive(Clone, Copy)]
+struct Held {
+    // the address of the lock
+    lock: usize,
+    class: Class,
+}
+
+// The locks held right now by one CPU. The interrupts are disabled while an `IrqSafeMutex` is
+// held, so nothing else runs on the CPU that could take locks in between.
+#[derive(Clone, Copy)]
+struct HeldLocks {
+    locks: [Option<Held>; MAX_HELD],
+    len: usize,
+}
+
+impl HeldLocks {
+    fn iter(&self) -> impl Iterator<Item = Held> + '_ {
+        self.locks[..self.len].iter().flatten().copied()
+    }
+
+    fn push(&mut self, lock: usize, class: Class) -> Result<(), &'static str> {
+        if self.len == MAX_HELD {
+            return Err("too many locks held at once");
+        }
+        self.locks[self.len] = Some(Held { lock, class });
+        self.len += 1;
+        Ok(())
+    }
+}
+
+struct State {
+    // the locks held by every CPU, by its index
+    held: [HeldLocks; MAX_CPUS],
+    // (first, second): a lock of class `second` was taken while holding one of class `first`
+    orders: [Option<(Class, Class)>; MAX_ORDERS],
+    orders_len: usize,
+    // set once a problem was reported, so that the panic handler can take any lock
+    disabled: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    held: [HeldLocks {
+        locks: [None; MAX_HELD],
+        len: 0,
+    }; MAX_CPUS],
+    orders: [None; MAX_ORDERS],
+    orders_len: 0,
+    disabled: false,
+});
+
+impl State {
+    fn orders(&self) -> impl Iterator<Item = (Class, Class)> + '_ {
+        self.orders[..self.orders_len].iter().flatten().copied()
+    }
+
+    // Whether a lock of class `to` has been taken while holding one of class `from`, or while
+    // holding a lock that was taken while holding one of class `from`, and so on
+    fn reaches(&self, from: Class, to: Class) -> bool {
+        // a depth-first search over the orders, each order is visited once
+        let mut visited = [false; MAX_ORDERS];
+        let mut stack = [from; MAX_ORDERS + 1];
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let class = stack[stack_len];
+            if class == to {
+                return true;
+            }
+            for (i, (first, second)) in self.orders().enumerate() {
+                if !visited[i] && first == class {
+                    visited[i] = true;
+                    stack[stack_len] = second;
+                    stack_len += 1;
+                }
+            }
+        }
+        false
+    }
+}
+
+// Called before the lock at `lock` is taken, with the interrupts disabled
+pub fn acquire(lock: usize, class: Class) {
+    let cpu = percpu::index();
+    let mut state = STATE.lock();
+    if state.disabled {
+        return;
+    }
+    let again = state.held[cpu].iter().find(|held| held.lock == lock);
+    if let Some(held) = again {
+        drop(state);
+        report(format_args!(
+            "deadlock: lock created at {} taken again while held",
+            held.class
+        ));
+    }
+    for i in 0..state.held[cpu].len {
+        let held = state.held[cpu].locks[i].expect("held lock missing");
+        // the locks of one class don't have an order among each other
+        if held.class == class || state.orders().any(|order| order == (held.class, class)) {
+            continue;
+        }
+        if state.reaches(class, held.class) {
+            drop(state);
+            report(format_args!(
+                "lock order inversion: lock created at {} taken while holding the one created \
+                 at {}, which was taken after it before",
+                class, held.class
+            ));
+        }
+        if state.orders_len < MAX_ORDERS {
+            let len = state.orders_len;
+            state.orders[len] = Some((held.class, class));
+            state.orders_len += 1;
+        }
+    }
+    if let Err(message) = state.held[cpu].push(lock, class) {
+        drop(state);
+        report(format_args!("{}", message));
+    }
+}
+
+// Called when `try_lock` took the lock at `lock`. Trying can't deadlock, so there is no order
+// to check.
+pub fn acquired(lock: usize, class: Class) {
+    let cpu = percpu::index();
+    let mut state = STATE.lock();
+    if state.disabled {
+        return;
+    }
+    if let Err(message) = state.held[cpu].push(lock, class) {
+        drop(state);
+        report(format_args!("{}", message));
+    }
+}
+
+// Called when the lock at `lock` was released, which doesn't have to be the one taken last
+pub fn release(lock: usize) {
+    let cpu = percpu::index();
+    let mut state = STATE.lock();
+    let held = &mut state.held[cpu];
+    let len = held.len;
+    let position = held.iter().position(|held| held.lock == lock);
+    if let Some(i) = position {
+        held.locks.copy_within(i + 1..len, i);
+        held.locks[len - 1] = None;
+        held.len -= 1;
+    }
+}
+
+fn report(message: core::fmt::Arguments) -> ! {
+    // the locks held now are never released, the panic handler has to take them over
+    STATE.lock().disabled = true;
+    panic!("{}", message);
+}
+
+#[test_case]
+fn test_allowed_orders() {
+    use super::IrqSafeMutex;
+
+    let [first, second] = [const { IrqSafeMutex::new(()) }; 2];
+    let other = IrqSafeMutex::new(());
+    // the same order again is fine, and so are two locks of the same class in any order
+    for _ in 0..2 {
+        let _first = first.lock();
+        let _second = second.lock();
+        let _other = other.lock();
+    }
+    {
+        let _second = second.lock();
+        let _first = first.lock();
+    }
+    // trying the other way around can't deadlock
+    let _other = other.lock();
+    assert!(first.try_lock().is_some());
+    assert_eq!(STATE.lock().held[percpu::index()].iter().count(), 1);
+}
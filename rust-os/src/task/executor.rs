@@ -0,0 +1,304 @@
+/**
+ * The executor runs the tasks on every CPU.
+ *
+ * The tasks live in one table that all CPUs share, and every CPU has a queue of the tasks that
+ * are ready to be polled. A waker puts its task into the queue of the CPU that polled it last, a
+ * new task goes into the queue of the CPU that spawned it. A CPU whose queue is empty steals half
+ * of the queue of another CPU before it halts, so the tasks spread over the idle CPUs.
+ *
+ * A halted CPU only wakes up for an interrupt, so whoever queues a task wakes an idle CPU with an
+ * IPI (see `notify`). A task is taken out of the table while it is polled, so two CPUs never poll
+ * it at the same time: a CPU that finds the task missing remembers that it was woken, and the CPU
+ * polling it queues it again afterwards.
+ *
+ * A task is in at most one queue at a time, however often it is woken (see `TaskWaker::queued`).
+ * Every queue can hold all tasks, so queueing a task never fails.
+ */
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use core::{
+    mem,
+    sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::{Task, TaskId};
+use crate::{
+    interrupts::{apic, InterruptsIndex},
+    percpu::{self, MAX_CPUS},
+};
+
+// how many tasks can exist at the same time, the queue of every CPU has room for all of them
+const MAX_TASKS: usize = 100;
+
+struct Entry {
+    // `None` while a CPU polls the task
+    task: Option<Task>,
+    // we keep the waker, so that a task that is woken up multiple times does not allocate a new
+    // waker every time it is polled
+    waker: Arc<TaskWaker>,
+    // set when the task was woken while it was polled
+    woken: bool,
+    // set when the task finished while its id was still in a queue, the entry is removed when
+    // the id is taken out of the queue
+    finished: bool,
+}
+
+// every task that has not finished yet. Wakers don't use it, so interrupt handlers never take
+// the lock.
+static TASKS: Mutex<BTreeMap<TaskId, Entry>> = Mutex::new(BTreeMap::new());
+
+lazy_static! {
+    // the ids of the tasks that are ready to be polled, by CPU index. Wakers can be called from
+    // interrupt handlers, so these are lock-free queues that do not allocate on push
+    static ref QUEUES: [ArrayQueue<TaskId>; MAX_CPUS] =
+        core::array::from_fn(|_| ArrayQueue::new(MAX_TASKS));
+}
+
+// a bit for every CPU that is halted because it found no task to poll
+static IDLE: AtomicU64 = AtomicU64::new(0);
+
+// Spawns a task from within another task, which can't reach the executor itself. The task is
+// polled by the next CPU that runs its executor. Must not be called from interrupt handlers.
+pub fn spawn(task: Task) {
+    let cpu = percpu::index();
+    let task_id = task.id;
+    // every new task has to be polled at least once, so it starts out queued
+    let waker = Arc::new(TaskWaker {
+        task_id,
+        cpu: AtomicUsize::new(cpu),
+        queued: AtomicBool::new(true),
+    });
+    let entry = Entry {
+        task: Some(task),
+        waker,
+        woken: false,
+        finished: false,
+    };
+    {
+        let mut tasks = TASKS.lock();
+        if tasks.len() >= MAX_TASKS {
+            panic!("too many tasks");
+        }
+        if tasks.insert(task_id, entry).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+    }
+    push(cpu, task_id);
+}
+
+// Queues the task on `cpu`. The task must not be in any queue yet.
+fn push(cpu: usize, task_id: TaskId) {
+    // there are never more queued tasks than tasks, so the queue has room
+    QUEUES[cpu]
+        .push(task_id)
+        .unwrap_or_else(|_| unreachable!("task queue full"));
+    notify(cpu);
+}
+
+// Wakes up a CPU that is halted, since the queue of `cpu` has a task now: `cpu` itself if it is
+// idle, otherwise another one that can steal the task. The running CPU looks at its queue anyway.
+fn notify(cpu: usize) {
+    // pairs with the fence in `sleep_if_idle`: either the idle CPU sees the task in the queue,
+    // or we see its idle bit
+    atomic::fence(Ordering::SeqCst);
+    let current = percpu::index();
+    let idle = IDLE.load(Ordering::SeqCst);
+    // an interrupt that woke this CPU up from its `hlt`, it finds the task right after
+    if cpu == current && idle & (1 << current) != 0 {
+        return;
+    }
+    let idle = idle & !(1 << current);
+    let target = if idle & (1 << cpu) != 0 {
+        cpu
+    } else if idle != 0 {
+        idle.trailing_zeros() as usize
+    } else {
+        return;
+    };
+    // only the first one to clear the bit sends the IPI
+    if IDLE.fetch_and(!(1 << target), Ordering::SeqCst) & (1 << target) == 0 {
+        return;
+    }
+    if let (Some(local_apic), Some(target)) = (apic::local_apic(), percpu::get(target)) {
+        local_apic.send_ipi(target.apic_id(), InterruptsIndex::Wakeup as u8);
+    }
+}
+
+// The Executor only polls a task when it's waker was invoked, instead of polling every task in a
+// loop like `SimpleExecutor` does. This way, the CPU can sleep while there is no work to do.
+//
+// Every CPU runs one executor, they share the tasks.
+pub struct Executor {
+    // the index of the CPU the executor runs on
+    cpu: usize,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            cpu: percpu::index(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        spawn(task);
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&self) {
+        while let Some(task_id) = QUEUES[self.cpu].pop().ok().or_else(|| self.steal()) {
+            self.poll(task_id);
+        }
+    }
+
+    fn poll(&self, task_id: TaskId) {
+        let (mut task, task_waker) = {
+            let mut tasks = TASKS.lock();
+            let entry = match tasks.get_mut(&task_id) {
+                Some(entry) => entry,
+                // the task has already finished, but it was woken up again
+                None => return,
+            };
+            match entry.task.take() {
+                Some(task) => {
+                    // the task is woken on this CPU from now on, and a wakeup while we poll it
+                    // queues it again
+                    entry.waker.cpu.store(self.cpu, Ordering::Relaxed);
+                    entry.waker.queued.store(false, Ordering::SeqCst);
+                    (task, entry.waker.clone())
+                }
+                None if entry.finished => {
+                    tasks.remove(&task_id);
+                    return;
+                }
+                None => {
+                    // another CPU polls the task right now, it queues the task again afterwards
+                    entry.woken = true;
+                    return;
+                }
+            }
+        };
+        let waker = Waker::from(task_waker.clone());
+        let mut context = Context::from_waker(&waker);
+        let result = task.poll(&mut context);
+        let mut tasks = TASKS.lock();
+        let entry = tasks.get_mut(&task_id).expect("polled task disappeared");
+        match result {
+            // task done -> remove it and its waker, unless it was woken in the meantime and its
+            // id is still in a queue. Marking it queued keeps the waker from queueing it again.
+            Poll::Ready(()) => {
+                if task_waker.queued.swap(true, Ordering::SeqCst) && !entry.woken {
+                    entry.finished = true;
+                } else {
+                    tasks.remove(&task_id);
+                }
+            }
+            Poll::Pending => {
+                entry.task = Some(task);
+                // the CPU that found the task missing took its id out of the queue
+                if mem::take(&mut entry.woken) {
+                    drop(tasks);
+                    push(self.cpu, task_id);
+                }
+            }
+        }
+    }
+
+    // Moves half of the tasks (rounded up) of the first other CPU that has any into our queue
+    // and returns one of them
+    fn steal(&self) -> Option<TaskId> {
+        let local = &QUEUES[self.cpu];
+        for other in (1..MAX_CPUS).map(|i| (self.cpu + i) % MAX_CPUS) {
+            let queue = &QUEUES[other];
+            let count = queue.len().div_ceil(2);
+            let first = match queue.pop() {
+                Ok(task_id) => task_id,
+                Err(_) => continue,
+            };
+            let mut moved = false;
+            for _ in 1..count {
+                let task_id = match queue.pop() {
+                    Ok(task_id) => task_id,
+                    Err(_) => break,
+                };
+                // there are never more queued tasks than tasks, so our queue has room
+                local
+                    .push(task_id)
+                    .unwrap_or_else(|_| unreachable!("task queue full"));
+                moved = true;
+            }
+            // we poll `first` now, the tasks in our queue can be stolen by an idle CPU
+            if moved {
+                notify(self.cpu);
+            }
+            return Some(first);
+        }
+        None
+    }
+
+    // Halts the CPU until the next interrupt if there are no ready tasks, on any CPU.
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        // An interrupt could wake a task right after we checked the queue, and then we would
+        // sleep although there is work to do. To avoid this race, we disable interrupts before
+        // checking the queue and atomically re-enable them together with the `hlt` instruction.
+        // Another CPU that queues a task after our check sees the idle bit and sends us an IPI,
+        // which arrives right after the `hlt` as well.
+        interrupts::disable();
+        let bit = 1 << self.cpu;
+        IDLE.fetch_or(bit, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        if QUEUES.iter().all(|queue| queue.is_empty()) {
+            enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+        IDLE.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    // the CPU whose queue the task goes into
+    cpu: AtomicUsize,
+    // set while the task's id is in a queue, or will be put back into one by the CPU polling
+    // it, so that it is queued only once however often it is woken. The executor clears it
+    // right before polling the task.
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            push(self.cpu.load(Ordering::Relaxed), self.task_id);
+        }
+    }
+}
+
+// The `Wake` trait lets us create a `Waker` from an `Arc` without having to build the
+// `RawWakerVTable` by hand like we did for the `dummy_waker`.
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -1,28 +1,65 @@
 use core::{
     future::Future,
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use spin::Mutex;
 
-pub mod keyboard;
+pub mod executor;
 pub mod simple_executor;
 
+// Every task gets a unique id, so that a waker only has to remember which task it belongs to
+// instead of holding a reference to the task itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        // tasks can be created from anywhere, so the counter is atomic. `Relaxed` is enough since
+        // we only need each id to be unique.
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+// The names of all tasks that have not finished yet, so that they can be listed for debugging
+// (e.g. by the `tasks` shell command). The executor itself is not reachable from a task.
+static TASKS: Mutex<BTreeMap<TaskId, &'static str>> = Mutex::new(BTreeMap::new());
+
+// Returns the id and name of every task that has not finished yet
+pub fn tasks() -> Vec<(TaskId, &'static str)> {
+    TASKS.lock().iter().map(|(&id, &name)| (id, name)).collect()
+}
+
 pub struct Task {
+    id: TaskId,
     // dyn keyword allows us to set the type parameter of the Box as anything that implements the
     // Future trais (trait objects). Rust will use dynamic dispatch (where the methods to be called
     // are calculated at runtime) for calling the methods of the trait object
     //
     // we use Pin because the futures created by async/await might be self-referential
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
     // by using 'static here, I am affirming that the `future` will be valid for the whole lifetime
     // of the program
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+    pub fn new<F: Future<Output = ()> + Send + 'static>(future: F) -> Self {
+        // the future of an `async fn` is named after the function, e.g.
+        // `rust_os::shell::run::{{closure}}`
+        let name = core::any::type_name::<F>();
+        let name = name.strip_suffix("::{{closure}}").unwrap_or(name);
+        let id = TaskId::new();
+        TASKS.lock().insert(id, name);
         Task {
+            id,
             future: Box::pin(future),
         }
     }
@@ -32,3 +69,32 @@ impl Task {
         self.future.as_mut().poll(context)
     }
 }
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        TASKS.lock().remove(&self.id);
+    }
+}
+
+// Returns a future that lets the other ready tasks run before it completes
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        // the waker puts the task at the end of the queue of ready tasks
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -1,4 +1,6 @@
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, sync::Arc};
+use core::future::Future;
+use spin::Mutex;
 
 use super::Task;
 
@@ -27,6 +29,21 @@ impl SimpleExecutor {
     }
 }
 
+// Runs the future to the end and returns its output. The simple executor polls it over and over
+// instead of waiting for a wakeup, so the tests that use it also check that the interrupt handlers
+// mark the requests as done, not only that they wake the tasks.
+pub fn block_on<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) -> T {
+    let result = Arc::new(Mutex::new(None));
+    let task_result = result.clone();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        *task_result.lock() = Some(future.await);
+    }));
+    executor.run();
+    let value = result.lock().take();
+    value.expect("task did not finish")
+}
+
 use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
 fn dummy_raw_waker() -> RawWaker {
@@ -0,0 +1,375 @@
+/**
+ * Preemptive kernel threads.
+ *
+ * Every thread has its own stack with an unmapped guard page below it, so that an overflow
+ * faults instead of silently overwriting the neighbouring stack. The scheduler runs the ready
+ * threads round-robin: a thread runs until it blocks (`yield_now`, `sleep`, `JoinHandle::join`)
+ * or until its time slice is used up, at which point the timer interrupt switches to the next
+ * thread. The code that called `init` (the boot code, which goes on to run the async executor)
+ * becomes the thread `main` and is scheduled like every other thread.
+ *
+ * A thread switch saves the callee-saved registers on the stack of the old thread and restores
+ * them from the stack of the new one (see `thread_switch`), all other registers are saved by the
+ * caller of the switch or by the interrupt handler that preempted the thread.
+ *
+ * The timer interrupt only preempts kernel code. A user process runs until its next trap, which
+ * returns into the kernel thread that entered it (see `process`).
+ *
+ * The threads only run on the boot CPU, which is the one the timer interrupt goes to. The
+ * application processors only run the async executor (see `smp`).
+ */
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::instructions::interrupts;
+
+use crate::{percpu, sync::IrqSafeMutex, timer};
+
+pub(crate) mod stack;
+
+use stack::Stack;
+
+// the number of timer ticks a thread runs before it is preempted
+const TIME_SLICE: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    // waiting in the ready queue
+    Ready,
+    // until the given tick
+    Sleeping(u64),
+    // until the given thread has finished
+    Joining(ThreadId),
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    // the stack pointer while the thread is not running, `thread_switch` saved the registers there
+    rsp: u64,
+    // The boot thread keeps running on the stack of the bootloader. Only kept to be freed together
+    // with the thread.
+    #[allow(dead_code)]
+    stack: Option<Stack>,
+    // the function that a new thread runs, taken by `thread_start`
+    entry: Option<Box<dyn FnOnce() + Send>>,
+}
+
+struct Scheduler {
+    // the threads are boxed, so that the saved stack pointer doesn't move while switching
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    // Every thread is in here at most once. The timer interrupt must not allocate, so the queue
+    // always has room for every thread.
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    // runs when no other thread is ready, it is never in the ready queue
+    idle: ThreadId,
+    // the tick at which the time slice of the current thread ends
+    slice_end: u64,
+    // Threads that have finished. Their stacks are freed by `reap`, once no longer in use. Like
+    // the ready queue, it always has room for every thread.
+    finished: Vec<Thread>,
+}
+
+impl Scheduler {
+    // Makes the thread ready to run. Only this allocates, `switch` must not, since it runs in the
+    // timer interrupt.
+    fn add(&mut self, thread: Box<Thread>) {
+        let id = thread.id;
+        self.threads.insert(id, thread);
+        self.ready.reserve(self.threads.len());
+        self.finished.reserve(self.threads.len());
+        self.ready.push_back(id);
+    }
+
+    // Puts the current thread into `state` and picks the thread to run next. Returns where to
+    // save the stack pointer of the current thread and the stack pointer of the next thread, or
+    // `None` if the current thread keeps running.
+    fn switch(&mut self, state: State) -> Option<(*mut u64, u64)> {
+        let now = timer::ticks();
+        for thread in self.threads.values_mut() {
+            if let State::Sleeping(deadline) = thread.state {
+                if deadline <= now {
+                    thread.state = State::Ready;
+                    self.ready.push_back(thread.id);
+                }
+            }
+        }
+
+        let current_id = self.current;
+        let state = match state {
+            // the thread has finished before we could wait for it
+            State::Joining(id) if !self.threads.contains_key(&id) => State::Ready,
+            state => state,
+        };
+        if current_id != self.idle {
+            self.threads.get_mut(&current_id).unwrap().state = state;
+            if state == State::Ready {
+                self.ready.push_back(current_id);
+            }
+        }
+        if state == State::Finished {
+            for thread in self.threads.values_mut() {
+                if thread.state == State::Joining(current_id) {
+                    thread.state = State::Ready;
+                    self.ready.push_back(thread.id);
+                }
+            }
+        }
+
+        let next_id = self.ready.pop_front().unwrap_or(self.idle);
+        self.slice_end = now + TIME_SLICE;
+        self.threads.get_mut(&next_id).unwrap().state = State::Running;
+        if next_id == current_id {
+            return None;
+        }
+        self.current = next_id;
+        let current = if state == State::Finished {
+            // the stack is still in use until the switch, so it is freed later
+            let thread = self.threads.remove(&current_id).unwrap();
+            self.finished.push(*thread);
+            self.finished.last_mut().unwrap()
+        } else {
+            self.threads.get_mut(&current_id).unwrap()
+        };
+        let old_rsp = &mut current.rsp as *mut u64;
+        Some((old_rsp, self.threads[&next_id].rsp))
+    }
+}
+
+static SCHEDULER: IrqSafeMutex<Option<Scheduler>> = IrqSafeMutex::new(None);
+
+global_asm!(
+    // thread_switch(old_rsp: *mut u64, new_rsp: u64)
+    ".global thread_switch",
+    "thread_switch:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+extern "C" {
+    fn thread_switch(old_rsp: *mut u64, new_rsp: u64);
+}
+
+// Turns the running code into the thread `main` and starts the scheduler. Needs the heap.
+pub fn init() {
+    let main = Box::new(Thread {
+        id: ThreadId::new(),
+        name: "main",
+        state: State::Running,
+        rsp: 0,
+        stack: None,
+        entry: None,
+    });
+    let idle = Thread::new("idle", Box::new(idle));
+    let mut scheduler = Scheduler {
+        threads: BTreeMap::new(),
+        ready: VecDeque::with_capacity(2),
+        current: main.id,
+        idle: idle.id,
+        slice_end: timer::ticks() + TIME_SLICE,
+        finished: Vec::new(),
+    };
+    scheduler.threads.insert(idle.id, idle);
+    scheduler.threads.insert(main.id, main);
+    *SCHEDULER.lock() = Some(scheduler);
+}
+
+fn idle() {
+    loop {
+        // the timer interrupt switches to the next ready thread
+        x86_64::instructions::hlt();
+    }
+}
+
+impl Thread {
+    fn new(name: &'static str, entry: Box<dyn FnOnce() + Send>) -> Box<Thread> {
+        let stack = Stack::allocate().expect("failed to map a thread stack");
+        // The first switch to the thread "returns" into `thread_start`, after popping the six
+        // callee-saved registers. The stack is 16 byte aligned again at the `call` in there.
+        let top = stack.top().as_mut_ptr::<u64>();
+        let rsp = unsafe {
+            top.sub(1).write(0);
+            top.sub(2).write(thread_start as *const () as u64);
+            for i in 3..=8 {
+                top.sub(i).write(0);
+            }
+            top.sub(8) as u64
+        };
+        Box::new(Thread {
+            id: ThreadId::new(),
+            name,
+            state: State::Ready,
+            rsp,
+            stack: Some(stack),
+            entry: Some(entry),
+        })
+    }
+}
+
+extern "C" fn thread_start() -> ! {
+    let entry = SCHEDULER.lock().as_mut().and_then(|scheduler| {
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).unwrap().entry.take()
+    });
+    // the thread was switched to with the interrupts disabled
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    interrupts::disable();
+    schedule(State::Finished);
+    unreachable!("a finished thread was scheduled again");
+}
+
+// Switches to the next thread, with the current thread put into `state`. Must be called with the
+// interrupts disabled, they stay disabled until the thread runs again.
+fn schedule(state: State) {
+    assert!(
+        percpu::current().is_boot_cpu(),
+        "threads can only block on the boot CPU"
+    );
+    let switch = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => scheduler.switch(state),
+        None => return,
+    };
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { thread_switch(old_rsp, new_rsp) };
+    }
+}
+
+// Called by the timer interrupt handler when it interrupted the kernel. Switches to the next
+// thread once the time slice of the current thread is used up.
+pub(crate) fn preempt() {
+    let expired = SCHEDULER.lock().as_ref().is_some_and(|scheduler| {
+        scheduler.current == scheduler.idle || timer::ticks() >= scheduler.slice_end
+    });
+    if expired {
+        schedule(State::Ready);
+    }
+}
+
+// Frees the stacks of the finished threads. Not done by the scheduler itself, since it runs in the
+// timer interrupt, which must not take the kernel memory lock.
+fn reap() {
+    let finished = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => core::mem::take(&mut scheduler.finished),
+        None => return,
+    };
+    drop(finished);
+}
+
+// Starts a new thread that runs `f`. Panics if the stack can't be mapped, like `std` does.
+pub fn spawn_thread<F, T>(name: &'static str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    reap();
+    let result = Arc::new(IrqSafeMutex::new(None));
+    let thread_result = result.clone();
+    let thread = Thread::new(
+        name,
+        Box::new(move || {
+            let value = f();
+            *thread_result.lock() = Some(value);
+        }),
+    );
+    let id = thread.id;
+    SCHEDULER
+        .lock()
+        .as_mut()
+        .expect("threads are not initialized")
+        .add(thread);
+    JoinHandle { id, result }
+}
+
+// Lets the other ready threads run first
+pub fn yield_now() {
+    interrupts::without_interrupts(|| schedule(State::Ready));
+}
+
+// Blocks the current thread for at least `duration`
+pub fn sleep(duration: Duration) {
+    let deadline = timer::ticks().saturating_add(timer::duration_to_ticks(duration));
+    while timer::ticks() < deadline {
+        interrupts::without_interrupts(|| schedule(State::Sleeping(deadline)));
+    }
+}
+
+// Returns the id of the running thread, `None` before `init`
+pub fn current() -> Option<ThreadId> {
+    SCHEDULER.lock().as_ref().map(|scheduler| scheduler.current)
+}
+
+// Returns the id, name and state of every thread, for debugging
+pub fn threads() -> Vec<(ThreadId, &'static str, State)> {
+    match SCHEDULER.lock().as_ref() {
+        Some(scheduler) => scheduler
+            .threads
+            .values()
+            .map(|thread| (thread.id, thread.name, thread.state))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<IrqSafeMutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    // Blocks until the thread has finished and returns what it returned
+    pub fn join(self) -> T {
+        loop {
+            if let Some(value) = self.result.lock().take() {
+                reap();
+                return value;
+            }
+            interrupts::without_interrupts(|| schedule(State::Joining(self.id)));
+        }
+    }
+}
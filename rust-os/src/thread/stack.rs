@@ -0,0 +1,77 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::memory;
+
+// The thread stacks are mapped into this region. Every stack has a slot of its own, which starts
+// with the unmapped guard page.
+const STACKS_START: u64 = 0x_3333_0000_0000;
+const STACK_SIZE: u64 = 64 * 1024;
+const SLOT_SIZE: u64 = STACK_SIZE + 4096;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+// the slots of the freed stacks, which are used again before new ones
+static FREE_SLOTS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+pub struct Stack {
+    slot: u64,
+}
+
+impl Stack {
+    pub fn allocate() -> Result<Stack, MapToError<Size4KiB>> {
+        let slot = FREE_SLOTS
+            .lock()
+            .pop()
+            .unwrap_or_else(|| NEXT_SLOT.fetch_add(1, Ordering::Relaxed));
+        let stack = Stack { slot };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let mapped = memory::with_kernel_memory(|memory| unsafe {
+            memory::map_range(
+                stack.bottom(),
+                STACK_SIZE,
+                flags,
+                &mut memory.mapper,
+                &mut memory.frame_allocator,
+            )
+        });
+        if let Err(error) = mapped {
+            // `map_range` has unmapped the slot again, so it can be used by the next stack
+            FREE_SLOTS.lock().push(stack.slot);
+            core::mem::forget(stack);
+            return Err(error);
+        }
+        Ok(stack)
+    }
+
+    // the lowest address of the stack, right above the guard page
+    fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(STACKS_START + self.slot * SLOT_SIZE + 4096)
+    }
+
+    // the address right above the stack, where the stack pointer starts
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        let first = Page::<Size4KiB>::containing_address(self.bottom());
+        let last = Page::containing_address(self.top() - 1u64);
+        memory::with_kernel_memory(|memory| {
+            for page in Page::range_inclusive(first, last) {
+                let (frame, flush) = memory.mapper.unmap(page).expect("stack page not mapped");
+                flush.flush();
+                unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            }
+        });
+        FREE_SLOTS.lock().push(self.slot);
+    }
+}
@@ -0,0 +1,230 @@
+/**
+ * This module programs the PIT (Programmable Interval Timer) to fire the timer interrupt at a
+ * known frequency and builds the kernel's notion of time on top of it: a monotonic tick counter,
+ * the uptime, and an async `sleep` whose wakers are kept in a timer wheel.
+ */
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use x86_64::instructions::port::Port;
+
+use crate::sync::IrqSafeMutex;
+
+// the frequency of the oscillator driving the PIT, in Hz
+const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+// how often the timer interrupt fires, in Hz. A tick is 10 milliseconds long.
+pub const TIMER_FREQUENCY: u32 = 100;
+const NANOS_PER_TICK: u64 = 1_000_000_000 / TIMER_FREQUENCY as u64;
+// how many expired timers the timer interrupt takes out of the wheel at once. Their wakers are
+// woken after the lock is released, and the batch lives on the stack, so nothing is allocated.
+const WAKE_BATCH: usize = 16;
+
+// number of timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static WHEEL: IrqSafeMutex<TimerWheel> = IrqSafeMutex::new(TimerWheel::new());
+
+// Programs channel 0 of the PIT, which is connected to IRQ 0, to fire at `TIMER_FREQUENCY`
+pub fn init() {
+    let divisor = PIT_BASE_FREQUENCY / TIMER_FREQUENCY;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+    unsafe {
+        // channel 0, access mode lobyte/hibyte, mode 3 (square wave generator), binary mode
+        command.write(0b0011_0110);
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+// Called by the timer interrupt handler on every tick.
+//
+// Must not block or allocate
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    loop {
+        // the lock can't be held by the code we interrupted, since the interrupts are disabled
+        // while it's held. Other CPUs only hold it briefly.
+        let mut expired: [Option<Waker>; WAKE_BATCH] = Default::default();
+        let count = WHEEL.lock().expire(now, &mut expired);
+        // waking a task can drop the last reference to its waker, so this happens outside of the
+        // lock
+        for waker in expired.into_iter().flatten() {
+            waker.wake();
+        }
+        if count < WAKE_BATCH {
+            break;
+        }
+    }
+}
+
+// Returns the number of timer ticks since the interrupts were enabled
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Returns the time since the timer interrupts were enabled, with a resolution of one tick
+pub fn uptime() -> Duration {
+    Duration::from_nanos(ticks() * NANOS_PER_TICK)
+}
+
+// Converts the duration to ticks, rounding up so that we never sleep shorter than asked for
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos();
+    let ticks = nanos.div_ceil(u128::from(NANOS_PER_TICK));
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+// Returns a future that completes once `duration` has passed
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: ticks().saturating_add(duration_to_ticks(duration)),
+        timer: None,
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    // the id of our timer in the wheel, after the first poll. Later polls only replace its waker.
+    timer: Option<u64>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // the timer interrupt must not fire between the check and the registration, otherwise we
+        // could miss the tick that would have woken us up. It can't while we hold the lock.
+        let mut wheel = WHEEL.lock();
+        let deadline = self.deadline;
+        if ticks() >= deadline {
+            // the timer has usually fired and is gone, unless we were polled for another reason
+            let timer = self.timer.take().and_then(|id| wheel.remove(deadline, id));
+            drop(wheel);
+            drop(timer);
+            return Poll::Ready(());
+        }
+        let replaced = match self.timer.and_then(|id| wheel.timer(deadline, id)) {
+            Some(timer) if timer.waker.will_wake(cx.waker()) => None,
+            Some(timer) => Some(mem::replace(&mut timer.waker, cx.waker().clone())),
+            None => {
+                self.timer = Some(wheel.insert(deadline, cx.waker().clone()));
+                None
+            }
+        };
+        drop(wheel);
+        // the old waker is dropped outside of the lock, like the ones the interrupt wakes
+        drop(replaced);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer {
+            let waker = WHEEL.lock().remove(self.deadline, id);
+            drop(waker);
+        }
+    }
+}
+
+const WHEEL_SLOTS: usize = 64;
+
+// A timer wheel is a ring of slots where each slot holds the timers that expire on the ticks
+// that map onto it (`deadline % WHEEL_SLOTS`). On every tick only the current slot has to be
+// looked at instead of every sleeping task. Timers that are further away than one rotation stay
+// in their slot until the wheel comes around with their deadline.
+struct TimerWheel {
+    slots: [Vec<Timer>; WHEEL_SLOTS],
+    next_id: u64,
+}
+
+struct Timer {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        const EMPTY: Vec<Timer> = Vec::new();
+        TimerWheel {
+            slots: [EMPTY; WHEEL_SLOTS],
+            next_id: 0,
+        }
+    }
+
+    fn slot(deadline: u64) -> usize {
+        (deadline % WHEEL_SLOTS as u64) as usize
+    }
+
+    // Adds a timer and returns its id
+    fn insert(&mut self, deadline: u64, waker: Waker) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.slots[Self::slot(deadline)].push(Timer {
+            id,
+            deadline,
+            waker,
+        });
+        id
+    }
+
+    // Returns the timer, if it hasn't expired yet
+    fn timer(&mut self, deadline: u64, id: u64) -> Option<&mut Timer> {
+        self.slots[Self::slot(deadline)]
+            .iter_mut()
+            .find(|timer| timer.id == id)
+    }
+
+    // Removes the timer and returns its waker, if it hasn't expired yet
+    fn remove(&mut self, deadline: u64, id: u64) -> Option<Waker> {
+        let slot = &mut self.slots[Self::slot(deadline)];
+        let index = slot.iter().position(|timer| timer.id == id)?;
+        Some(slot.swap_remove(index).waker)
+    }
+
+    // Moves the wakers of the timers in the current slot whose deadline has been reached into
+    // `expired`, until it is full. Returns how many there were.
+    fn expire(&mut self, now: u64, expired: &mut [Option<Waker>]) -> usize {
+        let slot = &mut self.slots[Self::slot(now)];
+        let mut count = 0;
+        let mut i = 0;
+        while i < slot.len() && count < expired.len() {
+            if slot[i].deadline <= now {
+                expired[count] = Some(slot.swap_remove(i).waker);
+                count += 1;
+            } else {
+                i += 1;
+            }
+        }
+        count
+    }
+}
+
+#[test_case]
+fn test_duration_to_ticks() {
+    assert_eq!(duration_to_ticks(Duration::from_millis(0)), 0);
+    assert_eq!(duration_to_ticks(Duration::from_millis(1)), 1);
+    assert_eq!(duration_to_ticks(Duration::from_millis(10)), 1);
+    assert_eq!(duration_to_ticks(Duration::from_millis(11)), 2);
+    assert_eq!(
+        duration_to_ticks(Duration::from_secs(1)),
+        u64::from(TIMER_FREQUENCY)
+    );
+}
+
+#[test_case]
+fn test_uptime_advances() {
+    let start = ticks();
+    while ticks() < start + 5 {
+        x86_64::instructions::hlt();
+    }
+    assert!(uptime() >= Duration::from_millis(50));
+}
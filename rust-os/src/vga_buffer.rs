@@ -1,8 +1,12 @@
 use core::fmt::Write;
-use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
+use crate::{framebuffer::FrameBuffer, sync::IrqSafeMutex};
+
+mod ansi;
+pub mod cp437;
+mod graphics;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
 #[repr(u8)]
@@ -25,12 +29,45 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    // Returns the color with the given ANSI color number (0 to 7)
+    fn from_ansi(number: u16) -> Color {
+        // ANSI orders the colors by their RGB bits, VGA by their BGR bits
+        const COLORS: [Color; 8] = [
+            Color::Black,
+            Color::Red,
+            Color::Green,
+            Color::Brown,
+            Color::Blue,
+            Color::Magenta,
+            Color::Cyan,
+            Color::LightGray,
+        ];
+        COLORS[usize::from(number) % COLORS.len()]
+    }
+
+    // the colors 8 to 15 are the bright versions of the colors 0 to 7
+    fn bright(self) -> Color {
+        const BRIGHT: [Color; 8] = [
+            Color::DarkGray,
+            Color::LightBlue,
+            Color::LightGreen,
+            Color::LightCyan,
+            Color::LightRed,
+            Color::Pink,
+            Color::Yellow,
+            Color::White,
+        ];
+        BRIGHT[self as usize % BRIGHT.len()]
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct ColorCode(u8);
 
 impl ColorCode {
-    pub fn new(background_color: Color, foreground_color: Color) -> ColorCode {
+    pub const fn new(background_color: Color, foreground_color: Color) -> ColorCode {
         ColorCode((background_color as u8) << 4 | (foreground_color as u8))
     }
 }
@@ -41,8 +78,20 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
+// the size of the VGA text buffer
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
+// the first row is reserved for the status line and does not scroll with the rest of the text
+const STATUS_ROW: usize = 0;
+const FIRST_ROW: usize = STATUS_ROW + 1;
+// the widest lines that are kept, a wider framebuffer shows a blank stripe on the right
+const MAX_COLUMNS: usize = 128;
+// the number of lines that are kept, including the ones on the screen
+const HISTORY_LINES: usize = 200;
+
+// the colors after `ESC [ 0 m`
+const DEFAULT_FOREGROUND: Color = Color::Black;
+const DEFAULT_BACKGROUND: Color = Color::Yellow;
 
 #[repr(transparent)]
 struct Buffer {
@@ -50,17 +99,100 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
-// 'lazy_static' macro, which comes from the lazy_static library, helps us write
-// static declarations lazily. This means that the initialization will be done during
-// the runtime and not compile time.
-lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+// Where the console shows its text
+enum Display {
+    // the VGA text buffer at 0xb8000, where the BIOS leaves us
+    Text,
+    // a framebuffer, see `use_framebuffer`
+    Graphics(graphics::Screen),
+}
+
+impl Display {
+    // the number of rows, including the status line, and of columns
+    fn size(&self) -> (usize, usize) {
+        match self {
+            Display::Text => (BUFFER_HEIGHT, BUFFER_WIDTH),
+            Display::Graphics(screen) => screen.size(),
+        }
+    }
+
+    fn text_buffer() -> &'static mut Buffer {
+        // only called while the writer is locked, so there is only one reference at a time
+        unsafe { &mut *(0xb8000 as *mut Buffer) }
+    }
+
+    fn write(&mut self, row: usize, column: usize, character: ScreenChar) {
+        match self {
+            Display::Text => Self::text_buffer().chars[row][column].write(character),
+            Display::Graphics(screen) => screen.write(row, column, character),
+        }
+    }
+
+    // Moves the rows below `first` up by one, the last row keeps its characters
+    fn scroll(&mut self, first: usize) {
+        match self {
+            Display::Text => {
+                let buffer = Self::text_buffer();
+                for row in first..BUFFER_HEIGHT - 1 {
+                    for column in 0..BUFFER_WIDTH {
+                        let character = buffer.chars[row + 1][column].read();
+                        buffer.chars[row][column].write(character);
+                    }
+                }
+            }
+            Display::Graphics(screen) => screen.scroll(first),
+        }
+    }
+
+    // Moves the cursor to the given row and column, or hides it
+    fn move_cursor(&mut self, position: Option<(usize, usize)>) {
+        use x86_64::instructions::port::Port;
+
+        if let Display::Graphics(screen) = self {
+            return screen.move_cursor(position);
+        }
+        // the hardware cursor (the blinking underscore) is moved off the screen to hide it
+        let position = match position {
+            Some((row, column)) => row * BUFFER_WIDTH + column,
+            None => BUFFER_HEIGHT * BUFFER_WIDTH,
+        };
+        // the CRT controller registers are selected through the index port 0x3D4 and then
+        // accessed through the data port 0x3D5, the position is split into two registers
+        let mut index: Port<u8> = Port::new(0x3d4);
+        let mut data: Port<u8> = Port::new(0x3d5);
+        unsafe {
+            index.write(0x0f);
+            data.write(position as u8);
+            index.write(0x0e);
+            data.write((position >> 8) as u8);
+        }
+    }
 }
 
+const BLANK: ScreenChar = ScreenChar {
+    ascii_code: b' ',
+    color_code: ColorCode::new(DEFAULT_BACKGROUND, DEFAULT_FOREGROUND),
+};
+
+// The timer and keyboard interrupt handlers print too, so the lock keeps the interrupts disabled
+// while it is held.
+pub static WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer {
+    rows: BUFFER_HEIGHT - FIRST_ROW,
+    columns: BUFFER_WIDTH,
+    // the output starts at the bottom of the screen and scrolls up
+    row: BUFFER_HEIGHT - FIRST_ROW - 1,
+    column: 0,
+    foreground: DEFAULT_FOREGROUND,
+    background: DEFAULT_BACKGROUND,
+    color_code: ColorCode::new(DEFAULT_BACKGROUND, DEFAULT_FOREGROUND),
+    parser: ansi::Parser::new(),
+    lines: [[BLANK; MAX_COLUMNS]; HISTORY_LINES],
+    top: 0,
+    history_len: 0,
+    scrollback: 0,
+    display: Display::Text,
+});
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
@@ -72,20 +204,65 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// Overwrites the status line at the top of the screen with the given text
+#[macro_export]
+macro_rules! print_status {
+    ($($arg:tt)*) => ($crate::vga_buffer::_print_status(format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print_status(args: core::fmt::Arguments) {
+    WRITER.lock().write_status_line(args);
+}
+
+// Scrolls the view one page back into the history (Shift-PageUp)
+pub fn scroll_page_up() {
+    let mut writer = WRITER.lock();
+    let rows = writer.rows as isize;
+    writer.scroll(rows);
+}
+
+// Scrolls the view one page towards the newest output again (Shift-PageDown)
+pub fn scroll_page_down() {
+    let mut writer = WRITER.lock();
+    let rows = writer.rows as isize;
+    writer.scroll(-rows);
+}
+
+// Moves the console onto the framebuffer, from then on `println!` draws its text there. The
+// lines on the screen and in the history are kept.
+pub fn use_framebuffer(framebuffer: FrameBuffer) {
+    let screen = graphics::Screen::new(framebuffer);
+    WRITER.lock().set_display(Display::Graphics(screen));
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    use x86_64::instructions::interrupts;
-    // we run this in an interrupt-free environment to prevent deadlocks.
-    // deadlocks happen when a thread tries to acquire a lock that can never become free.
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    // the lock disables the interrupts to prevent deadlocks, which happen when an interrupt
+    // handler tries to acquire the lock that the interrupted code holds
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 pub struct Writer {
-    column_position: usize,
+    // the size of the screen, without the status line
+    rows: usize,
+    columns: usize,
+    // the cursor position, the row is relative to the first row below the status line
+    row: usize,
+    column: usize,
+    foreground: Color,
+    background: Color,
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    parser: ansi::Parser,
+    // The scrollback history and the visible screen are kept in a ring of lines, the screen
+    // being the last `rows` lines starting at `top`. The display only shows a copy.
+    lines: [[ScreenChar; MAX_COLUMNS]; HISTORY_LINES],
+    top: usize,
+    // the number of lines above the screen that can be scrolled back to
+    history_len: usize,
+    // how many lines the view is currently scrolled back, 0 shows the screen
+    scrollback: usize,
+    display: Display,
 }
 
 impl Write for Writer {
@@ -96,53 +273,331 @@ impl Write for Writer {
 }
 
 impl Writer {
+    // Writes a single byte of code page 437 at the cursor, without interpreting it
     pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
+        if self.column >= self.columns {
+            self.new_line();
+        }
+        let character = ScreenChar {
+            ascii_code: byte,
+            color_code: self.color_code,
+        };
+        self.write_char_at(self.row, self.column, character);
+        self.column += 1;
+    }
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
+    pub fn write_string(&mut self, s: &str) {
+        // new output always scrolls the view back down to the screen
+        if self.scrollback != 0 {
+            self.scrollback = 0;
+            self.redraw();
+        }
+        for c in s.chars() {
+            match self.parser.advance(c) {
+                Some(ansi::Action::Print(c)) => self.write_byte(cp437::encode(c)),
+                Some(ansi::Action::Control(c)) => self.control(c),
+                Some(ansi::Action::Csi(csi)) => self.control_sequence(&csi),
+                None => {}
+            }
+        }
+        self.update_cursor();
+    }
 
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_code: byte,
-                    color_code,
-                });
-                self.column_position += 1;
+    fn control(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.column = 0,
+            // backspace only moves the cursor, the next character overwrites the old one
+            '\x08' => self.column = self.column.min(self.columns - 1).saturating_sub(1),
+            '\t' => self.column = ((self.column / 8 + 1) * 8).min(self.columns - 1),
+            _ => self.write_byte(cp437::UNKNOWN),
+        }
+    }
+
+    fn control_sequence(&mut self, csi: &ansi::Csi) {
+        // the cursor movements treat a missing or zero count as 1
+        let count = usize::from(csi.param(0, 1).max(1));
+        match csi.final_char {
+            'A' => self.row = self.row.saturating_sub(count),
+            'B' => self.row = (self.row + count).min(self.rows - 1),
+            'C' => self.column = (self.column + count).min(self.columns - 1),
+            'D' => self.column = self.column.min(self.columns - 1).saturating_sub(count),
+            // the positions are 1-based
+            'H' | 'f' => {
+                self.row = usize::from(csi.param(0, 1).max(1) - 1).min(self.rows - 1);
+                self.column = usize::from(csi.param(1, 1).max(1) - 1).min(self.columns - 1);
+            }
+            'J' => {
+                let (first, last) = match csi.param(0, 0) {
+                    0 => ((self.row, self.column), (self.rows - 1, self.columns)),
+                    1 => ((0, 0), (self.row, self.column + 1)),
+                    _ => ((0, 0), (self.rows - 1, self.columns)),
+                };
+                self.clear(first, last);
             }
+            'K' => {
+                let row = self.row;
+                let (first, last) = match csi.param(0, 0) {
+                    0 => (self.column, self.columns),
+                    1 => (0, self.column + 1),
+                    _ => (0, self.columns),
+                };
+                self.clear((row, first), (row, last));
+            }
+            'm' => self.select_graphic_rendition(csi.params()),
+            // everything else is not supported and ignored
+            _ => {}
         }
     }
-    fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        // `ESC [ m` is the same as `ESC [ 0 m`
+        let params = if params.is_empty() { &[0][..] } else { params };
+        for &param in params {
+            match param {
+                0 => {
+                    self.foreground = DEFAULT_FOREGROUND;
+                    self.background = DEFAULT_BACKGROUND;
+                }
+                // bold is shown as the bright version of the color
+                1 => self.foreground = self.foreground.bright(),
+                30..=37 => self.foreground = Color::from_ansi(param - 30),
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.background = Color::from_ansi(param - 40),
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = Color::from_ansi(param - 90).bright(),
+                100..=107 => self.background = Color::from_ansi(param - 100).bright(),
+                _ => {}
             }
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
+        self.color_code = ColorCode::new(self.background, self.foreground);
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row < self.rows - 1 {
+            self.row += 1;
+            return;
+        }
+        // the top line of the screen becomes part of the history
+        self.top = (self.top + 1) % HISTORY_LINES;
+        self.history_len = (self.history_len + 1).min(HISTORY_LINES - self.rows);
+        if self.scrollback == 0 {
+            self.display.scroll(FIRST_ROW);
+        } else {
+            self.redraw();
+        }
+        self.clear((self.rows - 1, 0), (self.rows - 1, self.columns));
     }
-    fn clear_row(&mut self, row: usize) {
+
+    // Clears the characters from `first` up to (excluding) `last`, given as (row, column)
+    fn clear(&mut self, first: (usize, usize), last: (usize, usize)) {
         let blank = ScreenChar {
             ascii_code: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        for row in first.0..=last.0 {
+            let start = if row == first.0 { first.1 } else { 0 };
+            let end = if row == last.0 { last.1 } else { self.columns };
+            for col in start..end.min(self.columns) {
+                self.write_char_at(row, col, blank);
+            }
         }
     }
-    pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+
+    fn line_index(&self, row: usize) -> usize {
+        (self.top + row) % HISTORY_LINES
+    }
+
+    fn write_char_at(&mut self, row: usize, col: usize, character: ScreenChar) {
+        let line = self.line_index(row);
+        self.lines[line][col] = character;
+        if self.scrollback == 0 {
+            self.display.write(FIRST_ROW + row, col, character);
+        }
+    }
+
+    // Copies the lines of the current view onto the display
+    fn redraw(&mut self) {
+        for row in 0..self.rows {
+            let line = (self.top + HISTORY_LINES - self.scrollback + row) % HISTORY_LINES;
+            for col in 0..self.columns {
+                self.display
+                    .write(FIRST_ROW + row, col, self.lines[line][col]);
+            }
+        }
+    }
+
+    // Scrolls the view back into the history (positive `lines`) or towards the screen again
+    pub fn scroll(&mut self, lines: isize) {
+        let scrollback = (self.scrollback as isize + lines).clamp(0, self.history_len as isize);
+        if scrollback as usize != self.scrollback {
+            self.scrollback = scrollback as usize;
+            self.redraw();
+            self.update_cursor();
+        }
+    }
+
+    // Moves the cursor of the display to the cursor position
+    fn update_cursor(&mut self) {
+        // the cursor is hidden while the history is shown
+        let position = if self.scrollback == 0 {
+            Some((FIRST_ROW + self.row, self.column.min(self.columns - 1)))
+        } else {
+            None
+        };
+        self.display.move_cursor(position);
+    }
+
+    // Shows the console on another display, keeping the lines at the bottom of the screen
+    fn set_display(&mut self, display: Display) {
+        self.display = display;
+        let (rows, columns) = self.display.size();
+        let rows = (rows - FIRST_ROW).min(HISTORY_LINES);
+        // a taller screen shows more of the history above the current screen, a lower one less
+        let added = rows as isize - self.rows as isize;
+        self.top = (self.top as isize - added).rem_euclid(HISTORY_LINES as isize) as usize;
+        self.history_len =
+            (self.history_len as isize - added).clamp(0, (HISTORY_LINES - rows) as isize) as usize;
+        self.row = (self.row as isize + added).clamp(0, rows as isize - 1) as usize;
+        self.rows = rows;
+        self.columns = columns.min(MAX_COLUMNS);
+        self.column = self.column.min(self.columns);
+        self.scrollback = 0;
+        self.redraw();
+        self.update_cursor();
+    }
+
+    fn write_status_line(&mut self, args: core::fmt::Arguments) {
+        // writes into the status row instead of the bottom row, truncating at the end of the row
+        struct StatusLine<'a> {
+            writer: &'a mut Writer,
+            column: usize,
+        }
+        impl Write for StatusLine<'_> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                for c in s.chars() {
+                    if self.column >= self.writer.columns {
+                        break;
+                    }
+                    let character = ScreenChar {
+                        ascii_code: cp437::encode(c),
+                        color_code: ColorCode::new(Color::Blue, Color::White),
+                    };
+                    self.writer
+                        .display
+                        .write(STATUS_ROW, self.column, character);
+                    self.column += 1;
+                }
+                Ok(())
             }
         }
+
+        let mut status_line = StatusLine {
+            writer: self,
+            column: 0,
+        };
+        // the status line never fails to write, it only truncates
+        let _ = status_line.write_fmt(args);
+        let padding = status_line.writer.columns - status_line.column;
+        for _ in 0..padding {
+            let _ = status_line.write_str(" ");
+        }
     }
 }
+
+#[test_case]
+fn test_println_simple() {
+    println!("test_println_simple output");
+}
+
+#[test_case]
+fn test_println_many() {
+    for _ in 0..200 {
+        println!("test_println_many output");
+    }
+}
+
+#[test_case]
+fn test_println_output() {
+    use x86_64::instructions::interrupts;
+
+    let s = "Some test string that fits on a single line";
+    // the timer interrupt handler prints a `.` and could shift our output, so we keep the lock
+    // (with interrupts disabled) until we have checked the buffer
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = Display::text_buffer().chars[BUFFER_HEIGHT - 2][i].read();
+            assert_eq!(char::from(screen_char.ascii_code), c);
+        }
+    });
+}
+
+#[test_case]
+fn test_ansi_colors() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\n\x1b[31mred\x1b[0m plain").expect("writeln failed");
+        let row = &Display::text_buffer().chars[BUFFER_HEIGHT - 2];
+        let red = ColorCode::new(DEFAULT_BACKGROUND, Color::Red);
+        for (i, c) in "red".chars().enumerate() {
+            assert_eq!(char::from(row[i].read().ascii_code), c);
+            assert_eq!(row[i].read().color_code, red);
+        }
+        // the escape sequences themselves are not printed
+        assert_eq!(row[3].read().ascii_code, b' ');
+        assert_eq!(row[4].read().ascii_code, b'p');
+        assert_eq!(
+            writer.color_code,
+            ColorCode::new(DEFAULT_BACKGROUND, DEFAULT_FOREGROUND)
+        );
+    });
+}
+
+#[test_case]
+fn test_ansi_cursor_position() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\x1b[2;5Hx").expect("write failed");
+        assert_eq!(
+            Display::text_buffer().chars[FIRST_ROW + 1][4]
+                .read()
+                .ascii_code,
+            b'x'
+        );
+        // back to the bottom row, where the other tests expect the cursor
+        let rows = writer.rows;
+        write!(writer, "\x1b[{};1H", rows).expect("write failed");
+    });
+}
+
+#[test_case]
+fn test_scrollback() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\nscrolled").expect("writeln failed");
+        writer.scroll(1);
+        // the line moved down by one row, the hardware cursor is hidden
+        assert_eq!(
+            Display::text_buffer().chars[BUFFER_HEIGHT - 1][0]
+                .read()
+                .ascii_code,
+            b's'
+        );
+        writer.scroll(-1);
+        assert_eq!(
+            Display::text_buffer().chars[BUFFER_HEIGHT - 2][0]
+                .read()
+                .ascii_code,
+            b's'
+        );
+    });
+}
@@ -0,0 +1,160 @@
+// A parser for the subset of the ANSI/VT100 escape sequences that the VGA console understands.
+// It is fed one character at a time and tells the writer what to do with it, without knowing
+// anything about the screen itself.
+//
+// Only the CSI (`ESC [`) sequences are supported, every other escape sequence is dropped.
+
+// sequences with more parameters are parsed, but the extra parameters are ignored
+const MAX_PARAMS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    // an ESC was received
+    Escape,
+    // an ESC [ was received, the parameters and the final character follow
+    Csi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Print(char),
+    // control characters such as `\n` or `\r`
+    Control(char),
+    Csi(Csi),
+}
+
+// A complete control sequence, e.g. `ESC [ 1 ; 31 m`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    pub final_char: char,
+}
+
+impl Csi {
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    // Returns the parameter at the given index, or `default` if it was not given
+    pub fn param(&self, index: usize, default: u16) -> u16 {
+        self.params().get(index).copied().unwrap_or(default)
+    }
+}
+
+pub struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    // the number of parameters started so far, can be larger than MAX_PARAMS
+    len: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            len: 0,
+        }
+    }
+
+    // Feeds the next character to the parser. Returns `None` while a sequence is incomplete.
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                '\x1b' => {
+                    self.state = State::Escape;
+                    None
+                }
+                '\0'..='\x1f' | '\x7f' => Some(Action::Control(c)),
+                _ => Some(Action::Print(c)),
+            },
+            State::Escape => {
+                if c == '[' {
+                    self.state = State::Csi;
+                    self.params = [0; MAX_PARAMS];
+                    self.len = 0;
+                } else {
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::Csi => self.advance_csi(c),
+        }
+    }
+
+    fn advance_csi(&mut self, c: char) -> Option<Action> {
+        match c {
+            '0'..='9' => {
+                self.len = self.len.max(1);
+                if let Some(param) = self.params.get_mut(self.len - 1) {
+                    let digit = c as u16 - '0' as u16;
+                    *param = param.saturating_mul(10).saturating_add(digit);
+                }
+                None
+            }
+            // an empty parameter (as in `ESC [ ; 5 H`) counts as 0
+            ';' => {
+                self.len = self.len.max(1) + 1;
+                None
+            }
+            // private markers and intermediate characters (as in `ESC [ ? 25 l`) are ignored
+            '<'..='?' | ' '..='/' => None,
+            '@'..='~' => {
+                self.state = State::Ground;
+                Some(Action::Csi(Csi {
+                    params: self.params,
+                    len: self.len.min(MAX_PARAMS),
+                    final_char: c,
+                }))
+            }
+            // anything else is invalid and cancels the sequence
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn parse_last(s: &str) -> Option<Action> {
+    let mut parser = Parser::new();
+    s.chars().fold(None, |_, c| parser.advance(c))
+}
+
+#[test_case]
+fn test_ansi_plain_text() {
+    assert_eq!(parse_last("a"), Some(Action::Print('a')));
+    assert_eq!(parse_last("\n"), Some(Action::Control('\n')));
+}
+
+#[test_case]
+fn test_ansi_csi_params() {
+    match parse_last("\x1b[1;31m") {
+        Some(Action::Csi(csi)) => {
+            assert_eq!(csi.params(), &[1, 31]);
+            assert_eq!(csi.final_char, 'm');
+        }
+        action => panic!("unexpected action {:?}", action),
+    }
+    match parse_last("\x1b[;5H") {
+        Some(Action::Csi(csi)) => {
+            assert_eq!(csi.param(0, 1), 0);
+            assert_eq!(csi.param(1, 1), 5);
+            assert_eq!(csi.param(2, 1), 1);
+        }
+        action => panic!("unexpected action {:?}", action),
+    }
+}
+
+#[test_case]
+fn test_ansi_incomplete_sequence() {
+    assert_eq!(parse_last("\x1b[12"), None);
+    // unsupported escape sequences are dropped, the text after them is printed again
+    let mut parser = Parser::new();
+    assert_eq!(parser.advance('\x1b'), None);
+    assert_eq!(parser.advance('c'), None);
+    assert_eq!(parser.advance('x'), Some(Action::Print('x')));
+}
@@ -0,0 +1,54 @@
+// The VGA text mode font uses code page 437, which has glyphs for some accented letters, box
+// drawing characters and symbols in addition to ASCII. This module maps the Unicode characters
+// to their code page 437 bytes.
+
+// the glyphs of the bytes 0x01 to 0x1f, in text mode these are not control characters
+const LOW: &str = "☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+
+// the glyphs of the bytes 0x80 to 0xff
+const HIGH: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜ¢£¥₧ƒ",
+    "áíóúñÑªº¿⌐¬½¼¡«»",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧",
+    "╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩",
+    "≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}",
+);
+
+// shown for every character that has no glyph
+pub const UNKNOWN: u8 = 0xfe;
+
+// Returns the code page 437 byte for the given character, or `UNKNOWN` if there is none
+pub fn encode(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8,
+        '⌂' => 0x7f,
+        _ => {
+            if let Some(i) = HIGH.chars().position(|glyph| glyph == c) {
+                0x80 + i as u8
+            } else if let Some(i) = LOW.chars().position(|glyph| glyph == c) {
+                0x01 + i as u8
+            } else {
+                UNKNOWN
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_cp437_tables() {
+    assert_eq!(LOW.chars().count(), 0x1f);
+    assert_eq!(HIGH.chars().count(), 0x80);
+}
+
+#[test_case]
+fn test_cp437_encode() {
+    assert_eq!(encode('A'), b'A');
+    assert_eq!(encode('☺'), 0x01);
+    assert_eq!(encode('é'), 0x82);
+    assert_eq!(encode('─'), 0xc4);
+    assert_eq!(encode('█'), 0xdb);
+    assert_eq!(encode('😀'), UNKNOWN);
+}
@@ -0,0 +1,101 @@
+// The console on a framebuffer: the characters of the text screen are drawn with the bitmap font,
+// in the colors of the VGA text mode. The cursor is an underline, drawn by inverting the bottom
+// rows of its cell, so it can be removed again without knowing the character below.
+
+use super::ScreenChar;
+use crate::framebuffer::{self, font, FrameBuffer, Rgb};
+
+// the 16 colors of the VGA text mode, in the order of `Color`
+const PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00),
+    Rgb::new(0x00, 0x00, 0xaa),
+    Rgb::new(0x00, 0xaa, 0x00),
+    Rgb::new(0x00, 0xaa, 0xaa),
+    Rgb::new(0xaa, 0x00, 0x00),
+    Rgb::new(0xaa, 0x00, 0xaa),
+    Rgb::new(0xaa, 0x55, 0x00),
+    Rgb::new(0xaa, 0xaa, 0xaa),
+    Rgb::new(0x55, 0x55, 0x55),
+    Rgb::new(0x55, 0x55, 0xff),
+    Rgb::new(0x55, 0xff, 0x55),
+    Rgb::new(0x55, 0xff, 0xff),
+    Rgb::new(0xff, 0x55, 0x55),
+    Rgb::new(0xff, 0x55, 0xff),
+    Rgb::new(0xff, 0xff, 0x55),
+    Rgb::new(0xff, 0xff, 0xff),
+];
+
+// the height of the cursor in pixels
+const CURSOR_HEIGHT: usize = 2;
+
+pub struct Screen {
+    framebuffer: FrameBuffer,
+    rows: usize,
+    columns: usize,
+    // the cell where the cursor is drawn
+    cursor: Option<(usize, usize)>,
+}
+
+impl Screen {
+    pub fn new(mut framebuffer: FrameBuffer) -> Screen {
+        framebuffer.clear(Rgb::BLACK);
+        Screen {
+            rows: framebuffer.height() / font::HEIGHT,
+            columns: framebuffer.width() / font::WIDTH,
+            framebuffer,
+            cursor: None,
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.columns)
+    }
+
+    pub fn write(&mut self, row: usize, column: usize, character: ScreenChar) {
+        // the character replaces the cursor along with the old one
+        if self.cursor == Some((row, column)) {
+            self.cursor = None;
+        }
+        let color_code = character.color_code.0;
+        let colors = (
+            PALETTE[usize::from(color_code & 0xf)],
+            PALETTE[usize::from(color_code >> 4)],
+        );
+        let (x, y) = (column * font::WIDTH, row * font::HEIGHT);
+        framebuffer::draw_char(&mut self.framebuffer, x, y, character.ascii_code, colors);
+    }
+
+    pub fn scroll(&mut self, first: usize) {
+        // the cursor would move up with its cell
+        self.move_cursor(None);
+        let top = first * font::HEIGHT;
+        let height = (self.rows - first - 1) * font::HEIGHT;
+        self.framebuffer.copy_rows(top + font::HEIGHT, top, height);
+    }
+
+    pub fn move_cursor(&mut self, position: Option<(usize, usize)>) {
+        if self.cursor == position {
+            return;
+        }
+        if let Some(old) = self.cursor.take() {
+            self.invert_cursor(old);
+        }
+        if let Some(new) = position {
+            self.invert_cursor(new);
+        }
+        self.cursor = position;
+    }
+
+    fn invert_cursor(&mut self, (row, column): (usize, usize)) {
+        let x = column * font::WIDTH;
+        let bottom = (row + 1) * font::HEIGHT;
+        for y in bottom - CURSOR_HEIGHT..bottom {
+            for x in x..x + font::WIDTH {
+                if let Some(color) = self.framebuffer.get_pixel(x, y) {
+                    let inverted = Rgb::new(!color.red, !color.green, !color.blue);
+                    self.framebuffer.set_pixel(x, y, inverted);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+/**
+ * Drivers for virtio devices, the paravirtualized devices of QEMU and other hypervisors.
+ *
+ * We use the legacy interface of the PCI transport: the registers of the device are I/O ports in
+ * its first BAR, and every virtqueue is a single physically contiguous area whose page number is
+ * written to the device. QEMU's virtio devices are "transitional" and offer this interface next to
+ * the modern one. The driver passes buffers to the device through the virtqueues (see `queue`).
+ */
+use x86_64::instructions::port::Port;
+
+use crate::{
+    interrupts::irq,
+    pci::{self, Bar, PciDevice},
+};
+
+pub mod blk;
+pub mod net;
+mod queue;
+
+pub use queue::{Buffer, VirtQueue};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+// the device ids of the transitional devices, the modern ones start at 0x1040
+pub const DEVICE_ID_NET: u16 = 0x1000;
+pub const DEVICE_ID_BLOCK: u16 = 0x1001;
+
+// registers of the legacy interface, offsets into the I/O BAR
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_DRIVER_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13;
+// the configuration of the device type follows, as long as MSI-X is disabled
+const REG_DEVICE_CONFIG: u16 = 0x14;
+
+// bits of the device status
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+// bit of the interrupt status: the device put buffers into a used ring
+pub const ISR_QUEUE: u8 = 1;
+
+#[derive(Debug)]
+pub enum InitError {
+    // the device has no I/O BAR, so it doesn't offer the legacy interface
+    NoLegacyInterface,
+    NoInterrupt,
+    // the device doesn't have the queue with this index
+    NoQueue(u16),
+    OutOfMemory,
+    Interrupt(irq::RegisterError),
+}
+
+// Sets up the virtio devices found on the PCI bus. Needs `pci::init` and the device interrupts.
+pub fn init() {
+    blk::init();
+    net::init();
+}
+
+pub struct Transport {
+    io_base: u16,
+}
+
+impl Transport {
+    // Resets the device and tells it that a driver has been found for it
+    pub fn new(device: &PciDevice) -> Result<Transport, InitError> {
+        let io_base = match device.bars[0] {
+            Some(Bar::Io { port, .. }) => port,
+            _ => return Err(InitError::NoLegacyInterface),
+        };
+        unsafe { device.enable(pci::COMMAND_IO_SPACE | pci::COMMAND_BUS_MASTER) };
+        let transport = Transport { io_base };
+        unsafe {
+            transport.write_u8(REG_DEVICE_STATUS, 0);
+            transport.write_u8(REG_DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        }
+        Ok(transport)
+    }
+
+    unsafe fn write_u8(&self, register: u16, value: u8) {
+        Port::new(self.io_base + register).write(value)
+    }
+
+    unsafe fn write_u16(&self, register: u16, value: u16) {
+        Port::new(self.io_base + register).write(value)
+    }
+
+    unsafe fn write_u32(&self, register: u16, value: u32) {
+        Port::new(self.io_base + register).write(value)
+    }
+
+    pub fn device_features(&self) -> u32 {
+        unsafe { Port::new(self.io_base + REG_DEVICE_FEATURES).read() }
+    }
+
+    // The legacy interface has no handshake for the features, the device has to accept the
+    // subset of its features that we write here
+    pub fn set_driver_features(&self, features: u32) {
+        unsafe { self.write_u32(REG_DRIVER_FEATURES, features) };
+    }
+
+    // Allocates the queue with the given index and hands it to the device
+    pub fn setup_queue(&self, index: u16) -> Result<VirtQueue, InitError> {
+        let size: u16 = unsafe {
+            self.write_u16(REG_QUEUE_SELECT, index);
+            Port::new(self.io_base + REG_QUEUE_SIZE).read()
+        };
+        if size == 0 {
+            return Err(InitError::NoQueue(index));
+        }
+        let queue = VirtQueue::new(index, size).ok_or(InitError::OutOfMemory)?;
+        // the legacy interface takes the page number of the queue
+        let page = (queue.phys_addr().as_u64() / 4096) as u32;
+        unsafe { self.write_u32(REG_QUEUE_ADDRESS, page) };
+        Ok(queue)
+    }
+
+    // Tells the device that the driver is set up, it doesn't use the queues before
+    pub fn driver_ok(&self) {
+        unsafe {
+            self.write_u8(
+                REG_DEVICE_STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+            )
+        };
+    }
+
+    // Tells the device that the driver gave up on it
+    pub fn fail(&self) {
+        unsafe { self.write_u8(REG_DEVICE_STATUS, STATUS_FAILED) };
+    }
+
+    // Tells the device that there are new buffers in the available ring of the queue
+    pub fn notify(&self, queue: &VirtQueue) {
+        unsafe { self.write_u16(REG_QUEUE_NOTIFY, queue.index()) };
+    }
+
+    // Reads and clears the interrupt status, which also deasserts the interrupt line
+    pub fn read_isr(&self) -> u8 {
+        unsafe { Port::new(self.io_base + REG_ISR_STATUS).read() }
+    }
+
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        unsafe { Port::new(self.io_base + REG_DEVICE_CONFIG + offset).read() }
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        unsafe { Port::new(self.io_base + REG_DEVICE_CONFIG + offset).read() }
+    }
+
+    // The legacy interface has no way to read 64 bits at once, the device can change the value
+    // between the halves. We only use this for values that don't change.
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        let low = self.read_config_u32(offset);
+        let high = self.read_config_u32(offset + 4);
+        u64::from(high) << 32 | u64::from(low)
+    }
+}
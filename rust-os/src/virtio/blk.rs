@@ -0,0 +1,329 @@
+/**
+ * The driver for virtio block devices, e.g. the disks QEMU adds with `-drive if=virtio`.
+ *
+ * Every request is a chain of three buffers: a header with the type of the request and the first
+ * sector, the data, and a status byte that the device writes when it is done. All three are in
+ * one `DmaBuffer` per request, the data is copied between it and the buffer of the caller. The
+ * interrupt handler marks the finished requests and wakes the tasks waiting for them.
+ */
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    ptr,
+    task::{Context, Poll, Waker},
+};
+
+use super::{Buffer, InitError, Transport, VirtQueue, DEVICE_ID_BLOCK, ISR_QUEUE, VENDOR_ID};
+use crate::{
+    block::{self, BlockDevice, BlockError, BlockFuture, BLOCK_SIZE},
+    interrupts::irq,
+    memory::DmaBuffer,
+    pci::{self, PciDevice},
+    sync::IrqSafeMutex,
+    task,
+};
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const STATUS_OK: u8 = 0;
+
+const FEATURE_READ_ONLY: u32 = 1 << 5;
+// the capacity in 512 byte sectors, at the start of the device configuration
+const CONFIG_CAPACITY: u16 = 0;
+
+// the layout of the buffer of a request: the header, the status byte and then the data, which
+// starts at the next block
+const HEADER_SIZE: usize = 16;
+const STATUS_OFFSET: usize = HEADER_SIZE;
+const DATA_OFFSET: usize = BLOCK_SIZE;
+// larger reads and writes are split, so that a request only needs a few contiguous frames
+const MAX_REQUEST_BLOCKS: usize = 128;
+
+// Sets up every virtio block device on the PCI bus and registers them as `vda`, `vdb`, ...
+pub fn init() {
+    let mut count = 0;
+    while let Some(device) = pci::claim("virtio-blk", |d| d.is(VENDOR_ID, DEVICE_ID_BLOCK)) {
+        let name = format!("vd{}", char::from(b'a' + count));
+        match VirtioBlk::new(name, &device) {
+            Ok(blk) => {
+                block::register(blk);
+                count += 1;
+            }
+            Err(error) => log::warn!("virtio-blk at {}: {:?}", device.address, error),
+        }
+    }
+}
+
+struct Request {
+    buffer: DmaBuffer,
+    len: usize,
+    device_writes: bool,
+}
+
+impl Request {
+    fn new(kind: u32, sector: u64, len: usize) -> Result<Request, BlockError> {
+        let buffer = DmaBuffer::allocate(DATA_OFFSET + len).ok_or(BlockError::OutOfMemory)?;
+        unsafe {
+            let header = buffer.as_mut_ptr::<u8>();
+            ptr::write_unaligned(header.cast::<u32>(), kind);
+            ptr::write_unaligned(header.add(8).cast::<u64>(), sector);
+            // the device overwrites this
+            header.add(STATUS_OFFSET).write(0xff);
+        }
+        Ok(Request {
+            buffer,
+            len,
+            device_writes: kind == REQUEST_IN,
+        })
+    }
+
+    fn buffers(&self) -> [Buffer; 3] {
+        let start = self.buffer.phys_addr();
+        [
+            Buffer {
+                addr: start,
+                len: HEADER_SIZE as u32,
+                device_writes: false,
+            },
+            Buffer {
+                addr: start + DATA_OFFSET as u64,
+                len: self.len as u32,
+                device_writes: self.device_writes,
+            },
+            Buffer {
+                addr: start + STATUS_OFFSET as u64,
+                len: 1,
+                device_writes: true,
+            },
+        ]
+    }
+
+    fn data(&mut self) -> &mut [u8] {
+        unsafe {
+            let data = self.buffer.as_mut_ptr::<u8>().add(DATA_OFFSET);
+            core::slice::from_raw_parts_mut(data, self.len)
+        }
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { ptr::read_volatile(self.buffer.as_mut_ptr::<u8>().add(STATUS_OFFSET)) }
+    }
+}
+
+// The state of a request in the queue, found by the first descriptor of its chain
+#[derive(Default)]
+struct Slot {
+    done: bool,
+    waker: Option<Waker>,
+    // The buffer of a request whose future was dropped before the request finished. The device
+    // still writes into it, so it is only freed once the request is done.
+    abandoned: Option<DmaBuffer>,
+}
+
+struct Inner {
+    queue: VirtQueue,
+    slots: Vec<Slot>,
+    // the buffers of abandoned requests that have finished, the interrupt handler can't free them
+    garbage: Vec<DmaBuffer>,
+}
+
+pub struct VirtioBlk {
+    name: String,
+    transport: Transport,
+    capacity: u64,
+    read_only: bool,
+    inner: IrqSafeMutex<Inner>,
+}
+
+impl VirtioBlk {
+    fn new(name: String, device: &PciDevice) -> Result<Arc<VirtioBlk>, InitError> {
+        if device.interrupt_pin == 0 || device.interrupt_line == 0xff {
+            return Err(InitError::NoInterrupt);
+        }
+        let transport = Transport::new(device)?;
+        // the device tells us if it's read-only, but we don't use any of the optional features
+        let read_only = transport.device_features() & FEATURE_READ_ONLY != 0;
+        transport.set_driver_features(0);
+        let queue = match transport.setup_queue(0) {
+            Ok(queue) => queue,
+            Err(error) => {
+                transport.fail();
+                return Err(error);
+            }
+        };
+        let size = usize::from(queue.size());
+        let blk = Arc::new(VirtioBlk {
+            name,
+            capacity: transport.read_config_u64(CONFIG_CAPACITY),
+            read_only,
+            transport,
+            inner: IrqSafeMutex::new(Inner {
+                queue,
+                slots: (0..size).map(|_| Slot::default()).collect(),
+                garbage: Vec::with_capacity(size),
+            }),
+        });
+
+        let handler_blk = blk.clone();
+        let handler = Box::new(move || handler_blk.handle_interrupt());
+        if let Err(error) = irq::register(device.interrupt_line, handler) {
+            blk.transport.fail();
+            return Err(InitError::Interrupt(error));
+        }
+        blk.transport.driver_ok();
+        Ok(blk)
+    }
+
+    fn handle_interrupt(&self) {
+        // the line can be shared, the interrupt status tells whether it was this device
+        if self.transport.read_isr() & ISR_QUEUE == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        let Inner {
+            queue,
+            slots,
+            garbage,
+        } = &mut *inner;
+        while let Some((head, _)) = queue.pop_used() {
+            let slot = &mut slots[usize::from(head)];
+            match slot.abandoned.take() {
+                Some(buffer) => garbage.push(buffer),
+                None => slot.done = true,
+            }
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    // Hands the request to the device and waits until it is done
+    async fn submit(&self, mut request: Request) -> Result<Request, BlockError> {
+        let head = loop {
+            let (head, garbage) = {
+                let mut inner = self.inner.lock();
+                let capacity = inner.slots.len();
+                let garbage = if inner.garbage.is_empty() {
+                    Vec::new()
+                } else {
+                    core::mem::replace(&mut inner.garbage, Vec::with_capacity(capacity))
+                };
+                let head = inner.queue.add(&request.buffers());
+                if let Some(head) = head {
+                    inner.slots[usize::from(head)] = Slot::default();
+                }
+                (head, garbage)
+            };
+            drop(garbage);
+            match head {
+                Some(head) => break head,
+                // the queue is full, one of the other requests has to finish first
+                None => task::yield_now().await,
+            }
+        };
+        self.transport.notify(&self.inner.lock().queue);
+
+        let completion = Completion {
+            device: self,
+            head,
+            request: Some(request),
+        };
+        request = completion.await;
+        if request.status() == STATUS_OK {
+            Ok(request)
+        } else {
+            Err(BlockError::Device)
+        }
+    }
+}
+
+// Finishes when the device is done with the request
+struct Completion<'a> {
+    device: &'a VirtioBlk,
+    head: u16,
+    request: Option<Request>,
+}
+
+impl Future for Completion<'_> {
+    type Output = Request;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Request> {
+        let mut inner = self.device.inner.lock();
+        let slot = &mut inner.slots[usize::from(self.head)];
+        if slot.done {
+            slot.done = false;
+            drop(inner);
+            Poll::Ready(
+                self.request
+                    .take()
+                    .expect("request polled after it finished"),
+            )
+        } else {
+            slot.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Completion<'_> {
+    fn drop(&mut self) {
+        let request = match self.request.take() {
+            Some(request) => request,
+            None => return,
+        };
+        let mut inner = self.device.inner.lock();
+        let slot = &mut inner.slots[usize::from(self.head)];
+        if slot.done {
+            slot.done = false;
+        } else {
+            slot.waker = None;
+            slot.abandoned = Some(request.buffer);
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check_request(self, start, buf.len())?;
+            let chunks = buf.chunks_mut(MAX_REQUEST_BLOCKS * BLOCK_SIZE);
+            for (i, chunk) in chunks.enumerate() {
+                let sector = start + (i * MAX_REQUEST_BLOCKS) as u64;
+                let request = Request::new(REQUEST_IN, sector, chunk.len())?;
+                let mut request = self.submit(request).await?;
+                chunk.copy_from_slice(request.data());
+            }
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check_request(self, start, buf.len())?;
+            if self.read_only {
+                return Err(BlockError::ReadOnly);
+            }
+            let chunks = buf.chunks(MAX_REQUEST_BLOCKS * BLOCK_SIZE);
+            for (i, chunk) in chunks.enumerate() {
+                let sector = start + (i * MAX_REQUEST_BLOCKS) as u64;
+                let mut request = Request::new(REQUEST_OUT, sector, chunk.len())?;
+                request.data().copy_from_slice(chunk);
+                self.submit(request).await?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,240 @@
+/**
+ * The driver for virtio network devices, e.g. the ones QEMU adds with `-device virtio-net-pci`.
+ *
+ * The device has a queue for the received frames and one for the frames to send. Every frame is
+ * preceded by a header for the offloads, which we don't use. The legacy interface wants the
+ * header in a descriptor of its own, so every buffer is a chain of two descriptors.
+ *
+ * The receive queue is kept filled with buffers, the interrupt handler only wakes the task that
+ * waits for frames, which takes them out of the queue and hands the buffers back to the device.
+ * Frames are sent from a fixed set of buffers, which are taken back once the device has used
+ * them.
+ */
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::{future, task::Poll};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::{Buffer, InitError, Transport, VirtQueue, DEVICE_ID_NET, ISR_QUEUE, VENDOR_ID};
+use crate::{
+    interrupts::irq,
+    memory::DmaBuffer,
+    net::{self, ethernet::MacAddress, NetDevice, NetError, ReceiveFuture},
+    pci::{self, PciDevice},
+};
+
+const QUEUE_RECEIVE: u16 = 0;
+const QUEUE_TRANSMIT: u16 = 1;
+
+// the device has an address, in the first 6 bytes of the device configuration
+const FEATURE_MAC: u32 = 1 << 5;
+// a locally administered address, for a device that doesn't have one
+const DEFAULT_MAC: MacAddress = MacAddress([0x02, 0, 0, 0, 0, 1]);
+
+// the size of the header without the "mergeable receive buffers" feature
+const HEADER_SIZE: usize = 10;
+// the layout of a buffer: the header, then the frame at the next 16 bytes
+const FRAME_OFFSET: usize = 16;
+const MAX_FRAME_SIZE: usize = 1514;
+const BUFFER_SIZE: usize = 2048;
+// the queues can have more descriptors, but this is plenty for a VM
+const MAX_BUFFERS: usize = 32;
+
+// Sets up the first virtio network device on the PCI bus and attaches it as `eth0`. The stack
+// supports only one interface.
+pub fn init() {
+    let device = match pci::claim("virtio-net", |d| d.is(VENDOR_ID, DEVICE_ID_NET)) {
+        Some(device) => device,
+        None => return,
+    };
+    match VirtioNet::new(&device) {
+        Ok(net) => {
+            net::attach(net);
+        }
+        Err(error) => log::warn!("virtio-net at {}: {:?}", device.address, error),
+    }
+}
+
+// A queue together with the buffers of its descriptor chains
+struct Queue {
+    queue: VirtQueue,
+    buffers: DmaBuffer,
+    // the buffer of the chain that starts at a descriptor
+    chains: Vec<usize>,
+    // the buffers that are not in the queue
+    free: Vec<usize>,
+}
+
+impl Queue {
+    fn new(queue: VirtQueue) -> Result<Queue, InitError> {
+        let count = (usize::from(queue.size()) / 2).min(MAX_BUFFERS);
+        let buffers = DmaBuffer::allocate(count * BUFFER_SIZE).ok_or(InitError::OutOfMemory)?;
+        Ok(Queue {
+            chains: vec![0; usize::from(queue.size())],
+            queue,
+            buffers,
+            free: (0..count).collect(),
+        })
+    }
+
+    fn buffer(&mut self, index: usize) -> &mut [u8] {
+        unsafe {
+            let start = self.buffers.as_mut_ptr::<u8>().add(index * BUFFER_SIZE);
+            core::slice::from_raw_parts_mut(start, BUFFER_SIZE)
+        }
+    }
+
+    // Hands a free buffer with a frame of `len` bytes to the device. Returns false if there is
+    // no free buffer.
+    fn add(&mut self, len: usize, device_writes: bool) -> bool {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => return false,
+        };
+        let start = self.buffers.phys_addr() + (index * BUFFER_SIZE) as u64;
+        let chain = [
+            Buffer {
+                addr: start,
+                len: HEADER_SIZE as u32,
+                device_writes,
+            },
+            Buffer {
+                addr: start + FRAME_OFFSET as u64,
+                len: len as u32,
+                device_writes,
+            },
+        ];
+        // there are two descriptors for every buffer, so this can't fail
+        let head = self.queue.add(&chain).expect("virtqueue full");
+        self.chains[usize::from(head)] = index;
+        true
+    }
+
+    // Takes the next buffer the device is done with, with the number of bytes it wrote
+    fn pop_used(&mut self) -> Option<(usize, usize)> {
+        let (head, len) = self.queue.pop_used()?;
+        let index = self.chains[usize::from(head)];
+        self.free.push(index);
+        Some((index, len as usize))
+    }
+}
+
+pub struct VirtioNet {
+    transport: Transport,
+    mac: MacAddress,
+    receive: Mutex<Queue>,
+    transmit: Mutex<Queue>,
+    // the task waiting for frames
+    waker: AtomicWaker,
+}
+
+impl VirtioNet {
+    fn new(device: &PciDevice) -> Result<Arc<VirtioNet>, InitError> {
+        if device.interrupt_pin == 0 || device.interrupt_line == 0xff {
+            return Err(InitError::NoInterrupt);
+        }
+        let transport = Transport::new(device)?;
+        let features = transport.device_features() & FEATURE_MAC;
+        transport.set_driver_features(features);
+        let mac = if features & FEATURE_MAC != 0 {
+            let mut mac = [0; 6];
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = transport.read_config_u8(i as u16);
+            }
+            MacAddress(mac)
+        } else {
+            DEFAULT_MAC
+        };
+        let queues = transport
+            .setup_queue(QUEUE_RECEIVE)
+            .and_then(Queue::new)
+            .and_then(|receive| {
+                let transmit = transport.setup_queue(QUEUE_TRANSMIT).and_then(Queue::new)?;
+                Ok((receive, transmit))
+            });
+        let (mut receive, transmit) = match queues {
+            Ok(queues) => queues,
+            Err(error) => {
+                transport.fail();
+                return Err(error);
+            }
+        };
+        // the device drops the frames that arrive while there are no buffers
+        while receive.add(MAX_FRAME_SIZE, true) {}
+
+        let net = Arc::new(VirtioNet {
+            transport,
+            mac,
+            receive: Mutex::new(receive),
+            transmit: Mutex::new(transmit),
+            waker: AtomicWaker::new(),
+        });
+        let handler_net = net.clone();
+        let handler = Box::new(move || handler_net.handle_interrupt());
+        if let Err(error) = irq::register(device.interrupt_line, handler) {
+            net.transport.fail();
+            return Err(InitError::Interrupt(error));
+        }
+        net.transport.driver_ok();
+        net.transport.notify(&net.receive.lock().queue);
+        Ok(net)
+    }
+
+    fn handle_interrupt(&self) {
+        // the line can be shared, the interrupt status tells whether it was this device
+        if self.transport.read_isr() & ISR_QUEUE != 0 {
+            self.waker.wake();
+        }
+    }
+
+    // Takes the next received frame out of the queue and gives its buffer back to the device
+    fn try_receive(&self) -> Option<Vec<u8>> {
+        let mut receive = self.receive.lock();
+        let (index, len) = receive.pop_used()?;
+        let len = len.saturating_sub(HEADER_SIZE).min(MAX_FRAME_SIZE);
+        let frame = Vec::from(&receive.buffer(index)[FRAME_OFFSET..FRAME_OFFSET + len]);
+        receive.add(MAX_FRAME_SIZE, true);
+        self.transport.notify(&receive.queue);
+        Some(frame)
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &str {
+        "eth0"
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::TooLarge);
+        }
+        let mut transmit = self.transmit.lock();
+        // take back the buffers of the frames that have been sent
+        while transmit.pop_used().is_some() {}
+        let index = *transmit.free.last().ok_or(NetError::Busy)?;
+        let buffer = transmit.buffer(index);
+        buffer[..HEADER_SIZE].fill(0);
+        buffer[FRAME_OFFSET..FRAME_OFFSET + frame.len()].copy_from_slice(frame);
+        transmit.add(frame.len(), false);
+        self.transport.notify(&transmit.queue);
+        Ok(())
+    }
+
+    fn receive(&self) -> ReceiveFuture<'_> {
+        Box::pin(future::poll_fn(move |cx| {
+            if let Some(frame) = self.try_receive() {
+                return Poll::Ready(frame);
+            }
+            self.waker.register(cx.waker());
+            // a frame could have arrived before the waker was registered
+            match self.try_receive() {
+                Some(frame) => Poll::Ready(frame),
+                None => Poll::Pending,
+            }
+        }))
+    }
+}
@@ -0,0 +1,182 @@
+/**
+ * Split virtqueues, through which the driver hands buffers to a virtio device.
+ *
+ * A queue is made of three parts in memory that is shared with the device:
+ * - the descriptor table, where every descriptor points to a buffer and can be chained to the
+ *   next one, so that a request can consist of several buffers
+ * - the available ring, where the driver puts the first descriptors of the chains it hands over
+ * - the used ring, where the device returns the chains it is done with
+ *
+ * In the legacy layout, the used ring starts at the first page boundary after the available ring.
+ *
+ * The descriptors that are not part of a chain are kept in a free list, linked through their
+ * `next` field.
+ */
+use core::{
+    mem::size_of,
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+use x86_64::PhysAddr;
+
+use crate::memory::DmaBuffer;
+
+// the descriptor continues in the one at `next`
+const DESC_F_NEXT: u16 = 1;
+// the device writes to the buffer, instead of reading it
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UsedElement {
+    // the first descriptor of the chain
+    id: u32,
+    // the number of bytes the device wrote into the chain
+    len: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    used_offset: usize,
+    free_head: u16,
+    free_count: u16,
+    // our copy of the index of the available ring, the device only reads it
+    avail_idx: u16,
+    // the entries of the used ring before this one have been taken
+    last_used: u16,
+}
+
+impl VirtQueue {
+    pub(super) fn new(index: u16, size: u16) -> Option<VirtQueue> {
+        let entries = usize::from(size);
+        // the available ring has a flags and an index field in front of the entries
+        let avail_end = entries * size_of::<Descriptor>() + 4 + entries * 2;
+        let used_offset = (avail_end + 4095) & !4095;
+        let used_size = 4 + entries * size_of::<UsedElement>();
+        let queue = VirtQueue {
+            index,
+            size,
+            memory: DmaBuffer::allocate(used_offset + used_size)?,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            avail_idx: 0,
+            last_used: 0,
+        };
+        // at first every descriptor is free
+        for i in 0..size {
+            unsafe { (*queue.descriptor(i)).next = i.wrapping_add(1) };
+        }
+        Some(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub(super) fn phys_addr(&self) -> PhysAddr {
+        self.memory.phys_addr()
+    }
+
+    fn descriptor(&self, i: u16) -> *mut Descriptor {
+        unsafe { self.memory.as_mut_ptr::<Descriptor>().add(usize::from(i)) }
+    }
+
+    // the flags and index fields, followed by the entries
+    fn avail_ring(&self) -> *mut u16 {
+        self.descriptor(self.size).cast()
+    }
+
+    fn used_ring(&self) -> *mut u16 {
+        unsafe { self.memory.as_mut_ptr::<u8>().add(self.used_offset).cast() }
+    }
+
+    // Hands the buffers to the device as one chain. Returns the index of the first descriptor,
+    // which identifies the chain when it is returned by `pop_used`, or `None` if there are not
+    // enough free descriptors. The device has to be notified afterwards.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let descriptor = unsafe { &mut *self.descriptor(index) };
+            descriptor.addr = buffer.addr.as_u64();
+            descriptor.len = buffer.len;
+            descriptor.flags = if buffer.device_writes {
+                DESC_F_WRITE
+            } else {
+                0
+            };
+            if i + 1 < buffers.len() {
+                descriptor.flags |= DESC_F_NEXT;
+            }
+            index = descriptor.next;
+        }
+        self.free_head = index;
+        self.free_count -= buffers.len() as u16;
+
+        let slot = usize::from(self.avail_idx % self.size);
+        unsafe { ptr::write_volatile(self.avail_ring().add(2 + slot), head) };
+        // the device must see the entry before the index that makes it visible
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe { ptr::write_volatile(self.avail_ring().add(1), self.avail_idx) };
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    // Takes the next chain that the device is done with and frees its descriptors. Returns the
+    // first descriptor of the chain and the number of bytes the device wrote into it.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { ptr::read_volatile(self.used_ring().add(1)) };
+        if used_idx == self.last_used {
+            return None;
+        }
+        // the entry must not be read before the index that made it visible
+        fence(Ordering::SeqCst);
+        let slot = usize::from(self.last_used % self.size);
+        let element: UsedElement =
+            unsafe { ptr::read_volatile(self.used_ring().add(2).cast::<UsedElement>().add(slot)) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = element.id as u16;
+        let mut last = head;
+        let mut count = 1;
+        loop {
+            let descriptor = unsafe { &*self.descriptor(last) };
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            last = descriptor.next;
+            count += 1;
+        }
+        unsafe { (*self.descriptor(last)).next = self.free_head };
+        self.free_head = head;
+        self.free_count += count;
+        Some((head, element.len))
+    }
+}
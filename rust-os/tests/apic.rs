@@ -0,0 +1,67 @@
+// Switches from the 8259 PICs to the APIC and checks that the hardware interrupts still arrive.
+// QEMU's default machine has an APIC, so this runs headless like the other tests.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{acpi::Madt, interrupts::apic, timer};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    memory::with_kernel_memory(|memory| {
+        apic::init(&mut memory.mapper, &mut memory.frame_allocator)
+    })
+    .expect("APIC initialization failed");
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_describes_the_system() {
+    let madt = Madt::parse().expect("no MADT");
+    assert!(!madt.processors.is_empty());
+    assert!(!madt.io_apics.is_empty());
+    // QEMU connects the PIT to the second input of the IOAPIC
+    assert_eq!(madt.isa_irq(0).gsi, 2);
+}
+
+#[test_case]
+fn apic_is_enabled() {
+    assert!(apic::is_enabled());
+    assert!(apic::local_apic().is_some());
+}
+
+// the timer interrupts are now delivered by the IOAPIC and acknowledged at the local APIC, if
+// either of them was configured wrong the ticks would stop after the first one
+#[test_case]
+fn timer_ticks_advance() {
+    for _ in 0..3 {
+        let before = timer::ticks();
+        while timer::ticks() == before {
+            x86_64::instructions::hlt();
+        }
+    }
+}
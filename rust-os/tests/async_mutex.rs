@@ -0,0 +1,89 @@
+// Tests for the `AsyncMutex`, with tasks that keep it locked across an `.await`
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    future::{self, Future},
+    panic::PanicInfo,
+    task::Poll,
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator,
+    memory::{self, BootInfoFrameAllocator},
+    sync::AsyncMutex,
+    task::{self, simple_executor::SimpleExecutor, Task},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// Every task appends its number twice, yielding in between. Without the lock the other tasks
+// would append theirs in between.
+#[test_case]
+fn tasks_are_excluded() {
+    let log = Arc::new(AsyncMutex::new(Vec::new()));
+    let mut executor = SimpleExecutor::new();
+    for n in 0..3 {
+        let log = log.clone();
+        executor.spawn(Task::new(async move {
+            let mut log = log.lock().await;
+            log.push(n);
+            task::yield_now().await;
+            log.push(n);
+        }));
+    }
+    executor.run();
+
+    let log = log.try_lock().expect("mutex still locked");
+    assert_eq!(log.len(), 6);
+    for pair in log.chunks(2) {
+        assert_eq!(pair[0], pair[1]);
+    }
+}
+
+// A task that gave up waiting for the lock must not keep the others from getting it
+#[test_case]
+fn dropped_waiter_does_not_block() {
+    let mutex = Arc::new(AsyncMutex::new(0));
+    let mut executor = SimpleExecutor::new();
+    let guard = mutex.try_lock().unwrap();
+    let first = mutex.clone();
+    executor.spawn(Task::new(async move {
+        // polls the lock once and drops it, while it's held
+        let mut lock = Box::pin(first.lock());
+        let pending = future::poll_fn(|cx| Poll::Ready(lock.as_mut().poll(cx).is_pending())).await;
+        assert!(pending);
+    }));
+    executor.run();
+    drop(guard);
+
+    let second = mutex.clone();
+    executor.spawn(Task::new(async move {
+        *second.lock().await += 1;
+    }));
+    executor.run();
+    assert_eq!(*mutex.try_lock().unwrap(), 1);
+}
@@ -0,0 +1,58 @@
+// Freeing the same allocation twice has to panic in debug mode, instead of corrupting the heap.
+// Like `lock_order.rs`, this doesn't use the test runner, since the panic handler can't return to
+// it.
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{allocator, exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+// set right before the second free, so that no other panic passes the test
+static FREEING_TWICE: AtomicBool = AtomicBool::new(false);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    allocator::set_debug_mode(true);
+
+    serial_print!("double_free::double_free_panics...\t");
+    let layout = Layout::new::<u64>();
+    unsafe {
+        let value = alloc(layout);
+        dealloc(value, layout);
+        FREEING_TWICE.store(true, Ordering::SeqCst);
+        dealloc(value, layout);
+    }
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if FREEING_TWICE.load(Ordering::SeqCst) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rust_os::hlt_loop();
+}
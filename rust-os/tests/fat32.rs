@@ -0,0 +1,153 @@
+// Reads the FAT32 image that build.rs makes from the files in tests/disk, which the test-args in
+// Cargo.toml attach as the second virtio disk. The expected contents are those of the files.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator,
+    block::{self, BlockDevice, BlockError, BLOCK_SIZE},
+    fs::{self, fat32::Fat32, FileType, FsError},
+    interrupts::apic,
+    memory::{self, BootInfoFrameAllocator},
+    pci,
+    task::simple_executor::block_on,
+    virtio,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    memory::with_kernel_memory(|memory| {
+        apic::init(&mut memory.mapper, &mut memory.frame_allocator)
+    })
+    .expect("APIC initialization failed");
+    pci::init();
+    virtio::init();
+    // vda can't be mounted, so this has to find the image
+    block_on(fs::mount_root()).expect("mounting the image failed");
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn read_file(path: &'static str) -> Result<Vec<u8>, FsError> {
+    block_on(fs::read_file(path))
+}
+
+fn names(path: &'static str) -> Vec<String> {
+    let entries = block_on(fs::read_dir(path)).expect("listing the directory failed");
+    entries.into_iter().map(|entry| entry.name).collect()
+}
+
+#[test_case]
+fn root_is_mounted() {
+    assert_eq!(fs::mounts(), [(String::from("/"), "fat32")]);
+    assert_eq!(names("/"), ["docs", "hello.txt", "rust-os.cfg"]);
+    let entries = block_on(fs::read_dir("/")).unwrap();
+    assert_eq!(entries[0].metadata.file_type, FileType::Directory);
+    assert_eq!(entries[1].metadata.file_type, FileType::File);
+    assert_eq!(entries[1].metadata.size, 21);
+}
+
+#[test_case]
+fn read_small_file() {
+    assert_eq!(read_file("/hello.txt").unwrap(), b"Hello from the disk!\n");
+    // FAT ignores the case
+    assert_eq!(read_file("/HELLO.TXT").unwrap(), b"Hello from the disk!\n");
+}
+
+#[test_case]
+fn long_names() {
+    assert_eq!(
+        names("/docs"),
+        ["A file with a long name.txt", "numbers.txt"]
+    );
+    let expected = b"FAT stores this name in long file name entries.\n";
+    assert_eq!(
+        read_file("/docs/A file with a long name.txt").unwrap(),
+        expected
+    );
+    // the 8.3 name that goes with the long name
+    assert_eq!(read_file("/docs/AFILEW~1.TXT").unwrap(), expected);
+}
+
+// the file spans 12 clusters
+#[test_case]
+fn read_large_file() {
+    let data = read_file("/docs/numbers.txt").unwrap();
+    assert_eq!(data.len(), 6000);
+    let text = core::str::from_utf8(&data).unwrap();
+    for (i, line) in text.lines().enumerate() {
+        let number: usize = line.strip_prefix("line ").unwrap().parse().unwrap();
+        assert_eq!(number, i);
+    }
+}
+
+#[test_case]
+fn read_at_offset() {
+    let inode = block_on(fs::lookup("/docs/numbers.txt")).unwrap();
+    let data = block_on(async move {
+        // across the boundary of the first two clusters
+        let mut buf = [0; 20];
+        let read = inode.read_at(505, &mut buf).await?;
+        Ok::<_, FsError>(Vec::from(&buf[..read]))
+    });
+    assert_eq!(data.unwrap(), b"0050\nline 0051\nline ");
+}
+
+#[test_case]
+fn paths_are_normalized() {
+    assert_eq!(
+        fs::normalize("docs/./numbers.txt").unwrap(),
+        "/docs/numbers.txt"
+    );
+    assert_eq!(fs::normalize("/docs//..").unwrap(), "/");
+    assert_eq!(fs::normalize("/.."), Err(FsError::InvalidPath));
+    assert_eq!(
+        read_file("/docs/../hello.txt").unwrap(),
+        b"Hello from the disk!\n"
+    );
+}
+
+#[test_case]
+fn errors() {
+    assert_eq!(read_file("/missing.txt"), Err(FsError::NotFound));
+    assert_eq!(read_file("/docs"), Err(FsError::IsADirectory));
+    assert_eq!(read_file("/hello.txt/x"), Err(FsError::NotADirectory));
+    let result = block_on(fs::read_dir("/hello.txt"));
+    assert_eq!(result, Err(FsError::NotADirectory));
+}
+
+#[test_case]
+fn zeroed_disk_is_not_mounted() {
+    let disk = block::find("vda").unwrap();
+    let result = block_on(async move { Fat32::mount(disk).await.map(|_| ()) });
+    assert_eq!(result, Err(FsError::Unsupported));
+}
+
+#[test_case]
+fn image_is_read_only() {
+    let disk: Arc<dyn BlockDevice> = block::find("vdb").unwrap();
+    assert!(disk.read_only());
+    let result = block_on(async move { disk.write_blocks(0, &[0; BLOCK_SIZE]).await });
+    assert_eq!(result, Err(BlockError::ReadOnly));
+}
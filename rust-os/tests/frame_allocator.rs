@@ -0,0 +1,93 @@
+// Tests for the physical frame allocator. They allocate every free frame, so they run in their
+// own binary instead of next to tests that need memory.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::memory::{self, frame_stats, phys_to_virt, BootInfoFrameAllocator};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// marks the end of the list of allocated frames, frame 0 is never usable
+const END: u64 = 0;
+
+#[test_case]
+fn allocate_and_free_all_frames() {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    let free = frame_stats().free;
+    assert!(free > 0);
+
+    // There is no heap to remember the frames in, so every frame stores the address of the frame
+    // allocated before it. A frame that was handed out twice would break the list and be freed
+    // twice, which panics.
+    let mut last = END;
+    let mut count = 0;
+    while let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator) {
+        let addr = frame.start_address();
+        unsafe { phys_to_virt(addr).as_mut_ptr::<u64>().write(last) };
+        last = addr.as_u64();
+        count += 1;
+    }
+    assert_eq!(count, free);
+    assert_eq!(frame_stats().free, 0);
+
+    while last != END {
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(last));
+        last = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(frame_stats().free, free);
+}
+
+#[test_case]
+fn allocate_huge_frame() {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    let before = frame_stats();
+
+    let frame: PhysFrame<Size2MiB> = frame_allocator
+        .allocate_frame()
+        .expect("no free 2 MiB frame");
+    assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+    assert_eq!(frame_stats().used, before.used + 512);
+
+    // none of the 4 KiB frames inside of it can be allocated now
+    let small: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
+    let (start, addr) = (frame.start_address(), small.start_address());
+    assert!(addr < start || addr >= start + Size2MiB::SIZE);
+    unsafe {
+        frame_allocator.deallocate_frame(small);
+        frame_allocator.deallocate_frame(frame);
+    }
+    assert_eq!(frame_stats(), before);
+}
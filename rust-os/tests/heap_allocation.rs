@@ -0,0 +1,213 @@
+// Heap stress tests. They run against whichever allocator is selected with the cargo features,
+// so every allocator can be tested with the same code:
+//
+//     cargo test --test heap_allocation
+//     cargo test --test heap_allocation --features linked_list_allocator
+//     cargo test --test heap_allocation --features fixed_size_block_allocator
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator::{self, HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START},
+    memory,
+};
+use x86_64::{
+    structures::paging::{FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+// allocates more than the whole heap in total, which only works if freed memory is reused
+#[test_case]
+fn many_boxes() {
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+// The BumpAllocator can only reuse memory once every allocation is freed, so a single long lived
+// allocation makes it run out of memory. This is a known limitation and not tested for it.
+#[cfg(any(
+    feature = "linked_list_allocator",
+    feature = "fixed_size_block_allocator"
+))]
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+// boxes of different sizes and alignments that are all alive at the same time
+#[test_case]
+fn mixed_sizes() {
+    let small: Vec<Box<u8>> = (0..100).map(Box::new).collect();
+    let medium: Vec<Box<[u64; 16]>> = (0..20).map(|i| Box::new([i; 16])).collect();
+    let large = Box::new([7u8; 4096]);
+    for (i, value) in small.iter().enumerate() {
+        assert_eq!(**value as usize, i);
+    }
+    for (i, value) in medium.iter().enumerate() {
+        assert!(value.iter().all(|&v| v as usize == i));
+    }
+    assert!(large.iter().all(|&v| v == 7));
+}
+
+// Three quarters of the heap are only available as one piece without growing the heap if the
+// regions that were freed before have been merged again.
+#[test_case]
+fn freed_regions_are_merged() {
+    let heap_size = allocator::heap_size();
+    let chunks: Vec<Vec<u8>> = (0..3).map(|_| Vec::with_capacity(HEAP_SIZE / 4)).collect();
+    drop(chunks);
+    let large: Vec<u8> = Vec::with_capacity(HEAP_SIZE * 3 / 4);
+    assert_eq!(large.capacity(), HEAP_SIZE * 3 / 4);
+    assert_eq!(allocator::heap_size(), heap_size);
+}
+
+#[test_case]
+fn stats_count_allocations() {
+    let before = allocator::stats();
+    // 24 bytes are counted as 32, the third block size
+    let value = Box::new([0u8; 24]);
+    let during = allocator::stats();
+    assert_eq!(during.used, before.used + 24);
+    assert!(during.peak >= during.used);
+    assert_eq!(during.classes[2].block_size, Some(32));
+    assert_eq!(
+        during.classes[2].allocations,
+        before.classes[2].allocations + 1
+    );
+    assert_eq!(during.classes[2].live, before.classes[2].live + 1);
+    drop(value);
+    let after = allocator::stats();
+    assert_eq!(after.used, before.used);
+    assert_eq!(after.classes[2].live, before.classes[2].live);
+    assert!(after.allocator.expect("allocator locked").free > 0);
+}
+
+// The allocators keep their lists in the freed memory, but they don't use more than 16 bytes of a
+// block, so the rest has to be poisoned. The freed memory is only read through the allocator.
+#[cfg(debug_assertions)]
+#[test_case]
+fn freed_memory_is_poisoned() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    allocator::set_debug_mode(true);
+    let layout = Layout::new::<[u8; 64]>();
+    unsafe {
+        let block = alloc(layout);
+        assert!(!block.is_null());
+        block.write_bytes(1, 64);
+        dealloc(block, layout);
+        assert!(allocator::is_poisoned(block, 64));
+    }
+}
+
+// The tests below grow the heap, so they run last.
+
+#[test_case]
+fn heap_grows() {
+    let heap_size = allocator::heap_size();
+    let large = alloc::vec![1u8; HEAP_SIZE * 4];
+    assert!(allocator::heap_size() > heap_size);
+    assert!(allocator::heap_size() >= HEAP_SIZE * 4);
+    assert!(large.iter().all(|&byte| byte == 1));
+}
+
+// A page that is already mapped in the way of the next growth makes mapping the new part of the
+// heap fail halfway. The pages that were mapped before it have to be unmapped and their frames
+// freed again, and the heap keeps its size.
+#[test_case]
+fn failed_growth_is_rolled_back() {
+    let heap_size = allocator::heap_size();
+    let heap_end = VirtAddr::new((HEAP_START + heap_size) as u64);
+    let blocker = Page::<Size4KiB>::containing_address(heap_end + 4096u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::with_kernel_memory(|memory| unsafe {
+        memory::map_range(
+            blocker.start_address(),
+            4096,
+            flags,
+            &mut memory.mapper,
+            &mut memory.frame_allocator,
+        )
+    })
+    .expect("mapping the blocking page failed");
+    // the blocking page and the first page of the heap's next part share their page tables
+    let used_frames = memory::frame_stats().used;
+
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(heap_size + 1).is_err());
+    assert_eq!(allocator::heap_size(), heap_size);
+    assert_eq!(memory::frame_stats().used, used_frames);
+    let offset = memory::physical_memory_offset();
+    assert_eq!(unsafe { memory::translate_addr(heap_end, offset) }, None);
+
+    memory::with_kernel_memory(|memory| {
+        let (frame, flush) = memory.mapper.unmap(blocker).unwrap();
+        flush.flush();
+        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+    });
+    assert!(vec.try_reserve(heap_size + 1).is_ok());
+}
+
+#[test_case]
+fn heap_growth_is_limited() {
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(HEAP_MAX_SIZE).is_err());
+
+    allocator::set_heap_limit(allocator::heap_size());
+    assert!(vec.try_reserve(allocator::heap_size()).is_err());
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+}
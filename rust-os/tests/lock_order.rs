@@ -0,0 +1,56 @@
+// Taking three locks in a cycle has to panic in debug builds, long before two CPUs ever deadlock
+// on them. Like `should_panic.rs`, this doesn't use the test runner, since the panic handler can't
+// return to it.
+#![no_std]
+#![no_main]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{exit_qemu, serial_print, serial_println, sync::IrqSafeMutex, QemuExitCode};
+
+entry_point!(main);
+
+static A: IrqSafeMutex<()> = IrqSafeMutex::new(());
+static B: IrqSafeMutex<()> = IrqSafeMutex::new(());
+static C: IrqSafeMutex<()> = IrqSafeMutex::new(());
+
+// set right before the lock that closes the cycle, so that no other panic passes the test
+static CLOSING_CYCLE: AtomicBool = AtomicBool::new(false);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    serial_print!("lock_order::cycle_panics...\t");
+    {
+        let _a = A.lock();
+        let _b = B.lock();
+    }
+    {
+        let _b = B.lock();
+        let _c = C.lock();
+    }
+    {
+        let _c = C.lock();
+        CLOSING_CYCLE.store(true, Ordering::SeqCst);
+        let _a = A.lock();
+    }
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if CLOSING_CYCLE.load(Ordering::SeqCst) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rust_os::hlt_loop();
+}
@@ -0,0 +1,296 @@
+// The network stack, first over the virtio device that the test-args in Cargo.toml add to QEMU,
+// then over a fake device that records the frames the stack sends, with the frames of a made-up
+// host fed to the stack directly.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use futures_util::future::{join, select, Either};
+use rust_os::{
+    allocator,
+    interrupts::apic,
+    memory::{self, BootInfoFrameAllocator},
+    net::{
+        self, arp,
+        ethernet::{self, Frame, MacAddress},
+        ipv4::{self, Ipv4Address},
+        udp::UdpSocket,
+        Interface, NetDevice, NetError, ReceiveFuture,
+    },
+    pci,
+    task::{self, simple_executor::block_on},
+    virtio,
+};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    memory::with_kernel_memory(|memory| {
+        apic::init(&mut memory.mapper, &mut memory.frame_allocator)
+    })
+    .expect("APIC initialization failed");
+    pci::init();
+    virtio::init();
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// the address QEMU gives the interface, unless told otherwise
+const QEMU_MAC: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+// the address of the gateway of QEMU's user networking
+const GATEWAY_MAC: MacAddress = MacAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+
+// these tests use the virtio device, so they have to run before the fake device replaces it
+
+#[test_case]
+fn virtio_device_is_attached() {
+    let interface = net::interface().expect("no network interface");
+    assert_eq!(interface.device().name(), "eth0");
+    assert_eq!(interface.mac_address(), QEMU_MAC);
+}
+
+// a request and its answer through both queues of the device
+#[test_case]
+fn gateway_answers_arp() {
+    let interface = net::interface().unwrap();
+    let gateway = net::config().gateway.unwrap();
+    let result = block_on(async move {
+        let run = Box::pin(interface.run());
+        let resolve = Box::pin(arp::resolve(&interface, gateway));
+        match select(resolve, run).await {
+            Either::Left((result, _)) => result,
+            Either::Right(((), _)) => unreachable!(),
+        }
+    });
+    assert_eq!(result, Ok(GATEWAY_MAC));
+}
+
+const OUR_MAC: MacAddress = MacAddress([0x52, 0x54, 0, 0, 0, 1]);
+const HOST_MAC: MacAddress = MacAddress([0x52, 0x54, 0, 0, 0, 2]);
+const HOST_IP: Ipv4Address = Ipv4Address([10, 0, 2, 100]);
+
+struct FakeDevice {
+    sent: Mutex<Vec<Vec<u8>>>,
+}
+
+impl NetDevice for FakeDevice {
+    fn name(&self) -> &str {
+        "fake0"
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        OUR_MAC
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.sent.lock().push(Vec::from(frame));
+        Ok(())
+    }
+
+    // the frames are handed to the stack by the tests
+    fn receive(&self) -> ReceiveFuture<'_> {
+        Box::pin(core::future::pending())
+    }
+}
+
+fn fake() -> (Arc<FakeDevice>, Arc<Interface>) {
+    let device = Arc::new(FakeDevice {
+        sent: Mutex::new(Vec::new()),
+    });
+    let interface = net::attach(device.clone());
+    (device, interface)
+}
+
+fn arp_frame(operation: u16, target: MacAddress, target_ip: Ipv4Address) -> Vec<u8> {
+    let packet = arp::Packet {
+        operation,
+        sender_mac: HOST_MAC,
+        sender_ip: HOST_IP,
+        target_mac: target,
+        target_ip,
+    };
+    let destination = match operation {
+        1 => MacAddress::BROADCAST,
+        _ => target,
+    };
+    Frame {
+        destination,
+        source: HOST_MAC,
+        ethertype: ethernet::TYPE_ARP,
+        payload: &packet.build(),
+    }
+    .build()
+}
+
+// A packet from the host to us
+fn ipv4_frame(protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let packet = ipv4::Packet {
+        source: HOST_IP,
+        destination: net::config().address,
+        protocol,
+        payload,
+    };
+    Frame {
+        destination: OUR_MAC,
+        source: HOST_MAC,
+        ethertype: ethernet::TYPE_IPV4,
+        payload: &packet.build(1),
+    }
+    .build()
+}
+
+// A UDP datagram from the host, without a checksum
+fn udp_frame(source_port: u16, port: u16, data: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&port.to_be_bytes());
+    datagram.extend_from_slice(&(8 + data.len() as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+    ipv4_frame(ipv4::PROTOCOL_UDP, &datagram)
+}
+
+#[test_case]
+fn arp_request_is_answered() {
+    let (device, interface) = fake();
+    let our_ip = net::config().address;
+    interface.handle_frame(&arp_frame(1, MacAddress([0; 6]), our_ip));
+
+    let sent = device.sent.lock();
+    assert_eq!(sent.len(), 1);
+    let frame = Frame::parse(&sent[0]).unwrap();
+    assert_eq!(frame.destination, HOST_MAC);
+    assert_eq!(frame.ethertype, ethernet::TYPE_ARP);
+    let reply = arp::Packet::parse(frame.payload).unwrap();
+    assert_eq!(reply.operation, 2);
+    assert_eq!((reply.sender_mac, reply.sender_ip), (OUR_MAC, our_ip));
+    assert_eq!((reply.target_mac, reply.target_ip), (HOST_MAC, HOST_IP));
+    // the host that asked is remembered
+    assert_eq!(arp::lookup(HOST_IP), Some(HOST_MAC));
+}
+
+#[test_case]
+fn arp_for_others_is_ignored() {
+    let (device, interface) = fake();
+    interface.handle_frame(&arp_frame(
+        1,
+        MacAddress([0; 6]),
+        Ipv4Address([10, 0, 2, 99]),
+    ));
+    assert!(device.sent.lock().is_empty());
+    assert_eq!(arp::lookup(HOST_IP), None);
+}
+
+#[test_case]
+fn ping_is_answered() {
+    let (device, interface) = fake();
+    let mut request = vec![8, 0, 0, 0, 0x12, 0x34, 0, 7];
+    request.extend_from_slice(b"ping data");
+    let checksum = ipv4::checksum(0, &request);
+    request[2..4].copy_from_slice(&checksum.to_be_bytes());
+    interface.handle_frame(&ipv4_frame(ipv4::PROTOCOL_ICMP, &request));
+
+    let sent = device.sent.lock();
+    assert_eq!(sent.len(), 1);
+    let frame = Frame::parse(&sent[0]).unwrap();
+    assert_eq!(frame.destination, HOST_MAC);
+    let packet = ipv4::Packet::parse(frame.payload).expect("invalid IPv4 packet");
+    assert_eq!(packet.source, net::config().address);
+    assert_eq!(packet.destination, HOST_IP);
+    assert_eq!(packet.protocol, ipv4::PROTOCOL_ICMP);
+    let reply = packet.payload;
+    assert_eq!(ipv4::checksum(0, reply), 0);
+    // an echo reply with the identifier, the sequence number and the data of the request
+    assert_eq!(reply[0], 0);
+    assert_eq!(reply[4..], request[4..]);
+}
+
+#[test_case]
+fn udp_datagram_is_delivered() {
+    let (_device, interface) = fake();
+    let socket = UdpSocket::bind(5000).unwrap();
+    interface.handle_frame(&udp_frame(1234, 5000, b"hello"));
+    // nobody listens on this port
+    interface.handle_frame(&udp_frame(1234, 5001, b"lost"));
+
+    let datagram = block_on(async move { socket.recv_from().await });
+    assert_eq!(datagram.data, b"hello");
+    assert_eq!((datagram.source, datagram.source_port), (HOST_IP, 1234));
+}
+
+// the host is not in the ARP cache yet, so the stack has to ask for it first
+#[test_case]
+fn udp_send_resolves_address() {
+    let (device, interface) = fake();
+    let socket = UdpSocket::bind(0).unwrap();
+    let local_port = socket.local_port();
+    let host = device.clone();
+    let result = block_on(async move {
+        let send = socket.send_to(b"to the host", HOST_IP, 9000);
+        let answer = async move {
+            while host.sent.lock().is_empty() {
+                task::yield_now().await;
+            }
+            interface.handle_frame(&arp_frame(2, OUR_MAC, net::config().address));
+        };
+        join(send, answer).await.0
+    });
+    assert_eq!(result, Ok(()));
+
+    let sent = device.sent.lock();
+    assert_eq!(sent.len(), 2);
+    let request = arp::Packet::parse(Frame::parse(&sent[0]).unwrap().payload).unwrap();
+    assert_eq!((request.operation, request.target_ip), (1, HOST_IP));
+    let frame = Frame::parse(&sent[1]).unwrap();
+    assert_eq!(frame.destination, HOST_MAC);
+    let packet = ipv4::Packet::parse(frame.payload).unwrap();
+    assert_eq!(packet.destination, HOST_IP);
+    let datagram = packet.payload;
+    assert_eq!(datagram[0..2], local_port.to_be_bytes());
+    assert_eq!(datagram[2..4], 9000u16.to_be_bytes());
+    assert_eq!(&datagram[8..], b"to the host");
+}
+
+#[test_case]
+fn udp_to_ourselves() {
+    let (device, _interface) = fake();
+    let receiver = UdpSocket::bind(6000).unwrap();
+    let sender = UdpSocket::bind(0).unwrap();
+    let sender_port = sender.local_port();
+    let datagram = block_on(async move {
+        let our_ip = net::config().address;
+        sender.send_to(b"loopback", our_ip, 6000).await.unwrap();
+        receiver.recv_from().await
+    });
+    assert_eq!(datagram.data, b"loopback");
+    assert_eq!(datagram.source_port, sender_port);
+    assert!(device.sent.lock().is_empty());
+}
+
+#[test_case]
+fn ports_are_exclusive() {
+    let socket = UdpSocket::bind(7000).unwrap();
+    assert_eq!(UdpSocket::bind(7000).err(), Some(NetError::AddressInUse));
+    drop(socket);
+    assert!(UdpSocket::bind(7000).is_ok());
+}
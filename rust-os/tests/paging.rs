@@ -0,0 +1,127 @@
+// Tests for the page table walker and for mapping ranges with huge pages
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::memory::{self, phys_to_virt, BootInfoFrameAllocator};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, Translate, TranslateResult},
+        OffsetPageTable, PageSize, PageTableFlags, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+static MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    *MEMORY.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    unsafe { memory::translate_addr(addr, memory::physical_memory_offset()) }
+}
+
+// the page size that `addr` is mapped with
+fn page_size(mapper: &OffsetPageTable, addr: VirtAddr) -> u64 {
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, .. } => match frame {
+            MappedFrame::Size4KiB(_) => Size4KiB::SIZE,
+            MappedFrame::Size2MiB(_) => Size2MiB::SIZE,
+            MappedFrame::Size1GiB(frame) => frame.size(),
+        },
+        _ => panic!("{:?} is not mapped", addr),
+    }
+}
+
+// The bootloader maps the complete physical memory with huge pages, which the walker used to
+// panic on
+#[test_case]
+fn translate_physical_memory_mapping() {
+    for &phys in &[0xb8000, 0x10_0000, 0x20_1234, 0x3ff_fff8] {
+        let phys = PhysAddr::new(phys);
+        assert_eq!(translate(phys_to_virt(phys)), Some(phys));
+    }
+    let memory = MEMORY.lock();
+    let (mapper, _) = memory.as_ref().unwrap();
+    assert!(page_size(mapper, phys_to_virt(PhysAddr::new(0x20_0000))) > Size4KiB::SIZE);
+}
+
+#[test_case]
+fn translate_unmapped() {
+    assert_eq!(translate(VirtAddr::new(0x_7777_0000_0000)), None);
+}
+
+#[test_case]
+fn map_range_uses_huge_pages() {
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    // 4 KiB before a 2 MiB boundary, then a full 2 MiB page, then 4 KiB again
+    let start = VirtAddr::new(0x_6666_0000_0000 - Size4KiB::SIZE);
+    let size = Size4KiB::SIZE + Size2MiB::SIZE + Size4KiB::SIZE;
+    unsafe { memory::map_range(start, size, flags, mapper, frame_allocator).unwrap() };
+
+    assert_eq!(page_size(mapper, start), Size4KiB::SIZE);
+    assert_eq!(page_size(mapper, start + Size4KiB::SIZE), Size2MiB::SIZE);
+    assert_eq!(page_size(mapper, start + size - 1u64), Size4KiB::SIZE);
+
+    // the walker agrees with the mapper, and the memory is usable
+    for offset in (0..size).step_by(0x1_0008) {
+        let addr = start + offset;
+        let phys = translate(addr).expect("not mapped");
+        assert_eq!(Some(phys), mapper.translate_addr(addr));
+        unsafe { addr.as_mut_ptr::<u64>().write_volatile(offset) };
+        assert_eq!(
+            unsafe { phys_to_virt(phys).as_ptr::<u64>().read_volatile() },
+            offset
+        );
+    }
+}
+
+#[test_case]
+fn map_physical_range_falls_back_to_small_pages() {
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    // the virtual address is 2 MiB aligned, but the physical address is not
+    let start = VirtAddr::new(0x_6666_4000_0000);
+    let phys = PhysAddr::new(0x40_1000);
+    unsafe {
+        memory::map_physical_range(start, phys, Size2MiB::SIZE, flags, mapper, frame_allocator)
+            .unwrap()
+    };
+    assert_eq!(page_size(mapper, start), Size4KiB::SIZE);
+    assert_eq!(translate(start + 0x1234u64), Some(phys + 0x1234u64));
+
+    // with both addresses aligned, a huge page is used
+    let start = VirtAddr::new(0x_6666_8000_0000);
+    let phys = PhysAddr::new(0x40_0000);
+    unsafe {
+        memory::map_physical_range(start, phys, Size2MiB::SIZE, flags, mapper, frame_allocator)
+            .unwrap()
+    };
+    assert_eq!(page_size(mapper, start), Size2MiB::SIZE);
+    assert_eq!(translate(start + 0x1f_fff8u64), Some(phys + 0x1f_fff8u64));
+}
@@ -0,0 +1,115 @@
+// A panic has to come with a backtrace that names the functions that led to it. Like the kernel's
+// panic handler, this one prints the backtrace over serial, after checking that it lists the
+// functions below from the innermost one outwards. This doesn't use the test runner, since the
+// panic handler can't return to it.
+#![no_std]
+#![no_main]
+
+use core::{
+    fmt::{self, Write},
+    hint::black_box,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    backtrace::{self, Backtrace},
+    exit_qemu, memory, serial_print, serial_println, QemuExitCode,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// the functions that have to show up in the backtrace, in this order
+const EXPECTED: [&str; 4] = [
+    "panic_backtrace::inner+",
+    "panic_backtrace::middle+",
+    "panic_backtrace::outer+",
+    "panic_backtrace::main+",
+];
+
+// set right before the panic, so that no other panic passes the test
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    unsafe { backtrace::symbols::load(&boot_info.memory_map) }.expect("no symbol table");
+
+    serial_print!("panic_backtrace::backtrace_names_functions...\t");
+    let result = outer();
+    serial_println!("[test did not panic] {}", result);
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::hlt_loop();
+}
+
+// Every function does something after its call, so that the call can't become a jump and the
+// caller's frame stays on the stack
+#[inline(never)]
+fn outer() -> u32 {
+    middle() + 1
+}
+
+#[inline(never)]
+fn middle() -> u32 {
+    inner() + 1
+}
+
+#[inline(never)]
+fn inner() -> u32 {
+    if black_box(true) {
+        PANICKING.store(true, Ordering::SeqCst);
+        panic!("deliberate panic");
+    }
+    0
+}
+
+// The heap isn't initialized, so the backtrace is formatted into a fixed buffer
+struct Buffer {
+    bytes: [u8; 4096],
+    len: usize,
+}
+
+impl fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.bytes
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !PANICKING.load(Ordering::SeqCst) {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+        rust_os::hlt_loop();
+    }
+
+    let mut buffer = Buffer {
+        bytes: [0; 4096],
+        len: 0,
+    };
+    let written = write!(buffer, "{}", Backtrace::here()).is_ok();
+    let backtrace = core::str::from_utf8(&buffer.bytes[..buffer.len]).unwrap_or("");
+    let positions = EXPECTED.map(|name| backtrace.find(name));
+    let in_order = positions
+        .windows(2)
+        .all(|pair| matches!(pair, [Some(a), Some(b)] if a < b));
+    if written && in_order {
+        serial_println!("[ok]");
+        serial_print!("{}", backtrace);
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: expected {:?} in\n{}", EXPECTED, backtrace);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rust_os::hlt_loop();
+}
@@ -0,0 +1,81 @@
+// Scans the PCI buses of QEMU's default machine and checks that its standard devices are found.
+// The default machine is the i440FX, which has no MCFG, so the configuration ports are used.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator,
+    memory::{self, BootInfoFrameAllocator},
+    pci::{self, Bar, PciAddress},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    pci::init();
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn host_bridge_is_first() {
+    let devices = pci::devices();
+    let (host_bridge, _) = devices.first().expect("no PCI devices");
+    assert_eq!(host_bridge.address, PciAddress::new(0, 0, 0));
+    assert!(host_bridge.has_class(0x06, 0x00));
+}
+
+// QEMU's standard VGA card, with its framebuffer in the first BAR
+#[test_case]
+fn vga_is_found() {
+    let devices = pci::devices();
+    let (vga, _) = devices
+        .iter()
+        .find(|(device, _)| device.is(0x1234, 0x1111))
+        .expect("no VGA card");
+    assert!(vga.has_class(0x03, 0x00));
+    match vga.bars[0] {
+        Some(Bar::Memory { size, .. }) => assert_eq!(size, 16 << 20),
+        bar => panic!("unexpected framebuffer BAR {:?}", bar),
+    }
+}
+
+// the IDE controller of the i440FX, or the AHCI controller of q35
+#[test_case]
+fn disk_controller_is_found() {
+    let found = pci::devices()
+        .iter()
+        .any(|(device, _)| device.has_class(0x01, 0x01) || device.has_class(0x01, 0x06));
+    assert!(found);
+}
+
+#[test_case]
+fn devices_are_claimed_once() {
+    let is_vga = |device: &pci::PciDevice| device.has_class(0x03, 0x00);
+    let vga = pci::claim("test", is_vga).expect("VGA card not claimable");
+    assert!(pci::claim("other", is_vga).is_none());
+    let registered = pci::devices()
+        .into_iter()
+        .find(|(device, _)| device.address == vga.address)
+        .unwrap();
+    assert_eq!(registered.1, Some("test"));
+}
@@ -0,0 +1,128 @@
+// Starts the application processors and checks that the tasks of the executor run on all of them.
+// The test arguments in Cargo.toml give QEMU four CPUs.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    interrupts::apic,
+    percpu, smp,
+    task::{executor, Task},
+    timer,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    memory::with_kernel_memory(|memory| {
+        apic::init(&mut memory.mapper, &mut memory.frame_allocator)
+    })
+    .expect("APIC initialization failed");
+    smp::init().expect("starting the application processors failed");
+
+    // the boot CPU runs the tests instead of an executor, so the tasks only run on the others
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// Halts until `done` returns true, panics if that takes longer than `ticks`
+fn wait_for(ticks: u64, done: impl Fn() -> bool) {
+    let deadline = timer::ticks() + ticks;
+    while !done() {
+        assert!(timer::ticks() < deadline, "timed out");
+        x86_64::instructions::hlt();
+    }
+}
+
+// the bits of the application processors
+fn all_aps() -> u64 {
+    (1 << percpu::count()) - 2
+}
+
+#[test_case]
+fn every_cpu_is_started() {
+    let madt = apic::madt().expect("no MADT");
+    assert_eq!(percpu::count(), madt.processors.len());
+    assert_eq!(percpu::count(), 4);
+}
+
+#[test_case]
+fn cpus_have_their_own_data() {
+    let cpus: Vec<_> = (0..percpu::count())
+        .map(|index| percpu::get(index).expect("CPU missing"))
+        .collect();
+    assert!(percpu::current().is_boot_cpu());
+    for (index, cpu) in cpus.iter().enumerate() {
+        assert_eq!(cpu.index(), index);
+        assert!(cpu.tss().is_some());
+        for other in &cpus[..index] {
+            assert_ne!(cpu.apic_id(), other.apic_id());
+            let (tss, other_tss) = (cpu.tss().unwrap(), other.tss().unwrap());
+            assert!(!core::ptr::eq(tss, other_tss));
+        }
+    }
+}
+
+// The tasks are all spawned on the boot CPU, and every task keeps its CPU busy until tasks have
+// run on all application processors, so they only finish if every CPU steals some
+#[test_case]
+fn tasks_are_stolen_by_every_cpu() {
+    static CPUS: AtomicU64 = AtomicU64::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    let tasks = 2 * (percpu::count() - 1);
+
+    for _ in 0..tasks {
+        executor::spawn(Task::new(async {
+            CPUS.fetch_or(1 << percpu::index(), Ordering::SeqCst);
+            while CPUS.load(Ordering::SeqCst) != all_aps() {
+                core::hint::spin_loop();
+            }
+            DONE.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    wait_for(500, || DONE.load(Ordering::SeqCst) == tasks);
+    assert_eq!(CPUS.load(Ordering::SeqCst), all_aps());
+}
+
+// The timer interrupt wakes the tasks on the boot CPU, while the CPUs that polled them are halted
+#[test_case]
+fn halted_cpus_are_woken_up() {
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    const TASKS: usize = 6;
+
+    for i in 0..TASKS {
+        executor::spawn(Task::new(async move {
+            for _ in 0..3 {
+                timer::sleep(Duration::from_millis(10 * i as u64)).await;
+            }
+            DONE.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    wait_for(500, || DONE.load(Ordering::SeqCst) == TASKS);
+}
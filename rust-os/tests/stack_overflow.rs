@@ -0,0 +1,78 @@
+// Overflows the kernel stack and checks that the resulting double fault is handled on the
+// separate stack from the Interrupt Stack Table. Like `should_panic`, this is a single test
+// without the test runner, since there is no way to return from the double fault handler.
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::{arch::asm, panic::PanicInfo};
+
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use rust_os::{exit_qemu, gdt, serial_print, serial_println, QemuExitCode};
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_overflow::stack_overflow...\t");
+
+    // we load our own IDT, whose double fault handler exits QEMU instead of panicking
+    gdt::init();
+    init_test_idt();
+
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    // prevents tail call optimizations, which would turn the recursion into a loop
+    volatile::Volatile::new(0).read();
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+
+    // if the CPU had not switched stacks, we would not even get here, but we still make sure that
+    // we are running on the stack we set up for double faults
+    if gdt::double_fault_stack().contains(&VirtAddr::new(rsp)) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("double fault handler runs on the wrong stack: {:#x}", rsp);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
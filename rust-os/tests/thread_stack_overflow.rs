@@ -0,0 +1,82 @@
+// Overflows the stack of a kernel thread and checks that it runs into the unmapped guard page
+// below the stack, instead of into whatever lies below it. The page fault can't be handled on the
+// overflowed stack, so it turns into a double fault, which runs on its own stack and panics. Like
+// `stack_overflow`, this doesn't use the test runner, since the panic handler can't return to it.
+#![no_std]
+#![no_main]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, exit_qemu,
+    memory::{self, BootInfoFrameAllocator},
+    serial_print, serial_println, thread, QemuExitCode,
+};
+use x86_64::{registers::control::Cr2, VirtAddr};
+
+entry_point!(main);
+
+// the size of a thread stack, the guard page lies right below it
+const STACK_SIZE: u64 = 64 * 1024;
+
+// an address near the top of the overflowing thread's stack, set before it overflows
+static STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    thread::init();
+
+    serial_print!("thread_stack_overflow::guard_page_faults...\t");
+    thread::spawn_thread("overflow", || {
+        let local = 0u8;
+        STACK_TOP.store(&local as *const u8 as u64, Ordering::SeqCst);
+        stack_overflow();
+    })
+    .join();
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::hlt_loop();
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    // for each recursion, the return address is pushed
+    stack_overflow();
+    // prevents tail call optimizations, which would turn the recursion into a loop
+    volatile::Volatile::new(0).read();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let top = STACK_TOP.load(Ordering::SeqCst);
+    let address = Cr2::read().as_u64();
+    let offset = memory::physical_memory_offset();
+    // the faulting access was below the stack, but not further than the guard page, and nothing
+    // is mapped there
+    let in_guard_page = top != 0
+        && address < top
+        && top - address <= STACK_SIZE + 4096
+        && unsafe { memory::translate_addr(VirtAddr::new(address), offset) }.is_none();
+    if in_guard_page {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: fault at {:#x}, stack top {:#x}\n{}",
+            address,
+            top,
+            info
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rust_os::hlt_loop();
+}
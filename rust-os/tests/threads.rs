@@ -0,0 +1,96 @@
+// Tests for the kernel threads and the preemptive scheduler
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator,
+    memory::{self, BootInfoFrameAllocator},
+    thread, timer,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    thread::init();
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_the_result() {
+    let handle = thread::spawn_thread("answer", || 6 * 7);
+    assert_eq!(handle.join(), 42);
+}
+
+// Neither thread yields, so they only make progress together if the timer interrupt switches
+// between them
+#[test_case]
+fn threads_are_preempted() {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    let handle = thread::spawn_thread("spinner", || {
+        while !STOP.load(Ordering::SeqCst) {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    while COUNTER.load(Ordering::SeqCst) == 0 {
+        core::hint::spin_loop();
+    }
+    STOP.store(true, Ordering::SeqCst);
+    handle.join();
+}
+
+#[test_case]
+fn sleep_waits_for_the_duration() {
+    let duration = Duration::from_millis(50);
+    let handle = thread::spawn_thread("sleeper", move || {
+        let start = timer::ticks();
+        thread::sleep(duration);
+        timer::ticks() - start
+    });
+    assert!(handle.join() >= timer::duration_to_ticks(duration));
+}
+
+#[test_case]
+fn many_threads() {
+    let handles: Vec<_> = (0..20u64)
+        .map(|i| {
+            thread::spawn_thread("worker", move || {
+                thread::yield_now();
+                i * i
+            })
+        })
+        .collect();
+    let sum: u64 = handles.into_iter().map(|handle| handle.join()).sum();
+    assert_eq!(sum, (0..20u64).map(|i| i * i).sum());
+    // a thread can still be about to exit after its result was stored, but eventually only
+    // `main` and `idle` are left
+    while thread::threads().len() > 2 {
+        thread::yield_now();
+    }
+}
@@ -0,0 +1,92 @@
+// Runs the embedded user programs in ring 3 and checks how they exit. The programs that fault
+// must only kill their process, the tests after them still run.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator,
+    memory::{self, frame_stats, BootInfoFrameAllocator},
+    process::{programs, syscall::SYSCALL_ERROR, ExitStatus, Process},
+    task::{simple_executor::SimpleExecutor, Task},
+};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// Runs the embedded program to the end and returns how it exited
+fn run(name: &'static str) -> ExitStatus {
+    static STATUS: Mutex<Option<ExitStatus>> = Mutex::new(None);
+
+    let program = programs::find(name).expect("no such program");
+    let process = Process::new(program.name, program.code()).expect("process creation failed");
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        *STATUS.lock() = Some(process.run().await);
+    }));
+    executor.run();
+    STATUS.lock().take().expect("process did not finish")
+}
+
+#[test_case]
+fn hello_exits() {
+    assert_eq!(run("hello"), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn kernel_memory_is_not_accessible() {
+    match run("fault") {
+        ExitStatus::Killed(fault) => {
+            assert_eq!(fault.name, "PAGE FAULT");
+            assert_eq!(
+                fault.address,
+                Some(VirtAddr::new(allocator::HEAP_START as u64))
+            );
+        }
+        status => panic!("process {}", status),
+    }
+}
+
+#[test_case]
+fn privileged_instructions_fault() {
+    match run("privileged") {
+        ExitStatus::Killed(fault) => assert_eq!(fault.name, "GENERAL PROTECTION FAULT"),
+        status => panic!("process {}", status),
+    }
+}
+
+#[test_case]
+fn write_rejects_kernel_memory() {
+    assert_eq!(run("bad-write"), ExitStatus::Exited(SYSCALL_ERROR));
+}
+
+// every frame of the address space is freed when the process ends
+#[test_case]
+fn process_memory_is_freed() {
+    let before = frame_stats();
+    assert_eq!(run("hello"), ExitStatus::Exited(0));
+    assert_eq!(frame_stats(), before);
+}
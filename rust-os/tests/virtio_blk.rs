@@ -0,0 +1,119 @@
+// Drives the virtio disk that the test-args in Cargo.toml add to QEMU. The disk is backed by
+// QEMU's null driver, so it reads as zeros, and the writes go to a temporary snapshot that QEMU
+// throws away when it exits. Every request goes through the queue and finishes with the device
+// interrupt.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator,
+    block::{self, BlockDevice, BlockError, RamDisk, BLOCK_SIZE},
+    interrupts::apic,
+    memory::{self, BootInfoFrameAllocator},
+    pci,
+    task::simple_executor::block_on,
+    virtio,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(mapper, frame_allocator).expect("heap initialization failed");
+    memory::with_kernel_memory(|memory| {
+        apic::init(&mut memory.mapper, &mut memory.frame_allocator)
+    })
+    .expect("APIC initialization failed");
+    pci::init();
+    virtio::init();
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn disk() -> Arc<dyn BlockDevice> {
+    block::find("vda").expect("no virtio disk")
+}
+
+fn read(disk: Arc<dyn BlockDevice>, start: u64, blocks: usize) -> Result<Vec<u8>, BlockError> {
+    block_on(async move {
+        // the disk reads as zeros, so every byte that is not overwritten is noticed
+        let mut buf = vec![0xaa; blocks * BLOCK_SIZE];
+        disk.read_blocks(start, &mut buf).await.map(|()| buf)
+    })
+}
+
+#[test_case]
+fn disk_is_registered() {
+    let disk = disk();
+    assert_eq!(disk.block_count(), 1024 * 1024 / BLOCK_SIZE as u64);
+    assert!(!disk.read_only());
+}
+
+#[test_case]
+fn read_finishes() {
+    let data = read(disk(), 0, 8).expect("read failed");
+    assert!(data.iter().all(|&byte| byte == 0));
+}
+
+// more blocks than fit into one request
+#[test_case]
+fn large_read_is_split() {
+    let data = read(disk(), 100, 300).expect("read failed");
+    assert!(data.iter().all(|&byte| byte == 0));
+}
+
+// more blocks than fit into one request, up to the end of the disk
+#[test_case]
+fn written_data_is_read_back() {
+    let disk = disk();
+    let start = disk.block_count() - 300;
+    // a pattern that differs between the blocks, so misplaced blocks are noticed as well
+    let data: Vec<u8> = (0..300 * BLOCK_SIZE).map(|i| (i / 7) as u8).collect();
+    let writer = disk.clone();
+    let written = data.clone();
+    block_on(async move { writer.write_blocks(start, &written).await }).expect("write failed");
+    assert_eq!(read(disk, start, 300), Ok(data));
+}
+
+#[test_case]
+fn requests_are_checked() {
+    let disk = disk();
+    let end = disk.block_count();
+    assert_eq!(read(disk.clone(), end - 1, 2), Err(BlockError::OutOfRange));
+    let result = block_on(async move {
+        let mut buf = [0; 100];
+        disk.read_blocks(0, &mut buf).await
+    });
+    assert_eq!(result, Err(BlockError::UnalignedBuffer));
+}
+
+#[test_case]
+fn ram_disk_keeps_data() {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("ram0", 16));
+    let writer = disk.clone();
+    block_on(async move {
+        let buf: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect();
+        writer.write_blocks(3, &buf).await
+    })
+    .expect("write failed");
+    let data = read(disk, 3, 2).expect("read failed");
+    assert!(data.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+}